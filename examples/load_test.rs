@@ -0,0 +1,129 @@
+//! Mixed read and write load against one house, first behind a plain mutex
+//! and then behind the shared house lock the web example uses. Readers
+//! serialize the house and build an energy report like `GET /api/home` and
+//! `GET /api/energy`; writers switch sockets and serialize the changed room
+//! like the room commands do.
+//!
+//! cargo run --release --example load_test -- [readers] [writers] [seconds]
+
+use smarthouse_web::devices::{Device, SmartSocket};
+use smarthouse_web::energy::Period;
+use smarthouse_web::events::now_millis;
+use smarthouse_web::registry::SharedHouse;
+use smarthouse_web::smarthouse::SmartHouse;
+use smarthouse_web::smartroom::SmartRoom;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+const ROOMS: usize = 20;
+const SOCKETS_PER_ROOM: usize = 10;
+
+#[derive(Clone)]
+enum Guarded {
+    Mutex(Arc<Mutex<SmartHouse>>),
+    Shared(SharedHouse),
+}
+
+impl Guarded {
+    async fn read(&self) -> usize {
+        let now = now_millis();
+        let read = |house: &SmartHouse| serde_json::to_vec(&house.view()).unwrap().len() + house.energy_in(Period::Day, now).rooms.len();
+        match self {
+            Guarded::Mutex(house) => read(&*house.lock().await),
+            Guarded::Shared(house) => read(&*house.read().await),
+        }
+    }
+
+    async fn write(&self, op: u64) -> usize {
+        let room_name = format!("Room {}", op as usize % ROOMS);
+        let device_name = format!("Socket {}", op as usize / ROOMS % SOCKETS_PER_ROOM);
+        let switch = |house: &mut SmartHouse| house.switch_socket(&room_name, &device_name, op.is_multiple_of(2)).unwrap();
+        let room = |house: &SmartHouse| serde_json::to_vec(&house.get_room_by_name(&room_name)).unwrap().len();
+        match self {
+            Guarded::Mutex(house) => {
+                let mut house = house.lock().await;
+                switch(&mut house);
+                room(&house)
+            }
+            Guarded::Shared(house) => {
+                let mut house = house.write().await;
+                switch(&mut house);
+                room(&house.downgrade())
+            }
+        }
+    }
+}
+
+fn build_house() -> SmartHouse {
+    let mut house = SmartHouse::new("Load".to_string());
+    for room in 0..ROOMS {
+        let room_name = format!("Room {}", room);
+        house.add_smart_room(&SmartRoom::default(room_name.clone())).unwrap();
+        for socket in 0..SOCKETS_PER_ROOM {
+            let socket = SmartSocket::default(format!("Socket {}", socket));
+            house.add_device(&room_name, Device::SmartSocket(socket)).unwrap();
+        }
+    }
+    house
+}
+
+/// Operations per second of the readers and of the writers.
+async fn run(house: Guarded, readers: usize, writers: usize, duration: Duration) -> (f64, f64) {
+    let (reads, writes) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+    let deadline = Instant::now() + duration;
+    let mut tasks = Vec::new();
+    for _ in 0..readers {
+        let (house, reads) = (house.clone(), reads.clone());
+        tasks.push(tokio::spawn(async move {
+            while Instant::now() < deadline {
+                std::hint::black_box(house.read().await);
+                reads.fetch_add(1, Ordering::Relaxed);
+                tokio::task::yield_now().await;
+            }
+        }));
+    }
+    for _ in 0..writers {
+        let (house, writes) = (house.clone(), writes.clone());
+        tasks.push(tokio::spawn(async move {
+            while Instant::now() < deadline {
+                let op = writes.fetch_add(1, Ordering::Relaxed);
+                std::hint::black_box(house.write(op).await);
+                tokio::task::yield_now().await;
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let seconds = duration.as_secs_f64();
+    (reads.load(Ordering::Relaxed) as f64 / seconds, writes.load(Ordering::Relaxed) as f64 / seconds)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<u64>().expect("numeric argument"));
+    let readers = args.next().unwrap_or(8) as usize;
+    let writers = args.next().unwrap_or(2) as usize;
+    let duration = Duration::from_secs(args.next().unwrap_or(3));
+    println!(
+        "{} readers, {} writers, {:?} per run, {} worker threads",
+        readers,
+        writers,
+        duration,
+        std::thread::available_parallelism().map_or(1, |threads| threads.get())
+    );
+
+    let mutex = Guarded::Mutex(Arc::new(Mutex::new(build_house())));
+    let (mutex_reads, mutex_writes) = run(mutex, readers, writers, duration).await;
+    println!("mutex:       {:>10.0} reads/s {:>10.0} writes/s", mutex_reads, mutex_writes);
+
+    let shared = Guarded::Shared(Arc::new(RwLock::new(build_house())));
+    let (shared_reads, shared_writes) = run(shared, readers, writers, duration).await;
+    println!("shared lock: {:>10.0} reads/s {:>10.0} writes/s", shared_reads, shared_writes);
+
+    let total = |reads: f64, writes: f64| reads + writes;
+    println!("speedup:     {:>10.2}x", total(shared_reads, shared_writes) / total(mutex_reads, mutex_writes));
+}
@@ -1,34 +1,92 @@
 use actix_web::body::BoxBody;
-use actix_web::http::StatusCode;
-use actix_web::{web, App, HttpResponse, HttpServer, ResponseError, Scope};
+use actix_web::dev::{HttpServiceFactory, Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{ETag, EntityTag, IfMatch};
+use actix_web::http::{StatusCode, Uri};
+use actix_web::{
+    delete, get, post, put, web, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer, ResponseError, Scope,
+};
+use rumqttc::Transport;
 use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+use utoipa_rapidoc::RapiDoc;
 
-use smarthouse_web::devices::{Device, SmartSocket, SmartThermometer};
-use smarthouse_web::smarthouse::{BorrowingDeviceInfoProvider, OwningDeviceInfoProvider, SmartHouse};
-use smarthouse_web::smartroom::SmartRoom;
+use smarthouse_web::devices::{
+    redacted, AccessCode, AccessMethod, ContactSensor, Cover, CoverKind, CoverMotion, CoverState, Device, DeviceKind,
+    EnergyMeter, EnvironmentSensor, Hazard, LightState, MeterReading, Metric, MotionSensor, PhaseReading, SafetyDetector,
+    SmartLight, SmartLock, SmartSocket, SmartThermometer, Thermostat, ThermostatSettings,
+};
+use smarthouse_web::actor::{
+    self, actuate, ActorConfig, ActorError, ActorState, DeviceCommand, DeviceHandle, DeviceReply, SimulatedConnection,
+};
+use smarthouse_web::availability::{Availability, DeviceAvailability, Heartbeat};
+use smarthouse_web::area::{Area, AreaKind, AreaNode, HouseTree, LevelSummary, RoomNode};
+use smarthouse_web::batch::BatchOutcome;
+use smarthouse_web::bridge::{BridgeConfig, MqttBridge};
+use smarthouse_web::modbus::{ModbusConnection, ModbusSimulator, Register, RegisterMap};
+use smarthouse_web::discovery::{Announcement, Candidate, Capability, DeviceEmulator, Discovery, DiscoveryError, ProbeFailure};
+use smarthouse_web::energy::{DeviceEnergy, EnergyReport, Period, RoomEnergy};
+use smarthouse_web::events::{now_millis, EventRecord, HouseEvent, HouseSettings};
+use smarthouse_web::group::{DeviceQuery, Group, GroupSummary, TaggedDevice};
+use smarthouse_web::metrics::{Exposition, HttpMetrics, CONTENT_TYPE};
+use smarthouse_web::registry::{HouseInfo, HouseRegistry, RegistryError, SharedHouse, DEFAULT_HOUSE};
+use smarthouse_web::smarthouse::{
+    BorrowingDeviceInfoProvider, HouseView, OwningDeviceInfoProvider, SmartHouse, SmartHouseError,
+};
+use smarthouse_web::smartroom::{MetricSummary, Occupancy, SmartRoom};
+use smarthouse_web::subscription::{Alarm, EventFilter, HouseAlarm, Notification};
+use smarthouse_web::tariff::{Band, CostReport, DeviceCost, MonthProjection, Pricing, RoomCost, Tariff};
+use smarthouse_web::thermostat::{converged, plan, Simulation, SimulationModel, MAX_SIMULATION_STEPS};
+use smarthouse_web::transition::{fade, travel};
 
+use std::collections::BTreeSet;
 use std::error::Error as StdError;
-use std::ops::Deref;
+use std::future::{ready, Future, Ready};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::sync::Mutex;
+
+const UNDO_DEPTH: usize = 20;
+const REGULATION_PERIOD: Duration = Duration::from_secs(30);
 
 pub type CustomResult<T> = Result<T, CustomError>;
 
-#[derive(Debug, Error, Clone, Serialize, Deserialize)]
+#[derive(Debug, Error, Clone, Serialize, Deserialize, ToSchema)]
 pub enum CustomError {
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+
     #[error("Internal server error: {0}")]
     InternalError(String),
+
+    #[error("Gateway timeout: {0}")]
+    GatewayTimeout(String),
 }
 
 impl ResponseError for CustomError {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
             Self::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 
@@ -38,50 +96,649 @@ impl ResponseError for CustomError {
     }
 }
 
+impl From<SmartHouseError> for CustomError {
+    fn from(err: SmartHouseError) -> Self {
+        match err {
+            SmartHouseError::RoomNotFound(_)
+            | SmartHouseError::DeviceNotFound(_)
+            | SmartHouseError::HistoryCompacted(_)
+            | SmartHouseError::TariffNotSet
+            | SmartHouseError::GroupNotFound(_)
+            | SmartHouseError::AreaNotFound(_) => {
+                Self::NotFound(err.to_string())
+            }
+            SmartHouseError::AddRoomError(_)
+            | SmartHouseError::InvalidDevice(_)
+            | SmartHouseError::InvalidTariff(_)
+            | SmartHouseError::InvalidArea(_)
+            | SmartHouseError::DeviceUnreachable(ActorError::Unsupported(_)) => {
+                Self::BadRequest(err.to_string())
+            }
+            SmartHouseError::DeviceUnreachable(_) => Self::GatewayTimeout(err.to_string()),
+            SmartHouseError::AccessDenied(_) => Self::Forbidden(err.to_string()),
+            SmartHouseError::RoomAlreadyExists(_)
+            | SmartHouseError::DeviceAlreadyExists(_)
+            | SmartHouseError::NothingToUndo
+            | SmartHouseError::NothingToRedo
+            | SmartHouseError::AreaNotEmpty(_)
+            | SmartHouseError::UndoConflict(_) => Self::Conflict(err.to_string()),
+            _ => Self::InternalError(err.to_string()),
+        }
+    }
+}
+
+impl From<DiscoveryError> for CustomError {
+    fn from(err: DiscoveryError) -> Self {
+        match err {
+            DiscoveryError::CandidateNotFound(_) => Self::NotFound(err.to_string()),
+            DiscoveryError::Unsupported(_) => Self::BadRequest(err.to_string()),
+            DiscoveryError::Io(_) => Self::InternalError(err.to_string()),
+            DiscoveryError::House(err) => err.into(),
+        }
+    }
+}
+
+impl From<RegistryError> for CustomError {
+    fn from(err: RegistryError) -> Self {
+        match err {
+            RegistryError::HouseNotFound(_) => Self::NotFound(err.to_string()),
+            RegistryError::InvalidHouseId(_) => Self::BadRequest(err.to_string()),
+            RegistryError::HouseAlreadyExists(_) | RegistryError::DefaultHouse => Self::Conflict(err.to_string()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Context {
-    context: Arc<Mutex<SmartHouse>>,
+    houses: Arc<HouseRegistry>,
+    discovery: Option<Arc<Discovery>>,
+    metrics: Arc<HttpMetrics>,
 }
 
 impl Context {
     pub fn new(house : SmartHouse) -> Self {
-        let home = house;
-        let home = Mutex::new(home);
-        let home = Arc::new(home);
+        Self {
+            houses: Arc::new(HouseRegistry::new(house)),
+            discovery: None,
+            metrics: Arc::new(HttpMetrics::default()),
+        }
+    }
+
+    pub fn with_discovery(self, discovery: Discovery) -> Self {
+        Self {
+            discovery: Some(Arc::new(discovery)),
+            ..self
+        }
+    }
+
+    pub fn houses(&self) -> &HouseRegistry {
+        &self.houses
+    }
+
+    pub fn metrics(&self) -> &HttpMetrics {
+        &self.metrics
+    }
 
-        Self { context: home }
+    pub fn discovery(&self) -> CustomResult<&Discovery> {
+        self.discovery
+            .as_deref()
+            .ok_or_else(|| CustomError::NotFound("Discovery is disabled".to_string()))
     }
+}
+
+/// The house named by a `/api/houses/{house_id}/...` path.
+#[derive(Clone)]
+struct HouseId(String);
+
+/// The house a request is for: the one named in the path, or the default one.
+/// Queries read it side by side. Commands hold it exclusively only until
+/// their change is committed and downgrade to serialize the response.
+pub struct HouseContext {
+    context: SharedHouse,
+}
 
-    pub fn get_context(&self) -> &Arc<Mutex<SmartHouse>> {
+impl HouseContext {
+    pub fn get_context(&self) -> &SharedHouse {
         &self.context
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+impl FromRequest for HouseContext {
+    type Error = CustomError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let house_id = req
+            .extensions()
+            .get::<HouseId>()
+            .map_or_else(|| DEFAULT_HOUSE.to_string(), |house_id| house_id.0.clone());
+        let context = match req.app_data::<web::Data<Context>>() {
+            Some(ctx) => ctx.houses().get(&house_id).map_err(CustomError::from),
+            None => Err(CustomError::InternalError("No house registry".to_string())),
+        };
+        ready(context.map(|context| Self { context }))
+    }
+}
+
+/// Serves `/api/houses/{house_id}/...` with the routes of a single house:
+/// the path is rewritten to `/api/...` and the house is remembered for
+/// [`HouseContext`].
+fn route_house(req: &mut ServiceRequest) {
+    let Some((house_id, rest)) = req.path().strip_prefix("/api/houses/").and_then(|rest| rest.split_once('/')) else {
+        return;
+    };
+    if rest.is_empty() || rest == "houses" || rest.starts_with("houses/") {
+        return;
+    }
+    let uri = match req.query_string() {
+        "" => format!("/api/{}", rest),
+        query => format!("/api/{}?{}", rest, query),
+    };
+    let (house_id, Ok(uri)) = (HouseId(house_id.to_string()), uri.parse::<Uri>()) else {
+        return;
+    };
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+    req.extensions_mut().insert(house_id);
+}
+
+/// Records each answered request in [`Context::metrics`] under its route
+/// pattern. Requests no route matched share the `unmatched` label.
+fn observe<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let ctx = req.app_data::<web::Data<Context>>().cloned();
+    let response = srv.call(req);
+    async move {
+        let response = response.await?;
+        if let Some(ctx) = ctx {
+            let route = response.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+            ctx.metrics().record(&method, &route, response.status().as_u16(), started.elapsed());
+        }
+        Ok(response)
+    }
+}
+
+/// Leaves the PIN hashes out of every response, see [`redacted`].
+fn redact<S, B>(req: ServiceRequest, srv: &S) -> Redacted<S::Future>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    Redacted(Box::pin(srv.call(req)))
+}
+
+/// A future polled with secrets redacted. Handlers serialize their
+/// responses while they are polled.
+struct Redacted<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for Redacted<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<F::Output> {
+        redacted(|| self.0.as_mut().poll(cx))
+    }
+}
+
+type IfMatchHeader = Option<web::Header<IfMatch>>;
+
+fn etag(revision: u64) -> ETag {
+    ETag(EntityTag::new_strong(revision.to_string()))
+}
+
+/// Rejects the request when `If-Match` doesn't name the current revision.
+fn check_revision(if_match: &IfMatchHeader, revision: u64) -> CustomResult<()> {
+    let current = EntityTag::new_strong(revision.to_string());
+    match if_match.as_deref() {
+        // A request without the header parses as an empty list of tags
+        Some(IfMatch::Items(tags)) if !tags.is_empty() && !tags.iter().any(|tag| tag.strong_eq(&current)) => {
+            Err(CustomError::PreconditionFailed(format!("Current revision: {}", revision)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn room_revision(house: &SmartHouse, room_name: &str) -> CustomResult<u64> {
+    house
+        .get_room_by_name(room_name)
+        .map(|room| room.revision)
+        .ok_or_else(|| CustomError::NotFound(format!("Room: {}", room_name)))
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct Data {
     name: String,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct DeviceData {
     name: String,
     device_type: DeviceType,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct LightCommand {
+    state: LightState,
+    /// Fade to the new state over this many milliseconds.
+    transition_ms: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct SensorState {
+    /// Motion detected or contact open.
+    active: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub enum CoverAction {
+    Open,
+    Close,
+    Stop,
+    /// Travel to this position, 0 is closed and 100 is open.
+    Position(u8),
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct CoverCommand {
+    action: CoverAction,
+    /// Slat angle in percent, blinds only.
+    tilt: Option<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct CoverCalibration {
+    /// Time from fully closed to fully open.
+    travel_ms: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct DetectorReport {
+    /// Water or smoke is detected right now.
+    detected: bool,
+    battery: u8,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct SelfTestResult {
+    passed: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct LockCommand {
+    locked: bool,
+    /// Operate the lock with a guest code instead of as the API.
+    pin: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct LockStatus {
+    jammed: bool,
+    battery: u8,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewAccessCode {
+    label: String,
+    pin: String,
+    /// Defaults to now.
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct MetricReading {
+    metric: Metric,
+    value: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct OccupancySettings {
+    timeout_ms: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct HeartbeatSettings {
+    timeout_ms: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct SimulationRequest {
+    model: SimulationModel,
+    /// At most 10000.
+    steps: usize,
+    step_minutes: u32,
+    /// Allowed deviation from the target once the room has settled.
+    tolerance: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct SimulationResult {
+    temperatures: Vec<f32>,
+    converged: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub enum DeviceType {
     Socket,
     Thermo,
+    Light,
+    Motion,
+    Contact,
+    Thermostat,
+    Lock,
+    Blind,
+    Shutter,
+    GarageDoor,
+    LeakDetector,
+    SmokeDetector,
+    EnergyMeter,
+    Humidity,
+    Co2,
+    AirQuality,
+}
+
+impl DeviceData {
+    fn into_device(self) -> Device {
+        let name = self.name;
+        match self.device_type {
+            DeviceType::Socket => Device::SmartSocket(SmartSocket::default(name)),
+            DeviceType::Thermo => Device::SmartThermometr(SmartThermometer::default(name)),
+            DeviceType::Light => Device::SmartLight(SmartLight::default(name)),
+            DeviceType::Motion => Device::MotionSensor(MotionSensor::default(name)),
+            DeviceType::Contact => Device::ContactSensor(ContactSensor::default(name)),
+            DeviceType::Thermostat => Device::Thermostat(Thermostat::default(name)),
+            DeviceType::Lock => Device::SmartLock(SmartLock::default(name)),
+            DeviceType::Blind => Device::Cover(Cover::new(name, CoverKind::Blind)),
+            DeviceType::Shutter => Device::Cover(Cover::new(name, CoverKind::Shutter)),
+            DeviceType::GarageDoor => Device::Cover(Cover::new(name, CoverKind::GarageDoor)),
+            DeviceType::LeakDetector => Device::SafetyDetector(SafetyDetector::new(name, Hazard::Leak)),
+            DeviceType::SmokeDetector => Device::SafetyDetector(SafetyDetector::new(name, Hazard::Smoke)),
+            DeviceType::EnergyMeter => Device::EnergyMeter(EnergyMeter::default(name)),
+            DeviceType::Humidity => Device::EnvironmentSensor(EnvironmentSensor::humidity(name)),
+            DeviceType::Co2 => Device::EnvironmentSensor(EnvironmentSensor::co2(name)),
+            DeviceType::AirQuality => Device::EnvironmentSensor(EnvironmentSensor::air_quality(name)),
+        }
+    }
+}
+
+/// One step of a batch. Each runs through the same checks as its own route.
+/// Sockets are left out, they are switched through their devices.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub enum BatchOperation {
+    CreateRoom { name: String },
+    DeleteRoom { room_name: String },
+    RenameRoom { room_name: String, name: String },
+    PlaceRoom { room_name: String, area: Option<String> },
+    CreateDevice { room_name: String, device: DeviceData },
+    DeleteDevice { room_name: String, device_name: String },
+    MoveDevice { room_name: String, device_name: String, to: String },
+    SetTags { room_name: String, device_name: String, tags: BTreeSet<String> },
+    SetLight { room_name: String, device_name: String, state: LightState },
 }
- 
-#[derive(Clone, Serialize, Deserialize)]
+
+impl BatchOperation {
+    fn apply(self, house: &mut SmartHouse) -> Result<(), SmartHouseError> {
+        match self {
+            BatchOperation::CreateRoom { name } => house.add_smart_room(&SmartRoom::default(name)),
+            BatchOperation::DeleteRoom { room_name } => match house.get_room_by_name(&room_name).cloned() {
+                Some(room) => house.remove_smart_room(&room),
+                None => Err(SmartHouseError::RoomNotFound(room_name)),
+            },
+            BatchOperation::RenameRoom { room_name, name } => house.rename_room(&room_name, &name),
+            BatchOperation::PlaceRoom { room_name, area } => house.place_room(&room_name, area.as_deref()),
+            BatchOperation::CreateDevice { room_name, device } => house.add_device(&room_name, device.into_device()),
+            BatchOperation::DeleteDevice { room_name, device_name } => house.remove_device(&room_name, &device_name),
+            BatchOperation::MoveDevice { room_name, device_name, to } => house.move_device(&device_name, &room_name, &to),
+            BatchOperation::SetTags { room_name, device_name, tags } => house.set_tags(&room_name, &device_name, tags),
+            BatchOperation::SetLight { room_name, device_name, state } => house.set_light(&room_name, &device_name, state),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PowerReading {
+    watts: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewHouse {
+    /// Letters, digits, `-` and `_`.
+    house_id: String,
+    name: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProbeReport {
+    candidates: Vec<Candidate>,
+    /// Targets the probe couldn't be sent to.
+    failures: Vec<ProbeFailure>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Adoption {
+    room_name: String,
+    /// Defaults to the name the device suggested.
+    name: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct AreaData {
+    kind: AreaKind,
+    /// Floors are ordered by it, zones usually leave it out.
+    #[serde(default)]
+    level: i32,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoomPlacement {
+    /// Floor or zone, none to take the room off its area.
+    area: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeviceTags {
+    tags: BTreeSet<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct SocketSwitch {
+    status: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct GroupSwitch {
+    status: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct EnergyQuery {
+    /// Defaults to the current day.
+    period: Option<Period>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub enum Provider {
     Owning,
     Borrowing,
+    /// Daily, weekly and monthly totals with the top consumers of the month.
+    Energy,
+    /// Cost of the month so far and its projection.
+    Cost,
 }
 
-#[actix_web::main]
-async fn main() -> Result<(), Box<dyn StdError>> {
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        get_openapi,
+        get_houses,
+        create_house,
+        get_house,
+        delete_house,
+        get_home,
+        get_history,
+        get_home_as_of,
+        get_events,
+        get_alarms,
+        get_energy,
+        get_tariff,
+        set_tariff,
+        get_costs,
+        find_devices,
+        get_groups,
+        get_group,
+        set_group,
+        delete_group,
+        switch_group,
+        get_reports,
+        get_tree,
+        get_areas,
+        get_area,
+        set_area,
+        delete_area,
+        get_area_rooms,
+        get_area_room,
+        get_area_devices,
+        place_room,
+        get_rooms,
+        create_room,
+        delete_room,
+        rename_room,
+        get_devices,
+        create_devices,
+        delete_device,
+        move_device,
+        set_light,
+        set_sensor,
+        record_metric,
+        record_power,
+        switch_socket,
+        poll_device,
+        get_connection,
+        get_availability,
+        get_candidates,
+        probe_devices,
+        adopt_candidate,
+        get_device_availability,
+        record_heartbeat,
+        set_heartbeat_timeout,
+        record_meter,
+        set_tags,
+        get_environment,
+        set_cover,
+        calibrate_cover,
+        report_detector,
+        test_detector,
+        acknowledge_alarm,
+        set_lock,
+        report_lock,
+        get_access_codes,
+        add_access_code,
+        remove_access_code,
+        get_occupancy,
+        set_occupancy_timeout,
+        configure_thermostat,
+        simulate_thermostat,
+        undo,
+        redo,
+        batch,
+    ),
+    components(schemas(
+        Data,
+        NewHouse,
+        Adoption,
+        Announcement,
+        Candidate,
+        ProbeFailure,
+        ProbeReport,
+        Capability,
+        HouseInfo,
+        DeviceData,
+        DeviceType,
+        LightCommand,
+        SensorState,
+        MetricReading,
+        Metric,
+        PowerReading,
+        DeviceTags,
+        DeviceKind,
+        Area,
+        AreaKind,
+        AreaData,
+        AreaNode,
+        RoomNode,
+        HouseTree,
+        LevelSummary,
+        RoomPlacement,
+        DeviceQuery,
+        TaggedDevice,
+        Group,
+        GroupSwitch,
+        SocketSwitch,
+        DeviceCommand,
+        DeviceReply,
+        ActorState,
+        GroupSummary,
+        MeterReading,
+        PhaseReading,
+        EnergyQuery,
+        Period,
+        EnergyReport,
+        RoomEnergy,
+        DeviceEnergy,
+        Tariff,
+        Pricing,
+        Band,
+        CostReport,
+        RoomCost,
+        DeviceCost,
+        MonthProjection,
+        CoverAction,
+        CoverCommand,
+        CoverCalibration,
+        DetectorReport,
+        SelfTestResult,
+        Alarm,
+        HouseAlarm,
+        Notification,
+        LockCommand,
+        LockStatus,
+        NewAccessCode,
+        AccessCode,
+        MetricSummary,
+        OccupancySettings,
+        HeartbeatSettings,
+        Availability,
+        Heartbeat,
+        DeviceAvailability,
+        Occupancy,
+        ThermostatSettings,
+        SimulationRequest,
+        SimulationResult,
+        Provider,
+        CustomError,
+        SmartHouse,
+        HouseView,
+        HouseSettings,
+        SmartRoom,
+        Device,
+        HouseEvent,
+        EventRecord,
+        BatchOutcome,
+        BatchOperation,
+    )),
+    info(title = "Smart house API"),
+    servers(
+        (url = "/api", description = "The default house"),
+        (url = "/api/houses/{house_id}", description = "Any house of the registry",
+            variables(("house_id" = (default = "default", description = "Id of the house")))
+        ),
+    ),
+)]
+struct ApiDoc;
+
+fn build_house() -> SmartHouse {
     let socket = SmartSocket::default("Smart_socket".to_string());
     let thermo = SmartThermometer::default("Smart_thetmometr".to_string());
     
@@ -103,10 +760,168 @@ async fn main() -> Result<(), Box<dyn StdError>> {
     house.add_smart_room(&bathroom).unwrap();
     house.add_smart_room(&living).unwrap();
     house.add_smart_room(&hall.clone()).unwrap();
-    let ctx = Context::new(house);
+    house.set_area(Area::floor("Ground".to_string(), 0)).unwrap();
+    house.set_area(Area::floor("Upstairs".to_string(), 1)).unwrap();
+    for room in ["Kitchen", "Hall", "Living room"] {
+        house.place_room(room, Some("Ground")).unwrap();
+    }
+    house.place_room("Bathroom", Some("Upstairs")).unwrap();
+    house.set_undo_depth(UNDO_DEPTH);
+    house
+}
+
+/// Backs the sockets and thermometers with simulated connections, so
+/// commands go through device actors as they would for real hardware.
+fn connect_devices(house: &mut SmartHouse) {
+    let mut devices = Vec::new();
+    for room in house.get_rooms_list() {
+        for (device_name, device) in &room.smart_device {
+            let connection = match device {
+                Device::SmartSocket(_) => SimulatedConnection {
+                    latency: Duration::from_millis(20),
+                    power: Some(1_500.0),
+                    ..SimulatedConnection::default()
+                },
+                Device::SmartThermometr(_) => SimulatedConnection {
+                    latency: Duration::from_millis(20),
+                    temperature: Some(21.5),
+                    ..SimulatedConnection::default()
+                },
+                _ => continue,
+            };
+            devices.push((room.room_name.clone(), device_name.clone(), connection));
+        }
+    }
+    for (room_name, device_name, connection) in devices {
+        let handle = DeviceHandle::spawn(connection, ActorConfig::default());
+        house.attach_actor(&room_name, &device_name, handle).unwrap();
+    }
+}
+
+/// Backs the hall socket with a Modbus relay and meter, simulated on
+/// localhost: the coil switches it, holding registers hold decivolts and
+/// deciwatts.
+async fn connect_modbus(house: &mut SmartHouse) -> std::io::Result<ModbusSimulator> {
+    let map = RegisterMap {
+        coil: Some(0),
+        voltage: Some(Register::new(0, 0.1)),
+        power: Some(Register::wide(1, 0.1)),
+        energy: Some(Register::wide(3, 0.01)),
+    };
+    let simulator = ModbusSimulator::device(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), &map, 229.8, 850.0).await?;
+    let connection = ModbusConnection::new(simulator.local_addr(), 1, map);
+    house
+        .attach_actor("Hall", "Smart_socket", DeviceHandle::spawn(connection, ActorConfig::default()))
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+    Ok(simulator)
+}
+
+/// Listens for devices on the network. Two emulated devices on localhost
+/// are probed as well, so there's something to adopt.
+async fn start_discovery() -> std::io::Result<(Discovery, Vec<DeviceEmulator>)> {
+    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let socket = SimulatedConnection {
+        power: Some(2_000.0),
+        ..SimulatedConnection::default()
+    };
+    let thermometer = SimulatedConnection {
+        temperature: Some(19.0),
+        ..SimulatedConnection::default()
+    };
+    let emulators = vec![
+        DeviceEmulator::spawn(localhost, Announcement::socket("emulated-socket", "Kettle"), socket).await?,
+        DeviceEmulator::spawn(localhost, Announcement::thermometer("emulated-thermometer", "Thermometer"), thermometer).await?,
+    ];
+    let mut targets = Discovery::broadcast();
+    for emulator in &emulators {
+        targets.push(emulator.local_addr()?);
+    }
+    let discovery = Discovery::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), targets).await?;
+    // Unreachable targets are logged, the others are probed anyway
+    discovery.probe().await;
+    Ok((discovery, emulators))
+}
+
+/// Bridges the default house to the broker in `MQTT_BROKER` (`host:port`)
+/// under the topic prefix in `MQTT_PREFIX`, if a broker is set. It logs in
+/// with `MQTT_USERNAME` and `MQTT_PASSWORD` and uses TLS trusting the CA
+/// certificate in the PEM file `MQTT_CA`, if these are set. Home Assistant
+/// discovery goes to `HA_DISCOVERY_PREFIX`, off if it's empty.
+async fn start_bridge(ctx: &Context) -> Option<MqttBridge> {
+    let broker = std::env::var("MQTT_BROKER").ok()?;
+    let mut config = BridgeConfig::new(DEFAULT_HOUSE);
+    if let Ok(prefix) = std::env::var("MQTT_PREFIX") {
+        config.prefix = prefix;
+    }
+    if let Ok(prefix) = std::env::var("HA_DISCOVERY_PREFIX") {
+        config.discovery_prefix = Some(prefix).filter(|prefix| !prefix.is_empty());
+    }
+    let Some((host, port)) = broker.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse().ok()?))) else {
+        log::error!("MQTT_BROKER must be host:port, not {}", broker);
+        return None;
+    };
+    let mut options = config.mqtt_options(host, port);
+    if let (Ok(username), Ok(password)) = (std::env::var("MQTT_USERNAME"), std::env::var("MQTT_PASSWORD")) {
+        options.set_credentials(username, password);
+    }
+    if let Ok(ca) = std::env::var("MQTT_CA") {
+        match std::fs::read(&ca) {
+            Ok(ca) => {
+                options.set_transport(Transport::tls(ca, None, None));
+            }
+            Err(err) => {
+                log::error!("Reading the MQTT CA certificate failed: {}", err);
+                return None;
+            }
+        }
+    }
+    let house = ctx.houses().get(DEFAULT_HOUSE).ok()?;
+    match MqttBridge::start(house, options, config).await {
+        Ok(bridge) => Some(bridge),
+        Err(err) => {
+            log::error!("MQTT bridge to {} failed: {}", broker, err);
+            None
+        }
+    }
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn StdError>> {
+    let mut house = build_house();
+    connect_devices(&mut house);
+    let _modbus = connect_modbus(&mut house).await?;
+    let (discovery, _emulators) = start_discovery().await?;
+    let ctx = Context::new(house).with_discovery(discovery);
+    let _bridge = start_bridge(&ctx).await;
+    let registry = ctx.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(REGULATION_PERIOD);
+        loop {
+            interval.tick().await;
+            for (house_id, house) in registry.houses().houses() {
+                let now = now_millis();
+                match house.write().await.check_availability(now) {
+                    Ok(offline) => offline.iter().for_each(|device| log::warn!("{}: {}", house_id, device)),
+                    Err(err) => log::error!("Availability check failed in {}: {}", house_id, err),
+                }
+                let events = plan(&*house.read().await, now);
+                if let Err(err) = actuate(&house, events, now).await {
+                    log::error!("Thermostat regulation failed in {}: {}", house_id, err);
+                }
+            }
+        }
+    });
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(ctx.clone()))
+            .wrap_fn(|mut req, srv| {
+                route_house(&mut req);
+                srv.call(req)
+            })
+            .wrap_fn(observe)
+            .wrap_fn(redact)
+            .service(get_metrics)
+            .service(build_docs())
             .service(build_service())
             .default_service(web::to(default_response))
     })
@@ -117,15 +932,112 @@ async fn main() -> Result<(), Box<dyn StdError>> {
     Ok(())
 }
 
+/// The `/api` scope, remembering the operations it routes. Only handlers
+/// with an OpenAPI description fit in.
+struct Api {
+    scope: Scope,
+    routes: BTreeSet<(String, String)>,
+}
+
+impl Api {
+    fn new() -> Self {
+        Api {
+            scope: web::scope("/api"),
+            routes: BTreeSet::new(),
+        }
+    }
+
+    fn service<H: HttpServiceFactory + utoipa::Path + 'static>(mut self, handler: H) -> Self {
+        for method in H::methods() {
+            let method = serde_json::to_value(method).unwrap_or_default();
+            self.routes.insert((method.as_str().unwrap_or_default().to_uppercase(), H::path()));
+        }
+        self.scope = self.scope.service(handler);
+        self
+    }
+}
+
+fn build_docs() -> RapiDoc {
+    RapiDoc::new("/api/openapi.json").path("/api/docs")
+}
+
 fn build_service() -> Scope {
-    web::scope("/api")
+    build_api().scope
+}
+
+fn build_api() -> Api {
+    Api::new()
+        .service(get_openapi)
+        .service(get_houses)
+        .service(create_house)
+        .service(get_house)
+        .service(delete_house)
         .service(get_home)
+        .service(get_history)
+        .service(get_home_as_of)
+        .service(get_events)
+        .service(get_alarms)
+        .service(get_energy)
+        .service(get_tariff)
+        .service(set_tariff)
+        .service(get_costs)
+        .service(find_devices)
+        .service(get_groups)
+        .service(get_group)
+        .service(set_group)
+        .service(delete_group)
+        .service(switch_group)
+        .service(get_tree)
+        .service(get_areas)
+        .service(get_area)
+        .service(set_area)
+        .service(delete_area)
+        .service(get_area_rooms)
+        .service(get_area_room)
+        .service(get_area_devices)
+        .service(place_room)
         .service(create_room)
         .service(get_rooms)
         .service(delete_room)
+        .service(rename_room)
         .service(create_devices)
         .service(get_devices)
         .service(delete_device)
+        .service(move_device)
+        .service(set_light)
+        .service(set_sensor)
+        .service(record_metric)
+        .service(record_power)
+        .service(switch_socket)
+        .service(poll_device)
+        .service(get_connection)
+        .service(get_availability)
+        .service(get_candidates)
+        .service(probe_devices)
+        .service(adopt_candidate)
+        .service(get_device_availability)
+        .service(record_heartbeat)
+        .service(set_heartbeat_timeout)
+        .service(record_meter)
+        .service(set_tags)
+        .service(get_environment)
+        .service(set_cover)
+        .service(calibrate_cover)
+        .service(report_detector)
+        .service(test_detector)
+        .service(acknowledge_alarm)
+        .service(set_lock)
+        .service(report_lock)
+        .service(get_access_codes)
+        .service(add_access_code)
+        .service(remove_access_code)
+        .service(get_occupancy)
+        .service(set_occupancy_timeout)
+        .service(configure_thermostat)
+        .service(simulate_thermostat)
+        .service(undo)
+        .service(redo)
+        .service(batch)
         .service(get_reports)
 }
 
@@ -133,22 +1045,156 @@ async fn default_response() -> CustomResult<HttpResponse> {
     Ok(HttpResponse::Ok().body("Go to '/api/home'"))
 }
 
-#[actix_web::get("/home")]
-async fn get_home(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
-    let house = ctx.get_context().lock().await;
-    let house_object = house.deref();
+#[utoipa::path(
+    tag = "docs",
+    responses(
+        (status = 200, description = "This OpenAPI document"),
+    ),
+)]
+#[get("/openapi.json")]
+async fn get_openapi() -> CustomResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiDoc::openapi()))
+}
+
+#[utoipa::path(
+    tag = "houses",
+    responses(
+        (status = 200, description = "Ids of all houses", body = [String]),
+    ),
+)]
+#[get("/houses")]
+async fn get_houses(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ctx.houses().ids()))
+}
+
+/// Gauges of every house and HTTP request metrics in the Prometheus text
+/// format. Served at the root, outside the API and its houses.
+#[get("/metrics")]
+async fn get_metrics(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
+    let mut exposition = Exposition::new();
+    for (house_id, house) in ctx.houses().houses() {
+        exposition.house(&house_id, &*house.read().await);
+    }
+    ctx.metrics().write(&mut exposition);
+    Ok(HttpResponse::Ok().content_type(CONTENT_TYPE).body(exposition.render()))
+}
+
+#[utoipa::path(
+    tag = "houses",
+    request_body = NewHouse,
+    responses(
+        (status = 201, description = "The new empty house", body = HouseInfo),
+        (status = 400, body = CustomError),
+        (status = 409, body = CustomError),
+    ),
+)]
+#[post("/houses")]
+async fn create_house(ctx: web::Data<Context>, body_data: web::Json<NewHouse>) -> CustomResult<HttpResponse> {
+    let data = body_data.into_inner();
+    let mut house = SmartHouse::new(data.name);
+    house.set_undo_depth(UNDO_DEPTH);
+    let info = HouseInfo::new(&data.house_id, &house);
+    ctx.houses().create(&data.house_id, house)?;
+
+    Ok(HttpResponse::Created().json(info))
+}
+
+#[utoipa::path(
+    tag = "houses",
+    responses(
+        (status = 200, body = HouseInfo),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/houses/{house_id}")]
+async fn get_house(ctx: web::Data<Context>, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let house_id = path.into_inner();
+    let house = ctx.houses().get(&house_id)?;
+    let house = house.read().await;
 
-    Ok(HttpResponse::Ok().json(house_object))
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(HouseInfo::new(&house_id, &house)))
 }
 
-#[actix_web::get("/reports/{provider}")]
+#[utoipa::path(
+    tag = "houses",
+    responses(
+        (status = 200, description = "Id of the removed house", body = String),
+        (status = 404, body = CustomError),
+        (status = 409, description = "The default house can't be removed", body = CustomError),
+    ),
+)]
+#[delete("/houses/{house_id}")]
+async fn delete_house(ctx: web::Data<Context>, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let house_id = path.into_inner();
+    ctx.houses().remove(&house_id)?;
+
+    Ok(HttpResponse::Ok().json(house_id))
+}
+
+#[utoipa::path(
+    tag = "home",
+    responses(
+        (status = 200, description = "The whole house without its history", body = HouseView),
+    ),
+)]
+#[get("/home")]
+async fn get_home(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.view()))
+}
+
+#[utoipa::path(
+    tag = "home",
+    responses(
+        (status = 200, description = "Every event committed to the house", body = [EventRecord]),
+    ),
+)]
+#[get("/home/history")]
+async fn get_history(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.history().records()))
+}
+
+#[utoipa::path(
+    tag = "home",
+    params(
+        ("timestamp" = u64, Path, description = "Milliseconds since the unix epoch"),
+    ),
+    responses(
+        (status = 200, description = "The house as it was at the timestamp", body = HouseView),
+    ),
+)]
+#[get("/home/as_of/{timestamp}")]
+async fn get_home_as_of(
+    ctx: HouseContext,
+    path: web::Path<u64>,
+) -> CustomResult<HttpResponse> {
+    let timestamp = path.into_inner();
+    let house = ctx.get_context().read().await;
+    let past = house.as_of(timestamp)?;
+
+    Ok(HttpResponse::Ok().json(past.view()))
+}
+
+#[utoipa::path(
+    tag = "reports",
+    params(
+        ("provider" = Provider, Path),
+    ),
+    responses(
+        (status = 200, description = "Text report", body = String),
+    ),
+)]
+#[get("/reports/{provider}")]
 async fn get_reports(
-    ctx: web::Data<Context>,
+    ctx: HouseContext,
     path: web::Path<Provider>,
 ) -> CustomResult<HttpResponse> {
     let provider = path.into_inner();
 
-    let house = ctx.get_context().lock().await;
+    let house = ctx.get_context().read().await;
     match provider {
         Provider::Owning => {
             let socket = SmartSocket::default(String::from("Socket 1"));
@@ -166,101 +1212,1900 @@ async fn get_reports(
             };
             let report = house.create_report(info_provider_2);
 
+            Ok(HttpResponse::Ok().json(report))
+        }
+        Provider::Energy => {
+            let now = now_millis();
+            let mut report = format!("House name: {}\n", house.house_name());
+            for period in [Period::Day, Period::Week, Period::Month] {
+                report.push_str(&format!("{:?}: {:.3} kWh\n", period, house.energy_in(period, now).kwh));
+            }
+            report.push_str(&format!("\n{}", house.energy_in(Period::Month, now)));
+
+            Ok(HttpResponse::Ok().json(report))
+        }
+        Provider::Cost => {
+            let costs = house.costs_in(Period::Month, now_millis())?;
+            let report = format!("House name: {}\n{}", house.house_name(), costs);
+
             Ok(HttpResponse::Ok().json(report))
         }
     }
 }
 
-#[actix_web::get("/rooms")]
-async fn get_rooms(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
-    let house = ctx.get_context().lock().await;
-    let rooms: Vec<&SmartRoom> = house.get_rooms_list().into_iter().collect();
+#[utoipa::path(
+    tag = "home",
+    params(
+        ("room" = Option<String>, Query, description = "Only events of this room"),
+        ("device" = Option<String>, Query, description = "Only events of this device"),
+    ),
+    responses(
+        (status = 200, description = "Server-sent `event` and `alarm` notifications. Alarms ignore the filter", body = Notification, content_type = "text/event-stream"),
+    ),
+)]
+#[get("/events")]
+async fn get_events(ctx: HouseContext, filter: web::Query<EventFilter>) -> CustomResult<HttpResponse> {
+    let receiver = ctx.get_context().write().await.subscribe(filter.into_inner());
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let (name, data) = match receiver.recv().await? {
+            Notification::Event(record) => ("event", redacted(|| serde_json::to_string(&record))),
+            Notification::Alarm(alarm) => ("alarm", serde_json::to_string(&alarm)),
+        };
+        let message = format!("event: {}\ndata: {}\n\n", name, data.unwrap_or_default());
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(message)), receiver))
+    });
 
-    Ok(HttpResponse::Ok().json(rooms))
+    Ok(HttpResponse::Ok().content_type("text/event-stream").streaming(stream))
 }
 
-#[actix_web::post("/rooms")]
-async fn create_room(
-    ctx: web::Data<Context>,
-    body_data: web::Json<Data>,
+#[utoipa::path(
+    tag = "home",
+    responses(
+        (status = 200, description = "Latched alarms of the whole house", body = HouseAlarm),
+    ),
+)]
+#[get("/alarms")]
+async fn get_alarms(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().json(house.alarm_state()))
+}
+
+#[utoipa::path(
+    tag = "energy",
+    params(
+        ("period" = Option<Period>, Query, description = "Day, Week or Month, the current day by default"),
+    ),
+    responses(
+        (status = 200, description = "Consumption per socket, room and house in the current period", body = EnergyReport),
+    ),
+)]
+#[get("/energy")]
+async fn get_energy(ctx: HouseContext, query: web::Query<EnergyQuery>) -> CustomResult<HttpResponse> {
+    let period = query.into_inner().period.unwrap_or(Period::Day);
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().json(house.energy_in(period, now_millis())))
+}
+
+#[utoipa::path(
+    tag = "energy",
+    responses(
+        (status = 200, description = "The tariff of the house", body = Tariff),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/tariff")]
+async fn get_tariff(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+    let tariff = house.tariff().ok_or(SmartHouseError::TariffNotSet)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(tariff))
+}
+
+#[utoipa::path(
+    tag = "energy",
+    request_body = Tariff,
+    responses(
+        (status = 200, description = "The new tariff", body = Tariff),
+        (status = 400, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/tariff")]
+async fn set_tariff(ctx: HouseContext, body_data: web::Json<Tariff>, if_match: IfMatchHeader) -> CustomResult<HttpResponse> {
+    let tariff = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    house.set_tariff(tariff.clone())?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(tariff))
+}
+
+#[utoipa::path(
+    tag = "energy",
+    params(
+        ("period" = Option<Period>, Query, description = "Day, Week or Month, the current day by default"),
+    ),
+    responses(
+        (status = 200, description = "Cost per socket, room and house with a projection for the month", body = CostReport),
+        (status = 404, description = "No tariff is set", body = CustomError),
+    ),
+)]
+#[get("/costs")]
+async fn get_costs(ctx: HouseContext, query: web::Query<EnergyQuery>) -> CustomResult<HttpResponse> {
+    let period = query.into_inner().period.unwrap_or(Period::Day);
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().json(house.costs_in(period, now_millis())?))
+}
+
+#[utoipa::path(
+    tag = "groups",
+    params(
+        ("tag" = Option<String>, Query, description = "Only devices with this tag"),
+        ("type" = Option<DeviceKind>, Query, description = "Only devices of this type"),
+        ("room" = Option<String>, Query, description = "Only devices of this room"),
+    ),
+    responses(
+        (status = 200, description = "Matching devices of all rooms", body = [TaggedDevice]),
+    ),
+)]
+#[get("/devices")]
+async fn find_devices(ctx: HouseContext, query: web::Query<DeviceQuery>) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().json(house.find_devices(&query)))
+}
+
+#[utoipa::path(
+    tag = "groups",
+    request_body = DeviceTags,
+    responses(
+        (status = 200, description = "The room with the new device tags", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/tags")]
+async fn set_tags(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<DeviceTags>,
+    if_match: IfMatchHeader,
 ) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
     let data = body_data.into_inner();
 
-    let mut house = ctx.get_context().lock().await;
-    let room: SmartRoom = SmartRoom::default(data.name);
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.set_tags(&room_name, &device_name, data.tags)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
 
-    house.add_smart_room(&room).unwrap();
+#[utoipa::path(
+    tag = "groups",
+    responses(
+        (status = 200, description = "All groups", body = [Group]),
+    ),
+)]
+#[get("/groups")]
+async fn get_groups(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
 
-    Ok(HttpResponse::Created().json(room.room_name))
+    Ok(HttpResponse::Ok().json(house.groups()))
 }
 
-#[actix_web::delete("/rooms/{room_name}")]
-async fn delete_room(ctx: web::Data<Context>, path: web::Path<SmartRoom>, ) -> CustomResult<HttpResponse> {
-    let room = path.into_inner();
-    let mut house = ctx.get_context().lock().await;
-    if let Some(_room) = house.get_room(&room) {
-        house.remove_smart_room(&room).unwrap();
+#[utoipa::path(
+    tag = "groups",
+    responses(
+        (status = 200, description = "Aggregates over the current members of the group", body = GroupSummary),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/groups/{group}")]
+async fn get_group(ctx: HouseContext, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
+    let house = ctx.get_context().read().await;
 
-        Ok(HttpResponse::Ok().json("Ok"))
-    } else {
-        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: {}", &room))))
-    }
+    Ok(HttpResponse::Ok().json(house.group_summary(&name, now_millis())?))
 }
 
-#[actix_web::get("/rooms/{room_name}/devices")]
-async fn get_devices(ctx: web::Data<Context>, room: web::Path<String>) -> CustomResult<HttpResponse> {
-    let room_name = room.into_inner();
-    let house = ctx.get_context().lock().await;
-    let devices = house.device_info(&room_name);
-    match devices {
-        Some(devices) => Ok(HttpResponse::Ok().json(devices)),
-        None => Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: {}", room_name)))),
-    }
+#[utoipa::path(
+    tag = "groups",
+    request_body = DeviceQuery,
+    responses(
+        (status = 200, description = "The group with its members", body = [TaggedDevice]),
+        (status = 400, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/groups/{group}")]
+async fn set_group(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<DeviceQuery>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    house.set_group(&name, body_data.into_inner())?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.group_members(&name)?))
 }
 
-#[actix_web::post("/rooms/{room_name}/devices")]
-async fn create_devices(
-    ctx: web::Data<Context>,
-    body_data: web::Json<DeviceData>,
-    mut room: web::Path<SmartRoom>,
+#[utoipa::path(
+    tag = "groups",
+    responses(
+        (status = 200, description = "The removed group", body = Group),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[delete("/groups/{group}")]
+async fn delete_group(ctx: HouseContext, path: web::Path<String>, if_match: IfMatchHeader) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    let group = house.remove_group(&name)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(group))
+}
+
+#[utoipa::path(
+    tag = "groups",
+    request_body = GroupSwitch,
+    responses(
+        (status = 200, description = "Every socket of the group was switched", body = BatchOutcome),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+        (status = 422, description = "No socket was switched", body = BatchOutcome),
+    ),
+)]
+#[post("/groups/{group}/switch")]
+async fn switch_group(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<GroupSwitch>,
+    if_match: IfMatchHeader,
 ) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
     let data = body_data.into_inner();
 
-    let mut house = ctx.get_context().lock().await;
-    if let Some(_room) = room.get_room_name().into() {
-        let device = match data.device_type {
-            DeviceType::Socket => Device::SmartSocket(SmartSocket::default(data.name)),
-            DeviceType::Thermo => Device::SmartThermometr(SmartThermometer::default(data.name)),
-        };
-        room.add_smart_device(device.clone()).unwrap();
-        house.add_smart_room(&room).unwrap();
+    let house = ctx.get_context();
+    let outcome = actor::switch_group(house, &name, data.status, |house| check_revision(&if_match, house.revision())).await?;
 
-        Ok(HttpResponse::Created().json(room.into_inner()))
+    if outcome.committed {
+        Ok(HttpResponse::Ok().insert_header(etag(house.read().await.revision())).json(outcome))
     } else {
-        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: {}", room.room_name))))
+        Ok(HttpResponse::UnprocessableEntity().json(outcome))
     }
 }
 
-#[actix_web::delete("/rooms/{room_name}/devices/{device_id}")]
-async fn delete_device(
-    ctx: web::Data<Context>,
-    path: web::Path<(SmartRoom, String)>,
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, description = "Floors, zones and rooms with aggregates at every level", body = HouseTree),
+    ),
+)]
+#[get("/tree")]
+async fn get_tree(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.tree(now_millis())))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, description = "Floors from the bottom up, then zones", body = [Area]),
+    ),
+)]
+#[get("/areas")]
+async fn get_areas(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().json(house.areas()))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, description = "The area with its rooms and aggregates", body = AreaNode),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/areas/{area}")]
+async fn get_area(ctx: HouseContext, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().json(house.area_node(&name, now_millis())?))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    request_body = AreaData,
+    responses(
+        (status = 200, description = "The new or changed area", body = Area),
+        (status = 400, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/areas/{area}")]
+async fn set_area(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<AreaData>,
+    if_match: IfMatchHeader,
 ) -> CustomResult<HttpResponse> {
-    let (room, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+    let area = Area {
+        name: path.into_inner(),
+        kind: data.kind,
+        level: data.level,
+    };
 
-    let mut house = ctx.get_context().lock().await;
-    if let Some(room) = house.get_room(&room) {
-        if let Some(device) = room.get_device(device_name) {
-            let mut room = room.clone();
-            room.delite_device(&device).unwrap();
-            house.add_smart_room(&room).unwrap();
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    house.set_area(area.clone())?;
 
-            Ok(HttpResponse::Ok().json("OK"))
-        } else {
-            Ok(HttpResponse::NotFound()
-                .json(CustomError::NotFound(format!("Device: not found "))))
-        }
-    } else {
-        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: not found", ))))
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(area))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, description = "The removed area", body = Area),
+        (status = 404, body = CustomError),
+        (status = 409, description = "Rooms are still placed on the area", body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[delete("/areas/{area}")]
+async fn delete_area(ctx: HouseContext, path: web::Path<String>, if_match: IfMatchHeader) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    let area = house.remove_area(&name)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(area))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, description = "Rooms of the area", body = [SmartRoom]),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/areas/{area}/rooms")]
+async fn get_area_rooms(ctx: HouseContext, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let name = path.into_inner();
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.rooms_in(&name)?))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, body = SmartRoom),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/areas/{area}/rooms/{room_name}")]
+async fn get_area_room(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (area, room_name) = path.into_inner();
+    let house = ctx.get_context().read().await;
+    let room = house.room_in(&area, &room_name)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(room.revision)).json(room))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    responses(
+        (status = 200, description = "Devices of the room", body = [Device]),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/areas/{area}/rooms/{room_name}/devices")]
+async fn get_area_devices(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (area, room_name) = path.into_inner();
+    let house = ctx.get_context().read().await;
+    let room = house.room_in(&area, &room_name)?;
+    let devices: Vec<&Device> = room.smart_device.values().collect();
+
+    Ok(HttpResponse::Ok().insert_header(etag(room.revision)).json(devices))
+}
+
+#[utoipa::path(
+    tag = "areas",
+    request_body = RoomPlacement,
+    responses(
+        (status = 200, description = "The room on its new area", body = SmartRoom),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/area")]
+async fn place_room(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<RoomPlacement>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.place_room(&room_name, data.area.as_deref())?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    responses(
+        (status = 200, description = "All rooms", body = [SmartRoom]),
+    ),
+)]
+#[get("/rooms")]
+async fn get_rooms(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+    let rooms: Vec<&SmartRoom> = house.get_rooms_list().into_iter().collect();
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(rooms))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    request_body = Data,
+    responses(
+        (status = 201, description = "Name of the created room", body = String),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/rooms")]
+async fn create_room(
+    ctx: HouseContext,
+    body_data: web::Json<Data>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    let room: SmartRoom = SmartRoom::default(data.name);
+
+    house.add_smart_room(&room)?;
+
+    Ok(HttpResponse::Created().insert_header(etag(house.revision())).json(room.room_name))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    responses(
+        (status = 200, body = String),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[delete("/rooms/{room_name}")]
+async fn delete_room(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    let room = house.get_room_by_name(&room_name).cloned().unwrap();
+    house.remove_smart_room(&room)?;
+
+    Ok(HttpResponse::Ok().json("Ok"))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    request_body = Data,
+    responses(
+        (status = 200, description = "The renamed room", body = SmartRoom),
+        (status = 404, body = CustomError),
+        (status = 409, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}")]
+async fn rename_room(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<Data>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.rename_room(&room_name, &data.name)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &data.name)?)).json(house.get_room_by_name(&data.name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, description = "Devices of the room", body = [Device]),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/rooms/{room_name}/devices")]
+async fn get_devices(ctx: HouseContext, room: web::Path<String>) -> CustomResult<HttpResponse> {
+    let room_name = room.into_inner();
+    let house = ctx.get_context().read().await;
+    let revision = room_revision(&house, &room_name)?;
+    let devices = house.device_info(&room_name);
+
+    Ok(HttpResponse::Ok().insert_header(etag(revision)).json(devices))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = DeviceData,
+    responses(
+        (status = 201, description = "The room with the new device", body = SmartRoom),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices")]
+async fn create_devices(
+    ctx: HouseContext,
+    body_data: web::Json<DeviceData>,
+    room: web::Path<String>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let data = body_data.into_inner();
+    let room_name = room.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    let device = data.into_device();
+    house.add_device(&room_name, device)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Created().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, body = String),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[delete("/rooms/{room_name}/devices/{device_id}")]
+async fn delete_device(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.remove_device(&room_name, &device_name)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json("OK"))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body(content = Data, description = "Destination room"),
+    responses(
+        (status = 200, description = "The destination room", body = SmartRoom),
+        (status = 404, body = CustomError),
+        (status = 409, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/move")]
+async fn move_device(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<Data>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.move_device(&device_name, &room_name, &data.name)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&data.name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = LightCommand,
+    responses(
+        (status = 200, description = "The room with the changed light", body = SmartRoom),
+        (status = 202, description = "The fade was started", body = String),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/light")]
+async fn set_light(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<LightCommand>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let command = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    match command.transition_ms {
+        Some(transition_ms) if transition_ms > 0 => {
+            command.state.validate().map_err(SmartHouseError::from)?;
+            match house.get_room_by_name(&room_name).and_then(|room| room.get_device(device_name.clone())) {
+                Some(Device::SmartLight(_)) => {}
+                _ => return Err(CustomError::NotFound(format!("Light: {}", device_name))),
+            }
+            let fading = fade(
+                ctx.get_context().clone(),
+                room_name,
+                device_name,
+                command.state,
+                Duration::from_millis(transition_ms),
+            );
+            actix_web::rt::spawn(async move {
+                if let Err(err) = fading.await {
+                    log::error!("Fade failed: {}", err);
+                }
+            });
+
+            Ok(HttpResponse::Accepted().json("Fading"))
+        }
+        _ => {
+            house.set_light(&room_name, &device_name, command.state)?;
+
+            let house = house.downgrade();
+            Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+        }
+    }
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = SensorState,
+    responses(
+        (status = 200, description = "The room with the changed sensor", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/sensor")]
+async fn set_sensor(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<SensorState>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.set_sensor(&room_name, &device_name, data.active)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = MetricReading,
+    responses(
+        (status = 200, description = "The room with the new reading", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/metrics")]
+async fn record_metric(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<MetricReading>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.record_metric(&room_name, &device_name, data.metric, data.value)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = CoverCommand,
+    responses(
+        (status = 200, description = "The room with the stopped cover", body = SmartRoom),
+        (status = 202, description = "The cover started moving", body = String),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/cover")]
+async fn set_cover(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<CoverCommand>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let command = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    let current = *house.get_cover(&room_name, &device_name)?.state();
+    let position = match command.action {
+        CoverAction::Open => 100,
+        CoverAction::Close => 0,
+        CoverAction::Position(position) => position,
+        CoverAction::Stop => current.position,
+    };
+    let state = CoverState {
+        position,
+        tilt: command.tilt.unwrap_or(current.tilt),
+        ..current
+    };
+    state.validate().map_err(SmartHouseError::from)?;
+    let stopped = CoverState {
+        motion: CoverMotion::Stopped,
+        position: current.position,
+        ..state
+    };
+    house.set_cover(&room_name, &device_name, stopped)?;
+    if let CoverAction::Stop = command.action {
+        return Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)));
+    }
+
+    let moving = travel(ctx.get_context().clone(), room_name, device_name, position);
+    actix_web::rt::spawn(async move {
+        if let Err(err) = moving.await {
+            log::error!("Cover travel failed: {}", err);
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json("Moving"))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = CoverCalibration,
+    responses(
+        (status = 200, description = "The room with the calibrated cover", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/cover/calibration")]
+async fn calibrate_cover(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<CoverCalibration>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.calibrate_cover(&room_name, &device_name, data.travel_ms)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "safety",
+    request_body = DetectorReport,
+    responses(
+        (status = 200, description = "Alarm state after the report", body = HouseAlarm),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/detector")]
+async fn report_detector(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<DetectorReport>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let report = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.report_detector(&room_name, &device_name, report.detected, report.battery)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.alarm_state()))
+}
+
+#[utoipa::path(
+    tag = "safety",
+    request_body = SelfTestResult,
+    responses(
+        (status = 200, description = "The room with the tested detector", body = SmartRoom),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/detector/test")]
+async fn test_detector(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<SelfTestResult>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let result = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.test_detector(&room_name, &device_name, result.passed)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "safety",
+    responses(
+        (status = 200, description = "Alarm state after the acknowledgement", body = HouseAlarm),
+        (status = 400, description = "No alarm, or the hazard is still detected", body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/alarm/acknowledge")]
+async fn acknowledge_alarm(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.acknowledge_alarm(&room_name, &device_name)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.alarm_state()))
+}
+
+#[utoipa::path(
+    tag = "locks",
+    request_body = LockCommand,
+    responses(
+        (status = 200, description = "The room with the operated lock", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 403, description = "The PIN is wrong or expired, or PINs are locked out after too many wrong ones", body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/lock")]
+async fn set_lock(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<LockCommand>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let command = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    match command.pin {
+        Some(pin) => house.set_lock_with_pin(&room_name, &device_name, command.locked, &pin)?,
+        None => house.set_lock(&room_name, &device_name, command.locked, AccessMethod::Api)?,
+    }
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "locks",
+    request_body = LockStatus,
+    responses(
+        (status = 200, description = "The room with the updated lock", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/lock/status")]
+async fn report_lock(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<LockStatus>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let status = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.report_lock(&room_name, &device_name, status.jammed, status.battery)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "locks",
+    responses(
+        (status = 200, description = "Guest codes of the lock, without the PINs", body = [AccessCode]),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/rooms/{room_name}/devices/{device_id}/codes")]
+async fn get_access_codes(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let house = ctx.get_context().read().await;
+    let lock = house.get_lock(&room_name, &device_name)?;
+
+    Ok(HttpResponse::Ok().json(lock.codes()))
+}
+
+#[utoipa::path(
+    tag = "locks",
+    request_body = NewAccessCode,
+    responses(
+        (status = 201, description = "Guest codes of the lock, without the PINs", body = [AccessCode]),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/codes")]
+async fn add_access_code(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<NewAccessCode>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    let valid_from = data.valid_from.unwrap_or_else(now_millis);
+    house.add_access_code(&room_name, &device_name, data.label, &data.pin, valid_from, data.valid_until)?;
+    let lock = house.get_lock(&room_name, &device_name)?;
+
+    Ok(HttpResponse::Created().insert_header(etag(room_revision(&house, &room_name)?)).json(lock.codes()))
+}
+
+#[utoipa::path(
+    tag = "locks",
+    responses(
+        (status = 200, description = "Remaining guest codes of the lock", body = [AccessCode]),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[delete("/rooms/{room_name}/devices/{device_id}/codes/{label}")]
+async fn remove_access_code(
+    ctx: HouseContext,
+    path: web::Path<(String, String, String)>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name, label) = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.remove_access_code(&room_name, &device_name, &label)?;
+    let lock = house.get_lock(&room_name, &device_name)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(lock.codes()))
+}
+
+#[utoipa::path(
+    tag = "energy",
+    request_body = PowerReading,
+    responses(
+        (status = 200, description = "The room with the new socket power", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/power")]
+async fn record_power(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<PowerReading>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.record_power(&room_name, &device_name, data.watts)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = SocketSwitch,
+    responses(
+        (status = 200, description = "The room with the socket switched by the device", body = SmartRoom),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+        (status = 504, description = "The device didn't confirm the switch", body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/socket")]
+async fn switch_socket(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<SocketSwitch>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let house = ctx.get_context();
+    let switch = DeviceCommand::Switch { status: data.status };
+    actor::command_if(house, &room_name, &device_name, switch, |house| {
+        check_revision(&if_match, room_revision(house, &room_name)?)
+    })
+    .await?;
+
+    let house = house.read().await;
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, description = "What the device reported, the reading is recorded", body = DeviceReply),
+        (status = 400, description = "The device can't be read", body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 504, description = "The device didn't answer", body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/poll")]
+async fn poll_device(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+
+    let house = ctx.get_context();
+    let reply = actor::poll(house, &room_name, &device_name).await?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.read().await.revision())).json(reply))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, description = "What the device actor last heard from the device", body = ActorState),
+        (status = 404, description = "The device has no connection", body = CustomError),
+    ),
+)]
+#[get("/rooms/{room_name}/devices/{device_id}/connection")]
+async fn get_connection(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+
+    let handle = {
+        let house = ctx.get_context().read().await;
+        house.get_device(&room_name, &device_name)?;
+        house.actor(&room_name, &device_name).cloned()
+    };
+    let handle = handle.ok_or_else(|| CustomError::NotFound(format!("Connection: {}", device_name)))?;
+    let state = handle.state().await.map_err(SmartHouseError::from)?;
+
+    Ok(HttpResponse::Ok().json(state))
+}
+
+#[utoipa::path(
+    tag = "discovery",
+    responses(
+        (status = 200, description = "Devices found on the network that aren't in any room yet", body = [Candidate]),
+        (status = 404, description = "Discovery is disabled", body = CustomError),
+    ),
+)]
+#[get("/discovery/candidates")]
+async fn get_candidates(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ctx.discovery()?.candidates()))
+}
+
+#[utoipa::path(
+    tag = "discovery",
+    responses(
+        (status = 202, description = "Devices were asked to announce themselves, the targets that couldn't be probed are listed", body = ProbeReport),
+        (status = 404, description = "Discovery is disabled", body = CustomError),
+    ),
+)]
+#[post("/discovery/probe")]
+async fn probe_devices(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
+    let discovery = ctx.discovery()?;
+    let failures = discovery.probe().await;
+
+    Ok(HttpResponse::Accepted().json(ProbeReport {
+        candidates: discovery.candidates(),
+        failures,
+    }))
+}
+
+#[utoipa::path(
+    tag = "discovery",
+    request_body = Adoption,
+    responses(
+        (status = 201, description = "The room with the adopted device", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 409, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/discovery/candidates/{device_id}/adopt")]
+async fn adopt_candidate(
+    ctx: web::Data<Context>,
+    house: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<Adoption>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let device_id = path.into_inner();
+    let data = body_data.into_inner();
+
+    let house = house.get_context();
+    ctx.discovery()?
+        .adopt_if(house, &device_id, &data.room_name, data.name.as_deref(), ActorConfig::default(), |house| {
+            check_revision(&if_match, room_revision(house, &data.room_name)?)
+        })
+        .await?;
+
+    let house = house.read().await;
+    Ok(HttpResponse::Created().insert_header(etag(room_revision(&house, &data.room_name)?)).json(house.get_room_by_name(&data.room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, description = "Availability of every device", body = [DeviceAvailability]),
+    ),
+)]
+#[get("/availability")]
+async fn get_availability(ctx: HouseContext) -> CustomResult<HttpResponse> {
+    let house = ctx.get_context().read().await;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.availability()))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, body = DeviceAvailability),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/rooms/{room_name}/devices/{device_id}/availability")]
+async fn get_device_availability(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+
+    let house = ctx.get_context().read().await;
+    let availability = house.device_availability(&room_name, &device_name)?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(availability))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, description = "The device is online", body = DeviceAvailability),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/heartbeat")]
+async fn record_heartbeat(ctx: HouseContext, path: web::Path<(String, String)>) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    house.heartbeat(&room_name, &device_name)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(house.device_availability(&room_name, &device_name)?))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = HeartbeatSettings,
+    responses(
+        (status = 200, body = DeviceAvailability),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/heartbeat/timeout")]
+async fn set_heartbeat_timeout(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<HeartbeatSettings>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.set_heartbeat_timeout(&room_name, &device_name, data.timeout_ms)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.device_availability(&room_name, &device_name)?))
+}
+
+#[utoipa::path(
+    tag = "energy",
+    request_body = MeterReading,
+    responses(
+        (status = 200, description = "The room with the new meter reading", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/meter")]
+async fn record_meter(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<MeterReading>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let reading = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.record_meter(&room_name, &device_name, reading)?;
+
+    let house = house.downgrade();
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    responses(
+        (status = 200, description = "Metrics aggregated over the environment sensors", body = [MetricSummary]),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/rooms/{room_name}/environment")]
+async fn get_environment(ctx: HouseContext, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let house = ctx.get_context().read().await;
+    let room = house
+        .get_room_by_name(&room_name)
+        .ok_or_else(|| CustomError::NotFound(format!("Room: {}", room_name)))?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(room.revision)).json(room.environment()))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    responses(
+        (status = 200, description = "Occupancy derived from the motion sensors", body = Occupancy),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[get("/rooms/{room_name}/occupancy")]
+async fn get_occupancy(ctx: HouseContext, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let house = ctx.get_context().read().await;
+    let room = house
+        .get_room_by_name(&room_name)
+        .ok_or_else(|| CustomError::NotFound(format!("Room: {}", room_name)))?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(room.revision)).json(room.occupancy(now_millis())))
+}
+
+#[utoipa::path(
+    tag = "rooms",
+    request_body = OccupancySettings,
+    responses(
+        (status = 200, body = Occupancy),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/occupancy")]
+async fn set_occupancy_timeout(
+    ctx: HouseContext,
+    path: web::Path<String>,
+    body_data: web::Json<OccupancySettings>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let data = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, room_revision(&house, &room_name)?)?;
+    house.set_occupancy_timeout(&room_name, data.timeout_ms)?;
+    let occupancy = house.get_room_by_name(&room_name).map(|room| room.occupancy(now_millis()));
+
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(occupancy))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = ThermostatSettings,
+    responses(
+        (status = 200, description = "The room with the configured thermostat", body = SmartRoom),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[put("/rooms/{room_name}/devices/{device_id}/thermostat")]
+async fn configure_thermostat(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<ThermostatSettings>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let settings = body_data.into_inner();
+
+    let house = ctx.get_context();
+    {
+        let mut house = house.write().await;
+        check_revision(&if_match, room_revision(&house, &room_name)?)?;
+        house.configure_thermostat(&room_name, &device_name, settings)?;
+    }
+    let now = now_millis();
+    let events = plan(&*house.read().await, now);
+    actuate(house, events, now).await?;
+
+    let house = house.read().await;
+    Ok(HttpResponse::Ok().insert_header(etag(room_revision(&house, &room_name)?)).json(house.get_room_by_name(&room_name)))
+}
+
+#[utoipa::path(
+    tag = "devices",
+    request_body = SimulationRequest,
+    responses(
+        (status = 200, description = "Simulated temperatures, the house is not changed", body = SimulationResult),
+        (status = 400, body = CustomError),
+        (status = 404, body = CustomError),
+    ),
+)]
+#[post("/rooms/{room_name}/devices/{device_id}/thermostat/simulate")]
+async fn simulate_thermostat(
+    ctx: HouseContext,
+    path: web::Path<(String, String)>,
+    body_data: web::Json<SimulationRequest>,
+) -> CustomResult<HttpResponse> {
+    let (room_name, device_name) = path.into_inner();
+    let request = body_data.into_inner();
+
+    if request.steps > MAX_SIMULATION_STEPS {
+        return Err(CustomError::BadRequest(format!("At most {} steps", MAX_SIMULATION_STEPS)));
+    }
+
+    let room = ctx
+        .get_context()
+        .read()
+        .await
+        .get_room_by_name(&room_name)
+        .cloned()
+        .ok_or_else(|| CustomError::NotFound(format!("Room: {}", room_name)))?;
+    let now = now_millis();
+    let target = match room.get_device(device_name.clone()) {
+        Some(Device::Thermostat(thermostat)) => thermostat.settings().target_at(now),
+        _ => return Err(CustomError::NotFound(format!("Thermostat: {}", device_name))),
+    };
+    let mut simulation = Simulation::new(room, &device_name, request.model, now)?;
+    let temperatures = simulation.run(request.steps, request.step_minutes)?;
+    let converged = converged(&temperatures, target, request.tolerance);
+
+    Ok(HttpResponse::Ok().json(SimulationResult { temperatures, converged }))
+}
+
+#[utoipa::path(
+    tag = "history",
+    responses(
+        (status = 200, description = "The reverted edit", body = HouseEvent),
+        (status = 409, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/undo")]
+async fn undo(ctx: HouseContext, if_match: IfMatchHeader) -> CustomResult<HttpResponse> {
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    let event = house.undo()?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(event))
+}
+
+#[utoipa::path(
+    tag = "history",
+    responses(
+        (status = 200, description = "The reapplied edit", body = HouseEvent),
+        (status = 409, body = CustomError),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/redo")]
+async fn redo(ctx: HouseContext, if_match: IfMatchHeader) -> CustomResult<HttpResponse> {
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    let event = house.redo()?;
+
+    Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(event))
+}
+
+#[utoipa::path(
+    tag = "history",
+    request_body = [BatchOperation],
+    responses(
+        (status = 200, body = BatchOutcome),
+        (status = 422, description = "Nothing was applied", body = BatchOutcome),
+        (status = 412, body = CustomError),
+    ),
+)]
+#[post("/batch")]
+async fn batch(
+    ctx: HouseContext,
+    body_data: web::Json<Vec<BatchOperation>>,
+    if_match: IfMatchHeader,
+) -> CustomResult<HttpResponse> {
+    let operations = body_data.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    check_revision(&if_match, house.revision())?;
+    let outcome = house.transaction(operations, |house, operation| operation.apply(house));
+
+    if outcome.committed {
+        Ok(HttpResponse::Ok().insert_header(etag(house.revision())).json(outcome))
+    } else {
+        Ok(HttpResponse::UnprocessableEntity().json(outcome))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::Method;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, read_body, TestRequest};
+    use serde_json::Value;
+
+    fn operations(spec: &Value) -> Vec<(String, String, String)> {
+        let mut operations = Vec::new();
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for (method, operation) in item.as_object().unwrap() {
+                let operation_id = operation["operationId"].as_str().unwrap().to_string();
+                operations.push((method.to_uppercase(), path.clone(), operation_id));
+            }
+        }
+        operations
+    }
+
+    fn sample_uri(path: &str) -> String {
+        path.replace("{room_name}", "Kitchen")
+            .replace("{device_id}", "Smart_socket")
+            .replace("{provider}", "Owning")
+            .replace("{timestamp}", "0")
+            .replace("{label}", "guest")
+            .replace("{group}", "heaters")
+            .replace("{area}", "Ground")
+            .replace("{house_id}", "default")
+    }
+
+    #[actix_web::test]
+    async fn documented_operations_are_routed() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .wrap_fn(|mut req, srv| {
+                    route_house(&mut req);
+                    srv.call(req)
+                })
+                .service(build_service())
+                .default_service(web::to(default_response)),
+        )
+        .await;
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        for (method, path, _) in operations(&spec) {
+            let request = TestRequest::default()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri(&format!("/api{}", sample_uri(&path)))
+                .to_request();
+            let response = call_service(&app, request).await;
+            assert_ne!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+            if response.headers().get("content-type").is_some_and(|value| value == "text/event-stream") {
+                // Streams never end, and the fallback never streams
+                continue;
+            }
+            let body = read_body(response).await;
+            assert_ne!(body, "Go to '/api/home'", "{} {} is documented but not routed", method, path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn if_match_is_checked() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .service(build_service()),
+        )
+        .await;
+
+        let request = TestRequest::delete()
+            .uri("/api/rooms/Kitchen")
+            .insert_header(("If-Match", "\"0\""))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+
+        // Another room changes the house revision, not the one of the kitchen
+        let request = TestRequest::post()
+            .uri("/api/rooms/Kitchen/devices")
+            .set_json(DeviceData { name: "Kettle".to_string(), device_type: DeviceType::Socket })
+            .to_request();
+        let kitchen = call_service(&app, request).await.headers().get("ETag").unwrap().clone();
+        let request = TestRequest::post()
+            .uri("/api/rooms/Hall/devices")
+            .set_json(DeviceData { name: "Lamp".to_string(), device_type: DeviceType::Light })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::CREATED);
+        let request = TestRequest::delete()
+            .uri("/api/rooms/Kitchen/devices/Kettle")
+            .insert_header(("If-Match", kitchen))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+
+        let request = TestRequest::delete().uri("/api/rooms/Kitchen").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+
+        // So are reports of energy
+        let request = TestRequest::put()
+            .uri("/api/rooms/Hall/devices/Lamp/power")
+            .insert_header(("If-Match", "\"0\""))
+            .set_json(PowerReading { watts: 60.0 })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+
+        // And of detectors
+        let request = TestRequest::post()
+            .uri("/api/rooms/Hall/devices")
+            .set_json(DeviceData { name: "Smoke".to_string(), device_type: DeviceType::SmokeDetector })
+            .to_request();
+        let hall = call_service(&app, request).await.headers().get("ETag").unwrap().clone();
+        let request = TestRequest::put()
+            .uri("/api/rooms/Hall/devices/Smoke/detector")
+            .insert_header(("If-Match", "\"0\""))
+            .set_json(DetectorReport { detected: false, battery: 90 })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+        let request = TestRequest::post()
+            .uri("/api/rooms/Hall/devices/Smoke/detector/test")
+            .insert_header(("If-Match", "\"0\""))
+            .set_json(SelfTestResult { passed: true })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+        let request = TestRequest::put()
+            .uri("/api/rooms/Hall/devices/Smoke/detector")
+            .insert_header(("If-Match", hall))
+            .set_json(DetectorReport { detected: false, battery: 90 })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+
+        // House-wide settings are checked against the house revision
+        let request = TestRequest::put()
+            .uri("/api/tariff")
+            .insert_header(("If-Match", "\"0\""))
+            .set_json(Tariff::flat("EUR".to_string(), 0.3))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+        let request = TestRequest::put()
+            .uri("/api/groups/heaters")
+            .insert_header(("If-Match", "\"0\""))
+            .set_json(DeviceQuery::default())
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+        let request = TestRequest::put().uri("/api/groups/heaters").set_json(DeviceQuery::default()).to_request();
+        let group = call_service(&app, request).await.headers().get("ETag").unwrap().clone();
+        let request = TestRequest::delete()
+            .uri("/api/groups/heaters")
+            .insert_header(("If-Match", group))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+        let request = TestRequest::delete()
+            .uri("/api/areas/Ground")
+            .insert_header(("If-Match", "\"0\""))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[actix_web::test]
+    async fn home_leaves_out_history() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .service(build_service()),
+        )
+        .await;
+
+        let home: Value = call_and_read_body_json(&app, TestRequest::get().uri("/api/home").to_request()).await;
+        assert!(home.get("history").is_none());
+        assert!(home["smart_rooms"]["Hall"].is_object());
+        let history: Value = call_and_read_body_json(&app, TestRequest::get().uri("/api/home/history").to_request()).await;
+        assert_eq!(home["revision"].as_u64(), history.as_array().map(|records| records.len() as u64));
+    }
+
+    #[actix_web::test]
+    async fn pin_hashes_stay_private() {
+        let ctx = Context::new(build_house());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(ctx.clone()))
+                .wrap_fn(redact)
+                .service(build_service()),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/api/rooms/Hall/devices")
+            .set_json(DeviceData { name: "Door".to_string(), device_type: DeviceType::Lock })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::CREATED);
+        let code = NewAccessCode {
+            label: "guest".to_string(),
+            pin: "2468".to_string(),
+            valid_from: Some(0),
+            valid_until: None,
+        };
+        let request = TestRequest::post().uri("/api/rooms/Hall/devices/Door/codes").set_json(code).to_request();
+        assert!(call_service(&app, request).await.status().is_success());
+
+        for uri in ["/api/home", "/api/home/history", "/api/rooms/Hall/devices/Door/codes"] {
+            let body = read_body(call_service(&app, TestRequest::get().uri(uri).to_request()).await).await;
+            let body = String::from_utf8_lossy(&body);
+            assert!(body.contains("guest") && !body.contains("pin_hash"), "{} shows {}", uri, body);
+        }
+        let stored = serde_json::to_string(&*ctx.houses().get(DEFAULT_HOUSE).unwrap().read().await).unwrap();
+        assert!(stored.contains("pin_hash"));
+
+        let request = TestRequest::put()
+            .uri("/api/rooms/Hall/devices/Door/lock")
+            .set_json(LockCommand { locked: false, pin: Some("2468".to_string()) })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn batches_are_validated() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .service(build_service()),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/api/batch")
+            .set_json(vec![
+                BatchOperation::CreateRoom { name: "Study".to_string() },
+                BatchOperation::CreateDevice {
+                    room_name: "Study".to_string(),
+                    device: DeviceData { name: "Lamp".to_string(), device_type: DeviceType::Light },
+                },
+            ])
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+
+        let state = LightState { brightness: 200, ..LightState::default() };
+        let request = TestRequest::post()
+            .uri("/api/batch")
+            .set_json(vec![
+                BatchOperation::DeleteDevice { room_name: "Kitchen".to_string(), device_name: "Smart_socket".to_string() },
+                BatchOperation::SetLight { room_name: "Study".to_string(), device_name: "Lamp".to_string(), state },
+            ])
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let request = TestRequest::get().uri("/api/rooms/Kitchen/devices").to_request();
+        let devices: Vec<Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(devices.len(), 1);
+
+        // Raw events would skip the checks of the routes
+        let request = TestRequest::post()
+            .uri("/api/batch")
+            .set_json(vec![HouseEvent::RoomRemoved { room_name: "Kitchen".to_string() }])
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn houses_are_isolated() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .wrap_fn(|mut req, srv| {
+                    route_house(&mut req);
+                    srv.call(req)
+                })
+                .service(build_service()),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/api/houses")
+            .set_json(NewHouse {
+                house_id: "flat-2".to_string(),
+                name: "Flat".to_string(),
+            })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::CREATED);
+        let request = TestRequest::post()
+            .uri("/api/houses/flat-2/rooms")
+            .set_json(Data { name: "Studio".to_string() })
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::CREATED);
+
+        let request = TestRequest::get().uri("/api/houses/flat-2/rooms/Studio/devices").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+        let request = TestRequest::get().uri("/api/rooms/Studio/devices").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+        let request = TestRequest::get().uri("/api/houses/flat-2/rooms/Kitchen/devices").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+
+        let request = TestRequest::delete().uri("/api/houses/flat-2").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+        let request = TestRequest::get().uri("/api/houses/flat-2/home").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn sockets_switch_through_their_actor() {
+        let mut house = build_house();
+        connect_devices(&mut house);
+        let app = init_service(App::new().app_data(web::Data::new(Context::new(house))).service(build_service())).await;
+
+        let request = TestRequest::put()
+            .uri("/api/rooms/Kitchen/devices/Smart_socket/socket")
+            .set_json(SocketSwitch { status: true })
+            .to_request();
+        let room: SmartRoom = call_and_read_body_json(&app, request).await;
+        match room.smart_device.get("Smart_socket") {
+            Some(Device::SmartSocket(socket)) => assert!(socket.status()),
+            device => panic!("{:?}", device),
+        }
+        let request = TestRequest::post().uri("/api/rooms/Bathroom/devices/Smart_thetmometr/poll").to_request();
+        let reply: DeviceReply = call_and_read_body_json(&app, request).await;
+        assert_eq!(reply, DeviceReply::Temperature(21.5));
+
+        let request = TestRequest::get().uri("/api/rooms/Kitchen/devices/Smart_socket/connection").to_request();
+        let state: ActorState = call_and_read_body_json(&app, request).await;
+        assert_eq!((state.status, state.failures), (Some(true), 0));
+        let request = TestRequest::get().uri("/api/availability").to_request();
+        let devices: Vec<DeviceAvailability> = call_and_read_body_json(&app, request).await;
+        let online: Vec<_> = devices
+            .iter()
+            .filter(|device| device.heartbeat.availability == Availability::Online)
+            .map(|device| (device.room_name.as_str(), device.device_name.as_str()))
+            .collect();
+        assert_eq!(online, vec![("Bathroom", "Smart_thetmometr"), ("Kitchen", "Smart_socket")]);
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_exported() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .wrap_fn(|mut req, srv| {
+                    route_house(&mut req);
+                    srv.call(req)
+                })
+                .wrap_fn(observe)
+                .service(get_metrics)
+                .service(build_service()),
+        )
+        .await;
+        for uri in ["/api/rooms/Kitchen/devices", "/api/houses/default/rooms/Hall/devices", "/api/rooms/Attic/devices"] {
+            call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        // Only the root serves metrics, no house has metrics of its own
+        for uri in ["/api/metrics", "/api/houses/default/metrics"] {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(response.headers().get("content-type").unwrap(), CONTENT_TYPE);
+        let text = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        for line in [
+            r#"smarthouse_rooms{house="default"} 4"#,
+            r#"smarthouse_socket_on{house="default",room="Kitchen",device="Smart_socket"} 0"#,
+            r#"smarthouse_temperature_celsius{house="default",room="Bathroom",device="Smart_thetmometr"} 0"#,
+            r#"smarthouse_device_availability{house="default",room="Hall",device="Smart_socket",state="unknown"} 1"#,
+            r#"smarthouse_http_requests_total{method="GET",route="/api/rooms/{room_name}/devices",status="200"} 2"#,
+            r#"smarthouse_http_requests_total{method="GET",route="/api/rooms/{room_name}/devices",status="404"} 1"#,
+        ] {
+            assert!(text.lines().any(|sample| sample == line), "{} missing from\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn routed_handlers_are_documented() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let documented: BTreeSet<(String, String)> =
+            operations(&spec).into_iter().map(|(method, path, _)| (method, path)).collect();
+
+        let routed = build_api().routes;
+        assert_eq!(routed.difference(&documented).collect::<Vec<_>>(), Vec::<&(String, String)>::new(), "routed but not documented");
+        assert_eq!(documented.difference(&routed).collect::<Vec<_>>(), Vec::<&(String, String)>::new(), "documented but not routed");
+    }
+
+    #[actix_web::test]
+    async fn docs_are_served() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Context::new(build_house())))
+                .service(build_docs())
+                .service(build_service()),
+        )
+        .await;
+
+        let request = TestRequest::get().uri("/api/docs").to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = read_body(response).await;
+        assert!(String::from_utf8_lossy(&page).contains("/api/openapi.json"));
+
+        let request = TestRequest::get().uri("/api/openapi.json").to_request();
+        let spec: Value = call_and_read_body_json(&app, request).await;
+        assert_eq!(spec, serde_json::to_value(ApiDoc::openapi()).unwrap());
     }
 }
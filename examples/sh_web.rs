@@ -3,15 +3,17 @@ use actix_web::http::StatusCode;
 use actix_web::{web, App, HttpResponse, HttpServer, ResponseError, Scope};
 use serde::{Deserialize, Serialize};
 
-use smarthouse_web::devices::{Device, SmartSocket, SmartThermometer};
-use smarthouse_web::smarthouse::{BorrowingDeviceInfoProvider, OwningDeviceInfoProvider, SmartHouse};
+use smarthouse_web::devices::{
+    ContactSensor, Cover, CoverKind, Device, EnergyMeter, EnvironmentSensor, Hazard, MotionSensor, SafetyDetector,
+    SmartLight, SmartLock, SmartSocket, SmartThermometer, Thermostat,
+};
+use smarthouse_web::smarthouse::{BorrowingDeviceInfoProvider, OwningDeviceInfoProvider, SmartHouse, SmartHouseError};
 use smarthouse_web::smartroom::SmartRoom;
 
 use std::error::Error as StdError;
-use std::ops::Deref;
 use std::sync::Arc;
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 
 pub type CustomResult<T> = Result<T, CustomError>;
 
@@ -38,25 +40,42 @@ impl ResponseError for CustomError {
     }
 }
 
+impl From<SmartHouseError> for CustomError {
+    fn from(err: SmartHouseError) -> Self {
+        match err {
+            SmartHouseError::RoomNotFound(_) | SmartHouseError::DeviceNotFound(_) => {
+                Self::NotFound(err.to_string())
+            }
+            _ => Self::InternalError(err.to_string()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Context {
-    context: Arc<Mutex<SmartHouse>>,
+    context: Arc<RwLock<SmartHouse>>,
 }
 
 impl Context {
     pub fn new() -> Self {
         let home = SmartHouse::new(String::from("Мой дом"));
-        let home = Mutex::new(home);
+        let home = RwLock::new(home);
         let home = Arc::new(home);
 
         Self { context: home }
     }
 
-    pub fn get_context(&self) -> &Arc<Mutex<SmartHouse>> {
+    pub fn get_context(&self) -> &Arc<RwLock<SmartHouse>> {
         &self.context
     }
 }
 
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Data {
     name: String,
@@ -72,6 +91,20 @@ pub struct DeviceData {
 pub enum DeviceType {
     Socket,
     Thermo,
+    Light,
+    Motion,
+    Contact,
+    Thermostat,
+    Lock,
+    Blind,
+    Shutter,
+    GarageDoor,
+    LeakDetector,
+    SmokeDetector,
+    EnergyMeter,
+    Humidity,
+    Co2,
+    AirQuality,
 }
  
 #[derive(Clone, Serialize, Deserialize)]
@@ -114,10 +147,9 @@ async fn default_response() -> CustomResult<HttpResponse> {
 
 #[actix_web::get("/home")]
 async fn get_home(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
-    let house = ctx.get_context().lock().await;
-    let house_object = house.deref();
+    let house = ctx.get_context().read().await;
 
-    Ok(HttpResponse::Ok().json(house_object))
+    Ok(HttpResponse::Ok().json(house.view()))
 }
 
 #[actix_web::get("/reports/{provider}")]
@@ -127,7 +159,7 @@ async fn get_reports(
 ) -> CustomResult<HttpResponse> {
     let provider = path.into_inner();
 
-    let house = ctx.get_context().lock().await;
+    let house = ctx.get_context().read().await;
     match provider {
         Provider::Owning => {
             let socket = SmartSocket::default(String::from("Socket 1"));
@@ -152,7 +184,7 @@ async fn get_reports(
 
 #[actix_web::get("/rooms")]
 async fn get_rooms(ctx: web::Data<Context>) -> CustomResult<HttpResponse> {
-    let house = ctx.get_context().lock().await;
+    let house = ctx.get_context().read().await;
     let rooms: Vec<&SmartRoom> = house.get_rooms_list().into_iter().collect();
 
     Ok(HttpResponse::Ok().json(rooms))
@@ -165,7 +197,7 @@ async fn create_room(
 ) -> CustomResult<HttpResponse> {
     let data = body_data.into_inner();
 
-    let mut house = ctx.get_context().lock().await;
+    let mut house = ctx.get_context().write().await;
     let room: SmartRoom = SmartRoom::default(data.name);
 
     house.add_smart_room(&room).unwrap();
@@ -174,22 +206,22 @@ async fn create_room(
 }
 
 #[actix_web::delete("/rooms/{room_name}")]
-async fn delete_room(ctx: web::Data<Context>, path: web::Path<SmartRoom>, ) -> CustomResult<HttpResponse> {
-    let room = path.into_inner();
-    let mut house = ctx.get_context().lock().await;
-    if let Some(_room) = house.get_room(&room) {
-        house.remove_smart_room(&room).unwrap();
+async fn delete_room(ctx: web::Data<Context>, path: web::Path<String>) -> CustomResult<HttpResponse> {
+    let room_name = path.into_inner();
+    let mut house = ctx.get_context().write().await;
+    if let Some(room) = house.get_room_by_name(&room_name).cloned() {
+        house.remove_smart_room(&room)?;
 
         Ok(HttpResponse::Ok().json("Ok"))
     } else {
-        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: {}", &room))))
+        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: {}", room_name))))
     }
 }
 
 #[actix_web::get("/rooms/{room_name}/devices")]
 async fn get_devices(ctx: web::Data<Context>, room: web::Path<String>) -> CustomResult<HttpResponse> {
     let room_name = room.into_inner();
-    let house = ctx.get_context().lock().await;
+    let house = ctx.get_context().read().await;
     let devices = house.device_info(&room_name);
     match devices {
         Some(devices) => Ok(HttpResponse::Ok().json(devices)),
@@ -201,45 +233,44 @@ async fn get_devices(ctx: web::Data<Context>, room: web::Path<String>) -> Custom
 async fn create_devices(
     ctx: web::Data<Context>,
     body_data: web::Json<DeviceData>,
-    mut room: web::Path<SmartRoom>,
+    room: web::Path<String>,
 ) -> CustomResult<HttpResponse> {
     let data = body_data.into_inner();
+    let room_name = room.into_inner();
 
-    let mut house = ctx.get_context().lock().await;
-    if let Some(_room) = room.get_room_name().into() {
-        let device = match data.device_type {
-            DeviceType::Socket => Device::SmartSocket(SmartSocket::default(data.name)),
-            DeviceType::Thermo => Device::SmartThermometr(SmartThermometer::default(data.name)),
-        };
-        room.add_smart_device(device.clone()).unwrap();
-        house.add_smart_room(&room).unwrap();
-
-        Ok(HttpResponse::Created().json(room.into_inner()))
-    } else {
-        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: {}", room.room_name))))
-    }
+    let mut house = ctx.get_context().write().await;
+    let device = match data.device_type {
+        DeviceType::Socket => Device::SmartSocket(SmartSocket::default(data.name)),
+        DeviceType::Thermo => Device::SmartThermometr(SmartThermometer::default(data.name)),
+        DeviceType::Light => Device::SmartLight(SmartLight::default(data.name)),
+        DeviceType::Motion => Device::MotionSensor(MotionSensor::default(data.name)),
+        DeviceType::Contact => Device::ContactSensor(ContactSensor::default(data.name)),
+        DeviceType::Thermostat => Device::Thermostat(Thermostat::default(data.name)),
+        DeviceType::Lock => Device::SmartLock(SmartLock::default(data.name)),
+        DeviceType::Blind => Device::Cover(Cover::new(data.name, CoverKind::Blind)),
+        DeviceType::Shutter => Device::Cover(Cover::new(data.name, CoverKind::Shutter)),
+        DeviceType::GarageDoor => Device::Cover(Cover::new(data.name, CoverKind::GarageDoor)),
+        DeviceType::LeakDetector => Device::SafetyDetector(SafetyDetector::new(data.name, Hazard::Leak)),
+        DeviceType::SmokeDetector => Device::SafetyDetector(SafetyDetector::new(data.name, Hazard::Smoke)),
+        DeviceType::EnergyMeter => Device::EnergyMeter(EnergyMeter::default(data.name)),
+        DeviceType::Humidity => Device::EnvironmentSensor(EnvironmentSensor::humidity(data.name)),
+        DeviceType::Co2 => Device::EnvironmentSensor(EnvironmentSensor::co2(data.name)),
+        DeviceType::AirQuality => Device::EnvironmentSensor(EnvironmentSensor::air_quality(data.name)),
+    };
+    house.add_device(&room_name, device)?;
+
+    Ok(HttpResponse::Created().json(house.get_room_by_name(&room_name)))
 }
 
-#[actix_web::delete("/rooms/{id}/devices/{device_id}")]
+#[actix_web::delete("/rooms/{room_name}/devices/{device_id}")]
 async fn delete_device(
     ctx: web::Data<Context>,
-    path: web::Path<(SmartRoom, String)>,
+    path: web::Path<(String, String)>,
 ) -> CustomResult<HttpResponse> {
-    let (room, device_name) = path.into_inner();
-
-    let mut house = ctx.get_context().lock().await;
-    if let Some(room) = house.get_room(&room) {
-        if let Some(device) = room.get_device(device_name) {
-            let mut room = room.clone();
-            room.delite_device(&device).unwrap();
-            house.add_smart_room(&room).unwrap();
-
-            Ok(HttpResponse::Ok().json("OK"))
-        } else {
-            Ok(HttpResponse::NotFound()
-                .json(CustomError::NotFound(format!("Device: not found "))))
-        }
-    } else {
-        Ok(HttpResponse::NotFound().json(CustomError::NotFound(format!("Room: not found", ))))
-    }
+    let (room_name, device_name) = path.into_inner();
+
+    let mut house = ctx.get_context().write().await;
+    house.remove_device(&room_name, &device_name)?;
+
+    Ok(HttpResponse::Ok().json("OK"))
 }
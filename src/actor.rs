@@ -0,0 +1,739 @@
+use crate::availability::Availability;
+use crate::batch::BatchOutcome;
+use crate::devices::{Device, DeviceKind, MeterReading, PhaseReading};
+use crate::events::HouseEvent;
+use crate::registry::SharedHouse;
+use crate::smarthouse::{SmartHouse, SmartHouseError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Mutex, OwnedMutexGuard};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum ActorError {
+    #[error("No reply after {attempts} attempts")]
+    Timeout { attempts: u32 },
+    #[error("Device I/O failed: {0}")]
+    Io(String),
+    #[error("Unsupported command: {0}")]
+    Unsupported(String),
+    #[error("The device actor has stopped")]
+    Stopped,
+}
+
+impl ActorError {
+    /// Whether trying the same command again may help.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ActorError::Timeout { .. } | ActorError::Io(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum DeviceCommand {
+    Switch { status: bool },
+    ReadTemperature,
+    ReadPower,
+    ReadVoltage,
+    /// Energy counter of a meter.
+    ReadEnergy,
+    /// Last known state, answered by the actor without device I/O.
+    State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum DeviceReply {
+    Switched(bool),
+    Temperature(f32),
+    Power(f32),
+    Voltage(f32),
+    /// kWh.
+    Energy(f64),
+    State(ActorState),
+}
+
+/// What the actor last heard from its device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ActorState {
+    pub status: Option<bool>,
+    pub temperature: Option<f32>,
+    pub power: Option<f32>,
+    pub voltage: Option<f32>,
+    pub energy_kwh: Option<f64>,
+    /// Time of the last reply, in milliseconds since the unix epoch.
+    pub last_success: Option<u64>,
+    /// Commands that failed in a row.
+    pub failures: u32,
+}
+
+/// The way to a remote device. Only its actor uses it, one command at a time.
+pub trait DeviceConnection: Send + 'static {
+    fn send(&mut self, command: DeviceCommand) -> impl Future<Output = Result<DeviceReply, ActorError>> + Send;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ActorConfig {
+    /// How long a single attempt may take.
+    pub timeout: Duration,
+    /// Attempts after the first one for transient failures.
+    pub retries: u32,
+    /// Pause before each retry, doubled every time.
+    pub backoff: Duration,
+    /// Commands that may wait in the mailbox.
+    pub mailbox: usize,
+}
+
+impl Default for ActorConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            mailbox: 32,
+        }
+    }
+}
+
+type Request = (DeviceCommand, oneshot::Sender<Result<DeviceReply, ActorError>>);
+
+/// Sends commands to a device actor. The actor stops once every handle
+/// is dropped.
+#[derive(Debug, Clone)]
+pub struct DeviceHandle {
+    sender: mpsc::Sender<Request>,
+    /// The state cell of the device, held by a control command from
+    /// sending it until its reply is recorded.
+    cell: Arc<Mutex<()>>,
+}
+
+impl DeviceHandle {
+    /// Starts an actor owning `connection` on the current tokio runtime.
+    pub fn spawn<C: DeviceConnection>(connection: C, config: ActorConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.mailbox.max(1));
+        tokio::spawn(run(connection, config, receiver));
+        Self {
+            sender,
+            cell: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Waits for the control commands of the device that are still being
+    /// sent or recorded. Commands to other devices don't wait.
+    pub async fn take_turn(&self) -> OwnedMutexGuard<()> {
+        self.cell.clone().lock_owned().await
+    }
+
+    pub async fn request(&self, command: DeviceCommand) -> Result<DeviceReply, ActorError> {
+        let (reply, receiver) = oneshot::channel();
+        self.sender.send((command, reply)).await.map_err(|_| ActorError::Stopped)?;
+        receiver.await.map_err(|_| ActorError::Stopped)?
+    }
+
+    pub async fn state(&self) -> Result<ActorState, ActorError> {
+        match self.request(DeviceCommand::State).await? {
+            DeviceReply::State(state) => Ok(state),
+            reply => Err(ActorError::Io(format!("Unexpected reply {:?}", reply))),
+        }
+    }
+}
+
+async fn run<C: DeviceConnection>(mut connection: C, config: ActorConfig, mut receiver: mpsc::Receiver<Request>) {
+    let mut state = ActorState::default();
+    while let Some((command, reply)) = receiver.recv().await {
+        let result = match command {
+            DeviceCommand::State => Ok(DeviceReply::State(state)),
+            command => execute(&mut connection, &config, command).await,
+        };
+        match &result {
+            Ok(device_reply) => {
+                state.failures = 0;
+                state.last_success = Some(crate::events::now_millis());
+                match *device_reply {
+                    DeviceReply::Switched(status) => state.status = Some(status),
+                    DeviceReply::Temperature(temperature) => state.temperature = Some(temperature),
+                    DeviceReply::Power(power) => state.power = Some(power),
+                    DeviceReply::Voltage(voltage) => state.voltage = Some(voltage),
+                    DeviceReply::Energy(energy) => state.energy_kwh = Some(energy),
+                    DeviceReply::State(_) => {}
+                }
+            }
+            Err(err) if err.is_transient() => state.failures += 1,
+            Err(_) => {}
+        }
+        // The caller may have given up waiting
+        let _ = reply.send(result);
+    }
+}
+
+async fn execute<C: DeviceConnection>(
+    connection: &mut C,
+    config: &ActorConfig,
+    command: DeviceCommand,
+) -> Result<DeviceReply, ActorError> {
+    let mut backoff = config.backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error = match tokio::time::timeout(config.timeout, connection.send(command)).await {
+            Ok(Ok(reply)) => return Ok(reply),
+            Ok(Err(err)) if !err.is_transient() => return Err(err),
+            Ok(Err(err)) => err,
+            Err(_) => ActorError::Timeout { attempts: attempt },
+        };
+        if attempt > config.retries {
+            return Err(match error {
+                ActorError::Timeout { .. } => ActorError::Timeout { attempts: attempt },
+                err => err,
+            });
+        }
+        log::warn!("Device command {:?} failed, retrying: {}", command, error);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+/// Actor handles of the devices that have one, following the devices
+/// through moves and renames.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceActors {
+    handles: HashMap<(String, String), DeviceHandle>,
+}
+
+impl DeviceActors {
+    pub fn insert(&mut self, room_name: &str, device_name: &str, handle: DeviceHandle) {
+        self.handles.insert((room_name.to_string(), device_name.to_string()), handle);
+    }
+
+    pub fn get(&self, room_name: &str, device_name: &str) -> Option<&DeviceHandle> {
+        self.handles.get(&(room_name.to_string(), device_name.to_string()))
+    }
+
+    pub fn remove(&mut self, room_name: &str, device_name: &str) -> Option<DeviceHandle> {
+        self.handles.remove(&(room_name.to_string(), device_name.to_string()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Updates the keys after `event` was applied.
+    pub fn follow(&mut self, event: &HouseEvent) {
+        match event {
+            HouseEvent::DeviceRemoved { room_name, device_name } => {
+                self.remove(room_name, device_name);
+            }
+            HouseEvent::DeviceMoved { from, to, device_name } => {
+                if let Some(handle) = self.remove(from, device_name) {
+                    self.insert(to, device_name, handle);
+                }
+            }
+            HouseEvent::RoomRemoved { room_name } => self.handles.retain(|(room, _), _| room != room_name),
+            HouseEvent::RoomRenamed { room_name, new_name } => {
+                let moved: Vec<_> = self
+                    .handles
+                    .keys()
+                    .filter(|(room, _)| room == room_name)
+                    .cloned()
+                    .collect();
+                for key in moved {
+                    if let Some(handle) = self.handles.remove(&key) {
+                        self.handles.insert((new_name.clone(), key.1), handle);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends `command` to the device's actor without holding the house lock
+/// while waiting, then records the reply. A switch of a device without an
+/// actor is committed right away.
+pub async fn command(
+    house: &SharedHouse,
+    room_name: &str,
+    device_name: &str,
+    command: DeviceCommand,
+) -> Result<DeviceReply, SmartHouseError> {
+    command_if(house, room_name, device_name, command, |_| Ok(())).await
+}
+
+/// Like [`command`], but only if `check` passes, e.g. the room is still at
+/// the revision a client has seen. It is checked before the command is sent
+/// and again under the write lock the reply is recorded with, so nothing is
+/// committed over a change that came in meanwhile. A socket that switched
+/// by then is switched back.
+pub async fn command_if<E: From<SmartHouseError>>(
+    house: &SharedHouse,
+    room_name: &str,
+    device_name: &str,
+    command: DeviceCommand,
+    check: impl Fn(&SmartHouse) -> Result<(), E>,
+) -> Result<DeviceReply, E> {
+    let previous = {
+        let house = house.read().await;
+        check(&house)?;
+        match house.get_device(room_name, device_name) {
+            Ok(Device::SmartSocket(socket)) => Some(socket.status()),
+            _ => None,
+        }
+    };
+    let _turn = turn(house, room_name, device_name).await;
+    let reply = send(house, room_name, device_name, command).await?;
+    let mut guard = house.write().await;
+    if let Err(err) = check(&guard) {
+        drop(guard);
+        if let (DeviceReply::Switched(_), Some(status)) = (reply, previous) {
+            if let Err(err) = send(house, room_name, device_name, DeviceCommand::Switch { status }).await {
+                log::error!("Switching {} in {} back failed: {}", device_name, room_name, err);
+            }
+        }
+        return Err(err);
+    }
+    record(&mut guard, room_name, device_name, reply)?;
+    Ok(reply)
+}
+
+/// The turn of the device if it has an actor, see [`DeviceHandle::take_turn`].
+/// Held from sending until recording, replies are recorded in the order
+/// the device carried the commands out.
+async fn turn(house: &SharedHouse, room_name: &str, device_name: &str) -> Option<OwnedMutexGuard<()>> {
+    let handle = house.read().await.actor(room_name, device_name).cloned();
+    match handle {
+        Some(handle) => Some(handle.take_turn().await),
+        None => None,
+    }
+}
+
+/// Gets the reply to `command` without recording it.
+async fn send(
+    house: &SharedHouse,
+    room_name: &str,
+    device_name: &str,
+    command: DeviceCommand,
+) -> Result<DeviceReply, SmartHouseError> {
+    let handle = {
+        let house = house.read().await;
+        house.get_device(room_name, device_name)?;
+        house.actor(room_name, device_name).cloned()
+    };
+    match (handle, command) {
+        (Some(handle), command) => request(house, &handle, room_name, device_name, command).await,
+        (None, DeviceCommand::Switch { status }) => Ok(DeviceReply::Switched(status)),
+        (None, command) => Err(ActorError::Unsupported(format!("{:?} without a device connection", command)).into()),
+    }
+}
+
+/// Commits the reply. A switch confirmed by the device counts as a
+/// heartbeat, recorded only now so that bringing the device online doesn't
+/// fail the checks made before.
+fn record(house: &mut SmartHouse, room_name: &str, device_name: &str, reply: DeviceReply) -> Result<(), SmartHouseError> {
+    match reply {
+        DeviceReply::Switched(status) => {
+            house.switch_socket(room_name, device_name, status)?;
+            match house.actor(room_name, device_name) {
+                Some(_) => house.heartbeat(room_name, device_name),
+                None => Ok(()),
+            }
+        }
+        DeviceReply::Temperature(value) | DeviceReply::Voltage(value) => house.record_reading(room_name, device_name, value),
+        DeviceReply::Power(watts) => house.record_power(room_name, device_name, watts),
+        DeviceReply::Energy(_) | DeviceReply::State(_) => Ok(()),
+    }
+}
+
+/// Switches every socket of the group through its actor, one after the
+/// other. `check` guards the first switch like in [`command_if`]. If a
+/// socket fails, the ones switched before it are switched back, so either
+/// all sockets end up switched or none.
+pub async fn switch_group<E: From<SmartHouseError>>(
+    house: &SharedHouse,
+    name: &str,
+    status: bool,
+    check: impl Fn(&SmartHouse) -> Result<(), E>,
+) -> Result<BatchOutcome, E> {
+    let sockets = {
+        let house = house.read().await;
+        check(&house)?;
+        let mut sockets = Vec::new();
+        for member in house.group_members(name)? {
+            if let Device::SmartSocket(socket) = house.get_device(&member.room_name, &member.device_name)? {
+                sockets.push((member.room_name, member.device_name, socket.status()));
+            }
+        }
+        sockets
+    };
+    for (index, (room_name, device_name, _)) in sockets.iter().enumerate() {
+        let turn = turn(house, room_name, device_name).await;
+        let reply = send(house, room_name, device_name, DeviceCommand::Switch { status }).await;
+        let mut guard = house.write().await;
+        if index == 0 {
+            check(&guard)?;
+        }
+        if let Err(err) = reply.and_then(|reply| record(&mut guard, room_name, device_name, reply)) {
+            drop(guard);
+            drop(turn);
+            for (room_name, device_name, previous) in sockets[..index].iter().rev() {
+                if let Err(err) = self::command(house, room_name, device_name, DeviceCommand::Switch { status: *previous }).await {
+                    log::error!("Switching {} in {} back failed: {}", device_name, room_name, err);
+                }
+            }
+            return Ok(BatchOutcome::rolled_back(sockets.len(), index, err.to_string()));
+        }
+    }
+    Ok(BatchOutcome::committed(sockets.len()))
+}
+
+/// Sends `command`, a device that doesn't answer goes offline. Replies
+/// mark the device as seen once they're recorded.
+async fn request(
+    house: &SharedHouse,
+    handle: &DeviceHandle,
+    room_name: &str,
+    device_name: &str,
+    command: DeviceCommand,
+) -> Result<DeviceReply, SmartHouseError> {
+    match handle.request(command).await {
+        Ok(reply) => Ok(reply),
+        Err(ActorError::Unsupported(reason)) => Err(ActorError::Unsupported(reason).into()),
+        Err(err) => {
+            house.write().await.set_availability(room_name, device_name, Availability::Offline)?;
+            Err(err.into())
+        }
+    }
+}
+
+/// Reads the device through its actor: the temperature of thermometers,
+/// the power of sockets along with their voltage if the device measures
+/// it, and the counter, power and voltage of meters.
+pub async fn poll(house: &SharedHouse, room_name: &str, device_name: &str) -> Result<DeviceReply, SmartHouseError> {
+    let kind = house.read().await.get_device(room_name, device_name)?.kind();
+    let read = match kind {
+        DeviceKind::Thermometer => DeviceCommand::ReadTemperature,
+        DeviceKind::Socket => DeviceCommand::ReadPower,
+        DeviceKind::Meter => return poll_meter(house, room_name, device_name).await,
+        kind => return Err(ActorError::Unsupported(format!("Reading {:?} devices", kind)).into()),
+    };
+    let reply = command(house, room_name, device_name, read).await?;
+    if read == DeviceCommand::ReadPower {
+        match command(house, room_name, device_name, DeviceCommand::ReadVoltage).await {
+            Err(SmartHouseError::DeviceUnreachable(ActorError::Unsupported(_))) => {}
+            result => {
+                result?;
+            }
+        }
+    }
+    Ok(reply)
+}
+
+/// Records one reading of the meter, a single phase if it reports a voltage.
+async fn poll_meter(house: &SharedHouse, room_name: &str, device_name: &str) -> Result<DeviceReply, SmartHouseError> {
+    let handle = house.read().await.actor(room_name, device_name).cloned().ok_or_else(|| {
+        ActorError::Unsupported(format!("{:?} without a device connection", DeviceCommand::ReadEnergy))
+    })?;
+    let _turn = handle.take_turn().await;
+    let energy_kwh = match request(house, &handle, room_name, device_name, DeviceCommand::ReadEnergy).await? {
+        DeviceReply::Energy(energy_kwh) => energy_kwh,
+        reply => return Err(ActorError::Io(format!("Unexpected reply {:?}", reply)).into()),
+    };
+    let power = match request(house, &handle, room_name, device_name, DeviceCommand::ReadPower).await? {
+        DeviceReply::Power(power) => power,
+        reply => return Err(ActorError::Io(format!("Unexpected reply {:?}", reply)).into()),
+    };
+    let phases = match request(house, &handle, room_name, device_name, DeviceCommand::ReadVoltage).await {
+        Ok(DeviceReply::Voltage(voltage)) if voltage > 0.0 => vec![PhaseReading {
+            voltage,
+            current: power / voltage,
+            power,
+        }],
+        Ok(_) | Err(SmartHouseError::DeviceUnreachable(ActorError::Unsupported(_))) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    let reading = MeterReading {
+        energy_kwh,
+        power,
+        phases,
+    };
+    house.write().await.record_meter(room_name, device_name, reading)?;
+    Ok(DeviceReply::Energy(energy_kwh))
+}
+
+/// Commits planned events, e.g. from [`crate::thermostat::plan`]. Socket
+/// switches go through the device actors first and are only committed
+/// once the device confirms them.
+pub async fn actuate(house: &SharedHouse, events: Vec<HouseEvent>, now: u64) -> Result<(), SmartHouseError> {
+    for event in events {
+        let mut _turn = None;
+        let mut confirmed = None;
+        if let HouseEvent::SocketSwitched { room_name, device_name, status } = &event {
+            let handle = house.read().await.actor(room_name, device_name).cloned();
+            if let Some(handle) = handle {
+                _turn = Some(handle.take_turn().await);
+                let switch = DeviceCommand::Switch { status: *status };
+                if let Err(err) = request(house, &handle, room_name, device_name, switch).await {
+                    log::error!("Switching {} in {} failed: {}", device_name, room_name, err);
+                    continue;
+                }
+                confirmed = Some((room_name.clone(), device_name.clone()));
+            }
+        }
+        let mut house = house.write().await;
+        house.commit_at(event, now)?;
+        if let Some((room_name, device_name)) = confirmed {
+            house.heartbeat_at(&room_name, &device_name, now)?;
+        }
+    }
+    Ok(())
+}
+
+/// A device that lives in memory, for tests and demos. It answers after
+/// `latency` and fails the first `failures` commands.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedConnection {
+    pub latency: Duration,
+    pub failures: u32,
+    pub status: bool,
+    pub temperature: Option<f32>,
+    pub power: Option<f32>,
+}
+
+impl DeviceConnection for SimulatedConnection {
+    async fn send(&mut self, command: DeviceCommand) -> Result<DeviceReply, ActorError> {
+        tokio::time::sleep(self.latency).await;
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(ActorError::Io("Connection reset".to_string()));
+        }
+        match command {
+            DeviceCommand::Switch { status } if self.power.is_some() => {
+                self.status = status;
+                Some(DeviceReply::Switched(status))
+            }
+            DeviceCommand::ReadTemperature => self.temperature.map(DeviceReply::Temperature),
+            DeviceCommand::ReadPower => self.power.map(DeviceReply::Power),
+            _ => None,
+        }
+        .ok_or_else(|| ActorError::Unsupported(format!("{:?}", command)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::SmartSocket;
+    use crate::group::DeviceQuery;
+    use crate::smartroom::SmartRoom;
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn socket(latency: Duration, failures: u32) -> SimulatedConnection {
+        SimulatedConnection {
+            latency,
+            failures,
+            power: Some(1_000.0),
+            ..SimulatedConnection::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_and_timeouts() {
+        let config = ActorConfig::default();
+        let handle = DeviceHandle::spawn(socket(Duration::from_millis(10), 2), config);
+        assert_eq!(handle.request(DeviceCommand::Switch { status: true }).await.unwrap(), DeviceReply::Switched(true));
+        assert_eq!(handle.state().await.unwrap().status, Some(true));
+
+        let handle = DeviceHandle::spawn(socket(Duration::from_secs(5), 0), config);
+        let err = handle.request(DeviceCommand::ReadPower).await.unwrap_err();
+        assert!(matches!(err, ActorError::Timeout { attempts: 3 }));
+        assert_eq!(handle.state().await.unwrap().failures, 1);
+
+        let handle = DeviceHandle::spawn(socket(Duration::ZERO, 0), config);
+        let err = handle.request(DeviceCommand::ReadTemperature).await.unwrap_err();
+        assert!(matches!(err, ActorError::Unsupported(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn house_is_not_locked_while_waiting() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        house.attach_actor("Kitchen", "Kettle", DeviceHandle::spawn(socket(Duration::from_secs(1), 0), ActorConfig::default())).unwrap();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+
+        let task = tokio::spawn({
+            let house = house.clone();
+            async move { command(&house, "Kitchen", "Kettle", DeviceCommand::Switch { status: true }).await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(house.try_write().is_ok());
+        task.await.unwrap().unwrap();
+        poll(&house, "Kitchen", "Kettle").await.unwrap();
+
+        {
+            let house = house.read().await;
+            match house.get_device("Kitchen", "Kettle").unwrap() {
+                Device::SmartSocket(socket) => assert_eq!((socket.status(), socket.power()), (true, 1_000.0)),
+                device => panic!("{:?}", device),
+            }
+            assert_eq!(house.device_availability("Kitchen", "Kettle").unwrap().heartbeat.availability, Availability::Online);
+        }
+
+        // A device that stops answering goes offline
+        let silent = DeviceHandle::spawn(socket(Duration::from_secs(60), 0), ActorConfig::default());
+        house.write().await.attach_actor("Kitchen", "Kettle", silent).unwrap();
+        assert!(command(&house, "Kitchen", "Kettle", DeviceCommand::Switch { status: false }).await.is_err());
+        assert_eq!(house.read().await.get_room_by_name("Kitchen").unwrap().availability("Kettle"), Availability::Offline);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_seen_socket_passes_the_check() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        house.attach_actor("Kitchen", "Kettle", DeviceHandle::spawn(socket(Duration::from_secs(1), 0), ActorConfig::default())).unwrap();
+        let seen = house.get_room_by_name("Kitchen").unwrap().revision;
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let unchanged = move |house: &SmartHouse| match house.get_room_by_name("Kitchen").unwrap().revision == seen {
+            true => Ok(()),
+            false => Err(SmartHouseError::UndoConflict("stale".to_string())),
+        };
+
+        command_if(&house, "Kitchen", "Kettle", DeviceCommand::Switch { status: true }, unchanged).await.unwrap();
+        let house = house.read().await;
+        match house.get_device("Kitchen", "Kettle").unwrap() {
+            Device::SmartSocket(socket) => assert!(socket.status()),
+            device => panic!("{:?}", device),
+        }
+        assert_eq!(house.device_availability("Kitchen", "Kettle").unwrap().heartbeat.availability, Availability::Online);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commands_take_turns_per_device() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        for name in ["Kettle", "Toaster"] {
+            house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default(name.to_string()))).unwrap();
+            house.attach_actor("Kitchen", name, DeviceHandle::spawn(socket(Duration::from_secs(1), 0), ActorConfig::default())).unwrap();
+        }
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let switch = |name: &'static str, status: bool| {
+            let house = house.clone();
+            tokio::spawn(async move { command(&house, "Kitchen", name, DeviceCommand::Switch { status }).await })
+        };
+        let status = |house: &SmartHouse, name: &str| match house.get_device("Kitchen", name).unwrap() {
+            Device::SmartSocket(socket) => socket.status(),
+            device => panic!("{:?}", device),
+        };
+
+        let on = switch("Kettle", true);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let off = switch("Kettle", false);
+        let toaster = switch("Toaster", true);
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+        // The toaster didn't wait for the kettle, whose second switch waits for the first
+        assert!(on.is_finished() && toaster.is_finished() && !off.is_finished());
+        assert!(status(&*house.read().await, "Kettle") && status(&*house.read().await, "Toaster"));
+
+        off.await.unwrap().unwrap();
+        let house = house.read().await;
+        assert!(!status(&house, "Kettle"));
+        assert_eq!(house.actor("Kitchen", "Kettle").unwrap().state().await.unwrap().status, Some(false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_revision_is_not_committed() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        house.attach_actor("Kitchen", "Kettle", DeviceHandle::spawn(socket(Duration::from_secs(1), 0), ActorConfig::default())).unwrap();
+        let seen = house.revision();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let unchanged = move |house: &SmartHouse| match house.revision() == seen {
+            true => Ok(()),
+            false => Err(SmartHouseError::UndoConflict("stale".to_string())),
+        };
+
+        let task = tokio::spawn({
+            let house = house.clone();
+            async move { command_if(&house, "Kitchen", "Kettle", DeviceCommand::Switch { status: true }, unchanged).await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        house.write().await.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        assert!(task.await.unwrap().is_err());
+        let house = house.read().await;
+        match house.get_device("Kitchen", "Kettle").unwrap() {
+            Device::SmartSocket(socket) => assert!(!socket.status()),
+            device => panic!("{:?}", device),
+        }
+        // The device switched before the change was noticed and is switched back
+        assert_eq!(house.actor("Kitchen", "Kettle").unwrap().state().await.unwrap().status, Some(false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn group_switch_is_rolled_back() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        let heater = BTreeSet::from(["heater".to_string()]);
+        for (name, failures) in [("A heater", 0), ("B heater", 0), ("C heater", 10)] {
+            house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default(name.to_string()))).unwrap();
+            house.set_tags("Kitchen", name, heater.clone()).unwrap();
+            let handle = DeviceHandle::spawn(socket(Duration::ZERO, failures), ActorConfig::default());
+            house.attach_actor("Kitchen", name, handle).unwrap();
+        }
+        let query = DeviceQuery {
+            tag: Some("heater".to_string()),
+            kind: None,
+            room: None,
+        };
+        house.set_group("heaters", query).unwrap();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let status = |house: &SmartHouse, name: &str| match house.get_device("Kitchen", name).unwrap() {
+            Device::SmartSocket(socket) => socket.status(),
+            device => panic!("{:?}", device),
+        };
+
+        let outcome = switch_group(&house, "heaters", true, |_| Ok::<_, SmartHouseError>(())).await.unwrap();
+        assert!(!outcome.committed);
+        {
+            let house = house.read().await;
+            assert!(["A heater", "B heater", "C heater"].iter().all(|name| !status(&house, name)));
+        }
+
+        house.write().await.remove_device("Kitchen", "C heater").unwrap();
+        let outcome = switch_group(&house, "heaters", true, |_| Ok::<_, SmartHouseError>(())).await.unwrap();
+        assert!(outcome.committed);
+        assert!(status(&*house.read().await, "B heater"));
+        let missing = switch_group(&house, "outdoor", true, |_| Ok::<_, SmartHouseError>(())).await;
+        assert!(matches!(missing, Err(SmartHouseError::GroupNotFound(_))));
+    }
+
+    #[test]
+    fn handles_follow_devices() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let _guard = runtime.enter();
+        let mut actors = DeviceActors::default();
+        actors.insert("Kitchen", "Kettle", DeviceHandle::spawn(socket(Duration::ZERO, 0), ActorConfig::default()));
+        actors.follow(&HouseEvent::RoomRenamed {
+            room_name: "Kitchen".to_string(),
+            new_name: "Cuisine".to_string(),
+        });
+        actors.follow(&HouseEvent::DeviceMoved {
+            from: "Cuisine".to_string(),
+            to: "Hall".to_string(),
+            device_name: "Kettle".to_string(),
+        });
+        assert!(actors.get("Hall", "Kettle").is_some());
+        actors.follow(&HouseEvent::RoomRemoved {
+            room_name: "Hall".to_string(),
+        });
+        assert!(actors.is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::devices::Device;
+use crate::smartroom::SmartRoom;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum AreaError {
+    #[error("Invalid area: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+pub enum AreaKind {
+    Floor,
+    /// Outdoor parts of the property such as a garden or a driveway.
+    Zone,
+}
+
+/// A floor or zone that rooms can be placed on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Area {
+    pub name: String,
+    pub kind: AreaKind,
+    /// Orders floors from the bottom up. Zones usually leave it at zero.
+    #[serde(default)]
+    pub level: i32,
+}
+
+impl Area {
+    pub fn floor(name: String, level: i32) -> Self {
+        Self {
+            name,
+            kind: AreaKind::Floor,
+            level,
+        }
+    }
+
+    pub fn zone(name: String) -> Self {
+        Self {
+            name,
+            kind: AreaKind::Zone,
+            level: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AreaError> {
+        if self.name.trim().is_empty() {
+            return Err(AreaError::Invalid("Empty name".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Floors from the bottom up, then zones.
+    pub fn sort_key(&self) -> (AreaKind, i32, &str) {
+        (self.kind, self.level, &self.name)
+    }
+}
+
+impl Display for Area {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            AreaKind::Floor => write!(f, "{} (floor {})", self.name, self.level),
+            AreaKind::Zone => write!(f, "{} (zone)", self.name),
+        }
+    }
+}
+
+/// State aggregated over a set of rooms.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LevelSummary {
+    pub rooms: usize,
+    pub devices: usize,
+    pub occupied_rooms: usize,
+    pub sockets_on: usize,
+    /// Watts drawn by the sockets that are on.
+    pub power: f32,
+    pub average_temperature: Option<f32>,
+    pub alarms: usize,
+}
+
+impl LevelSummary {
+    pub fn new<'a>(rooms: impl IntoIterator<Item = &'a SmartRoom>, now: u64) -> Self {
+        let mut summary = Self {
+            rooms: 0,
+            devices: 0,
+            occupied_rooms: 0,
+            sockets_on: 0,
+            power: 0.0,
+            average_temperature: None,
+            alarms: 0,
+        };
+        let mut temperatures = Vec::new();
+        for room in rooms {
+            summary.rooms += 1;
+            summary.devices += room.smart_device.len();
+            summary.occupied_rooms += usize::from(room.is_occupied(now));
+            for device in room.smart_device.values() {
+                match device {
+                    Device::SmartSocket(socket) if socket.status() => {
+                        summary.sockets_on += 1;
+                        summary.power += socket.drawn_power();
+                    }
+                    Device::SmartThermometr(thermometer) => temperatures.push(thermometer.temperature()),
+                    Device::SafetyDetector(detector) if detector.alarm_since().is_some() => summary.alarms += 1,
+                    _ => {}
+                }
+            }
+        }
+        if !temperatures.is_empty() {
+            summary.average_temperature = Some(temperatures.iter().sum::<f32>() / temperatures.len() as f32);
+        }
+        summary
+    }
+}
+
+impl Display for LevelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} rooms ({} occupied), {} devices, {} sockets on drawing {:.1} W",
+            self.rooms, self.occupied_rooms, self.devices, self.sockets_on, self.power
+        )?;
+        if let Some(temperature) = self.average_temperature {
+            write!(f, ", average temperature {:.1}", temperature)?;
+        }
+        if self.alarms > 0 {
+            write!(f, ", {} alarms", self.alarms)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoomNode {
+    pub room_name: String,
+    pub summary: LevelSummary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AreaNode {
+    pub area: Area,
+    pub summary: LevelSummary,
+    pub rooms: Vec<RoomNode>,
+}
+
+/// The house split into floors and zones. Rooms that aren't placed on
+/// any of them are listed on their own.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HouseTree {
+    pub house_name: String,
+    pub summary: LevelSummary,
+    pub areas: Vec<AreaNode>,
+    pub unplaced: Vec<RoomNode>,
+}
+
+impl AreaNode {
+    pub fn new(area: Area, rooms: &[&SmartRoom], now: u64) -> Self {
+        Self {
+            area,
+            summary: LevelSummary::new(rooms.iter().copied(), now),
+            rooms: room_nodes(rooms, now),
+        }
+    }
+}
+
+pub fn room_nodes(rooms: &[&SmartRoom], now: u64) -> Vec<RoomNode> {
+    let mut nodes: Vec<RoomNode> = rooms
+        .iter()
+        .map(|room| RoomNode {
+            room_name: room.room_name.clone(),
+            summary: LevelSummary::new([*room], now),
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.room_name.cmp(&b.room_name));
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{SmartSocket, SmartThermometer};
+
+    #[test]
+    fn summary_over_rooms() {
+        let mut kitchen = SmartRoom::default("Kitchen".to_string());
+        let mut socket = SmartSocket::default("Kettle".to_string());
+        socket.switch(true);
+        kitchen.add_smart_device(Device::SmartSocket(socket)).unwrap();
+        let mut hall = SmartRoom::default("Hall".to_string());
+        let mut thermometer = Device::SmartThermometr(SmartThermometer::default("Thermometer".to_string()));
+        thermometer.record_reading(20.0).unwrap();
+        hall.add_smart_device(thermometer).unwrap();
+
+        let summary = LevelSummary::new([&kitchen, &hall], 0);
+        assert_eq!((summary.rooms, summary.devices, summary.sockets_on), (2, 2, 1));
+        assert_eq!(summary.average_temperature, Some(20.0));
+        assert_eq!(summary.to_string(), "2 rooms (0 occupied), 2 devices, 1 sockets on drawing 0.0 W, average temperature 20.0");
+    }
+
+    #[test]
+    fn floors_before_zones() {
+        let mut areas = [Area::zone("Garden".to_string()), Area::floor("Upstairs".to_string(), 1), Area::floor("Ground".to_string(), 0)];
+        areas.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        let names: Vec<_> = areas.iter().map(|area| area.name.as_str()).collect();
+        assert_eq!(names, vec!["Ground", "Upstairs", "Garden"]);
+        assert!(Area::zone(" ".to_string()).validate().is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use utoipa::ToSchema;
+
+/// A device is offline when it wasn't heard from for this long, in milliseconds.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 5 * 60 * 1000;
+
+fn default_heartbeat_timeout() -> u64 {
+    DEFAULT_HEARTBEAT_TIMEOUT
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Availability {
+    Online,
+    Offline,
+    /// Never heard from.
+    #[default]
+    Unknown,
+}
+
+impl Display for Availability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Availability::Online => write!(f, "online"),
+            Availability::Offline => write!(f, "offline"),
+            Availability::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// When a device was last heard from, by a heartbeat or any report.
+/// `last_seen` isn't part of the event log, only changes of availability are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Heartbeat {
+    pub availability: Availability,
+    /// Milliseconds since the unix epoch.
+    pub last_seen: Option<u64>,
+    #[serde(default = "default_heartbeat_timeout")]
+    pub timeout_ms: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            availability: Availability::Unknown,
+            last_seen: None,
+            timeout_ms: DEFAULT_HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Moves `last_seen` forward. Availability only changes through events.
+    pub fn seen(&mut self, timestamp: u64) {
+        self.last_seen = Some(self.last_seen.map_or(timestamp, |last_seen| last_seen.max(timestamp)));
+    }
+
+    /// Online, but not heard from within the timeout.
+    pub fn expired(&self, now: u64) -> bool {
+        self.availability == Availability::Online
+            && self
+                .last_seen
+                .is_some_and(|last_seen| now.saturating_sub(last_seen) >= self.timeout_ms)
+    }
+}
+
+/// Availability of one device, as reported by the house.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeviceAvailability {
+    pub room_name: String,
+    pub device_name: String,
+    #[serde(flatten)]
+    pub heartbeat: Heartbeat,
+}
+
+impl Display for DeviceAvailability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} in {} is {}", self.device_name, self.room_name, self.heartbeat.availability)?;
+        if let Some(last_seen) = self.heartbeat.last_seen {
+            write!(f, ", last seen at {}", last_seen)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_after_timeout() {
+        let mut heartbeat = Heartbeat {
+            availability: Availability::Online,
+            timeout_ms: 1_000,
+            ..Heartbeat::default()
+        };
+        assert!(!heartbeat.expired(10_000));
+        heartbeat.seen(5_000);
+        heartbeat.seen(4_000);
+        assert_eq!(heartbeat.last_seen, Some(5_000));
+        assert!(!heartbeat.expired(5_999));
+        assert!(heartbeat.expired(6_000));
+        heartbeat.availability = Availability::Offline;
+        assert!(!heartbeat.expired(10_000));
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum OperationStatus {
+    Applied,
+    Failed(String),
+    RolledBack,
+    Skipped,
+}
+
+/// Per-operation results of [`crate::smarthouse::SmartHouse::transaction`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BatchOutcome {
+    pub committed: bool,
+    pub results: Vec<OperationStatus>,
+}
+
+impl BatchOutcome {
+    pub fn committed(len: usize) -> BatchOutcome {
+        BatchOutcome {
+            committed: true,
+            results: vec![OperationStatus::Applied; len],
+        }
+    }
+
+    /// Everything before `failed` was rolled back, everything after it never ran.
+    pub fn rolled_back(len: usize, failed: usize, error: String) -> BatchOutcome {
+        let results = (0..len)
+            .map(|index| match index.cmp(&failed) {
+                std::cmp::Ordering::Less => OperationStatus::RolledBack,
+                std::cmp::Ordering::Equal => OperationStatus::Failed(error.clone()),
+                std::cmp::Ordering::Greater => OperationStatus::Skipped,
+            })
+            .collect();
+        BatchOutcome {
+            committed: false,
+            results,
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use crate::actor::{self, DeviceCommand};
+use crate::availability::Availability;
+use crate::devices::{redacted, Device, DeviceKind, LightState};
+use crate::homeassistant::{self, DISCOVERY_PREFIX};
+use crate::mqtt::{Message, MqttError};
+use crate::registry::SharedHouse;
+use crate::smarthouse::{SmartHouse, SmartHouseError};
+use crate::subscription::EventFilter;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS, SubscribeFilter};
+use tokio::sync::mpsc::{unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+pub const DEFAULT_PREFIX: &str = "smarthouse";
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Requests the client queues while the connection is busy.
+const REQUEST_CAPACITY: usize = 64;
+
+/// Names as single topic levels. Separators, wildcards and `%` itself are
+/// percent-encoded, so different names never share a topic.
+pub fn topic_segment(name: &str) -> String {
+    let mut segment = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' | '/' | '+' | '#' | '\0' => segment.push_str(&format!("%{:02X}", c as u32)),
+            c => segment.push(c),
+        }
+    }
+    segment
+}
+
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// First level of every topic.
+    pub prefix: String,
+    pub house_id: String,
+    pub client_id: String,
+    pub keep_alive: Duration,
+    /// Whether device states are retained by the broker.
+    pub retain: bool,
+    /// Where Home Assistant discovery configs go, `None` to publish none.
+    pub discovery_prefix: Option<String>,
+    /// How long to wait before connecting again after the broker was lost.
+    pub reconnect_delay: Duration,
+}
+
+impl BridgeConfig {
+    pub fn new(house_id: &str) -> Self {
+        Self {
+            prefix: DEFAULT_PREFIX.to_string(),
+            house_id: house_id.to_string(),
+            client_id: format!("{}-{}", DEFAULT_PREFIX, house_id),
+            keep_alive: Duration::from_secs(30),
+            retain: true,
+            discovery_prefix: Some(DISCOVERY_PREFIX.to_string()),
+            reconnect_delay: RECONNECT_DELAY,
+        }
+    }
+
+    /// `{prefix}/{house}`
+    pub fn house_topic(&self) -> String {
+        format!("{}/{}", self.prefix, topic_segment(&self.house_id))
+    }
+
+    /// `online` while the bridge runs, `offline` once it stops or is cut off.
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.house_topic())
+    }
+
+    /// `{prefix}/{house}/{room}/{device}`, followed by `/state` or `/set`.
+    pub fn device_topic(&self, room_name: &str, device_name: &str) -> String {
+        format!("{}/{}/{}", self.house_topic(), topic_segment(room_name), topic_segment(device_name))
+    }
+
+    /// Options to connect to the broker at `host`, with the client id and
+    /// keep alive of the config. Credentials and TLS can be added to them.
+    pub fn mqtt_options(&self, host: &str, port: u16) -> MqttOptions {
+        let mut options = MqttOptions::new(self.client_id.clone(), host, port);
+        options.set_keep_alive(self.keep_alive);
+        options
+    }
+}
+
+/// What the bridge publishes on a device's state topic.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StatePayload {
+    pub kind: DeviceKind,
+    pub availability: Availability,
+    /// The fields of the device, e.g. `status` and `power` of a socket.
+    pub state: serde_json::Value,
+}
+
+impl StatePayload {
+    pub fn new(device: &Device, availability: Availability) -> Self {
+        let state = match redacted(|| serde_json::to_value(device)) {
+            Ok(serde_json::Value::Object(variant)) => variant.into_iter().next().map(|(_, state)| state),
+            _ => None,
+        };
+        Self {
+            kind: device.kind(),
+            availability,
+            state: state.unwrap_or_default(),
+        }
+    }
+}
+
+/// State and discovery config messages of every device, by topic.
+fn snapshot(house: &SmartHouse, config: &BridgeConfig) -> BTreeMap<String, Message> {
+    let mut messages = BTreeMap::new();
+    for room in house.get_rooms_list() {
+        for (device_name, device) in &room.smart_device {
+            if let Some(discovery_prefix) = &config.discovery_prefix {
+                for entity in homeassistant::entities(config, &room.room_name, device_name, device) {
+                    let topic = entity.topic(discovery_prefix);
+                    let payload = serde_json::to_vec(&entity).unwrap_or_default();
+                    messages.insert(topic.clone(), Message::new(topic, payload, true));
+                }
+            }
+            let payload = StatePayload::new(device, room.availability(device_name));
+            let topic = format!("{}/state", config.device_topic(&room.room_name, device_name));
+            let payload = serde_json::to_vec(&payload).unwrap_or_default();
+            messages.insert(topic.clone(), Message::new(topic, payload, config.retain));
+        }
+    }
+    messages
+}
+
+/// `current` with the fields of a JSON object payload replaced, e.g.
+/// `{"position": 100}` for a cover.
+fn patch<T: Serialize + DeserializeOwned>(current: &T, payload: &[u8]) -> Option<T> {
+    let serde_json::Value::Object(fields) = serde_json::from_slice(payload).ok()? else {
+        return None;
+    };
+    let mut state = serde_json::to_value(current).ok()?;
+    let object = state.as_object_mut()?;
+    for (field, value) in fields {
+        object.insert(field, value);
+    }
+    serde_json::from_value(state).ok()
+}
+
+/// `ON`, `OFF`, `true`, `false` or `{"status": ...}`.
+fn switch_payload(payload: &[u8]) -> Option<bool> {
+    #[derive(Deserialize)]
+    struct Status {
+        status: bool,
+    }
+    match std::str::from_utf8(payload).ok()?.trim() {
+        "ON" | "on" | "true" => Some(true),
+        "OFF" | "off" | "false" => Some(false),
+        other => serde_json::from_str::<Status>(other).ok().map(|status| status.status),
+    }
+}
+
+/// Carries out a message of a `/set` topic.
+async fn apply(house: &SharedHouse, config: &BridgeConfig, message: &Message) -> Result<(), SmartHouseError> {
+    let device = {
+        let house = house.read().await;
+        house.get_rooms_list().into_iter().find_map(|room| {
+            room.smart_device.iter().find_map(|(device_name, device)| {
+                let topic = format!("{}/set", config.device_topic(&room.room_name, device_name));
+                (topic == message.topic).then(|| (room.room_name.clone(), device_name.clone(), device.clone()))
+            })
+        })
+    };
+    let Some((room_name, device_name, device)) = device else {
+        return Err(SmartHouseError::DeviceNotFound(message.topic.clone()));
+    };
+    let invalid = || SmartHouseError::InvalidDevice(crate::devices::DeviceError::InvalidValue(format!(
+        "Can't apply {:?} to {}",
+        String::from_utf8_lossy(&message.payload),
+        device_name
+    )));
+    match device {
+        Device::SmartSocket(_) => {
+            let status = switch_payload(&message.payload).ok_or_else(invalid)?;
+            actor::command(house, &room_name, &device_name, DeviceCommand::Switch { status }).await?;
+        }
+        Device::SmartLight(light) => {
+            let state = match patch(light.state(), &message.payload) {
+                Some(state) => state,
+                None => LightState {
+                    status: switch_payload(&message.payload).ok_or_else(invalid)?,
+                    ..*light.state()
+                },
+            };
+            house.write().await.set_light(&room_name, &device_name, state)?;
+        }
+        Device::Cover(cover) => {
+            let state = patch(cover.state(), &message.payload).ok_or_else(invalid)?;
+            house.write().await.set_cover(&room_name, &device_name, state)?;
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+/// Publishes the state of every device of a house to an MQTT broker and
+/// carries out what's published to the devices' `/set` topics. Unless
+/// disabled, devices are announced to Home Assistant as well, again whenever
+/// it comes online. A lost connection is made again after the reconnect
+/// delay of the config, with every state published anew.
+#[derive(Debug)]
+pub struct MqttBridge {
+    client: AsyncClient,
+    config: BridgeConfig,
+    connection: JoinHandle<()>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        self.connection.abort();
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl MqttBridge {
+    /// Connects with `options`, e.g. from [`BridgeConfig::mqtt_options`],
+    /// and fails if the first attempt does. The bridge sets the last will.
+    pub async fn start(house: SharedHouse, mut options: MqttOptions, config: BridgeConfig) -> Result<MqttBridge, MqttError> {
+        options.set_last_will(Message::new(config.availability_topic(), "offline", true).into());
+        let (client, mut connection) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let republish = Arc::new(Notify::new());
+        let (sender, commands) = unbounded_channel();
+        loop {
+            match connection.poll().await? {
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    connected(&client, &config)?;
+                    break;
+                }
+                event => log::debug!("MQTT event before CONNACK: {:?}", event),
+            }
+        }
+        let connection = tokio::spawn(keep_connected(connection, client.clone(), config.clone(), sender, republish.clone()));
+
+        let events = house.write().await.subscribe(EventFilter::default());
+        let publisher = tokio::spawn(publish_states(house.clone(), client.clone(), config.clone(), events, republish.clone()));
+        let receiver = tokio::spawn(receive_commands(house, config.clone(), commands, republish));
+        Ok(MqttBridge {
+            client,
+            config,
+            connection,
+            tasks: vec![publisher, receiver],
+        })
+    }
+
+    pub fn config(&self) -> &BridgeConfig {
+        &self.config
+    }
+
+    /// Publishes `message` on the bridge's connection.
+    pub async fn publish(&self, message: Message) -> Result<(), MqttError> {
+        message.publish(&self.client).await
+    }
+
+    /// Marks the house offline and disconnects.
+    pub async fn stop(mut self) -> Result<(), MqttError> {
+        self.tasks.iter().for_each(JoinHandle::abort);
+        self.publish(Message::new(self.config.availability_topic(), "offline", true)).await?;
+        self.client.disconnect().await?;
+        // The connection ends once the disconnect is sent
+        let _ = tokio::time::timeout(self.config.keep_alive, &mut self.connection).await;
+        Ok(())
+    }
+}
+
+/// Announces the bridge and subscribes to the commands on every
+/// (re)connect. A clean session forgets the subscriptions of the last one.
+fn connected(client: &AsyncClient, config: &BridgeConfig) -> Result<(), MqttError> {
+    Message::new(config.availability_topic(), "online", true).try_publish(client)?;
+    let mut filters = vec![format!("{}/+/+/set", config.house_topic())];
+    filters.extend(config.discovery_prefix.iter().map(|prefix| format!("{}/status", prefix)));
+    client.try_subscribe_many(filters.into_iter().map(|filter| SubscribeFilter::new(filter, QoS::AtMostOnce)))?;
+    Ok(())
+}
+
+/// Polls the connection, which also makes it again once it's lost, and
+/// hands received messages to [`receive_commands`].
+async fn keep_connected(
+    mut connection: rumqttc::EventLoop,
+    client: AsyncClient,
+    config: BridgeConfig,
+    commands: UnboundedSender<Message>,
+    republish: Arc<Notify>,
+) {
+    loop {
+        match connection.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("MQTT bridge reconnected");
+                if let Err(err) = connected(&client, &config) {
+                    log::error!("Announcing the bridge failed: {}", err);
+                }
+                republish.notify_one();
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if commands.send(Message::from(publish)).is_err() {
+                    break;
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(err) => {
+                log::warn!("MQTT connection lost, retrying in {:?}: {}", config.reconnect_delay, err);
+                tokio::time::sleep(config.reconnect_delay).await;
+            }
+        }
+    }
+}
+
+async fn publish_states(
+    house: SharedHouse,
+    client: AsyncClient,
+    config: BridgeConfig,
+    mut events: Receiver<crate::subscription::Notification>,
+    republish: Arc<Notify>,
+) {
+    let mut published: BTreeMap<String, Message> = BTreeMap::new();
+    loop {
+        let messages = snapshot(&*house.read().await, &config);
+        for (topic, message) in &messages {
+            if published.get(topic) != Some(message) {
+                if let Err(err) = message.clone().publish(&client).await {
+                    log::error!("Publishing {} failed: {}", topic, err);
+                }
+            }
+        }
+        // Clear the retained states and configs of removed devices
+        for topic in published.keys().filter(|topic| !messages.contains_key(*topic)) {
+            if let Err(err) = Message::new(topic.clone(), Vec::new(), true).publish(&client).await {
+                log::error!("Clearing {} failed: {}", topic, err);
+            }
+        }
+        published = messages;
+        let dropped = tokio::select! {
+            event = events.recv() => event.is_none(),
+            _ = republish.notified() => {
+                published.clear();
+                false
+            }
+        };
+        if dropped {
+            // Lagged behind, the next snapshot catches up on what was missed
+            events = house.write().await.subscribe(EventFilter::default());
+        }
+        // Alarms and events committed meanwhile are covered by one snapshot
+        while events.try_recv().is_ok() {}
+    }
+}
+
+async fn receive_commands(
+    house: SharedHouse,
+    config: BridgeConfig,
+    mut commands: UnboundedReceiver<Message>,
+    republish: Arc<Notify>,
+) {
+    while let Some(message) = commands.recv().await {
+        let status = config.discovery_prefix.as_ref().map(|prefix| format!("{}/status", prefix));
+        if Some(&message.topic) == status.as_ref() {
+            // Home Assistant restarted and needs the configs again
+            if message.payload == b"online" {
+                republish.notify_one();
+            }
+            continue;
+        }
+        if let Err(err) = apply(&house, &config, &message).await {
+            log::error!("MQTT command on {} failed: {}", message.topic, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{SmartLight, SmartSocket};
+    use crate::mqtt::broker::Broker;
+    use crate::smartroom::SmartRoom;
+    use tokio::sync::RwLock;
+    use tokio::time::timeout;
+
+    /// The next message on `topic` whose payload satisfies `check`.
+    async fn next(messages: &mut UnboundedReceiver<Message>, topic: &str, check: impl Fn(&[u8]) -> bool) -> Message {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let message = messages.recv().await.unwrap();
+                if message.topic == topic && check(&message.payload) {
+                    return message;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Nothing on {}", topic))
+    }
+
+    fn state(payload: &[u8]) -> StatePayload {
+        serde_json::from_slice(payload).unwrap()
+    }
+
+    #[test]
+    fn names_never_share_a_topic() {
+        assert_eq!(topic_segment("Tv/Hifi"), "Tv%2FHifi");
+        assert_ne!(topic_segment("Tv/Hifi"), topic_segment("Tv_Hifi"));
+        assert_ne!(topic_segment("Tv/Hifi"), topic_segment("Tv%2FHifi"));
+        assert_eq!(topic_segment("#1 + 2"), "%231 %2B 2");
+    }
+
+    #[tokio::test]
+    async fn announces_devices_to_home_assistant() {
+        let broker = Broker::bind("127.0.0.1:0").await.unwrap();
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let config = BridgeConfig::new("default");
+        let bridge = MqttBridge::start(house.clone(), broker.options(&config.client_id), config).await.unwrap();
+        let kettle = homeassistant::device_id(bridge.config(), "Kitchen", house.read().await.get_device("Kitchen", "Kettle").unwrap());
+        let topic = format!("homeassistant/switch/{}/switch/config", kettle);
+
+        let mut client = broker.connect(broker.options("home-assistant"));
+        client.subscribe("homeassistant/#").await;
+        let config = next(&mut client.messages, &topic, |_| true).await;
+        assert_eq!(broker.retained(&topic).unwrap().payload, config.payload);
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(config["command_topic"], "smarthouse/default/Kitchen/Kettle/set");
+        assert_eq!(config["device"]["suggested_area"], "Kitchen");
+
+        // Commands arrive through the command topic of the config
+        client.publish(Message::new("smarthouse/default/Kitchen/Kettle/set", "ON", false)).await;
+        timeout(Duration::from_secs(5), async {
+            while !house.read().await.get_device("Kitchen", "Kettle").is_ok_and(|device| matches!(device, Device::SmartSocket(socket) if socket.status())) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Home Assistant coming online gets every config again
+        client.publish(Message::new("homeassistant/status", "online", false)).await;
+        next(&mut client.messages, &topic, |_| true).await;
+        // Same entity in the renamed room
+        house.write().await.rename_room("Kitchen", "Cuisine").unwrap();
+        let config = next(&mut client.messages, &topic, |payload| {
+            serde_json::from_slice::<serde_json::Value>(payload).is_ok_and(|config| config["device"]["suggested_area"] == "Cuisine")
+        })
+        .await;
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(config["command_topic"], "smarthouse/default/Cuisine/Kettle/set");
+    }
+
+    #[tokio::test]
+    async fn states_out_commands_in() {
+        let broker = Broker::bind("127.0.0.1:0").await.unwrap();
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Living room".to_string())).unwrap();
+        house.add_device("Living room", Device::SmartSocket(SmartSocket::default("Tv/Hifi".to_string()))).unwrap();
+        house.add_device("Living room", Device::SmartLight(SmartLight::default("Lamp".to_string()))).unwrap();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let mut config = BridgeConfig::new("flat-1");
+        config.prefix = "test".to_string();
+        let bridge = MqttBridge::start(house.clone(), broker.options(&config.client_id), config).await.unwrap();
+
+        let mut client = broker.connect(broker.options("watcher"));
+        client.subscribe("test/#").await;
+        next(&mut client.messages, "test/flat-1/availability", |payload| payload == b"online").await;
+        let socket = next(&mut client.messages, "test/flat-1/Living room/Tv%2FHifi/state", |_| true).await;
+        assert_eq!(broker.retained("test/flat-1/Living room/Tv%2FHifi/state").unwrap().payload, socket.payload);
+        assert_eq!(state(&socket.payload).state["status"], false);
+
+        client.publish(Message::new("test/flat-1/Living room/Tv%2FHifi/set", "ON", false)).await;
+        let socket = next(&mut client.messages, "test/flat-1/Living room/Tv%2FHifi/state", |payload| state(payload).state["status"] == true).await;
+        assert_eq!(state(&socket.payload).availability, Availability::Unknown);
+        client.publish(Message::new("test/flat-1/Living room/Lamp/set", r#"{"status":true,"brightness":40,"color_temperature":2700,"rgb":null}"#, false)).await;
+        next(&mut client.messages, "test/flat-1/Living room/Lamp/state", |payload| state(payload).state["state"]["brightness"] == 40).await;
+
+        let lamp = homeassistant::device_id(bridge.config(), "Living room", house.read().await.get_device("Living room", "Lamp").unwrap());
+        house.write().await.remove_device("Living room", "Lamp").unwrap();
+        next(&mut client.messages, "test/flat-1/Living room/Lamp/state", <[u8]>::is_empty).await;
+        assert!(broker.retained("test/flat-1/Living room/Lamp/state").is_none());
+
+        // Removed devices vanish from Home Assistant too
+        assert!(broker.retained(&format!("homeassistant/light/{}/light/config", lamp)).is_none());
+
+        // Losing the bridge leaves the last will
+        drop(bridge);
+        next(&mut client.messages, "test/flat-1/availability", |payload| payload == b"offline").await;
+        assert_eq!(broker.retained("test/flat-1/availability").unwrap().payload, b"offline");
+    }
+
+    #[tokio::test]
+    async fn reconnects_to_a_restarted_broker() {
+        let broker = Broker::bind("127.0.0.1:0").await.unwrap();
+        let address = broker.local_addr();
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let mut config = BridgeConfig::new("default");
+        config.reconnect_delay = Duration::from_millis(50);
+        let _bridge = MqttBridge::start(house.clone(), broker.options(&config.client_id), config).await.unwrap();
+
+        drop(broker);
+        // The port is free once the aborted listener is dropped
+        let broker = timeout(Duration::from_secs(5), async {
+            loop {
+                match Broker::bind(address).await {
+                    Ok(broker) => return broker,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let mut client = broker.connect(broker.options("watcher"));
+        client.subscribe("smarthouse/#").await;
+        next(&mut client.messages, "smarthouse/default/availability", |payload| payload == b"online").await;
+        next(&mut client.messages, "smarthouse/default/Kitchen/Kettle/state", |_| true).await;
+
+        // Commands work on the new connection
+        client.publish(Message::new("smarthouse/default/Kitchen/Kettle/set", "ON", false)).await;
+        next(&mut client.messages, "smarthouse/default/Kitchen/Kettle/state", |payload| state(payload).state["status"] == true).await;
+    }
+}
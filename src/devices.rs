@@ -1,17 +1,35 @@
+use crate::energy::EnergyLedger;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use thiserror::Error;
 use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
 
 #[derive(Error, Debug, Serialize, Deserialize)]
 pub enum DeviceError {
     #[error("The device doesn't have a name")]
     DeviceNotName(String),
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
 }
 
-#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
 pub enum Device {
     SmartSocket(SmartSocket),
     SmartThermometr(SmartThermometer),
+    SmartLight(SmartLight),
+    MotionSensor(MotionSensor),
+    ContactSensor(ContactSensor),
+    Thermostat(Thermostat),
+    EnvironmentSensor(EnvironmentSensor),
+    SmartLock(SmartLock),
+    Cover(Cover),
+    SafetyDetector(SafetyDetector),
+    EnergyMeter(EnergyMeter),
 }
 
 impl Device {
@@ -19,34 +37,202 @@ impl Device {
         match self {
             Device::SmartSocket(smart_socket) => Ok(smart_socket.name.clone()),
             Device::SmartThermometr(smart_thermometer) => Ok(smart_thermometer.name.clone()),
+            Device::SmartLight(smart_light) => Ok(smart_light.name.clone()),
+            Device::MotionSensor(motion_sensor) => Ok(motion_sensor.name.clone()),
+            Device::ContactSensor(contact_sensor) => Ok(contact_sensor.name.clone()),
+            Device::Thermostat(thermostat) => Ok(thermostat.name.clone()),
+            Device::EnvironmentSensor(sensor) => Ok(sensor.name.clone()),
+            Device::SmartLock(lock) => Ok(lock.name.clone()),
+            Device::Cover(cover) => Ok(cover.name.clone()),
+            Device::SafetyDetector(detector) => Ok(detector.name.clone()),
+            Device::EnergyMeter(meter) => Ok(meter.name.clone()),
         }
     }
+
+    fn name(&self) -> String {
+        self.device_name().unwrap_or_default()
+    }
+
+    /// Identifies the device for good, whatever room it is in and whatever
+    /// that room is called. Empty until the device joins a house.
+    pub fn id(&self) -> &str {
+        match self {
+            Device::SmartSocket(device) => &device.id,
+            Device::SmartThermometr(device) => &device.id,
+            Device::SmartLight(device) => &device.id,
+            Device::MotionSensor(device) => &device.id,
+            Device::ContactSensor(device) => &device.id,
+            Device::Thermostat(device) => &device.id,
+            Device::EnvironmentSensor(device) => &device.id,
+            Device::SmartLock(device) => &device.id,
+            Device::Cover(device) => &device.id,
+            Device::SafetyDetector(device) => &device.id,
+            Device::EnergyMeter(device) => &device.id,
+        }
+    }
+
+    pub fn set_id(&mut self, id: String) {
+        match self {
+            Device::SmartSocket(device) => device.id = id,
+            Device::SmartThermometr(device) => device.id = id,
+            Device::SmartLight(device) => device.id = id,
+            Device::MotionSensor(device) => device.id = id,
+            Device::ContactSensor(device) => device.id = id,
+            Device::Thermostat(device) => device.id = id,
+            Device::EnvironmentSensor(device) => device.id = id,
+            Device::SmartLock(device) => device.id = id,
+            Device::Cover(device) => device.id = id,
+            Device::SafetyDetector(device) => device.id = id,
+            Device::EnergyMeter(device) => device.id = id,
+        }
+    }
+
+    pub fn kind(&self) -> DeviceKind {
+        match self {
+            Device::SmartSocket(_) => DeviceKind::Socket,
+            Device::SmartThermometr(_) => DeviceKind::Thermometer,
+            Device::SmartLight(_) => DeviceKind::Light,
+            Device::MotionSensor(_) => DeviceKind::Motion,
+            Device::ContactSensor(_) => DeviceKind::Contact,
+            Device::Thermostat(_) => DeviceKind::Thermostat,
+            Device::EnvironmentSensor(_) => DeviceKind::Environment,
+            Device::SmartLock(_) => DeviceKind::Lock,
+            Device::Cover(_) => DeviceKind::Cover,
+            Device::SafetyDetector(_) => DeviceKind::Detector,
+            Device::EnergyMeter(_) => DeviceKind::Meter,
+        }
+    }
+
+    pub fn record_reading(&mut self, value: f32) -> Result<(), DeviceError> {
+        match self {
+            Device::SmartSocket(smart_socket) => smart_socket.voltage = value,
+            Device::SmartThermometr(smart_thermometer) => smart_thermometer.temperature = value,
+            _ => return Err(DeviceError::Unsupported(format!("{} doesn't take readings", self.name()))),
+        }
+        Ok(())
+    }
+
+    /// Updates a binary sensor: motion detected or contact open.
+    pub fn set_active(&mut self, active: bool, timestamp: u64) -> Result<(), DeviceError> {
+        match self {
+            Device::MotionSensor(motion_sensor) => motion_sensor.set_motion(active, timestamp),
+            Device::ContactSensor(contact_sensor) => contact_sensor.set_open(active, timestamp),
+            _ => return Err(DeviceError::Unsupported(format!("{} is not a binary sensor", self.name()))),
+        }
+        Ok(())
+    }
 }
 impl std::fmt::Display for Device {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Device::SmartSocket(smart_socket) => write!(f, "{}", smart_socket),
             Device::SmartThermometr(smart_thermometer) => write!(f, "{}", smart_thermometer),
+            Device::SmartLight(smart_light) => write!(f, "{}", smart_light),
+            Device::MotionSensor(motion_sensor) => write!(f, "{}", motion_sensor),
+            Device::ContactSensor(contact_sensor) => write!(f, "{}", contact_sensor),
+            Device::Thermostat(thermostat) => write!(f, "{}", thermostat),
+            Device::EnvironmentSensor(sensor) => write!(f, "{}", sensor),
+            Device::SmartLock(lock) => write!(f, "{}", lock),
+            Device::Cover(cover) => write!(f, "{}", cover),
+            Device::SafetyDetector(detector) => write!(f, "{}", detector),
+            Device::EnergyMeter(meter) => write!(f, "{}", meter),
         }
     }
 }
 
+/// What a device is, without its state. Used to filter devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Socket,
+    Thermometer,
+    Light,
+    Motion,
+    Contact,
+    Thermostat,
+    Environment,
+    Lock,
+    Cover,
+    Detector,
+    Meter,
+}
+
 #[allow(dead_code)]
-#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
 pub struct SmartSocket {
     pub name: String,
+    #[serde(default)]
+    pub id: String,
     status: bool,
     voltage: f32,
+    /// Power drawn while switched on, in watts.
+    #[serde(default)]
+    power: f32,
+    #[serde(default)]
+    energy: EnergyLedger,
 }
 
 impl SmartSocket {
     pub fn default(name: String) -> SmartSocket {
         Self {
             name,
+            id: String::new(),
             status: false,
             voltage: 0.0,
+            power: 0.0,
+            energy: EnergyLedger::default(),
         }
     }
+
+    pub fn switch(&mut self, status: bool) {
+        self.status = status;
+    }
+
+    /// Switches at `timestamp`, booking the energy used until then.
+    pub fn switch_at(&mut self, status: bool, timestamp: u64) {
+        self.energy.advance(timestamp, self.drawn_power());
+        self.status = status;
+    }
+
+    pub fn power(&self) -> f32 {
+        self.power
+    }
+
+    /// Power actually drawn, zero while switched off.
+    pub fn drawn_power(&self) -> f32 {
+        if self.status {
+            self.power
+        } else {
+            0.0
+        }
+    }
+
+    pub fn record_power(&mut self, watts: f32, timestamp: u64) -> Result<(), DeviceError> {
+        if watts.is_nan() || watts < 0.0 {
+            return Err(DeviceError::InvalidValue(format!("Power {} W", watts)));
+        }
+        self.energy.advance(timestamp, self.drawn_power());
+        self.power = watts;
+        Ok(())
+    }
+
+    /// Energy used between the day boundaries `from` and `to`, in kWh.
+    pub fn energy(&self, from: u64, to: u64, now: u64) -> f64 {
+        self.energy.between(from, to, now, self.drawn_power()) / 1_000.0
+    }
+
+    /// Watt-hours used in each hour between `from` and `to`.
+    pub fn usage(&self, from: u64, to: u64, now: u64) -> BTreeMap<u64, f64> {
+        self.energy.usage_with(from, to, now, self.drawn_power())
+    }
+
+    pub fn status(&self) -> bool {
+        self.status
+    }
+
+    pub fn voltage(&self) -> f32 {
+        self.voltage
+    }
 }
 impl Display for SmartSocket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -58,9 +244,11 @@ impl Display for SmartSocket {
     }
 }
 #[allow(dead_code)]
-#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
 pub struct SmartThermometer {
     pub name: String,
+    #[serde(default)]
+    pub id: String,
     status: bool,
     temperature: f32,
 }
@@ -69,11 +257,15 @@ impl SmartThermometer {
     pub fn default(name: String) -> Self {
         Self {
             name,
+            id: String::new(),
             status: false,
             temperature: 0.0,
         }
     }
 
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
 }
 
 impl Display for SmartThermometer {
@@ -86,6 +278,1073 @@ impl Display for SmartThermometer {
     }
 }
 
+pub const MAX_BRIGHTNESS: u8 = 100;
+pub const MIN_COLOR_TEMPERATURE: u16 = 1500;
+pub const MAX_COLOR_TEMPERATURE: u16 = 9000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct LightState {
+    pub status: bool,
+    /// Percent, 0-100.
+    pub brightness: u8,
+    /// Kelvin.
+    pub color_temperature: u16,
+    pub rgb: Option<[u8; 3]>,
+}
+
+impl Default for LightState {
+    fn default() -> Self {
+        Self {
+            status: false,
+            brightness: MAX_BRIGHTNESS,
+            color_temperature: 2700,
+            rgb: None,
+        }
+    }
+}
+
+impl LightState {
+    pub fn validate(&self) -> Result<(), DeviceError> {
+        if self.brightness > MAX_BRIGHTNESS {
+            return Err(DeviceError::InvalidValue(format!(
+                "brightness {} is above {}",
+                self.brightness, MAX_BRIGHTNESS
+            )));
+        }
+        if !(MIN_COLOR_TEMPERATURE..=MAX_COLOR_TEMPERATURE).contains(&self.color_temperature) {
+            return Err(DeviceError::InvalidValue(format!(
+                "color temperature {}K is outside {}K-{}K",
+                self.color_temperature, MIN_COLOR_TEMPERATURE, MAX_COLOR_TEMPERATURE
+            )));
+        }
+        Ok(())
+    }
+
+    /// State `progress` (0.0-1.0) of the way from `self` to `target`.
+    /// The light stays on until the very end of a fade out.
+    pub fn interpolate(&self, target: &LightState, progress: f32) -> LightState {
+        if progress >= 1.0 {
+            return *target;
+        }
+        let lerp = |from: f32, to: f32| from + (to - from) * progress.max(0.0);
+        let rgb = match (self.rgb, target.rgb) {
+            (Some(from), Some(to)) => Some([0, 1, 2].map(|i| lerp(from[i] as f32, to[i] as f32).round() as u8)),
+            (from, _) => from,
+        };
+        let from_brightness = if self.status { self.brightness } else { 0 };
+        let to_brightness = if target.status { target.brightness } else { 0 };
+        LightState {
+            status: self.status || target.status,
+            brightness: lerp(from_brightness as f32, to_brightness as f32).round() as u8,
+            color_temperature: lerp(self.color_temperature as f32, target.color_temperature as f32).round() as u16,
+            rgb,
+        }
+    }
+}
+
+impl Display for LightState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "status: {}, brightness: {}%, color temperature: {}K",
+            self.status, self.brightness, self.color_temperature
+        )?;
+        if let Some([r, g, b]) = self.rgb {
+            write!(f, ", rgb: #{:02x}{:02x}{:02x}", r, g, b)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct SmartLight {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    state: LightState,
+}
+
+impl SmartLight {
+    pub fn default(name: String) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            state: LightState::default(),
+        }
+    }
+
+    pub fn state(&self) -> &LightState {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: LightState) -> Result<(), DeviceError> {
+        state.validate()?;
+        self.state = state;
+        Ok(())
+    }
+}
+
+impl Display for SmartLight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SmartLight name: {}, {}", self.name, self.state)
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct MotionSensor {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    motion: bool,
+    /// Milliseconds since the unix epoch.
+    last_changed: Option<u64>,
+    /// When motion was last seen, milliseconds since the unix epoch.
+    last_motion: Option<u64>,
+}
+
+impl MotionSensor {
+    pub fn default(name: String) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            motion: false,
+            last_changed: None,
+            last_motion: None,
+        }
+    }
+
+    pub fn motion(&self) -> bool {
+        self.motion
+    }
+
+    pub fn last_changed(&self) -> Option<u64> {
+        self.last_changed
+    }
+
+    pub fn last_motion(&self) -> Option<u64> {
+        self.last_motion
+    }
+
+    pub fn set_motion(&mut self, motion: bool, timestamp: u64) {
+        // Motion ending is the last moment it was seen
+        if motion || self.motion {
+            self.last_motion = Some(timestamp);
+        }
+        if motion != self.motion {
+            self.last_changed = Some(timestamp);
+        }
+        self.motion = motion;
+    }
+}
+
+impl Display for MotionSensor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MotionSensor name: {}, motion: {}", self.name, self.motion)
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct ContactSensor {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    open: bool,
+    /// Milliseconds since the unix epoch.
+    last_changed: Option<u64>,
+}
+
+impl ContactSensor {
+    pub fn default(name: String) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            open: false,
+            last_changed: None,
+        }
+    }
+
+    pub fn open(&self) -> bool {
+        self.open
+    }
+
+    pub fn last_changed(&self) -> Option<u64> {
+        self.last_changed
+    }
+
+    pub fn set_open(&mut self, open: bool, timestamp: u64) {
+        if open != self.open {
+            self.last_changed = Some(timestamp);
+        }
+        self.open = open;
+    }
+}
+
+impl Display for ContactSensor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = if self.open { "open" } else { "closed" };
+        write!(f, "ContactSensor name: {}, {}", self.name, state)
+    }
+}
+
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum ThermostatMode {
+    Off,
+    Heat,
+    Cool,
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum Demand {
+    Idle,
+    Heating,
+    Cooling,
+}
+
+/// Target temperature from `minute_of_day` (UTC) until the next setpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Setpoint {
+    pub minute_of_day: u16,
+    pub target: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ThermostatSettings {
+    pub mode: ThermostatMode,
+    /// Used when the schedule is empty.
+    pub target: f32,
+    /// Allowed deviation from the target before heating or cooling starts.
+    pub hysteresis: f32,
+    pub schedule: Vec<Setpoint>,
+    /// Thermometer in the same room.
+    pub sensor: Option<String>,
+    /// Socket in the same room that powers a heater.
+    pub heater: Option<String>,
+    /// Socket in the same room that powers a cooler.
+    pub cooler: Option<String>,
+}
+
+impl Default for ThermostatSettings {
+    fn default() -> Self {
+        Self {
+            mode: ThermostatMode::Off,
+            target: 21.0,
+            hysteresis: 0.5,
+            schedule: Vec::new(),
+            sensor: None,
+            heater: None,
+            cooler: None,
+        }
+    }
+}
+
+impl ThermostatSettings {
+    pub fn validate(&self) -> Result<(), DeviceError> {
+        if !self.target.is_finite() || self.schedule.iter().any(|setpoint| !setpoint.target.is_finite()) {
+            return Err(DeviceError::InvalidValue("target temperature must be a number".to_string()));
+        }
+        if self.hysteresis.is_nan() || self.hysteresis < 0.0 {
+            return Err(DeviceError::InvalidValue(format!("hysteresis {} is negative", self.hysteresis)));
+        }
+        if let Some(setpoint) = self.schedule.iter().find(|setpoint| setpoint.minute_of_day >= MINUTES_PER_DAY) {
+            return Err(DeviceError::InvalidValue(format!(
+                "setpoint minute {} is past the end of the day",
+                setpoint.minute_of_day
+            )));
+        }
+        Ok(())
+    }
+
+    /// Target at `now` (milliseconds since the unix epoch). The last setpoint
+    /// of the day stays active until the first one of the next day.
+    pub fn target_at(&self, now: u64) -> f32 {
+        let minute = ((now / 60_000) % MINUTES_PER_DAY as u64) as u16;
+        let latest = |setpoints: &mut dyn Iterator<Item = &Setpoint>| {
+            setpoints.max_by_key(|setpoint| setpoint.minute_of_day).map(|setpoint| setpoint.target)
+        };
+        latest(&mut self.schedule.iter().filter(|setpoint| setpoint.minute_of_day <= minute))
+            .or_else(|| latest(&mut self.schedule.iter()))
+            .unwrap_or(self.target)
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct Thermostat {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    settings: ThermostatSettings,
+    demand: Demand,
+}
+
+impl Thermostat {
+    pub fn default(name: String) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            settings: ThermostatSettings::default(),
+            demand: Demand::Idle,
+        }
+    }
+
+    pub fn settings(&self) -> &ThermostatSettings {
+        &self.settings
+    }
+
+    pub fn configure(&mut self, settings: ThermostatSettings) -> Result<(), DeviceError> {
+        settings.validate()?;
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn demand(&self) -> Demand {
+        self.demand
+    }
+
+    pub fn set_demand(&mut self, demand: Demand) {
+        self.demand = demand;
+    }
+
+    /// What the thermostat wants given the measured `temperature`. Inside the
+    /// hysteresis band the current demand is kept.
+    pub fn decide(&self, temperature: f32, now: u64) -> Demand {
+        let target = self.settings.target_at(now);
+        let hysteresis = self.settings.hysteresis;
+        let can_heat = matches!(self.settings.mode, ThermostatMode::Heat | ThermostatMode::Auto);
+        let can_cool = matches!(self.settings.mode, ThermostatMode::Cool | ThermostatMode::Auto);
+        if can_heat && temperature < target - hysteresis {
+            Demand::Heating
+        } else if can_cool && temperature > target + hysteresis {
+            Demand::Cooling
+        } else if (self.demand == Demand::Heating && can_heat && temperature < target + hysteresis)
+            || (self.demand == Demand::Cooling && can_cool && temperature > target - hysteresis)
+        {
+            self.demand
+        } else {
+            Demand::Idle
+        }
+    }
+}
+
+impl Display for Thermostat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Thermostat name: {}, mode: {:?}, target: {}, demand: {:?}",
+            self.name, self.settings.mode, self.settings.target, self.demand
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema)]
+pub enum Metric {
+    Humidity,
+    Co2,
+    Voc,
+    Pm25,
+}
+
+impl Metric {
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Metric::Humidity => "%",
+            Metric::Co2 => "ppm",
+            Metric::Voc => "index",
+            Metric::Pm25 => "µg/m³",
+        }
+    }
+
+    /// Lowest and highest value a sensor can report.
+    pub fn range(&self) -> (f32, f32) {
+        match self {
+            Metric::Humidity => (0.0, 100.0),
+            Metric::Co2 => (0.0, 10_000.0),
+            Metric::Voc => (1.0, 500.0),
+            Metric::Pm25 => (0.0, 1_000.0),
+        }
+    }
+
+    pub fn validate(&self, value: f32) -> Result<(), DeviceError> {
+        let (min, max) = self.range();
+        if (min..=max).contains(&value) {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidValue(format!(
+                "{} {} {} is outside {}-{}",
+                self, value, self.unit(), min, max
+            )))
+        }
+    }
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Metric::Humidity => "humidity",
+            Metric::Co2 => "CO2",
+            Metric::Voc => "VOC index",
+            Metric::Pm25 => "PM2.5",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Sensor that measures any of the supported [`Metric`]s.
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct EnvironmentSensor {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    metrics: Vec<Metric>,
+    readings: BTreeMap<Metric, f32>,
+}
+
+impl EnvironmentSensor {
+    pub fn new(name: String, metrics: Vec<Metric>) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            metrics,
+            readings: BTreeMap::new(),
+        }
+    }
+
+    pub fn humidity(name: String) -> Self {
+        Self::new(name, vec![Metric::Humidity])
+    }
+
+    pub fn co2(name: String) -> Self {
+        Self::new(name, vec![Metric::Co2])
+    }
+
+    pub fn air_quality(name: String) -> Self {
+        Self::new(name, vec![Metric::Humidity, Metric::Co2, Metric::Voc, Metric::Pm25])
+    }
+
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    pub fn reading(&self, metric: Metric) -> Option<f32> {
+        self.readings.get(&metric).copied()
+    }
+
+    pub fn record(&mut self, metric: Metric, value: f32) -> Result<(), DeviceError> {
+        if !self.metrics.contains(&metric) {
+            return Err(DeviceError::Unsupported(format!("{} doesn't measure {}", self.name, metric)));
+        }
+        metric.validate(value)?;
+        self.readings.insert(metric, value);
+        Ok(())
+    }
+}
+
+impl Display for EnvironmentSensor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EnvironmentSensor name: {}", self.name)?;
+        for (metric, value) in &self.readings {
+            write!(f, ", {}: {} {}", metric, value, metric.unit())?;
+        }
+        Ok(())
+    }
+}
+
+pub const BATTERY_LOW: u8 = 20;
+pub const ACCESS_HISTORY_LIMIT: usize = 100;
+/// Wrong PINs in a row before the lock stops taking PINs for a while.
+pub const PIN_ATTEMPTS: u32 = 3;
+/// First lockout after [`PIN_ATTEMPTS`] wrong PINs, doubled by every
+/// further wrong PIN up to [`PIN_LOCKOUT_MAX_MS`].
+pub const PIN_LOCKOUT_MS: u64 = 30_000;
+pub const PIN_LOCKOUT_MAX_MS: u64 = 3_600_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum LockState {
+    Locked,
+    Unlocked,
+    Jammed,
+}
+
+/// How a lock was operated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum AccessMethod {
+    Manual,
+    /// Opened with the guest code of this label.
+    Pin(String),
+    Api,
+    Automation,
+    /// A PIN that matched no valid code, the lock didn't move.
+    WrongPin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AccessEntry {
+    pub timestamp: u64,
+    pub locked: bool,
+    pub method: AccessMethod,
+}
+
+thread_local! {
+    static REDACTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `serialize` with the salts and hashes of PINs left out, for
+/// anything that goes to clients. Stored houses and event logs keep them.
+pub fn redacted<T>(serialize: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            REDACTED.set(self.0);
+        }
+    }
+    let _restore = Restore(REDACTED.replace(true));
+    serialize()
+}
+
+fn is_redacted<T>(_: &T) -> bool {
+    REDACTED.get()
+}
+
+/// A PIN code valid between `valid_from` and `valid_until`. Only a hash
+/// of the PIN with a random salt of its own is kept, and left out of
+/// output that's [`redacted`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AccessCode {
+    pub label: String,
+    #[serde(skip_serializing_if = "is_redacted", default)]
+    #[schema(ignore)]
+    salt: String,
+    #[serde(skip_serializing_if = "is_redacted", default)]
+    #[schema(ignore)]
+    pin_hash: String,
+    pub valid_from: u64,
+    pub valid_until: Option<u64>,
+}
+
+impl AccessCode {
+    pub fn new(label: String, pin: &str, valid_from: u64, valid_until: Option<u64>) -> Result<Self, DeviceError> {
+        if !(4..=8).contains(&pin.len()) || !pin.chars().all(|c| c.is_ascii_digit()) {
+            return Err(DeviceError::InvalidValue("PIN must be 4 to 8 digits".to_string()));
+        }
+        if valid_until.is_some_and(|until| until <= valid_from) {
+            return Err(DeviceError::InvalidValue(format!("Code {} expires before it starts", label)));
+        }
+        let mut salt = [0; 16];
+        getrandom::getrandom(&mut salt).map_err(|err| DeviceError::Unsupported(format!("No salt for the PIN: {}", err)))?;
+        let salt = hex(&salt);
+        Ok(Self {
+            pin_hash: hash_pin(&salt, pin),
+            salt,
+            label,
+            valid_from,
+            valid_until,
+        })
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        now >= self.valid_from && self.valid_until.is_none_or(|until| now < until)
+    }
+
+    pub fn matches(&self, pin: &str) -> bool {
+        !self.pin_hash.is_empty() && self.pin_hash == hash_pin(&self.salt, pin)
+    }
+}
+
+fn hash_pin(salt: &str, pin: &str) -> String {
+    hex(&Sha256::new().chain_update(salt).chain_update([0]).chain_update(pin).finalize())
+}
+
+/// A random id for [`Device::id`].
+pub fn new_device_id() -> Result<String, DeviceError> {
+    let mut id = [0; 8];
+    getrandom::getrandom(&mut id).map_err(|err| DeviceError::Unsupported(format!("No device id: {}", err)))?;
+    Ok(hex(&id))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct SmartLock {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    state: LockState,
+    battery: u8,
+    codes: Vec<AccessCode>,
+    history: Vec<AccessEntry>,
+    /// Where the lock was when it jammed.
+    #[serde(default)]
+    before_jam: Option<LockState>,
+    /// Wrong PINs since the last right one.
+    #[serde(default)]
+    failed_attempts: u32,
+    #[serde(default)]
+    locked_out_until: Option<u64>,
+}
+
+impl SmartLock {
+    pub fn default(name: String) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            state: LockState::Locked,
+            battery: 100,
+            codes: Vec::new(),
+            history: Vec::new(),
+            before_jam: None,
+            failed_attempts: 0,
+            locked_out_until: None,
+        }
+    }
+
+    pub fn state(&self) -> LockState {
+        self.state
+    }
+
+    pub fn battery(&self) -> u8 {
+        self.battery
+    }
+
+    pub fn battery_low(&self) -> bool {
+        self.battery < BATTERY_LOW
+    }
+
+    pub fn codes(&self) -> &[AccessCode] {
+        &self.codes
+    }
+
+    /// Latest lock and unlock operations, oldest first.
+    pub fn history(&self) -> &[AccessEntry] {
+        &self.history
+    }
+
+    /// Locks or unlocks the door. A jammed lock only moves by hand.
+    pub fn set_locked(&mut self, locked: bool, method: AccessMethod, timestamp: u64) -> Result<(), DeviceError> {
+        if self.state == LockState::Jammed && method != AccessMethod::Manual {
+            return Err(DeviceError::Unsupported(format!("{} is jammed", self.name)));
+        }
+        if method == AccessMethod::WrongPin {
+            return Err(DeviceError::InvalidValue("A wrong PIN doesn't move the lock".to_string()));
+        }
+        if matches!(method, AccessMethod::Pin(_)) {
+            self.failed_attempts = 0;
+            self.locked_out_until = None;
+        }
+        self.state = if locked { LockState::Locked } else { LockState::Unlocked };
+        self.before_jam = None;
+        self.log(locked, method, timestamp);
+        Ok(())
+    }
+
+    /// Records a wrong PIN. Once there were [`PIN_ATTEMPTS`] of them in a
+    /// row, every further one locks out PINs for twice as long.
+    pub fn reject_pin(&mut self, locked: bool, timestamp: u64) {
+        self.failed_attempts += 1;
+        if self.failed_attempts >= PIN_ATTEMPTS {
+            let doublings = (self.failed_attempts - PIN_ATTEMPTS).min(16);
+            let lockout = (PIN_LOCKOUT_MS << doublings).min(PIN_LOCKOUT_MAX_MS);
+            self.locked_out_until = Some(timestamp + lockout);
+        }
+        self.log(locked, AccessMethod::WrongPin, timestamp);
+    }
+
+    fn log(&mut self, locked: bool, method: AccessMethod, timestamp: u64) {
+        if self.history.len() == ACCESS_HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+        self.history.push(AccessEntry {
+            timestamp,
+            locked,
+            method,
+        });
+    }
+
+    /// Applies a status report of the lock hardware. A lock that is no
+    /// longer jammed is back where it was before.
+    pub fn report(&mut self, jammed: bool, battery: u8) -> Result<(), DeviceError> {
+        if battery > 100 {
+            return Err(DeviceError::InvalidValue(format!("Battery level {}", battery)));
+        }
+        self.battery = battery;
+        if jammed {
+            if self.state != LockState::Jammed {
+                self.before_jam = Some(self.state);
+            }
+            self.state = LockState::Jammed;
+        } else if self.state == LockState::Jammed {
+            self.state = self.before_jam.take().unwrap_or(LockState::Locked);
+        }
+        Ok(())
+    }
+
+    /// Until when PINs aren't taken after too many wrong ones.
+    pub fn locked_out_until(&self, now: u64) -> Option<u64> {
+        self.locked_out_until.filter(|until| now < *until)
+    }
+
+    /// Label of the code `pin` belongs to, if it is valid at `now`.
+    pub fn check_pin(&self, pin: &str, now: u64) -> Option<&str> {
+        self.codes
+            .iter()
+            .find(|code| code.is_valid_at(now) && code.matches(pin))
+            .map(|code| code.label.as_str())
+    }
+
+    pub fn add_code(&mut self, code: AccessCode) -> Result<(), DeviceError> {
+        if self.codes.iter().any(|existing| existing.label == code.label) {
+            return Err(DeviceError::InvalidValue(format!("Code {} already exists", code.label)));
+        }
+        self.codes.push(code);
+        Ok(())
+    }
+
+    pub fn remove_code(&mut self, label: &str) -> Result<(), DeviceError> {
+        let len = self.codes.len();
+        self.codes.retain(|code| code.label != label);
+        if self.codes.len() == len {
+            return Err(DeviceError::InvalidValue(format!("No code {}", label)));
+        }
+        Ok(())
+    }
+}
+
+impl Display for SmartLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SmartLock name: {}, state: {:?}, battery: {}%", self.name, self.state, self.battery)?;
+        if self.battery_low() {
+            write!(f, " (low)")?;
+        }
+        Ok(())
+    }
+}
+
+pub const DEFAULT_TRAVEL_MS: u64 = 20_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum CoverKind {
+    Blind,
+    Shutter,
+    GarageDoor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum CoverMotion {
+    Stopped,
+    Opening,
+    Closing,
+}
+
+/// `position` goes from 0 (closed) to 100 (open). Only blinds can tilt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CoverState {
+    pub position: u8,
+    pub tilt: u8,
+    pub motion: CoverMotion,
+}
+
+impl Default for CoverState {
+    fn default() -> Self {
+        Self {
+            position: 0,
+            tilt: 0,
+            motion: CoverMotion::Stopped,
+        }
+    }
+}
+
+impl CoverState {
+    pub fn validate(&self) -> Result<(), DeviceError> {
+        if self.position > 100 {
+            return Err(DeviceError::InvalidValue(format!("Position {}%", self.position)));
+        }
+        if self.tilt > 100 {
+            return Err(DeviceError::InvalidValue(format!("Tilt {}%", self.tilt)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct Cover {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    kind: CoverKind,
+    state: CoverState,
+    /// Time it takes to travel from fully closed to fully open.
+    travel_ms: u64,
+}
+
+impl Cover {
+    pub fn new(name: String, kind: CoverKind) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            kind,
+            state: CoverState::default(),
+            travel_ms: DEFAULT_TRAVEL_MS,
+        }
+    }
+
+    pub fn kind(&self) -> CoverKind {
+        self.kind
+    }
+
+    pub fn state(&self) -> &CoverState {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: CoverState) -> Result<(), DeviceError> {
+        state.validate()?;
+        if state.tilt != 0 && self.kind != CoverKind::Blind {
+            return Err(DeviceError::Unsupported(format!("{} can't tilt", self.name)));
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    pub fn travel_ms(&self) -> u64 {
+        self.travel_ms
+    }
+
+    pub fn calibrate(&mut self, travel_ms: u64) -> Result<(), DeviceError> {
+        if travel_ms == 0 {
+            return Err(DeviceError::InvalidValue("Travel time must be positive".to_string()));
+        }
+        self.travel_ms = travel_ms;
+        Ok(())
+    }
+
+    /// How long it takes to get from the current position to `position`.
+    pub fn travel_time(&self, position: u8) -> u64 {
+        self.travel_ms * self.state.position.abs_diff(position) as u64 / 100
+    }
+}
+
+impl Display for Cover {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cover name: {}, kind: {:?}, position: {}%, tilt: {}%, motion: {:?}",
+            self.name, self.kind, self.state.position, self.state.tilt, self.state.motion
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Hazard {
+    Leak,
+    Smoke,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SelfTest {
+    pub timestamp: u64,
+    pub passed: bool,
+}
+
+/// Leak or smoke detector. Once the hazard is detected the alarm stays
+/// latched until it is acknowledged, even if the hazard is gone by then.
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct SafetyDetector {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    hazard: Hazard,
+    detecting: bool,
+    /// When the latched alarm was raised.
+    alarm_since: Option<u64>,
+    battery: u8,
+    last_test: Option<SelfTest>,
+}
+
+impl SafetyDetector {
+    pub fn new(name: String, hazard: Hazard) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            hazard,
+            detecting: false,
+            alarm_since: None,
+            battery: 100,
+            last_test: None,
+        }
+    }
+
+    pub fn hazard(&self) -> Hazard {
+        self.hazard
+    }
+
+    pub fn detecting(&self) -> bool {
+        self.detecting
+    }
+
+    pub fn alarm_since(&self) -> Option<u64> {
+        self.alarm_since
+    }
+
+    pub fn battery(&self) -> u8 {
+        self.battery
+    }
+
+    pub fn battery_low(&self) -> bool {
+        self.battery < BATTERY_LOW
+    }
+
+    pub fn last_test(&self) -> Option<SelfTest> {
+        self.last_test
+    }
+
+    pub fn report(&mut self, detected: bool, battery: u8, timestamp: u64) -> Result<(), DeviceError> {
+        if battery > 100 {
+            return Err(DeviceError::InvalidValue(format!("Battery level {}", battery)));
+        }
+        self.battery = battery;
+        self.detecting = detected;
+        if detected && self.alarm_since.is_none() {
+            self.alarm_since = Some(timestamp);
+        }
+        Ok(())
+    }
+
+    /// Clears the latched alarm. Refused while the hazard is still detected.
+    pub fn acknowledge(&mut self) -> Result<(), DeviceError> {
+        if self.detecting {
+            return Err(DeviceError::Unsupported(format!("{} still detects {:?}", self.name, self.hazard)));
+        }
+        if self.alarm_since.take().is_none() {
+            return Err(DeviceError::Unsupported(format!("{} has no alarm", self.name)));
+        }
+        Ok(())
+    }
+
+    pub fn record_test(&mut self, passed: bool, timestamp: u64) {
+        self.last_test = Some(SelfTest { timestamp, passed });
+    }
+}
+
+impl Display for SafetyDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let alarm = if self.alarm_since.is_some() { "ALARM" } else { "ok" };
+        write!(f, "SafetyDetector name: {}, hazard: {:?}, alarm: {}, battery: {}%", self.name, self.hazard, alarm, self.battery)?;
+        if let Some(SelfTest { passed: false, .. }) = self.last_test {
+            write!(f, " (self-test failed)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct PhaseReading {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct MeterReading {
+    /// Cumulative energy counter.
+    pub energy_kwh: f64,
+    /// Instantaneous power over all phases, in watts.
+    pub power: f32,
+    pub phases: Vec<PhaseReading>,
+}
+
+impl MeterReading {
+    pub fn validate(&self) -> Result<(), DeviceError> {
+        if self.phases.len() > 3 {
+            return Err(DeviceError::InvalidValue(format!("{} phases", self.phases.len())));
+        }
+        let values = self
+            .phases
+            .iter()
+            .flat_map(|phase| [phase.voltage, phase.current, phase.power])
+            .chain([self.power]);
+        if !self.energy_kwh.is_finite() || self.energy_kwh < 0.0 || values.clone().any(|value| !value.is_finite()) {
+            return Err(DeviceError::InvalidValue("Meter values must be finite".to_string()));
+        }
+        if self.power < 0.0 || values.clone().any(|value| value < 0.0) {
+            return Err(DeviceError::InvalidValue("Meter values can't be negative".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
+pub struct EnergyMeter {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    reading: Option<MeterReading>,
+    read_at: Option<u64>,
+    energy: EnergyLedger,
+}
+
+impl EnergyMeter {
+    pub fn default(name: String) -> Self {
+        Self {
+            name,
+            id: String::new(),
+            reading: None,
+            read_at: None,
+            energy: EnergyLedger::default(),
+        }
+    }
+
+    pub fn reading(&self) -> Option<&MeterReading> {
+        self.reading.as_ref()
+    }
+
+    /// Takes a new reading. The counter increase since the previous reading
+    /// is booked evenly over the time in between.
+    pub fn record(&mut self, reading: MeterReading, timestamp: u64) -> Result<(), DeviceError> {
+        reading.validate()?;
+        if let (Some(previous), Some(read_at)) = (&self.reading, self.read_at) {
+            if reading.energy_kwh < previous.energy_kwh {
+                return Err(DeviceError::InvalidValue(format!(
+                    "Counter went back from {} to {} kWh",
+                    previous.energy_kwh, reading.energy_kwh
+                )));
+            }
+            self.energy
+                .add((reading.energy_kwh - previous.energy_kwh) * 1_000.0, read_at, timestamp);
+        }
+        self.reading = Some(reading);
+        self.read_at = Some(timestamp);
+        Ok(())
+    }
+
+    /// Energy counted between the day boundaries `from` and `to`, in kWh.
+    pub fn energy(&self, from: u64, to: u64, now: u64) -> f64 {
+        self.energy.between(from, to, now, 0.0) / 1_000.0
+    }
+
+    /// Watt-hours counted in each hour between `from` and `to`.
+    pub fn usage(&self, from: u64, to: u64, now: u64) -> BTreeMap<u64, f64> {
+        self.energy.usage(from, to, now)
+    }
+}
+
+impl Display for EnergyMeter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reading {
+            Some(reading) => write!(
+                f,
+                "EnergyMeter name: {}, energy: {} kWh, power: {} W, phases: {}",
+                self.name,
+                reading.energy_kwh,
+                reading.power,
+                reading.phases.len()
+            ),
+            None => write!(f, "EnergyMeter name: {}, no reading", self.name),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,4 +1361,221 @@ mod tests {
             Device::SmartThermometr(SmartThermometer::default("Smart thermometr".to_string()));
         assert!(thermo.device_name().is_ok());
     }
+
+    #[test]
+    fn record_reading() {
+        let mut thermo =
+            Device::SmartThermometr(SmartThermometer::default("Smart thermometr".to_string()));
+        thermo.record_reading(21.5).unwrap();
+        match thermo {
+            Device::SmartThermometr(thermo) => assert_eq!(thermo.temperature(), 21.5),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn light_state() {
+        let mut light = SmartLight::default("Lamp".to_string());
+        let mut state = *light.state();
+        state.brightness = 120;
+        assert!(light.set_state(state).is_err());
+        state.brightness = 80;
+        state.color_temperature = 1000;
+        assert!(light.set_state(state).is_err());
+
+        let off = LightState::default();
+        let on = LightState { status: true, brightness: 80, ..off };
+        let half = off.interpolate(&on, 0.5);
+        assert!(half.status);
+        assert_eq!(half.brightness, 40);
+        assert!(on.interpolate(&off, 0.5).status);
+        assert!(!on.interpolate(&off, 1.0).status);
+    }
+
+    #[test]
+    fn binary_sensors() {
+        let mut motion = Device::MotionSensor(MotionSensor::default("Motion".to_string()));
+        motion.set_active(true, 10).unwrap();
+        motion.set_active(true, 20).unwrap();
+        motion.set_active(false, 30).unwrap();
+        match &motion {
+            Device::MotionSensor(sensor) => {
+                assert_eq!(sensor.last_changed(), Some(30));
+                assert_eq!(sensor.last_motion(), Some(30));
+            }
+            _ => unreachable!(),
+        }
+
+        let mut contact = ContactSensor::default("Door".to_string());
+        contact.set_open(true, 10);
+        assert!(contact.open());
+        assert_eq!(contact.last_changed(), Some(10));
+
+        let mut socket = Device::SmartSocket(SmartSocket::default("Socket".to_string()));
+        assert!(socket.set_active(true, 10).is_err());
+    }
+
+    #[test]
+    fn thermostat_hysteresis() {
+        let mut thermostat = Thermostat::default("Thermostat".to_string());
+        let settings = ThermostatSettings {
+            mode: ThermostatMode::Heat,
+            target: 20.0,
+            hysteresis: 1.0,
+            ..ThermostatSettings::default()
+        };
+        thermostat.configure(settings).unwrap();
+        assert_eq!(thermostat.decide(18.5, 0), Demand::Heating);
+        assert_eq!(thermostat.decide(20.5, 0), Demand::Idle);
+        thermostat.set_demand(Demand::Heating);
+        assert_eq!(thermostat.decide(20.5, 0), Demand::Heating);
+        assert_eq!(thermostat.decide(21.0, 0), Demand::Idle);
+        assert_eq!(thermostat.decide(30.0, 0), Demand::Idle);
+    }
+
+    #[test]
+    fn thermostat_schedule() {
+        let settings = ThermostatSettings {
+            schedule: vec![
+                Setpoint { minute_of_day: 7 * 60, target: 21.0 },
+                Setpoint { minute_of_day: 23 * 60, target: 17.0 },
+            ],
+            ..ThermostatSettings::default()
+        };
+        let hour = 60 * 60 * 1000;
+        assert_eq!(settings.target_at(3 * hour), 17.0);
+        assert_eq!(settings.target_at(8 * hour), 21.0);
+        assert_eq!(settings.target_at(23 * hour), 17.0);
+
+        let invalid = ThermostatSettings {
+            schedule: vec![Setpoint { minute_of_day: MINUTES_PER_DAY, target: 21.0 }],
+            ..ThermostatSettings::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn lock_codes() {
+        let mut lock = SmartLock::default("Door".to_string());
+        let code = AccessCode::new("guest".to_string(), "1234", 100, Some(200)).unwrap();
+        let same = AccessCode::new("guest".to_string(), "1234", 100, Some(200)).unwrap();
+        assert_ne!(code.pin_hash, same.pin_hash);
+        lock.add_code(code).unwrap();
+        assert!(AccessCode::new("short".to_string(), "12", 0, None).is_err());
+
+        assert_eq!(lock.check_pin("1234", 150), Some("guest"));
+        assert_eq!(lock.check_pin("1234", 200), None);
+        assert_eq!(lock.check_pin("4321", 150), None);
+
+        let json = redacted(|| serde_json::to_string(&lock).unwrap());
+        assert!(!json.contains("pin_hash") && !json.contains("salt"));
+        let restored: SmartLock = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.check_pin("1234", 150), None);
+
+        let stored: SmartLock = serde_json::from_str(&serde_json::to_string(&lock).unwrap()).unwrap();
+        assert_eq!(stored.check_pin("1234", 150), Some("guest"));
+    }
+
+    #[test]
+    fn jammed_lock() {
+        let mut lock = SmartLock::default("Door".to_string());
+        lock.report(true, 10).unwrap();
+        assert!(lock.battery_low());
+        assert!(lock.set_locked(false, AccessMethod::Api, 1).is_err());
+        lock.set_locked(false, AccessMethod::Manual, 2).unwrap();
+        assert_eq!(lock.state(), LockState::Unlocked);
+        assert_eq!(lock.history().len(), 1);
+        assert_eq!(lock.to_string(), "SmartLock name: Door, state: Unlocked, battery: 10% (low)");
+
+        lock.report(true, 10).unwrap();
+        lock.report(false, 10).unwrap();
+        assert_eq!(lock.state(), LockState::Unlocked);
+    }
+
+    #[test]
+    fn wrong_pins_lock_out() {
+        let mut lock = SmartLock::default("Door".to_string());
+        lock.reject_pin(false, 0);
+        lock.reject_pin(false, 0);
+        assert_eq!(lock.locked_out_until(0), None);
+        lock.reject_pin(false, 0);
+        assert_eq!(lock.locked_out_until(0), Some(PIN_LOCKOUT_MS));
+        lock.reject_pin(false, 1_000);
+        assert_eq!(lock.locked_out_until(1_000), Some(1_000 + 2 * PIN_LOCKOUT_MS));
+        assert!(lock.history().iter().all(|entry| entry.method == AccessMethod::WrongPin));
+
+        lock.set_locked(false, AccessMethod::Pin("guest".to_string()), 100_000).unwrap();
+        assert_eq!(lock.locked_out_until(100_000), None);
+        lock.reject_pin(false, 100_000);
+        assert_eq!(lock.locked_out_until(100_000), None);
+    }
+
+    #[test]
+    fn cover() {
+        let mut shutter = Cover::new("Shutter".to_string(), CoverKind::Shutter);
+        shutter.calibrate(10_000).unwrap();
+        assert_eq!(shutter.travel_time(30), 3_000);
+        let tilted = CoverState {
+            tilt: 20,
+            ..CoverState::default()
+        };
+        assert!(shutter.set_state(tilted).is_err());
+        assert!(Cover::new("Blind".to_string(), CoverKind::Blind).set_state(tilted).is_ok());
+        assert!(shutter.calibrate(0).is_err());
+    }
+
+    #[test]
+    fn latched_alarm() {
+        let mut detector = SafetyDetector::new("Leak".to_string(), Hazard::Leak);
+        assert!(detector.acknowledge().is_err());
+        detector.report(true, 90, 10).unwrap();
+        detector.report(true, 90, 20).unwrap();
+        assert_eq!(detector.alarm_since(), Some(10));
+        assert!(detector.acknowledge().is_err());
+
+        detector.report(false, 90, 30).unwrap();
+        assert_eq!(detector.alarm_since(), Some(10));
+        detector.acknowledge().unwrap();
+        assert_eq!(detector.alarm_since(), None);
+    }
+
+    #[test]
+    fn socket_energy() {
+        let hour = 3_600_000;
+        let mut socket = SmartSocket::default("Kettle".to_string());
+        socket.record_power(2_000.0, 0).unwrap();
+        socket.switch_at(true, hour);
+        socket.switch_at(false, hour + hour / 2);
+        socket.switch_at(true, 3 * hour);
+        assert_eq!(socket.energy(0, crate::energy::DAY_MS, 4 * hour), 3.0);
+        assert!(socket.record_power(-1.0, 0).is_err());
+        // The booked energy is stored with the socket
+        let stored: SmartSocket = serde_json::from_str(&serde_json::to_string(&socket).unwrap()).unwrap();
+        assert_eq!(stored.energy(0, crate::energy::DAY_MS, 4 * hour), 3.0);
+        assert_eq!(stored.usage(0, crate::energy::DAY_MS, 4 * hour), socket.usage(0, crate::energy::DAY_MS, 4 * hour));
+    }
+
+    #[test]
+    fn meter_counter() {
+        let reading = |energy_kwh| MeterReading {
+            energy_kwh,
+            power: 500.0,
+            phases: vec![PhaseReading { voltage: 230.0, current: 2.2, power: 500.0 }],
+        };
+        let mut meter = EnergyMeter::default("Meter".to_string());
+        meter.record(reading(100.0), 0).unwrap();
+        meter.record(reading(102.5), 1_000).unwrap();
+        assert_eq!(meter.energy(0, crate::energy::DAY_MS, 1_000), 2.5);
+        assert!(meter.record(reading(90.0), 2_000).is_err());
+    }
+
+    #[test]
+    fn environment_sensor() {
+        let mut sensor = EnvironmentSensor::humidity("Humidity".to_string());
+        sensor.record(Metric::Humidity, 55.0).unwrap();
+        assert_eq!(sensor.reading(Metric::Humidity), Some(55.0));
+        assert!(sensor.record(Metric::Humidity, 120.0).is_err());
+        assert!(sensor.record(Metric::Co2, 800.0).is_err());
+        assert_eq!(sensor.to_string(), "EnvironmentSensor name: Humidity, humidity: 55 %");
+    }
 }
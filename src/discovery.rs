@@ -0,0 +1,447 @@
+use crate::actor::{ActorConfig, ActorError, DeviceCommand, DeviceConnection, DeviceHandle, DeviceReply, SimulatedConnection};
+use crate::devices::{Device, DeviceKind, SmartSocket, SmartThermometer};
+use crate::events::now_millis;
+use crate::registry::SharedHouse;
+use crate::smarthouse::{SmartHouse, SmartHouseError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+/// Devices listen for probes on this port.
+pub const DISCOVERY_PORT: u16 = 47_474;
+
+const MAX_DATAGRAM: usize = 1_500;
+/// Pause after a failed receive, so a broken socket doesn't spin.
+const RECEIVE_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum DiscoveryError {
+    #[error("Candidate not found: {0}")]
+    CandidateNotFound(String),
+    #[error("Devices of kind {0:?} can't be adopted")]
+    Unsupported(DeviceKind),
+    #[error("Discovery I/O failed: {0}")]
+    Io(String),
+    #[error("{0}")]
+    House(#[from] SmartHouseError),
+}
+
+impl From<io::Error> for DiscoveryError {
+    fn from(err: io::Error) -> Self {
+        DiscoveryError::Io(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    Switch,
+    Power,
+    Temperature,
+}
+
+/// What a device tells about itself when it's probed or comes up.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Announcement {
+    /// Stable id of the hardware, e.g. its MAC address.
+    pub device_id: String,
+    /// Suggested device name.
+    pub name: String,
+    pub kind: DeviceKind,
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl Announcement {
+    pub fn socket(device_id: &str, name: &str) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            name: name.to_string(),
+            kind: DeviceKind::Socket,
+            capabilities: BTreeSet::from([Capability::Switch, Capability::Power]),
+        }
+    }
+
+    pub fn thermometer(device_id: &str, name: &str) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            name: name.to_string(),
+            kind: DeviceKind::Thermometer,
+            capabilities: BTreeSet::from([Capability::Temperature]),
+        }
+    }
+}
+
+/// A target a probe couldn't be sent to.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProbeFailure {
+    pub target: String,
+    pub error: String,
+}
+
+/// A device that announced itself and isn't part of any room yet.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Candidate {
+    #[serde(flatten)]
+    pub announcement: Announcement,
+    /// Where the device answers commands.
+    pub address: String,
+    pub last_seen: u64,
+}
+
+/// Datagrams of the discovery protocol, one JSON object each.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Message {
+    Probe,
+    Announce(Announcement),
+    Command { id: u64, command: DeviceCommand },
+    Reply { id: u64, reply: Result<DeviceReply, ActorError> },
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    fn decode(datagram: &[u8]) -> Option<Self> {
+        serde_json::from_slice(datagram).ok()
+    }
+}
+
+/// A socket of the same address family that any port can answer to.
+async fn unbound_for(address: SocketAddr) -> io::Result<UdpSocket> {
+    match address {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Found {
+    candidates: BTreeMap<String, Candidate>,
+    adopted: BTreeSet<String>,
+}
+
+/// Probes the network and collects the devices that announce themselves.
+/// Announcements are received for as long as the discovery is alive.
+#[derive(Debug)]
+pub struct Discovery {
+    socket: Arc<UdpSocket>,
+    targets: Vec<SocketAddr>,
+    found: Arc<Mutex<Found>>,
+    listener: JoinHandle<()>,
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+impl Discovery {
+    /// Broadcast probes to every device on the local network.
+    pub fn broadcast() -> Vec<SocketAddr> {
+        vec![SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT))]
+    }
+
+    /// Listens on `address` and sends probes to `targets`.
+    pub async fn bind(address: SocketAddr, targets: Vec<SocketAddr>) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        socket.set_broadcast(true)?;
+        let found = Arc::new(Mutex::new(Found::default()));
+        let listener = tokio::spawn(listen(socket.clone(), found.clone()));
+        Ok(Self {
+            socket,
+            targets,
+            found,
+            listener,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Asks every device to announce itself. Answers arrive in the background.
+    /// Every target is probed even if some can't be reached, those are
+    /// returned.
+    pub async fn probe(&self) -> Vec<ProbeFailure> {
+        let probe = Message::Probe.encode();
+        let mut failures = Vec::new();
+        for target in &self.targets {
+            if let Err(err) = self.socket.send_to(&probe, target).await {
+                log::warn!("Probing {} failed: {}", target, err);
+                failures.push(ProbeFailure {
+                    target: target.to_string(),
+                    error: err.to_string(),
+                });
+            }
+        }
+        failures
+    }
+
+    /// Candidates ordered by device id.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        self.found().candidates.values().cloned().collect()
+    }
+
+    pub fn candidate(&self, device_id: &str) -> Option<Candidate> {
+        self.found().candidates.get(device_id).cloned()
+    }
+
+    /// Adds the candidate to the room as a new device backed by an actor
+    /// that talks to it over UDP. The device is named as it suggested
+    /// unless `name` is given.
+    pub async fn adopt(
+        &self,
+        house: &SharedHouse,
+        device_id: &str,
+        room_name: &str,
+        name: Option<&str>,
+        config: ActorConfig,
+    ) -> Result<Device, DiscoveryError> {
+        self.adopt_if(house, device_id, room_name, name, config, |_| Ok(())).await
+    }
+
+    /// Like [`Discovery::adopt`], but only if `check` passes under the write
+    /// lock the device is added with.
+    pub async fn adopt_if<E: From<DiscoveryError>>(
+        &self,
+        house: &SharedHouse,
+        device_id: &str,
+        room_name: &str,
+        name: Option<&str>,
+        config: ActorConfig,
+        check: impl Fn(&SmartHouse) -> Result<(), E>,
+    ) -> Result<Device, E> {
+        let candidate = self
+            .candidate(device_id)
+            .ok_or_else(|| DiscoveryError::CandidateNotFound(device_id.to_string()))?;
+        let name = name.unwrap_or(&candidate.announcement.name).to_string();
+        let device = match candidate.announcement.kind {
+            DeviceKind::Socket => Device::SmartSocket(SmartSocket::default(name.clone())),
+            DeviceKind::Thermometer => Device::SmartThermometr(SmartThermometer::default(name.clone())),
+            kind => return Err(DiscoveryError::Unsupported(kind).into()),
+        };
+        let address: SocketAddr = candidate
+            .address
+            .parse()
+            .map_err(|_| DiscoveryError::Io(format!("Bad address {}", candidate.address)))?;
+        let connection = UdpConnection::connect(address).await.map_err(DiscoveryError::from)?;
+
+        let mut house = house.write().await;
+        check(&house)?;
+        house.add_device(room_name, device.clone()).map_err(DiscoveryError::from)?;
+        house
+            .attach_actor(room_name, &name, DeviceHandle::spawn(connection, config))
+            .map_err(DiscoveryError::from)?;
+        house.heartbeat(room_name, &name).map_err(DiscoveryError::from)?;
+        let mut found = self.found();
+        found.candidates.remove(device_id);
+        found.adopted.insert(device_id.to_string());
+        Ok(device)
+    }
+
+    fn found(&self) -> std::sync::MutexGuard<'_, Found> {
+        self.found.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+async fn listen(socket: Arc<UdpSocket>, found: Arc<Mutex<Found>>) {
+    let mut buffer = [0; MAX_DATAGRAM];
+    loop {
+        let (len, from) = receive(&socket, &mut buffer).await;
+        let Some(Message::Announce(announcement)) = Message::decode(&buffer[..len]) else {
+            continue;
+        };
+        let mut found = found.lock().unwrap_or_else(PoisonError::into_inner);
+        if found.adopted.contains(&announcement.device_id) {
+            continue;
+        }
+        let candidate = Candidate {
+            announcement,
+            address: from.to_string(),
+            last_seen: now_millis(),
+        };
+        found.candidates.insert(candidate.announcement.device_id.clone(), candidate);
+    }
+}
+
+/// Waits for the next datagram. Failures are logged and retried after
+/// [`RECEIVE_BACKOFF`].
+async fn receive(socket: &UdpSocket, buffer: &mut [u8]) -> (usize, SocketAddr) {
+    loop {
+        match socket.recv_from(buffer).await {
+            Ok(received) => return received,
+            Err(err) => {
+                log::warn!("Discovery receive failed: {}", err);
+                tokio::time::sleep(RECEIVE_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Sends commands to a discovered device and waits for the matching reply.
+/// Late replies to earlier attempts are skipped.
+#[derive(Debug)]
+pub struct UdpConnection {
+    socket: UdpSocket,
+    next_id: u64,
+}
+
+impl UdpConnection {
+    pub async fn connect(address: SocketAddr) -> io::Result<Self> {
+        let socket = unbound_for(address).await?;
+        socket.connect(address).await?;
+        Ok(Self { socket, next_id: 0 })
+    }
+}
+
+impl DeviceConnection for UdpConnection {
+    async fn send(&mut self, command: DeviceCommand) -> Result<DeviceReply, ActorError> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let io = |err: io::Error| ActorError::Io(err.to_string());
+        self.socket.send(&Message::Command { id, command }.encode()).await.map_err(io)?;
+        let mut buffer = [0; MAX_DATAGRAM];
+        loop {
+            let len = self.socket.recv(&mut buffer).await.map_err(io)?;
+            if let Some(Message::Reply { id: reply_id, reply }) = Message::decode(&buffer[..len]) {
+                if reply_id == id {
+                    return reply;
+                }
+            }
+        }
+    }
+}
+
+/// A device on the local network, emulated for tests and demos. It
+/// answers probes with its announcement and commands like `device` does.
+#[derive(Debug)]
+pub struct DeviceEmulator {
+    socket: Arc<UdpSocket>,
+    announcement: Announcement,
+    task: JoinHandle<()>,
+}
+
+impl Drop for DeviceEmulator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl DeviceEmulator {
+    pub async fn spawn(address: SocketAddr, announcement: Announcement, device: SimulatedConnection) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        socket.set_broadcast(true)?;
+        let task = tokio::spawn(serve(socket.clone(), announcement.clone(), device));
+        Ok(Self {
+            socket,
+            announcement,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Announces the device without being probed, as it would on power up.
+    pub async fn announce(&self, target: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(&Message::Announce(self.announcement.clone()).encode(), target).await?;
+        Ok(())
+    }
+}
+
+async fn serve(socket: Arc<UdpSocket>, announcement: Announcement, mut device: SimulatedConnection) {
+    let mut buffer = [0; MAX_DATAGRAM];
+    loop {
+        let (len, from) = receive(&socket, &mut buffer).await;
+        let answer = match Message::decode(&buffer[..len]) {
+            Some(Message::Probe) => Message::Announce(announcement.clone()),
+            Some(Message::Command { id, command }) => Message::Reply {
+                id,
+                reply: device.send(command).await,
+            },
+            _ => continue,
+        };
+        if let Err(err) = socket.send_to(&answer.encode(), from).await {
+            log::warn!("Emulated device {} can't answer: {}", announcement.device_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::command;
+    use crate::smarthouse::SmartHouse;
+    use crate::smartroom::SmartRoom;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    async fn found(discovery: &Discovery, count: usize) -> Vec<Candidate> {
+        for _ in 0..100 {
+            let candidates = discovery.candidates();
+            if candidates.len() >= count {
+                return candidates;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Only found {:?}", discovery.candidates());
+    }
+
+    #[tokio::test]
+    async fn probe_and_adopt() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let socket = SimulatedConnection {
+            power: Some(2_000.0),
+            ..SimulatedConnection::default()
+        };
+        let kettle = DeviceEmulator::spawn(localhost, Announcement::socket("aa:01", "Kettle"), socket).await.unwrap();
+        let thermometer = SimulatedConnection {
+            temperature: Some(19.5),
+            ..SimulatedConnection::default()
+        };
+        let sensor = DeviceEmulator::spawn(localhost, Announcement::thermometer("aa:02", "Sensor"), thermometer).await.unwrap();
+
+        // A target of the other address family can't be probed, the rest still is
+        let unreachable = SocketAddr::from((Ipv6Addr::LOCALHOST, DISCOVERY_PORT));
+        let discovery = Discovery::bind(localhost, vec![unreachable, kettle.local_addr().unwrap()]).await.unwrap();
+        let failures = discovery.probe().await;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].target, unreachable.to_string());
+        sensor.announce(discovery.local_addr().unwrap()).await.unwrap();
+        let candidates = found(&discovery, 2).await;
+        assert_eq!(candidates[0].announcement.capabilities, BTreeSet::from([Capability::Switch, Capability::Power]));
+        assert_eq!(candidates[1].announcement.kind, DeviceKind::Thermometer);
+
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+        let config = ActorConfig::default();
+        assert!(matches!(
+            discovery.adopt(&house, "aa:03", "Kitchen", None, config).await,
+            Err(DiscoveryError::CandidateNotFound(_))
+        ));
+        discovery.adopt(&house, "aa:01", "Kitchen", None, config).await.unwrap();
+        discovery.adopt(&house, "aa:02", "Kitchen", Some("Thermometer"), config).await.unwrap();
+        assert!(discovery.candidates().is_empty());
+
+        command(&house, "Kitchen", "Kettle", DeviceCommand::Switch { status: true }).await.unwrap();
+        let reply = command(&house, "Kitchen", "Thermometer", DeviceCommand::ReadTemperature).await.unwrap();
+        assert_eq!(reply, DeviceReply::Temperature(19.5));
+
+        // Adopted devices aren't offered again
+        discovery.probe().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(discovery.candidates().is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use utoipa::ToSchema;
+
+pub const DAY_MS: u64 = 86_400_000;
+pub const HOUR_MS: u64 = 3_600_000;
+pub const TOP_CONSUMERS: usize = 5;
+
+/// Energy booked per UTC day, in watt-hours. Hours are booked as well, for
+/// tariffs that price them differently.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct EnergyLedger {
+    /// Days since the unix epoch to the energy used on that day.
+    daily: BTreeMap<u64, f64>,
+    /// Hours since the unix epoch to the energy used in that hour.
+    #[serde(default)]
+    hourly: BTreeMap<u64, f64>,
+    last_update: Option<u64>,
+}
+
+impl EnergyLedger {
+    /// Books `power` watts drawn from the last update until `timestamp`.
+    pub fn advance(&mut self, timestamp: u64, power: f32) {
+        match self.last_update {
+            Some(last) if timestamp > last => {
+                self.book(last, timestamp, watt_hours(power, last, timestamp));
+                self.last_update = Some(timestamp);
+            }
+            Some(_) => {}
+            None => self.last_update = Some(timestamp),
+        }
+    }
+
+    /// Books `wh` used evenly between `from` and `to`.
+    pub fn add(&mut self, wh: f64, from: u64, to: u64) {
+        self.book(from, to, wh);
+        self.last_update = Some(self.last_update.map_or(to, |last| last.max(to)));
+    }
+
+    fn book(&mut self, from: u64, to: u64, wh: f64) {
+        spread(&mut self.daily, DAY_MS, from, to, wh);
+        spread(&mut self.hourly, HOUR_MS, from, to, wh);
+    }
+
+    /// Watt-hours used in every hour between the hour boundaries `from` and
+    /// `to`, keyed by the start of the hour.
+    pub fn usage(&self, from: u64, to: u64, now: u64) -> BTreeMap<u64, f64> {
+        self.usage_with(from, to, now, 0.0)
+    }
+
+    /// Like [`EnergyLedger::usage`], with `power` watts still drawn from the
+    /// last update until `now`.
+    pub fn usage_with(&self, from: u64, to: u64, now: u64, power: f32) -> BTreeMap<u64, f64> {
+        let mut usage: BTreeMap<u64, f64> = self
+            .hourly
+            .range(from / HOUR_MS..to.div_ceil(HOUR_MS))
+            .map(|(hour, wh)| (*hour, *wh))
+            .collect();
+        if let Some(last) = self.last_update {
+            let (start, end) = (last.max(from), now.min(to));
+            if end > start {
+                spread(&mut usage, HOUR_MS, start, end, watt_hours(power, start, end));
+            }
+        }
+        usage.into_iter().map(|(hour, wh)| (hour * HOUR_MS, wh)).collect()
+    }
+
+    /// Watt-hours used between the day boundaries `from` and `to`, counting
+    /// `power` watts as still drawn from the last update until `now`.
+    pub fn between(&self, from: u64, to: u64, now: u64, power: f32) -> f64 {
+        let booked = self
+            .daily
+            .range(from / DAY_MS..to.div_ceil(DAY_MS))
+            .fold(0.0, |total, (_, wh)| total + wh);
+        let pending = match self.last_update {
+            Some(last) => {
+                let (start, end) = (last.max(from), now.min(to));
+                if end > start {
+                    watt_hours(power, start, end)
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        booked + pending
+    }
+}
+
+fn watt_hours(power: f32, from: u64, to: u64) -> f64 {
+    power as f64 * (to - from) as f64 / HOUR_MS as f64
+}
+
+/// Adds `wh` to the days or hours (`unit` long) between `from` and `to` in
+/// proportion to the time spent in each of them.
+fn spread(booked: &mut BTreeMap<u64, f64>, unit: u64, from: u64, to: u64, wh: f64) {
+    if to <= from {
+        *booked.entry(to / unit).or_default() += wh;
+        return;
+    }
+    let mut start = from;
+    while start < to {
+        let index = start / unit;
+        let end = ((index + 1) * unit).min(to);
+        *booked.entry(index).or_default() += wh * (end - start) as f64 / (to - from) as f64;
+        start = end;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    /// Start and end of the UTC day, week (from Monday) or month containing `now`.
+    pub fn bounds(&self, now: u64) -> (u64, u64) {
+        let day = now / DAY_MS;
+        let (first, last) = match self {
+            Period::Day => (day, day + 1),
+            Period::Week => {
+                // The epoch was a Thursday
+                let monday = day - (day + 3) % 7;
+                (monday, monday + 7)
+            }
+            Period::Month => {
+                let (year, month, _) = civil_from_days(day);
+                let next = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                (days_from_civil(year, month, 1), days_from_civil(next.0, next.1, 1))
+            }
+        };
+        (first * DAY_MS, last * DAY_MS)
+    }
+}
+
+/// Year, month and day of the given day since the unix epoch.
+pub fn civil_from_days(days: u64) -> (i64, u32, u32) {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days since the unix epoch of the given date.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe - 719_468) as u64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeviceEnergy {
+    pub room_name: String,
+    pub device_name: String,
+    pub kwh: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoomEnergy {
+    pub room_name: String,
+    pub kwh: f64,
+    pub devices: Vec<DeviceEnergy>,
+}
+
+/// Consumption of all sockets in a period. Energy meters are listed on
+/// their own since they usually measure sockets that are already counted.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct EnergyReport {
+    pub from: u64,
+    pub to: u64,
+    pub kwh: f64,
+    pub rooms: Vec<RoomEnergy>,
+    pub top_consumers: Vec<DeviceEnergy>,
+    pub meters: Vec<DeviceEnergy>,
+}
+
+impl EnergyReport {
+    pub fn new(from: u64, to: u64, mut rooms: Vec<RoomEnergy>, meters: Vec<DeviceEnergy>) -> Self {
+        rooms.sort_by(|a, b| a.room_name.cmp(&b.room_name));
+        let mut top_consumers: Vec<DeviceEnergy> = rooms.iter().flat_map(|room| room.devices.clone()).collect();
+        top_consumers.sort_by(|a, b| b.kwh.total_cmp(&a.kwh));
+        top_consumers.truncate(TOP_CONSUMERS);
+        Self {
+            from,
+            to,
+            kwh: rooms.iter().fold(0.0, |kwh, room| kwh + room.kwh),
+            rooms,
+            top_consumers,
+            meters,
+        }
+    }
+}
+
+impl Display for EnergyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Energy from {} to {}: {:.3} kWh", self.from, self.to, self.kwh)?;
+        for room in &self.rooms {
+            writeln!(f, "{}: {:.3} kWh", room.room_name, room.kwh)?;
+            for device in &room.devices {
+                writeln!(f, "  {}: {:.3} kWh", device.device_name, device.kwh)?;
+            }
+        }
+        writeln!(f, "Top consumers:")?;
+        for (place, device) in self.top_consumers.iter().enumerate() {
+            writeln!(f, "{}. {} in {}: {:.3} kWh", place + 1, device.device_name, device.room_name, device.kwh)?;
+        }
+        if !self.meters.is_empty() {
+            writeln!(f, "Meters:")?;
+            for meter in &self.meters {
+                writeln!(f, "  {} in {}: {:.3} kWh", meter.device_name, meter.room_name, meter.kwh)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ledger_splits_hours() {
+        let mut ledger = EnergyLedger::default();
+        ledger.advance(DAY_MS - HOUR_MS, 1_000.0);
+        ledger.advance(DAY_MS + HOUR_MS / 2, 1_000.0);
+        assert_eq!(ledger.between(0, DAY_MS, DAY_MS * 2, 0.0), 1_000.0);
+        assert_eq!(ledger.between(DAY_MS, DAY_MS * 2, DAY_MS * 2, 0.0), 500.0);
+        // Another hour and a half at 500 W that is not booked yet
+        let now = DAY_MS + 2 * HOUR_MS;
+        let usage = ledger.usage_with(DAY_MS, DAY_MS * 2, now, 500.0);
+        assert_eq!(usage.values().copied().collect::<Vec<_>>(), vec![750.0, 500.0]);
+        assert_eq!(ledger.between(DAY_MS, DAY_MS * 2, now, 500.0), 1_250.0);
+    }
+
+    #[test]
+    fn period_bounds() {
+        // 2024-02-15 12:00 UTC, a Thursday
+        let now = days_from_civil(2024, 2, 15) * DAY_MS + DAY_MS / 2;
+        assert_eq!(civil_from_days(now / DAY_MS), (2024, 2, 15));
+        assert_eq!(Period::Week.bounds(now).0, days_from_civil(2024, 2, 12) * DAY_MS);
+        let (from, to) = Period::Month.bounds(now);
+        assert_eq!((to - from) / DAY_MS, 29);
+        assert_eq!(civil_from_days(to / DAY_MS), (2024, 3, 1));
+    }
+}
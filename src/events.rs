@@ -0,0 +1,527 @@
+use crate::devices::{
+    AccessCode, AccessMethod, CoverState, Demand, Device, LightState, MeterReading, Metric, ThermostatSettings,
+};
+use crate::area::Area;
+use crate::availability::{Availability, Heartbeat};
+use crate::smartroom::SmartRoom;
+use crate::group::Group;
+use crate::tariff::Tariff;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 100;
+/// Snapshots kept by default. Older ones are compacted away together with
+/// the events they contain.
+pub const DEFAULT_SNAPSHOTS_KEPT: usize = 10;
+
+/// Milliseconds since the unix epoch, used to stamp every event.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum HouseEvent {
+    RoomAdded(SmartRoom),
+    RoomRemoved {
+        room_name: String,
+    },
+    DeviceAdded {
+        room_name: String,
+        device: Device,
+    },
+    DeviceRemoved {
+        room_name: String,
+        device_name: String,
+    },
+    /// Brings a removed device back together with its tags and heartbeat,
+    /// undoing the removal.
+    DeviceRestored {
+        room_name: String,
+        device: Device,
+        tags: BTreeSet<String>,
+        heartbeat: Option<Heartbeat>,
+    },
+    DeviceMoved {
+        from: String,
+        to: String,
+        device_name: String,
+    },
+    RoomRenamed {
+        room_name: String,
+        new_name: String,
+    },
+    SocketSwitched {
+        room_name: String,
+        device_name: String,
+        status: bool,
+    },
+    LightChanged {
+        room_name: String,
+        device_name: String,
+        state: LightState,
+    },
+    BinarySensorChanged {
+        room_name: String,
+        device_name: String,
+        active: bool,
+    },
+    OccupancyTimeoutSet {
+        room_name: String,
+        timeout_ms: u64,
+    },
+    ThermostatConfigured {
+        room_name: String,
+        device_name: String,
+        settings: ThermostatSettings,
+    },
+    ThermostatDemandChanged {
+        room_name: String,
+        device_name: String,
+        demand: Demand,
+    },
+    MetricRecorded {
+        room_name: String,
+        device_name: String,
+        metric: Metric,
+        value: f32,
+    },
+    LockChanged {
+        room_name: String,
+        device_name: String,
+        locked: bool,
+        method: AccessMethod,
+    },
+    /// A PIN that matched no valid code, `locked` is what was asked for.
+    PinRejected {
+        room_name: String,
+        device_name: String,
+        locked: bool,
+    },
+    LockReported {
+        room_name: String,
+        device_name: String,
+        jammed: bool,
+        battery: u8,
+    },
+    AccessCodeAdded {
+        room_name: String,
+        device_name: String,
+        code: AccessCode,
+    },
+    AccessCodeRemoved {
+        room_name: String,
+        device_name: String,
+        label: String,
+    },
+    CoverChanged {
+        room_name: String,
+        device_name: String,
+        state: CoverState,
+    },
+    CoverCalibrated {
+        room_name: String,
+        device_name: String,
+        travel_ms: u64,
+    },
+    DetectorReported {
+        room_name: String,
+        device_name: String,
+        detected: bool,
+        battery: u8,
+    },
+    DetectorTested {
+        room_name: String,
+        device_name: String,
+        passed: bool,
+    },
+    AlarmAcknowledged {
+        room_name: String,
+        device_name: String,
+    },
+    PowerRecorded {
+        room_name: String,
+        device_name: String,
+        watts: f32,
+    },
+    MeterRecorded {
+        room_name: String,
+        device_name: String,
+        reading: MeterReading,
+    },
+    ReadingRecorded {
+        room_name: String,
+        device_name: String,
+        value: f32,
+    },
+    /// Puts the room on a floor or zone, or takes it off with `None`.
+    RoomPlaced {
+        room_name: String,
+        area: Option<String>,
+    },
+    /// Replaces all tags of the device.
+    DeviceTagged {
+        room_name: String,
+        device_name: String,
+        tags: BTreeSet<String>,
+    },
+    /// How long the device may stay silent before it's offline.
+    HeartbeatTimeoutSet {
+        room_name: String,
+        device_name: String,
+        timeout_ms: u64,
+    },
+    AvailabilityChanged {
+        room_name: String,
+        device_name: String,
+        availability: Availability,
+    },
+    /// Replaces the tariff of the house, `None` removes it.
+    TariffSet {
+        tariff: Option<Tariff>,
+    },
+    /// Creates or replaces a group.
+    GroupSet {
+        group: Group,
+    },
+    GroupRemoved {
+        name: String,
+    },
+    /// Creates or replaces a floor or zone.
+    AreaSet {
+        area: Area,
+    },
+    /// Only empty areas can be removed.
+    AreaRemoved {
+        name: String,
+    },
+}
+
+impl HouseEvent {
+    /// Every room the event refers to, including removed and renamed ones.
+    pub fn room_names(&self) -> Vec<&str> {
+        match self {
+            HouseEvent::TariffSet { .. }
+            | HouseEvent::GroupSet { .. }
+            | HouseEvent::GroupRemoved { .. }
+            | HouseEvent::AreaSet { .. }
+            | HouseEvent::AreaRemoved { .. } => Vec::new(),
+            HouseEvent::RoomAdded(room) => vec![&room.room_name],
+            HouseEvent::DeviceMoved { from, to, .. } => vec![from, to],
+            HouseEvent::RoomRenamed { room_name, new_name } => vec![room_name, new_name],
+            HouseEvent::RoomRemoved { room_name }
+            | HouseEvent::DeviceAdded { room_name, .. }
+            | HouseEvent::DeviceRemoved { room_name, .. }
+            | HouseEvent::DeviceRestored { room_name, .. }
+            | HouseEvent::SocketSwitched { room_name, .. }
+            | HouseEvent::LightChanged { room_name, .. }
+            | HouseEvent::BinarySensorChanged { room_name, .. }
+            | HouseEvent::OccupancyTimeoutSet { room_name, .. }
+            | HouseEvent::ThermostatConfigured { room_name, .. }
+            | HouseEvent::ThermostatDemandChanged { room_name, .. }
+            | HouseEvent::MetricRecorded { room_name, .. }
+            | HouseEvent::LockChanged { room_name, .. }
+            | HouseEvent::PinRejected { room_name, .. }
+            | HouseEvent::LockReported { room_name, .. }
+            | HouseEvent::AccessCodeAdded { room_name, .. }
+            | HouseEvent::AccessCodeRemoved { room_name, .. }
+            | HouseEvent::CoverChanged { room_name, .. }
+            | HouseEvent::CoverCalibrated { room_name, .. }
+            | HouseEvent::DetectorReported { room_name, .. }
+            | HouseEvent::DetectorTested { room_name, .. }
+            | HouseEvent::AlarmAcknowledged { room_name, .. }
+            | HouseEvent::PowerRecorded { room_name, .. }
+            | HouseEvent::MeterRecorded { room_name, .. }
+            | HouseEvent::ReadingRecorded { room_name, .. }
+            | HouseEvent::DeviceTagged { room_name, .. }
+            | HouseEvent::HeartbeatTimeoutSet { room_name, .. }
+            | HouseEvent::AvailabilityChanged { room_name, .. }
+            | HouseEvent::RoomPlaced { room_name, .. } => vec![room_name],
+        }
+    }
+
+    /// The device the event refers to, if any.
+    pub fn device_name(&self) -> Option<String> {
+        match self {
+            HouseEvent::RoomAdded(_)
+            | HouseEvent::RoomRemoved { .. }
+            | HouseEvent::RoomRenamed { .. }
+            | HouseEvent::OccupancyTimeoutSet { .. }
+            | HouseEvent::RoomPlaced { .. }
+            | HouseEvent::TariffSet { .. }
+            | HouseEvent::GroupSet { .. }
+            | HouseEvent::GroupRemoved { .. }
+            | HouseEvent::AreaSet { .. }
+            | HouseEvent::AreaRemoved { .. } => None,
+            HouseEvent::DeviceAdded { device, .. } | HouseEvent::DeviceRestored { device, .. } => device.device_name().ok(),
+            HouseEvent::DeviceRemoved { device_name, .. }
+            | HouseEvent::DeviceMoved { device_name, .. }
+            | HouseEvent::SocketSwitched { device_name, .. }
+            | HouseEvent::LightChanged { device_name, .. }
+            | HouseEvent::BinarySensorChanged { device_name, .. }
+            | HouseEvent::ThermostatConfigured { device_name, .. }
+            | HouseEvent::ThermostatDemandChanged { device_name, .. }
+            | HouseEvent::MetricRecorded { device_name, .. }
+            | HouseEvent::LockChanged { device_name, .. }
+            | HouseEvent::PinRejected { device_name, .. }
+            | HouseEvent::LockReported { device_name, .. }
+            | HouseEvent::AccessCodeAdded { device_name, .. }
+            | HouseEvent::AccessCodeRemoved { device_name, .. }
+            | HouseEvent::CoverChanged { device_name, .. }
+            | HouseEvent::CoverCalibrated { device_name, .. }
+            | HouseEvent::DetectorReported { device_name, .. }
+            | HouseEvent::DetectorTested { device_name, .. }
+            | HouseEvent::AlarmAcknowledged { device_name, .. }
+            | HouseEvent::PowerRecorded { device_name, .. }
+            | HouseEvent::MeterRecorded { device_name, .. }
+            | HouseEvent::ReadingRecorded { device_name, .. }
+            | HouseEvent::DeviceTagged { device_name, .. }
+            | HouseEvent::HeartbeatTimeoutSet { device_name, .. }
+            | HouseEvent::AvailabilityChanged { device_name, .. } => Some(device_name.clone()),
+        }
+    }
+
+    /// Device and room of an event sent by the device itself, which shows
+    /// the device is reachable.
+    pub fn reporter(&self) -> Option<(&str, &str)> {
+        match self {
+            HouseEvent::BinarySensorChanged { room_name, device_name, .. }
+            | HouseEvent::MetricRecorded { room_name, device_name, .. }
+            | HouseEvent::LockReported { room_name, device_name, .. }
+            | HouseEvent::DetectorReported { room_name, device_name, .. }
+            | HouseEvent::PowerRecorded { room_name, device_name, .. }
+            | HouseEvent::MeterRecorded { room_name, device_name, .. }
+            | HouseEvent::ReadingRecorded { room_name, device_name, .. } => Some((room_name, device_name)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct EventRecord {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub event: HouseEvent,
+}
+
+/// State of the house next to its rooms. It only changes through events
+/// as well.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct HouseSettings {
+    #[serde(default)]
+    pub tariff: Option<Tariff>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, Group>,
+    #[serde(default)]
+    pub areas: BTreeMap<String, Area>,
+}
+
+/// State of all rooms right after the event with `sequence` was applied.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Snapshot {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub smart_rooms: HashMap<String, SmartRoom>,
+    #[serde(default)]
+    pub settings: HouseSettings,
+}
+
+/// Events starting right after the oldest kept snapshot, or from the very
+/// first event as long as nothing was compacted.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct EventLog {
+    records: Vec<EventRecord>,
+    snapshots: Vec<Snapshot>,
+    snapshot_interval: usize,
+    snapshots_kept: usize,
+    /// Sequence of the latest event, its record may be compacted already.
+    sequence: u64,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_SNAPSHOT_INTERVAL)
+    }
+}
+
+impl EventLog {
+    pub fn new(snapshot_interval: usize) -> EventLog {
+        EventLog {
+            records: Vec::new(),
+            snapshots: Vec::new(),
+            snapshot_interval: snapshot_interval.max(1),
+            snapshots_kept: DEFAULT_SNAPSHOTS_KEPT,
+            sequence: 0,
+        }
+    }
+
+    /// Keeps at most `snapshots_kept` snapshots, at least two so that the
+    /// latest event is never compacted right away.
+    pub fn set_snapshots_kept(&mut self, snapshots_kept: usize) {
+        self.snapshots_kept = snapshots_kept.max(2);
+        self.compact();
+    }
+
+    pub fn records(&self) -> &[EventRecord] {
+        &self.records
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Sequence of the latest event, 0 before the first one.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Kept records of the events after `sequence`.
+    pub fn records_after(&self, sequence: u64) -> &[EventRecord] {
+        let from = self.records.partition_point(|record| record.sequence <= sequence);
+        &self.records[from..]
+    }
+
+    /// Whether events older than the oldest snapshot were dropped.
+    pub fn is_compacted(&self) -> bool {
+        self.sequence > self.records.len() as u64
+    }
+
+    /// Appends an already applied event. `smart_rooms` and `settings` are
+    /// the state after the event and are stored as a snapshot every
+    /// `snapshot_interval` events.
+    pub fn append(
+        &mut self,
+        event: HouseEvent,
+        timestamp: u64,
+        smart_rooms: &HashMap<String, SmartRoom>,
+        settings: &HouseSettings,
+    ) -> &EventRecord {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        self.records.push(EventRecord {
+            sequence,
+            timestamp,
+            event,
+        });
+        if sequence.is_multiple_of(self.snapshot_interval as u64) {
+            self.snapshots.push(Snapshot {
+                sequence,
+                timestamp,
+                smart_rooms: smart_rooms.clone(),
+                settings: settings.clone(),
+            });
+            self.compact();
+        }
+        self.records.last().unwrap()
+    }
+
+    /// Drops the snapshots beyond `snapshots_kept` and every event the
+    /// oldest remaining snapshot already contains.
+    fn compact(&mut self) {
+        if self.snapshots.len() <= self.snapshots_kept {
+            return;
+        }
+        self.snapshots.drain(..self.snapshots.len() - self.snapshots_kept);
+        let base = self.snapshots[0].sequence;
+        let contained = self.records.partition_point(|record| record.sequence <= base);
+        self.records.drain(..contained);
+    }
+
+    /// Latest snapshot taken at or before `timestamp` together with the
+    /// events that have to be replayed on top of it. `None` when the state
+    /// at `timestamp` was compacted away.
+    pub fn replay_from(&self, timestamp: u64) -> Option<(Option<&Snapshot>, Vec<&EventRecord>)> {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.timestamp <= timestamp);
+        if snapshot.is_none() && self.is_compacted() {
+            return None;
+        }
+        let after = snapshot.map(|snapshot| snapshot.sequence).unwrap_or(0);
+        // The log is replayed in order, up to the first event past `timestamp`
+        let records = self
+            .records_after(after)
+            .iter()
+            .take_while(|record| record.timestamp <= timestamp)
+            .collect();
+        Some((snapshot, records))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_every_interval() {
+        let mut log = EventLog::new(2);
+        let rooms = HashMap::new();
+        for timestamp in 1..=5 {
+            let event = HouseEvent::RoomRemoved {
+                room_name: "Kitchen".to_string(),
+            };
+            log.append(event, timestamp, &rooms, &HouseSettings::default());
+        }
+        assert_eq!(log.len(), 5);
+        assert_eq!(log.snapshots().len(), 2);
+
+        let (snapshot, records) = log.replay_from(3).unwrap();
+        assert_eq!(snapshot.unwrap().sequence, 2);
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn replay_stops_at_first_later_event() {
+        let mut log = EventLog::new(10);
+        let rooms = HashMap::new();
+        for timestamp in [1, 5, 3] {
+            let event = HouseEvent::RoomRemoved {
+                room_name: "Kitchen".to_string(),
+            };
+            log.append(event, timestamp, &rooms, &HouseSettings::default());
+        }
+
+        // The third event is older than the second but happened after it
+        let (_, records) = log.replay_from(4).unwrap();
+        let sequences: Vec<u64> = records.iter().map(|record| record.sequence).collect();
+        assert_eq!(sequences, vec![1]);
+    }
+
+    #[test]
+    fn compaction_keeps_oldest_needed_snapshot() {
+        let mut log = EventLog::new(2);
+        log.set_snapshots_kept(2);
+        let rooms = HashMap::new();
+        for timestamp in 1..=7 {
+            let event = HouseEvent::RoomRemoved {
+                room_name: "Kitchen".to_string(),
+            };
+            log.append(event, timestamp, &rooms, &HouseSettings::default());
+        }
+
+        // Snapshots at 4 and 6 are kept, events up to 4 are in them
+        let sequences: Vec<u64> = log.snapshots().iter().map(|snapshot| snapshot.sequence).collect();
+        assert_eq!(sequences, vec![4, 6]);
+        assert_eq!(log.records()[0].sequence, 5);
+        assert_eq!(log.last_sequence(), 7);
+        assert!(log.is_compacted());
+
+        let (snapshot, records) = log.replay_from(5).unwrap();
+        assert_eq!((snapshot.unwrap().sequence, records.len()), (4, 1));
+        assert!(log.replay_from(3).is_none());
+    }
+}
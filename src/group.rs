@@ -0,0 +1,103 @@
+use crate::devices::{Device, DeviceKind};
+use crate::smartroom::SmartRoom;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use utoipa::ToSchema;
+
+/// Selects devices across rooms. Empty fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct DeviceQuery {
+    pub tag: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<DeviceKind>,
+    pub room: Option<String>,
+}
+
+impl DeviceQuery {
+    pub fn matches(&self, room: &SmartRoom, device_name: &str, device: &Device) -> bool {
+        let tag = self.tag.as_ref().is_none_or(|tag| room.has_tag(device_name, tag));
+        let kind = self.kind.is_none_or(|kind| device.kind() == kind);
+        let in_room = self.room.as_ref().is_none_or(|name| *name == room.room_name);
+        tag && kind && in_room
+    }
+}
+
+/// A device found by a [`DeviceQuery`] together with where it lives.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TaggedDevice {
+    pub room_name: String,
+    pub device_name: String,
+    pub kind: DeviceKind,
+    pub tags: BTreeSet<String>,
+    pub device: Device,
+}
+
+/// A named query. Members are looked up whenever the group is used, so
+/// devices join and leave it as they are tagged, moved or removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Group {
+    pub name: String,
+    pub query: DeviceQuery,
+}
+
+/// Aggregates over the current members of a group.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GroupSummary {
+    pub name: String,
+    pub devices: usize,
+    pub sockets: usize,
+    pub sockets_on: usize,
+    /// Watts drawn by the sockets that are on.
+    pub power: f32,
+    pub average_temperature: Option<f32>,
+    /// Energy used by the sockets since the start of the UTC day.
+    pub energy_today_kwh: f64,
+}
+
+impl GroupSummary {
+    pub fn new(name: String, members: &[TaggedDevice], energy_today_kwh: f64) -> Self {
+        let sockets: Vec<_> = members
+            .iter()
+            .filter_map(|member| match &member.device {
+                Device::SmartSocket(socket) => Some(socket),
+                _ => None,
+            })
+            .collect();
+        let temperatures: Vec<f32> = members
+            .iter()
+            .filter_map(|member| match &member.device {
+                Device::SmartThermometr(thermometer) => Some(thermometer.temperature()),
+                _ => None,
+            })
+            .collect();
+        let average_temperature = if temperatures.is_empty() {
+            None
+        } else {
+            Some(temperatures.iter().sum::<f32>() / temperatures.len() as f32)
+        };
+        Self {
+            name,
+            devices: members.len(),
+            sockets: sockets.len(),
+            sockets_on: sockets.iter().filter(|socket| socket.status()).count(),
+            power: sockets.iter().fold(0.0, |power, socket| power + socket.drawn_power()),
+            average_temperature,
+            energy_today_kwh,
+        }
+    }
+}
+
+impl Display for GroupSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Group {}: {} devices, {} of {} sockets on drawing {:.1} W, {:.3} kWh today",
+            self.name, self.devices, self.sockets_on, self.sockets, self.power, self.energy_today_kwh
+        )?;
+        if let Some(temperature) = self.average_temperature {
+            write!(f, ", average temperature {:.1}", temperature)?;
+        }
+        Ok(())
+    }
+}
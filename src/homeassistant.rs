@@ -0,0 +1,399 @@
+use crate::bridge::BridgeConfig;
+use crate::devices::{CoverKind, Device, Hazard, Metric};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+/// Topic prefix Home Assistant watches for discovery configs.
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Maps a device's availability in its state payload onto `online`/`offline`.
+/// Devices never heard from count as online until they time out.
+const AVAILABILITY_TEMPLATE: &str = "{{ 'offline' if value_json.availability == 'offline' else 'online' }}";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Switch,
+    #[default]
+    Sensor,
+    BinarySensor,
+    Light,
+    Cover,
+}
+
+impl Component {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Component::Switch => "switch",
+            Component::Sensor => "sensor",
+            Component::BinarySensor => "binary_sensor",
+            Component::Light => "light",
+            Component::Cover => "cover",
+        }
+    }
+}
+
+/// Lowercase ASCII letters, digits and `_` only.
+fn slug(name: &str) -> String {
+    let slug: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    slug.trim_matches('_').to_string()
+}
+
+/// Identifies a device of a house across restarts, moves and room
+/// renames, e.g. `smarthouse_default_1a2b3c4d5e6f7a8b` after [`Device::id`].
+/// Devices of houses saved before they had ids fall back to a hash of
+/// their room and name.
+pub fn device_id(config: &BridgeConfig, room_name: &str, device: &Device) -> String {
+    let id = match device.id() {
+        "" => {
+            let digest = Sha256::new()
+                .chain_update(room_name)
+                .chain_update([0])
+                .chain_update(device.device_name().unwrap_or_default())
+                .finalize();
+            digest.iter().take(8).map(|byte| format!("{:02x}", byte)).collect()
+        }
+        id => slug(id),
+    };
+    [slug(&config.prefix), slug(&config.house_id), id]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// The Home Assistant device all entities of one house device belong to.
+/// Rooms become areas.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct HaDevice {
+    pub identifiers: Vec<String>,
+    pub name: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub suggested_area: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct AvailabilityTopic {
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+}
+
+/// Discovery config of one Home Assistant entity, published retained on
+/// [`EntityConfig::topic`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct EntityConfig {
+    #[serde(skip)]
+    pub component: Component,
+    /// What the entity shows of the device, e.g. `power`.
+    #[serde(skip)]
+    pub key: String,
+    /// `None` names the entity after its device.
+    pub name: Option<String>,
+    pub unique_id: String,
+    pub state_topic: String,
+    pub availability: Vec<AvailabilityTopic>,
+    pub availability_mode: String,
+    pub device: HaDevice,
+    /// Component specific settings, e.g. `command_topic` or `unit_of_measurement`.
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub options: serde_json::Map<String, Value>,
+}
+
+impl EntityConfig {
+    /// `{discovery_prefix}/{component}/{device_id}/{key}/config`
+    pub fn topic(&self, discovery_prefix: &str) -> String {
+        let node_id = self.device.identifiers.first().map(String::as_str).unwrap_or_default();
+        format!("{}/{}/{}/{}/config", discovery_prefix, self.component.as_str(), node_id, self.key)
+    }
+}
+
+/// Collects the entities of one device.
+struct Entities<'a> {
+    config: &'a BridgeConfig,
+    device_id: String,
+    device: HaDevice,
+    state_topic: String,
+    command_topic: String,
+    entities: Vec<EntityConfig>,
+}
+
+impl Entities<'_> {
+    fn add(&mut self, component: Component, key: &str, name: Option<&str>, options: Value) {
+        let Value::Object(options) = options else {
+            return;
+        };
+        self.entities.push(EntityConfig {
+            component,
+            key: key.to_string(),
+            name: name.map(str::to_string),
+            unique_id: format!("{}_{}", self.device_id, key),
+            state_topic: self.state_topic.clone(),
+            availability: vec![
+                AvailabilityTopic {
+                    topic: self.config.availability_topic(),
+                    value_template: None,
+                },
+                AvailabilityTopic {
+                    topic: self.state_topic.clone(),
+                    value_template: Some(AVAILABILITY_TEMPLATE.to_string()),
+                },
+            ],
+            availability_mode: "all".to_string(),
+            device: self.device.clone(),
+            options,
+        });
+    }
+
+    fn measurement(&mut self, key: &str, name: &str, device_class: Option<&str>, unit: &str, template: &str) {
+        let mut options = json!({
+            "unit_of_measurement": unit,
+            "state_class": "measurement",
+            "value_template": template,
+        });
+        if let Some(device_class) = device_class {
+            options["device_class"] = json!(device_class);
+        }
+        self.add(Component::Sensor, key, Some(name), options);
+    }
+
+    fn battery(&mut self) {
+        self.measurement(
+            "battery",
+            "Battery",
+            Some("battery"),
+            "%",
+            "{{ value_json.state.battery }}",
+        );
+    }
+
+    fn binary(&mut self, key: &str, name: Option<&str>, device_class: &str, field: &str) {
+        self.add(
+            Component::BinarySensor,
+            key,
+            name,
+            json!({
+                "device_class": device_class,
+                "value_template": format!("{{{{ 'ON' if value_json.state.{} else 'OFF' }}}}", field),
+            }),
+        );
+    }
+}
+
+/// Home Assistant entities of a device: sockets become switches with power
+/// and voltage sensors, thermometers temperature sensors, and so on.
+pub fn entities(config: &BridgeConfig, room_name: &str, device_name: &str, device: &Device) -> Vec<EntityConfig> {
+    let device_id = device_id(config, room_name, device);
+    let device_topic = config.device_topic(room_name, device_name);
+    let mut entities = Entities {
+        config,
+        device: HaDevice {
+            identifiers: vec![device_id.clone()],
+            name: device_name.to_string(),
+            manufacturer: "SmartHouse".to_string(),
+            model: format!("{:?}", device.kind()),
+            suggested_area: room_name.to_string(),
+        },
+        device_id,
+        state_topic: format!("{}/state", device_topic),
+        command_topic: format!("{}/set", device_topic),
+        entities: Vec::new(),
+    };
+    match device {
+        Device::SmartSocket(_) => {
+            entities.add(
+                Component::Switch,
+                "switch",
+                None,
+                json!({
+                    "device_class": "outlet",
+                    "command_topic": entities.command_topic,
+                    "payload_on": "ON",
+                    "payload_off": "OFF",
+                    "value_template": "{{ 'ON' if value_json.state.status else 'OFF' }}",
+                }),
+            );
+            entities.measurement(
+                "power",
+                "Power",
+                Some("power"),
+                "W",
+                "{{ value_json.state.power if value_json.state.status else 0 }}",
+            );
+            entities.measurement("voltage", "Voltage", Some("voltage"), "V", "{{ value_json.state.voltage }}");
+        }
+        Device::SmartThermometr(_) => {
+            entities.measurement(
+                "temperature",
+                "Temperature",
+                Some("temperature"),
+                "°C",
+                "{{ value_json.state.temperature }}",
+            );
+        }
+        Device::SmartLight(_) => {
+            entities.add(
+                Component::Light,
+                "light",
+                None,
+                json!({
+                    "command_topic": entities.command_topic,
+                    "payload_on": "ON",
+                    "payload_off": "OFF",
+                    "state_value_template": "{{ 'ON' if value_json.state.state.status else 'OFF' }}",
+                }),
+            );
+        }
+        Device::MotionSensor(_) => entities.binary("motion", None, "motion", "motion"),
+        Device::ContactSensor(_) => entities.binary("contact", None, "opening", "open"),
+        Device::Thermostat(_) => {
+            entities.measurement(
+                "target",
+                "Target temperature",
+                Some("temperature"),
+                "°C",
+                "{{ value_json.state.settings.target }}",
+            );
+            entities.add(
+                Component::Sensor,
+                "demand",
+                Some("Demand"),
+                json!({
+                    "device_class": "enum",
+                    "options": ["Idle", "Heating", "Cooling"],
+                    "value_template": "{{ value_json.state.demand }}",
+                }),
+            );
+        }
+        Device::EnvironmentSensor(sensor) => {
+            for metric in sensor.metrics() {
+                let (key, device_class) = match metric {
+                    Metric::Humidity => ("humidity", Some("humidity")),
+                    Metric::Co2 => ("co2", Some("carbon_dioxide")),
+                    Metric::Voc => ("voc", None),
+                    Metric::Pm25 => ("pm25", Some("pm25")),
+                };
+                let template = format!("{{{{ value_json.state.readings.{:?} | default(none) }}}}", metric);
+                entities.measurement(key, &metric.to_string(), device_class, metric.unit(), &template);
+            }
+        }
+        Device::SmartLock(_) => {
+            entities.add(
+                Component::Sensor,
+                "lock",
+                None,
+                json!({
+                    "device_class": "enum",
+                    "options": ["Locked", "Unlocked", "Jammed"],
+                    "value_template": "{{ value_json.state.state }}",
+                }),
+            );
+            entities.battery();
+        }
+        Device::Cover(cover) => {
+            let device_class = match cover.kind() {
+                CoverKind::Blind => "blind",
+                CoverKind::Shutter => "shutter",
+                CoverKind::GarageDoor => "garage",
+            };
+            entities.add(
+                Component::Cover,
+                "cover",
+                None,
+                json!({
+                    "device_class": device_class,
+                    "command_topic": entities.command_topic,
+                    "payload_open": r#"{"position":100}"#,
+                    "payload_close": r#"{"position":0}"#,
+                    "payload_stop": null,
+                    "value_template": "{{ {'Opening': 'opening', 'Closing': 'closing'}.get(value_json.state.state.motion, 'open' if value_json.state.state.position > 0 else 'closed') }}",
+                    "position_topic": entities.state_topic,
+                    "position_template": "{{ value_json.state.state.position }}",
+                    "set_position_topic": entities.command_topic,
+                    "set_position_template": r#"{"position":{{ position }}}"#,
+                }),
+            );
+        }
+        Device::SafetyDetector(detector) => {
+            let device_class = match detector.hazard() {
+                Hazard::Leak => "moisture",
+                Hazard::Smoke => "smoke",
+            };
+            entities.binary("hazard", None, device_class, "detecting");
+            entities.battery();
+        }
+        Device::EnergyMeter(_) => {
+            entities.measurement(
+                "power",
+                "Power",
+                Some("power"),
+                "W",
+                "{{ value_json.state.reading.power if value_json.state.reading else none }}",
+            );
+            entities.add(
+                Component::Sensor,
+                "energy",
+                Some("Energy"),
+                json!({
+                    "device_class": "energy",
+                    "unit_of_measurement": "kWh",
+                    "state_class": "total_increasing",
+                    "value_template": "{{ value_json.state.reading.energy_kwh if value_json.state.reading else none }}",
+                }),
+            );
+        }
+    }
+    entities.entities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{SmartSocket, SmartThermometer};
+
+    #[test]
+    fn sockets_and_thermometers() {
+        let config = BridgeConfig::new("default");
+        let mut kettle = Device::SmartSocket(SmartSocket::default("Kettle".to_string()));
+        kettle.set_id("1a2b3c4d".to_string());
+        let socket = entities(&config, "Kitchen", "Kettle", &kettle);
+        let keys: Vec<_> = socket.iter().map(|entity| (entity.component, entity.key.as_str())).collect();
+        assert_eq!(
+            keys,
+            [
+                (Component::Switch, "switch"),
+                (Component::Sensor, "power"),
+                (Component::Sensor, "voltage")
+            ]
+        );
+        let switch = &socket[0];
+        let id = device_id(&config, "Kitchen", &kettle);
+        assert_eq!(id, "smarthouse_default_1a2b3c4d");
+        assert_eq!(switch.topic("homeassistant"), format!("homeassistant/switch/{}/switch/config", id));
+        assert_eq!(switch.unique_id, format!("{}_switch", id));
+        assert_eq!(switch.device.suggested_area, "Kitchen");
+        assert_eq!(switch.state_topic, "smarthouse/default/Kitchen/Kettle/state");
+        assert_eq!(switch.options["command_topic"], "smarthouse/default/Kitchen/Kettle/set");
+        let payload = serde_json::to_value(switch).unwrap();
+        assert_eq!(payload["name"], Value::Null);
+        assert_eq!(payload["availability"][0]["topic"], "smarthouse/default/availability");
+        assert!(socket.iter().all(|entity| entity.device == switch.device));
+
+        let thermometer = Device::SmartThermometr(SmartThermometer::default("Thermo".to_string()));
+        let sensor = &entities(&config, "Kitchen", "Thermo", &thermometer)[0];
+        assert_eq!(sensor.options["device_class"], "temperature");
+        assert_eq!(sensor.options["value_template"], "{{ value_json.state.temperature }}");
+
+        // Wherever the device goes
+        assert_eq!(id, device_id(&config, "Hall", &kettle));
+        let legacy = |name: &str| Device::SmartSocket(SmartSocket::default(name.to_string()));
+        assert_ne!(device_id(&config, "Hall", &legacy("Tv/Hifi")), device_id(&config, "Hall", &legacy("Tv Hifi")));
+    }
+}
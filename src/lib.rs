@@ -1,3 +1,22 @@
+pub mod actor;
+pub mod area;
+pub mod availability;
+pub mod batch;
+pub mod bridge;
 pub mod devices;
+pub mod discovery;
+pub mod energy;
+pub mod events;
+pub mod group;
+pub mod homeassistant;
+pub mod metrics;
+pub mod modbus;
+pub mod mqtt;
+pub mod registry;
 pub mod smarthouse;
 pub mod smartroom;
+pub mod subscription;
+pub mod tariff;
+pub mod thermostat;
+pub mod transition;
+pub mod undo;
@@ -0,0 +1,301 @@
+use crate::availability::Availability;
+use crate::devices::Device;
+use crate::smarthouse::SmartHouse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the request duration buckets, in seconds.
+pub const DURATION_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Label values with `\`, `"` and line breaks escaped.
+pub fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Family {
+    name: String,
+    kind: MetricKind,
+    help: String,
+    samples: Vec<String>,
+}
+
+/// Collects samples by metric family, so every family is written in one
+/// block whatever the order they're added in.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    families: Vec<Family>,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sample of family `name`, e.g. `smarthouse_http_request_duration_seconds`
+    /// with the `_bucket` suffix. The first sample of a family sets its help.
+    pub fn sample(&mut self, name: &str, kind: MetricKind, help: &str, suffix: &str, labels: &[(&str, &str)], value: f64) {
+        let index = match self.families.iter().position(|family| family.name == name) {
+            Some(index) => index,
+            None => {
+                self.families.push(Family {
+                    name: name.to_string(),
+                    kind,
+                    help: help.to_string(),
+                    samples: Vec::new(),
+                });
+                self.families.len() - 1
+            }
+        };
+        let mut sample = format!("{}{}", name, suffix);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+                .collect();
+            let _ = write!(sample, "{{{}}}", labels.join(","));
+        }
+        let _ = write!(sample, " {}", format_value(value));
+        self.families[index].samples.push(sample);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.sample(name, MetricKind::Gauge, help, "", labels, value);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.sample(name, MetricKind::Counter, help, "", labels, value);
+    }
+
+    /// Gauges of one house: rooms and devices, switches, power,
+    /// temperatures and availability.
+    pub fn house(&mut self, house_id: &str, house: &SmartHouse) {
+        let rooms = house.get_rooms_list();
+        self.gauge("smarthouse_rooms", "Rooms of the house.", &[("house", house_id)], rooms.len() as f64);
+        for room in rooms {
+            let room_labels = [("house", house_id), ("room", room.room_name.as_str())];
+            self.gauge(
+                "smarthouse_devices",
+                "Devices in the room.",
+                &room_labels,
+                room.smart_device.len() as f64,
+            );
+            for (device_name, device) in &room.smart_device {
+                let labels = [
+                    ("house", house_id),
+                    ("room", room.room_name.as_str()),
+                    ("device", device_name.as_str()),
+                ];
+                match device {
+                    Device::SmartSocket(socket) => {
+                        let on = if socket.status() { 1.0 } else { 0.0 };
+                        self.gauge("smarthouse_socket_on", "1 while the socket is switched on.", &labels, on);
+                        self.gauge(
+                            "smarthouse_socket_power_watts",
+                            "Power drawn by the socket, zero while switched off.",
+                            &labels,
+                            socket.drawn_power() as f64,
+                        );
+                    }
+                    Device::SmartThermometr(thermometer) => self.gauge(
+                        "smarthouse_temperature_celsius",
+                        "Last temperature of the thermometer.",
+                        &labels,
+                        thermometer.temperature() as f64,
+                    ),
+                    _ => {}
+                }
+                let availability = room.availability(device_name);
+                for state in [Availability::Online, Availability::Offline, Availability::Unknown] {
+                    let state_name = state.to_string();
+                    let mut state_labels = labels.to_vec();
+                    state_labels.push(("state", state_name.as_str()));
+                    self.gauge(
+                        "smarthouse_device_availability",
+                        "1 for the current availability of the device.",
+                        &state_labels,
+                        if availability == state { 1.0 } else { 0.0 },
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for family in &self.families {
+            let help = family.help.replace('\\', "\\\\").replace('\n', "\\n");
+            let _ = writeln!(text, "# HELP {} {}", family.name, help);
+            let _ = writeln!(text, "# TYPE {} {}", family.name, family.kind.as_str());
+            for sample in &family.samples {
+                let _ = writeln!(text, "{}", sample);
+            }
+        }
+        text
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations up to each of [`DURATION_BUCKETS`].
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+struct Requests {
+    /// By method, route and status.
+    counts: BTreeMap<(String, String, u16), u64>,
+    /// By method and route.
+    durations: BTreeMap<(String, String), Histogram>,
+}
+
+/// Counts handled HTTP requests and how long they took. Requests are told
+/// apart by route pattern, e.g. `/api/rooms/{room_name}`, not by path.
+#[derive(Debug, Default)]
+pub struct HttpMetrics {
+    requests: Mutex<Requests>,
+}
+
+impl HttpMetrics {
+    fn lock(&self) -> MutexGuard<'_, Requests> {
+        self.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn record(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let mut requests = self.lock();
+        *requests
+            .counts
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+        let histogram = requests
+            .durations
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    pub fn write(&self, exposition: &mut Exposition) {
+        let requests = self.lock();
+        for ((method, route, status), count) in &requests.counts {
+            let status = status.to_string();
+            exposition.counter(
+                "smarthouse_http_requests_total",
+                "HTTP requests handled.",
+                &[("method", method), ("route", route), ("status", &status)],
+                *count as f64,
+            );
+        }
+        let name = "smarthouse_http_request_duration_seconds";
+        let help = "Time taken to handle HTTP requests.";
+        for ((method, route), histogram) in &requests.durations {
+            let labels = [("method", method.as_str()), ("route", route.as_str())];
+            for (bucket, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+                let le = format_value(bound);
+                let mut bucket_labels = labels.to_vec();
+                bucket_labels.push(("le", le.as_str()));
+                exposition.sample(name, MetricKind::Histogram, help, "_bucket", &bucket_labels, *bucket as f64);
+            }
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", "+Inf"));
+            exposition.sample(name, MetricKind::Histogram, help, "_bucket", &bucket_labels, histogram.count as f64);
+            exposition.sample(name, MetricKind::Histogram, help, "_sum", &labels, histogram.sum);
+            exposition.sample(name, MetricKind::Histogram, help, "_count", &labels, histogram.count as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{SmartSocket, SmartThermometer};
+    use crate::smartroom::SmartRoom;
+
+    #[test]
+    fn house_and_requests() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kid's \"den\"".to_string())).unwrap();
+        house.add_device("Kid's \"den\"", Device::SmartSocket(SmartSocket::default("Lamp\\1".to_string()))).unwrap();
+        house.add_device("Kid's \"den\"", Device::SmartThermometr(SmartThermometer::default("Thermo".to_string()))).unwrap();
+        house.switch_socket("Kid's \"den\"", "Lamp\\1", true).unwrap();
+        house.record_power("Kid's \"den\"", "Lamp\\1", 40.0).unwrap();
+        house.record_reading("Kid's \"den\"", "Thermo", 21.5).unwrap();
+
+        let metrics = HttpMetrics::default();
+        metrics.record("GET", "/api/rooms", 200, Duration::from_millis(3));
+        metrics.record("GET", "/api/rooms", 200, Duration::from_millis(30));
+        metrics.record("GET", "/api/rooms", 304, Duration::from_secs(10));
+
+        let mut exposition = Exposition::new();
+        exposition.house("flat\n1", &house);
+        metrics.write(&mut exposition);
+        let text = exposition.render();
+        let lamp = r#"house="flat\n1",room="Kid's \"den\"",device="Lamp\\1""#;
+        for line in [
+            "# TYPE smarthouse_rooms gauge".to_string(),
+            r#"smarthouse_rooms{house="flat\n1"} 1"#.to_string(),
+            r#"smarthouse_devices{house="flat\n1",room="Kid's \"den\""} 2"#.to_string(),
+            format!("smarthouse_socket_on{{{}}} 1", lamp),
+            format!("smarthouse_socket_power_watts{{{}}} 40", lamp),
+            format!("smarthouse_device_availability{{{},state=\"online\"}} 1", lamp),
+            r#"smarthouse_temperature_celsius{house="flat\n1",room="Kid's \"den\"",device="Thermo"} 21.5"#.to_string(),
+            r#"smarthouse_http_requests_total{method="GET",route="/api/rooms",status="200"} 2"#.to_string(),
+            r#"smarthouse_http_request_duration_seconds_bucket{method="GET",route="/api/rooms",le="0.005"} 1"#.to_string(),
+            r#"smarthouse_http_request_duration_seconds_bucket{method="GET",route="/api/rooms",le="+Inf"} 3"#.to_string(),
+            r#"smarthouse_http_request_duration_seconds_count{method="GET",route="/api/rooms"} 3"#.to_string(),
+        ] {
+            assert!(text.lines().any(|sample| sample == line), "{} missing from\n{}", line, text);
+        }
+        // One block per family
+        assert_eq!(text.matches("# TYPE smarthouse_device_availability").count(), 1);
+    }
+}
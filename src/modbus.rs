@@ -0,0 +1,589 @@
+use crate::actor::{ActorError, DeviceCommand, DeviceConnection, DeviceReply};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+pub const MODBUS_PORT: u16 = 502;
+
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const SERVER_BUSY: u8 = 0x06;
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum ModbusError {
+    #[error("Modbus I/O failed: {0}")]
+    Io(String),
+    #[error("Modbus protocol error: {0}")]
+    Protocol(String),
+    #[error("Modbus exception {code:#04x} for function {function:#04x}")]
+    Exception { function: u8, code: u8 },
+}
+
+impl From<io::Error> for ModbusError {
+    fn from(err: io::Error) -> Self {
+        ModbusError::Io(err.to_string())
+    }
+}
+
+impl From<ModbusError> for ActorError {
+    /// Exceptions are answers of a working device, only a busy one may
+    /// accept the command later.
+    fn from(err: ModbusError) -> Self {
+        match err {
+            ModbusError::Exception { code: SERVER_BUSY, .. } | ModbusError::Io(_) | ModbusError::Protocol(_) => {
+                ActorError::Io(err.to_string())
+            }
+            ModbusError::Exception { .. } => ActorError::Unsupported(err.to_string()),
+        }
+    }
+}
+
+/// A value held in one or two holding registers, the high word first.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Register {
+    pub address: u16,
+    /// Two registers make a 32-bit value.
+    #[serde(default)]
+    pub wide: bool,
+    #[serde(default)]
+    pub signed: bool,
+    /// Unit per raw step, e.g. 0.1 for a register counting decivolts.
+    pub scale: f64,
+}
+
+impl Register {
+    pub fn new(address: u16, scale: f64) -> Self {
+        Self {
+            address,
+            wide: false,
+            signed: false,
+            scale,
+        }
+    }
+
+    pub fn wide(address: u16, scale: f64) -> Self {
+        Self {
+            wide: true,
+            ..Self::new(address, scale)
+        }
+    }
+
+    pub fn count(&self) -> u16 {
+        if self.wide {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn decode(&self, words: &[u16]) -> Option<f64> {
+        let raw = match (self.wide, self.signed, words) {
+            (false, false, [word]) => *word as f64,
+            (false, true, [word]) => *word as i16 as f64,
+            (true, false, [high, low]) => ((*high as u32) << 16 | *low as u32) as f64,
+            (true, true, [high, low]) => ((*high as u32) << 16 | *low as u32) as i32 as f64,
+            _ => return None,
+        };
+        Some(raw * self.scale)
+    }
+
+    /// Raw register words for `value`, as a device would hold them.
+    pub fn encode(&self, value: f64) -> Vec<u16> {
+        let raw = (value / self.scale).round();
+        let raw = if self.signed { raw as i64 as u32 } else { raw as u32 };
+        if self.wide {
+            vec![(raw >> 16) as u16, raw as u16]
+        } else {
+            vec![raw as u16]
+        }
+    }
+}
+
+/// Where a device keeps what the house reads and switches.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct RegisterMap {
+    /// Coil of the relay, on while set.
+    pub coil: Option<u16>,
+    /// Volts.
+    pub voltage: Option<Register>,
+    /// Watts.
+    pub power: Option<Register>,
+    /// Energy counter in kWh.
+    pub energy: Option<Register>,
+}
+
+/// A Modbus TCP frame: the MBAP header, then the PDU of a function code
+/// and its data.
+fn frame(transaction: u16, unit_id: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(7 + pdu.len());
+    frame.extend_from_slice(&transaction.to_be_bytes());
+    frame.extend_from_slice(&0u16.to_be_bytes());
+    frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    frame.push(unit_id);
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+/// Reads a frame, returning its transaction id, unit id and PDU.
+async fn read_frame(stream: &mut TcpStream) -> Result<(u16, u8, Vec<u8>), ModbusError> {
+    let mut header = [0; 7];
+    stream.read_exact(&mut header).await?;
+    let transaction = u16::from_be_bytes([header[0], header[1]]);
+    let protocol = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if protocol != 0 || !(2..=254).contains(&len) {
+        return Err(ModbusError::Protocol(format!("Protocol {}, length {}", protocol, len)));
+    }
+    let mut pdu = vec![0; len - 1];
+    stream.read_exact(&mut pdu).await?;
+    Ok((transaction, header[6], pdu))
+}
+
+/// Modbus TCP client of one unit.
+#[derive(Debug)]
+pub struct ModbusClient {
+    stream: TcpStream,
+    unit_id: u8,
+    transaction: u16,
+}
+
+impl ModbusClient {
+    pub async fn connect(address: SocketAddr, unit_id: u8) -> Result<Self, ModbusError> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            unit_id,
+            transaction: 0,
+        })
+    }
+
+    /// Sends `pdu` and returns the data of the matching answer.
+    async fn call(&mut self, pdu: &[u8]) -> Result<Vec<u8>, ModbusError> {
+        self.transaction = self.transaction.wrapping_add(1);
+        self.stream.write_all(&frame(self.transaction, self.unit_id, pdu)).await?;
+        loop {
+            let (transaction, _, answer) = read_frame(&mut self.stream).await?;
+            // An answer to an earlier request we gave up on
+            if transaction != self.transaction {
+                continue;
+            }
+            return match answer.split_first() {
+                Some((&function, data)) if function == pdu[0] => Ok(data.to_vec()),
+                Some((&function, [code])) if function == pdu[0] | 0x80 => Err(ModbusError::Exception {
+                    function: pdu[0],
+                    code: *code,
+                }),
+                _ => Err(ModbusError::Protocol(format!("Unexpected answer {:02x?}", answer))),
+            };
+        }
+    }
+
+    pub async fn read_coils(&mut self, address: u16, count: u16) -> Result<Vec<bool>, ModbusError> {
+        let mut pdu = vec![READ_COILS];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&count.to_be_bytes());
+        let data = self.call(&pdu).await?;
+        match data.split_first() {
+            Some((&len, bytes)) if len as usize == bytes.len() && bytes.len() * 8 >= count as usize => {
+                Ok((0..count as usize).map(|bit| bytes[bit / 8] & (1 << (bit % 8)) != 0).collect())
+            }
+            _ => Err(ModbusError::Protocol(format!("Bad coil data {:02x?}", data))),
+        }
+    }
+
+    pub async fn write_coil(&mut self, address: u16, on: bool) -> Result<(), ModbusError> {
+        let mut pdu = vec![WRITE_SINGLE_COIL];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(if on { &[0xFF, 0x00] } else { &[0x00, 0x00] });
+        // The answer echoes the request
+        match self.call(&pdu).await? {
+            data if data == pdu[1..] => Ok(()),
+            data => Err(ModbusError::Protocol(format!("Bad coil echo {:02x?}", data))),
+        }
+    }
+
+    pub async fn read_holding_registers(&mut self, address: u16, count: u16) -> Result<Vec<u16>, ModbusError> {
+        let mut pdu = vec![READ_HOLDING_REGISTERS];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&count.to_be_bytes());
+        let data = self.call(&pdu).await?;
+        match data.split_first() {
+            Some((&len, bytes)) if len as usize == bytes.len() && bytes.len() == count as usize * 2 => {
+                Ok(bytes.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect())
+            }
+            _ => Err(ModbusError::Protocol(format!("Bad register data {:02x?}", data))),
+        }
+    }
+
+    pub async fn write_register(&mut self, address: u16, value: u16) -> Result<(), ModbusError> {
+        let mut pdu = vec![WRITE_SINGLE_REGISTER];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&value.to_be_bytes());
+        match self.call(&pdu).await? {
+            data if data == pdu[1..] => Ok(()),
+            data => Err(ModbusError::Protocol(format!("Bad register echo {:02x?}", data))),
+        }
+    }
+
+    pub async fn read(&mut self, register: &Register) -> Result<f64, ModbusError> {
+        let words = self.read_holding_registers(register.address, register.count()).await?;
+        register
+            .decode(&words)
+            .ok_or_else(|| ModbusError::Protocol(format!("{} registers at {}", words.len(), register.address)))
+    }
+}
+
+/// Backs a socket or meter by a Modbus TCP device: switches go to the
+/// coil, readings come from the holding registers of `map`. The connection
+/// is opened on the first command and again after a failed or abandoned
+/// one.
+#[derive(Debug)]
+pub struct ModbusConnection {
+    address: SocketAddr,
+    unit_id: u8,
+    map: RegisterMap,
+    client: Option<ModbusClient>,
+    /// Set while a command runs. Still set at the next one if the actor
+    /// gave up waiting, leaving the stream in an unknown state.
+    pending: bool,
+}
+
+impl ModbusConnection {
+    pub fn new(address: SocketAddr, unit_id: u8, map: RegisterMap) -> Self {
+        Self {
+            address,
+            unit_id,
+            map,
+            client: None,
+            pending: false,
+        }
+    }
+
+    pub fn map(&self) -> &RegisterMap {
+        &self.map
+    }
+
+    async fn client(&mut self) -> Result<&mut ModbusClient, ModbusError> {
+        if self.pending {
+            self.client = None;
+        }
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => ModbusClient::connect(self.address, self.unit_id).await?,
+        };
+        Ok(self.client.insert(client))
+    }
+
+    async fn execute(&mut self, command: DeviceCommand) -> Result<DeviceReply, ActorError> {
+        let unmapped = || ActorError::Unsupported(format!("{:?} isn't in the register map", command));
+        let map = self.map.clone();
+        let reply = match command {
+            DeviceCommand::Switch { status } => {
+                let coil = map.coil.ok_or_else(unmapped)?;
+                self.client().await?.write_coil(coil, status).await?;
+                DeviceReply::Switched(status)
+            }
+            DeviceCommand::ReadPower => {
+                let power = map.power.ok_or_else(unmapped)?;
+                DeviceReply::Power(self.client().await?.read(&power).await? as f32)
+            }
+            DeviceCommand::ReadVoltage => {
+                let voltage = map.voltage.ok_or_else(unmapped)?;
+                DeviceReply::Voltage(self.client().await?.read(&voltage).await? as f32)
+            }
+            DeviceCommand::ReadEnergy => {
+                let energy = map.energy.ok_or_else(unmapped)?;
+                DeviceReply::Energy(self.client().await?.read(&energy).await?)
+            }
+            DeviceCommand::ReadTemperature | DeviceCommand::State => return Err(unmapped()),
+        };
+        Ok(reply)
+    }
+}
+
+impl DeviceConnection for ModbusConnection {
+    async fn send(&mut self, command: DeviceCommand) -> Result<DeviceReply, ActorError> {
+        self.pending = true;
+        let result = self.execute(command).await;
+        self.pending = false;
+        if let Err(err) = &result {
+            log::debug!("Modbus device {} unit {}: {}", self.address, self.unit_id, err);
+            if err.is_transient() {
+                self.client = None;
+            }
+        }
+        result
+    }
+}
+
+/// Coils and holding registers of a simulated unit. Addresses that were
+/// never set answer with an exception, as unmapped ones do on real devices.
+#[derive(Debug, Default)]
+struct Registers {
+    coils: BTreeMap<u16, bool>,
+    holding: BTreeMap<u16, u16>,
+}
+
+impl Registers {
+    fn answer(&mut self, pdu: &[u8]) -> Result<Vec<u8>, u8> {
+        let word = |at: usize| pdu.get(at..at + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+        let (Some(&function), Some(address), Some(value)) = (pdu.first(), word(1), word(3)) else {
+            return Err(ILLEGAL_DATA_VALUE);
+        };
+        let addresses = || address..address.saturating_add(value);
+        match function {
+            READ_COILS => {
+                if !(1..=2000).contains(&value) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let coils = addresses()
+                    .map(|address| self.coils.get(&address).copied())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+                let mut bytes = vec![0u8; coils.len().div_ceil(8)];
+                for (bit, _) in coils.iter().enumerate().filter(|(_, on)| **on) {
+                    bytes[bit / 8] |= 1 << (bit % 8);
+                }
+                let mut data = vec![function, bytes.len() as u8];
+                data.extend(bytes);
+                Ok(data)
+            }
+            READ_HOLDING_REGISTERS => {
+                if !(1..=125).contains(&value) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let words = addresses()
+                    .map(|address| self.holding.get(&address).copied())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+                let mut data = vec![function, words.len() as u8 * 2];
+                data.extend(words.iter().flat_map(|word| word.to_be_bytes()));
+                Ok(data)
+            }
+            WRITE_SINGLE_COIL => {
+                let on = match value {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(ILLEGAL_DATA_VALUE),
+                };
+                *self.coils.get_mut(&address).ok_or(ILLEGAL_DATA_ADDRESS)? = on;
+                Ok(pdu.to_vec())
+            }
+            WRITE_SINGLE_REGISTER => {
+                *self.holding.get_mut(&address).ok_or(ILLEGAL_DATA_ADDRESS)? = value;
+                Ok(pdu.to_vec())
+            }
+            _ => Err(ILLEGAL_FUNCTION),
+        }
+    }
+}
+
+fn lock(registers: &Mutex<Registers>) -> MutexGuard<'_, Registers> {
+    registers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A Modbus TCP device on localhost, for tests and demos. It answers every
+/// unit id from the same coils and registers.
+#[derive(Debug)]
+pub struct ModbusSimulator {
+    local_addr: SocketAddr,
+    registers: Arc<Mutex<Registers>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ModbusSimulator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ModbusSimulator {
+    pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let registers = Arc::new(Mutex::new(Registers::default()));
+        let task = tokio::spawn(accept(listener, registers.clone()));
+        Ok(Self {
+            local_addr,
+            registers,
+            task,
+        })
+    }
+
+    /// A relay with a meter, holding `voltage` and `power` where `map`
+    /// says, switched off and with an empty energy counter.
+    pub async fn device(address: SocketAddr, map: &RegisterMap, voltage: f64, power: f64) -> io::Result<Self> {
+        let simulator = Self::bind(address).await?;
+        if let Some(coil) = map.coil {
+            simulator.set_coil(coil, false);
+        }
+        for (register, value) in [(map.voltage, voltage), (map.power, power), (map.energy, 0.0)] {
+            if let Some(register) = register {
+                simulator.set_value(&register, value);
+            }
+        }
+        Ok(simulator)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn coil(&self, address: u16) -> Option<bool> {
+        lock(&self.registers).coils.get(&address).copied()
+    }
+
+    pub fn set_coil(&self, address: u16, on: bool) {
+        lock(&self.registers).coils.insert(address, on);
+    }
+
+    pub fn register(&self, address: u16) -> Option<u16> {
+        lock(&self.registers).holding.get(&address).copied()
+    }
+
+    pub fn set_register(&self, address: u16, word: u16) {
+        lock(&self.registers).holding.insert(address, word);
+    }
+
+    /// Stores `value` scaled to raw words.
+    pub fn set_value(&self, register: &Register, value: f64) {
+        let mut registers = lock(&self.registers);
+        for (address, word) in (register.address..).zip(register.encode(value)) {
+            registers.holding.insert(address, word);
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, registers: Arc<Mutex<Registers>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve(stream, registers.clone()));
+            }
+            Err(err) => log::warn!("Modbus simulator can't accept: {}", err),
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, registers: Arc<Mutex<Registers>>) {
+    while let Ok((transaction, unit_id, pdu)) = read_frame(&mut stream).await {
+        let answer = lock(&registers)
+            .answer(&pdu)
+            .unwrap_or_else(|code| vec![pdu.first().copied().unwrap_or_default() | 0x80, code]);
+        if stream.write_all(&frame(transaction, unit_id, &answer)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::{poll, ActorConfig, DeviceHandle};
+    use crate::devices::{Device, EnergyMeter, SmartSocket};
+    use crate::registry::SharedHouse;
+    use crate::smarthouse::SmartHouse;
+    use crate::smartroom::SmartRoom;
+    use std::net::Ipv4Addr;
+    use tokio::sync::RwLock;
+
+    fn map() -> RegisterMap {
+        RegisterMap {
+            coil: Some(0),
+            voltage: Some(Register::new(0, 0.1)),
+            power: Some(Register::wide(2, 0.1)),
+            energy: Some(Register::wide(4, 0.01)),
+        }
+    }
+
+    #[test]
+    fn scaling() {
+        let power = Register::wide(2, 0.1);
+        assert_eq!(power.encode(123_456.7), [0x0012, 0xD687]);
+        assert!((power.decode(&[0x0012, 0xD687]).unwrap() - 123_456.7).abs() < 1e-6);
+        let signed = Register {
+            signed: true,
+            ..Register::new(0, 0.5)
+        };
+        assert_eq!(signed.decode(&signed.encode(-20.0)), Some(-20.0));
+        assert_eq!(signed.decode(&[1, 2]), None);
+    }
+
+    #[tokio::test]
+    async fn sockets_and_meters_over_modbus() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let simulator = ModbusSimulator::device(localhost, &map(), 230.4, 1_250.0).await.unwrap();
+        simulator.set_value(&map().energy.unwrap(), 1_532.25);
+
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Garage".to_string())).unwrap();
+        house.add_device("Garage", Device::SmartSocket(SmartSocket::default("Charger".to_string()))).unwrap();
+        house.add_device("Garage", Device::EnergyMeter(EnergyMeter::default("Meter".to_string()))).unwrap();
+        for device in ["Charger", "Meter"] {
+            let connection = ModbusConnection::new(simulator.local_addr(), 1, map());
+            house.attach_actor("Garage", device, DeviceHandle::spawn(connection, ActorConfig::default())).unwrap();
+        }
+        let house: SharedHouse = Arc::new(RwLock::new(house));
+
+        crate::actor::command(&house, "Garage", "Charger", DeviceCommand::Switch { status: true }).await.unwrap();
+        assert_eq!(simulator.coil(0), Some(true));
+        poll(&house, "Garage", "Charger").await.unwrap();
+        poll(&house, "Garage", "Meter").await.unwrap();
+        {
+            let house = house.read().await;
+            match house.get_device("Garage", "Charger").unwrap() {
+                Device::SmartSocket(socket) => {
+                    assert!(socket.status());
+                    assert_eq!((socket.power(), socket.voltage()), (1_250.0, 230.4));
+                }
+                device => panic!("{:?}", device),
+            }
+            match house.get_device("Garage", "Meter").unwrap() {
+                Device::EnergyMeter(meter) => {
+                    let reading = meter.reading().unwrap();
+                    assert!((reading.energy_kwh - 1_532.25).abs() < 1e-9);
+                    assert_eq!((reading.power, reading.phases[0].voltage), (1_250.0, 230.4));
+                }
+                device => panic!("{:?}", device),
+            }
+        }
+
+        // Unmapped registers are refused by the device, not a reason to retry
+        let handle = DeviceHandle::spawn(
+            ModbusConnection::new(simulator.local_addr(), 1, RegisterMap {
+                power: Some(Register::new(100, 1.0)),
+                ..RegisterMap::default()
+            }),
+            ActorConfig::default(),
+        );
+        let err = handle.request(DeviceCommand::ReadPower).await.unwrap_err();
+        assert!(matches!(err, ActorError::Unsupported(_)), "{:?}", err);
+        assert_eq!(handle.state().await.unwrap().failures, 0);
+        let err = handle.request(DeviceCommand::Switch { status: true }).await.unwrap_err();
+        assert!(matches!(err, ActorError::Unsupported(_)), "{:?}", err);
+
+        // A device that went away
+        let address = simulator.local_addr();
+        drop(simulator);
+        let config = ActorConfig {
+            retries: 0,
+            ..ActorConfig::default()
+        };
+        let handle = DeviceHandle::spawn(ModbusConnection::new(address, 1, map()), config);
+        assert!(handle.request(DeviceCommand::ReadPower).await.unwrap_err().is_transient());
+    }
+}
@@ -0,0 +1,87 @@
+//! Messages the bridge exchanges with a broker through [`rumqttc`], which
+//! takes care of reconnecting, credentials and TLS. [`broker::Broker`] is a
+//! stand-in for a real broker in tests.
+
+#[cfg(test)]
+pub mod broker;
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum MqttError {
+    #[error("MQTT I/O failed: {0}")]
+    Io(String),
+    #[error("Malformed MQTT packet: {0}")]
+    Protocol(String),
+    #[error("MQTT connection failed: {0}")]
+    Connection(String),
+    #[error("MQTT client stopped: {0}")]
+    Client(String),
+}
+
+impl From<io::Error> for MqttError {
+    fn from(err: io::Error) -> Self {
+        MqttError::Io(err.to_string())
+    }
+}
+
+impl From<rumqttc::ConnectionError> for MqttError {
+    fn from(err: rumqttc::ConnectionError) -> Self {
+        MqttError::Connection(err.to_string())
+    }
+}
+
+impl From<rumqttc::ClientError> for MqttError {
+    fn from(err: rumqttc::ClientError) -> Self {
+        MqttError::Client(err.to_string())
+    }
+}
+
+/// An application message, also used as the last will.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+impl Message {
+    pub fn new(topic: impl Into<String>, payload: impl Into<Vec<u8>>, retain: bool) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain,
+        }
+    }
+
+    /// Publishes the message with QoS 0.
+    pub async fn publish(self, client: &rumqttc::AsyncClient) -> Result<(), MqttError> {
+        client.publish(self.topic, rumqttc::QoS::AtMostOnce, self.retain, self.payload).await?;
+        Ok(())
+    }
+
+    /// Like [`Message::publish`], for the task polling the connection, which
+    /// can't wait for room in the request queue.
+    pub fn try_publish(self, client: &rumqttc::AsyncClient) -> Result<(), MqttError> {
+        client.try_publish(self.topic, rumqttc::QoS::AtMostOnce, self.retain, self.payload)?;
+        Ok(())
+    }
+}
+
+impl From<rumqttc::Publish> for Message {
+    fn from(publish: rumqttc::Publish) -> Self {
+        Self {
+            topic: publish.topic,
+            payload: publish.payload.to_vec(),
+            retain: publish.retain,
+        }
+    }
+}
+
+impl From<Message> for rumqttc::LastWill {
+    fn from(message: Message) -> Self {
+        rumqttc::LastWill::new(message.topic, message.payload, rumqttc::QoS::AtMostOnce, message.retain)
+    }
+}
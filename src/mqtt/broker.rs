@@ -0,0 +1,513 @@
+//! The part of MQTT 3.1.1 the bridge needs, kept in memory: QoS 0
+//! publishing with retained messages, subscriptions with wildcards and a
+//! last will.
+
+use super::{Message, MqttError};
+use rumqttc::{AsyncClient, Event, MqttOptions, QoS};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+const PROTOCOL_LEVEL: u8 = 4;
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Connect {
+        client_id: String,
+        keep_alive: u16,
+        will: Option<Message>,
+    },
+    ConnAck {
+        code: u8,
+    },
+    /// Packet ids of QoS 1 messages from other clients are kept to acknowledge them.
+    Publish {
+        message: Message,
+        id: Option<u16>,
+    },
+    PubAck {
+        id: u16,
+    },
+    Subscribe {
+        id: u16,
+        filters: Vec<String>,
+    },
+    SubAck {
+        id: u16,
+        granted: usize,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+fn put_str(buffer: &mut Vec<u8>, value: &[u8]) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let header = match self {
+            Packet::Connect { client_id, keep_alive, will } => {
+                put_str(&mut body, b"MQTT");
+                body.push(PROTOCOL_LEVEL);
+                let mut flags = 0b0000_0010;
+                if let Some(will) = will {
+                    flags |= 0b0000_0100;
+                    if will.retain {
+                        flags |= 0b0010_0000;
+                    }
+                }
+                body.push(flags);
+                body.extend_from_slice(&keep_alive.to_be_bytes());
+                put_str(&mut body, client_id.as_bytes());
+                if let Some(will) = will {
+                    put_str(&mut body, will.topic.as_bytes());
+                    put_str(&mut body, &will.payload);
+                }
+                0x10
+            }
+            Packet::ConnAck { code } => {
+                body.extend_from_slice(&[0, *code]);
+                0x20
+            }
+            Packet::Publish { message, id } => {
+                put_str(&mut body, message.topic.as_bytes());
+                if let Some(id) = id {
+                    body.extend_from_slice(&id.to_be_bytes());
+                }
+                body.extend_from_slice(&message.payload);
+                0x30 | u8::from(message.retain) | if id.is_some() { 0b0010 } else { 0 }
+            }
+            Packet::PubAck { id } => {
+                body.extend_from_slice(&id.to_be_bytes());
+                0x40
+            }
+            Packet::Subscribe { id, filters } => {
+                body.extend_from_slice(&id.to_be_bytes());
+                for filter in filters {
+                    put_str(&mut body, filter.as_bytes());
+                    body.push(0);
+                }
+                0x82
+            }
+            Packet::SubAck { id, granted } => {
+                body.extend_from_slice(&id.to_be_bytes());
+                body.extend(std::iter::repeat_n(0, *granted));
+                0x90
+            }
+            Packet::PingReq => 0xC0,
+            Packet::PingResp => 0xD0,
+            Packet::Disconnect => 0xE0,
+        };
+        let mut packet = vec![header];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend(body);
+        packet
+    }
+
+    pub async fn read(reader: &mut (impl AsyncRead + Unpin)) -> Result<Packet, MqttError> {
+        let header = reader.read_u8().await?;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = reader.read_u8().await?;
+            len += usize::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 21 || len > MAX_REMAINING_LENGTH {
+                return Err(MqttError::Protocol("Remaining length too long".to_string()));
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await?;
+        Packet::decode(header, &body)
+    }
+
+    fn decode(header: u8, body: &[u8]) -> Result<Packet, MqttError> {
+        let mut body = Body(body);
+        let packet = match header >> 4 {
+            1 => {
+                if body.string()? != "MQTT" || body.u8()? != PROTOCOL_LEVEL {
+                    return Err(MqttError::Protocol("Unsupported protocol".to_string()));
+                }
+                let flags = body.u8()?;
+                let keep_alive = body.u16()?;
+                let client_id = body.string()?;
+                let will = if flags & 0b0000_0100 != 0 {
+                    Some(Message {
+                        topic: body.string()?,
+                        payload: body.bytes()?.to_vec(),
+                        retain: flags & 0b0010_0000 != 0,
+                    })
+                } else {
+                    None
+                };
+                Packet::Connect { client_id, keep_alive, will }
+            }
+            2 => {
+                body.u8()?;
+                Packet::ConnAck { code: body.u8()? }
+            }
+            3 => {
+                let topic = body.string()?;
+                let id = if header & 0b0110 != 0 { Some(body.u16()?) } else { None };
+                let message = Message {
+                    topic,
+                    payload: body.0.to_vec(),
+                    retain: header & 1 != 0,
+                };
+                Packet::Publish { message, id }
+            }
+            4 => Packet::PubAck { id: body.u16()? },
+            8 => {
+                let id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.0.is_empty() {
+                    filters.push(body.string()?);
+                    body.u8()?;
+                }
+                Packet::Subscribe { id, filters }
+            }
+            9 => Packet::SubAck {
+                id: body.u16()?,
+                granted: body.0.len(),
+            },
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            kind => return Err(MqttError::Protocol(format!("Unsupported packet type {}", kind))),
+        };
+        Ok(packet)
+    }
+}
+
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MqttError> {
+        if self.0.len() < len {
+            return Err(MqttError::Protocol("Packet too short".to_string()));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, MqttError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], MqttError> {
+        let len = self.u16()?;
+        self.take(usize::from(len))
+    }
+
+    fn string(&mut self) -> Result<String, MqttError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| MqttError::Protocol("Invalid UTF-8".to_string()))
+    }
+}
+
+/// Whether `topic` matches a subscription `filter` with `+` and `#` wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    retained: BTreeMap<String, Message>,
+    sessions: HashMap<u64, (Vec<String>, UnboundedSender<Packet>)>,
+    next_session: u64,
+    /// Reading and writing tasks of every connection, cut with the broker.
+    connections: Vec<JoinHandle<()>>,
+}
+
+impl BrokerState {
+    fn publish(&mut self, message: Message) {
+        if message.retain {
+            if message.payload.is_empty() {
+                self.retained.remove(&message.topic);
+            } else {
+                self.retained.insert(message.topic.clone(), message.clone());
+            }
+        }
+        let live = Message {
+            retain: false,
+            ..message
+        };
+        for (filters, sender) in self.sessions.values() {
+            if filters.iter().any(|filter| topic_matches(filter, &live.topic)) {
+                let _ = sender.send(Packet::Publish {
+                    message: live.clone(),
+                    id: None,
+                });
+            }
+        }
+    }
+}
+
+/// A client of the broker. Received messages arrive on `messages`, and
+/// dropping it cuts the connection without a disconnect.
+#[derive(Debug)]
+pub struct TestClient {
+    pub client: AsyncClient,
+    pub messages: UnboundedReceiver<Message>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl TestClient {
+    pub async fn publish(&self, message: Message) {
+        message.publish(&self.client).await.unwrap();
+    }
+
+    pub async fn subscribe(&self, filter: &str) {
+        self.client.subscribe(filter, QoS::AtMostOnce).await.unwrap();
+    }
+}
+
+/// A broker that keeps everything in memory and delivers with QoS 0.
+#[derive(Debug)]
+pub struct Broker {
+    address: SocketAddr,
+    state: Arc<Mutex<BrokerState>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.state().connections.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl Broker {
+    pub async fn bind(address: impl ToSocketAddrs) -> io::Result<Broker> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(BrokerState::default()));
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            let connection = tokio::spawn(serve(stream, state.clone()));
+                            lock(&state).connections.push(connection);
+                        }
+                        Err(err) => log::warn!("Broker accept failed: {}", err),
+                    }
+                }
+            }
+        });
+        Ok(Broker { address, state, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Options to connect `client_id` to the broker.
+    pub fn options(&self, client_id: &str) -> MqttOptions {
+        MqttOptions::new(client_id, self.address.ip().to_string(), self.address.port())
+    }
+
+    pub fn connect(&self, options: MqttOptions) -> TestClient {
+        let (client, mut connection) = AsyncClient::new(options, 16);
+        let (sender, messages) = unbounded_channel();
+        let task = tokio::spawn(async move {
+            loop {
+                match connection.poll().await {
+                    Ok(Event::Incoming(rumqttc::Packet::Publish(publish))) => {
+                        let _ = sender.send(Message::from(publish));
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::warn!("Test client lost the broker: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+        TestClient { client, messages, task }
+    }
+
+    /// The retained message of `topic`, if any.
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.state().retained.get(topic).cloned()
+    }
+
+    fn state(&self) -> MutexGuard<'_, BrokerState> {
+        lock(&self.state)
+    }
+}
+
+fn lock(state: &Mutex<BrokerState>) -> MutexGuard<'_, BrokerState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<BrokerState>>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let will = match Packet::read(&mut reader).await {
+        Ok(Packet::Connect { will, .. }) => will,
+        _ => return,
+    };
+    let (sender, mut outgoing) = unbounded_channel::<Packet>();
+    let _ = sender.send(Packet::ConnAck { code: 0 });
+    let writing = tokio::spawn(async move {
+        while let Some(packet) = outgoing.recv().await {
+            if writer.write_all(&packet.encode()).await.is_err() {
+                break;
+            }
+        }
+    });
+    lock(&state).connections.push(writing);
+    let session = {
+        let mut state = lock(&state);
+        state.next_session += 1;
+        let session = state.next_session;
+        state.sessions.insert(session, (Vec::new(), sender.clone()));
+        session
+    };
+
+    let mut clean = false;
+    while let Ok(packet) = Packet::read(&mut reader).await {
+        match packet {
+            Packet::Publish { message, id } => {
+                if let Some(id) = id {
+                    let _ = sender.send(Packet::PubAck { id });
+                }
+                lock(&state).publish(message);
+            }
+            Packet::Subscribe { id, filters } => {
+                let mut state = lock(&state);
+                let _ = sender.send(Packet::SubAck { id, granted: filters.len() });
+                for message in state.retained.values() {
+                    if filters.iter().any(|filter| topic_matches(filter, &message.topic)) {
+                        let _ = sender.send(Packet::Publish {
+                            message: message.clone(),
+                            id: None,
+                        });
+                    }
+                }
+                if let Some((subscribed, _)) = state.sessions.get_mut(&session) {
+                    subscribed.extend(filters);
+                }
+            }
+            Packet::PingReq => {
+                let _ = sender.send(Packet::PingResp);
+            }
+            Packet::Disconnect => {
+                clean = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let mut state = lock(&state);
+    state.sessions.remove(&session);
+    if let (false, Some(will)) = (clean, will) {
+        state.publish(will);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn packets_round_trip() {
+        let packets = [
+            Packet::Connect {
+                client_id: "bridge".to_string(),
+                keep_alive: 30,
+                will: Some(Message::new("house/availability", "offline", true)),
+            },
+            Packet::Publish {
+                message: Message::new("house/state", vec![b'x'; 200], true),
+                id: None,
+            },
+            Packet::Subscribe {
+                id: 7,
+                filters: vec!["house/+/set".to_string(), "#".to_string()],
+            },
+            Packet::SubAck { id: 7, granted: 2 },
+            Packet::Disconnect,
+        ];
+        for packet in packets {
+            let encoded = packet.encode();
+            assert_eq!(Packet::read(&mut encoded.as_slice()).await.unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+    }
+
+    #[tokio::test]
+    async fn retained_messages_and_last_will() {
+        let broker = Broker::bind("127.0.0.1:0").await.unwrap();
+        let mut options = broker.options("device");
+        options.set_last_will(Message::new("device/availability", "offline", true).into());
+        let device = broker.connect(options);
+        device.publish(Message::new("device/availability", "online", true)).await;
+        device.publish(Message::new("device/state", "on", true)).await;
+        while broker.retained("device/state").is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        let mut watcher = broker.connect(broker.options("watcher"));
+        watcher.subscribe("device/#").await;
+        let mut retained = [watcher.messages.recv().await.unwrap(), watcher.messages.recv().await.unwrap()];
+        retained.sort_by(|a, b| a.topic.cmp(&b.topic));
+        assert_eq!(retained[0], Message::new("device/availability", "online", true));
+        assert_eq!(retained[1], Message::new("device/state", "on", true));
+
+        drop(device);
+        assert_eq!(watcher.messages.recv().await.unwrap(), Message::new("device/availability", "offline", false));
+        assert_eq!(broker.retained("device/availability").unwrap().payload, b"offline");
+    }
+}
@@ -0,0 +1,152 @@
+use crate::smarthouse::SmartHouse;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, PoisonError, RwLock};
+use thiserror::Error;
+use tokio::sync::RwLock as HouseLock;
+use utoipa::ToSchema;
+
+/// The house served without a `/houses/{house_id}` prefix.
+pub const DEFAULT_HOUSE: &str = "default";
+
+/// Readers share the house, commands take it exclusively. Commands can't
+/// lock single rooms instead: every change is appended to the one ordered
+/// event log whose sequence is the revision clients send back in `If-Match`.
+/// Control commands to devices take turns per device instead, see
+/// [`crate::actor::DeviceHandle::take_turn`], and only lock the house to
+/// record the reply.
+pub type SharedHouse = Arc<HouseLock<SmartHouse>>;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum RegistryError {
+    #[error("House not found: {0}")]
+    HouseNotFound(String),
+    #[error("House already exists: {0}")]
+    HouseAlreadyExists(String),
+    #[error("Invalid house id: {0}")]
+    InvalidHouseId(String),
+    #[error("The default house can't be removed")]
+    DefaultHouse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HouseInfo {
+    pub house_id: String,
+    pub house_name: String,
+    pub revision: u64,
+    pub rooms: usize,
+}
+
+impl HouseInfo {
+    pub fn new(house_id: &str, house: &SmartHouse) -> Self {
+        Self {
+            house_id: house_id.to_string(),
+            house_name: house.house_name().to_string(),
+            revision: house.revision(),
+            rooms: house.get_rooms_list().len(),
+        }
+    }
+}
+
+/// Houses served by one process. Every house has a lock of its own, the
+/// registry itself is only locked to look houses up, add or remove them.
+#[derive(Debug, Default)]
+pub struct HouseRegistry {
+    houses: RwLock<BTreeMap<String, SharedHouse>>,
+}
+
+impl HouseRegistry {
+    /// A registry holding `house` as the default one.
+    pub fn new(house: SmartHouse) -> Self {
+        let houses = BTreeMap::from([(DEFAULT_HOUSE.to_string(), Arc::new(HouseLock::new(house)))]);
+        Self {
+            houses: RwLock::new(houses),
+        }
+    }
+
+    /// Ids are used as path segments, so only letters, digits, `-` and `_` are allowed.
+    pub fn validate_id(house_id: &str) -> Result<(), RegistryError> {
+        let valid = house_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if house_id.is_empty() || !valid {
+            return Err(RegistryError::InvalidHouseId(house_id.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn create(&self, house_id: &str, house: SmartHouse) -> Result<SharedHouse, RegistryError> {
+        Self::validate_id(house_id)?;
+        let mut houses = self.houses.write().unwrap_or_else(PoisonError::into_inner);
+        if houses.contains_key(house_id) {
+            return Err(RegistryError::HouseAlreadyExists(house_id.to_string()));
+        }
+        let house = Arc::new(HouseLock::new(house));
+        houses.insert(house_id.to_string(), house.clone());
+        Ok(house)
+    }
+
+    pub fn get(&self, house_id: &str) -> Result<SharedHouse, RegistryError> {
+        self.houses
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(house_id)
+            .cloned()
+            .ok_or_else(|| RegistryError::HouseNotFound(house_id.to_string()))
+    }
+
+    /// Requests that already hold the house finish on it.
+    pub fn remove(&self, house_id: &str) -> Result<SharedHouse, RegistryError> {
+        if house_id == DEFAULT_HOUSE {
+            return Err(RegistryError::DefaultHouse);
+        }
+        self.houses
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(house_id)
+            .ok_or_else(|| RegistryError::HouseNotFound(house_id.to_string()))
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.houses.read().unwrap_or_else(PoisonError::into_inner).keys().cloned().collect()
+    }
+
+    pub fn houses(&self) -> Vec<(String, SharedHouse)> {
+        self.houses
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(house_id, house)| (house_id.clone(), house.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn houses_are_isolated() {
+        let registry = HouseRegistry::new(SmartHouse::new("Home".to_string()));
+        let flat = registry.create("flat-1", SmartHouse::new("Flat".to_string())).unwrap();
+        assert!(matches!(
+            registry.create("flat-1", SmartHouse::new("Flat".to_string())),
+            Err(RegistryError::HouseAlreadyExists(_))
+        ));
+        assert!(registry.create("flat/2", SmartHouse::new("Flat".to_string())).is_err());
+
+        // A busy house doesn't hold the others up
+        let busy = flat.write().await;
+        let default = registry.get(DEFAULT_HOUSE).unwrap();
+        assert_eq!(default.try_read().unwrap().house_name(), "Home");
+        drop(busy);
+        // Readers share a house, commands wait for them
+        let reader = default.read().await;
+        assert!(default.try_read().is_ok());
+        assert!(default.try_write().is_err());
+        drop(reader);
+
+        assert_eq!(registry.ids(), vec!["default", "flat-1"]);
+        assert!(matches!(registry.remove(DEFAULT_HOUSE), Err(RegistryError::DefaultHouse)));
+        registry.remove("flat-1").unwrap();
+        assert!(matches!(registry.get("flat-1"), Err(RegistryError::HouseNotFound(_))));
+    }
+}
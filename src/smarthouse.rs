@@ -1,9 +1,21 @@
 use thiserror::Error;
+use crate::actor::*;
+use crate::area::*;
+use crate::availability::*;
+use crate::batch::*;
 use crate::devices::*;
+use crate::energy::*;
+use crate::events::*;
+use crate::group::*;
 use crate::smartroom::*;
-use std::collections::HashMap;
+use crate::subscription::*;
+use crate::tariff::*;
+use crate::transition::Fades;
+use crate::undo::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
 use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
 
 #[derive(Debug, Error, Serialize, Deserialize)]
 pub enum SmartHouseError {
@@ -11,11 +23,71 @@ pub enum SmartHouseError {
     AddRoomError(String),
     #[error("Error while removing smart room")]
     RemoveRoomError(String),
+    #[error("Room not found: {0}")]
+    RoomNotFound(String),
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Room already exists: {0}")]
+    RoomAlreadyExists(String),
+    #[error("Device already exists: {0}")]
+    DeviceAlreadyExists(String),
+    #[error("Nothing to undo")]
+    NothingToUndo,
+    #[error("Nothing to redo")]
+    NothingToRedo,
+    #[error("Later edits conflict with: {0}")]
+    UndoConflict(String),
+    #[error("History before {0} was compacted")]
+    HistoryCompacted(u64),
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+    #[error("No tariff is set")]
+    TariffNotSet,
+    #[error("Group not found: {0}")]
+    GroupNotFound(String),
+    #[error("Area not found: {0}")]
+    AreaNotFound(String),
+    #[error("Area still has rooms: {0}")]
+    AreaNotEmpty(String),
+    #[error("{0}")]
+    InvalidArea(#[from] AreaError),
+    #[error("{0}")]
+    InvalidTariff(#[from] TariffError),
+    #[error("{0}")]
+    InvalidDevice(#[from] DeviceError),
+    #[error("{0}")]
+    DeviceUnreachable(#[from] ActorError),
 }
-#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+#[derive(Debug, Clone, Error, Serialize, Deserialize, ToSchema)]
 pub struct SmartHouse {
     house_name: String,
-    pub smart_rooms: HashMap<String, SmartRoom>,
+    smart_rooms: HashMap<String, SmartRoom>,
+    #[serde(default)]
+    revision: u64,
+    #[serde(flatten)]
+    settings: HouseSettings,
+    #[serde(default)]
+    history: EventLog,
+    #[serde(skip)]
+    undo: UndoStack,
+    #[serde(skip)]
+    subscribers: Subscribers,
+    #[serde(skip)]
+    actors: DeviceActors,
+    #[serde(skip)]
+    fades: Fades,
+}
+
+/// The house as clients read it: rooms, settings and revision without the
+/// history, which has endpoints of its own and only grows. Serializing the
+/// whole [`SmartHouse`] stores it along with its history.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HouseView<'a> {
+    pub house_name: &'a str,
+    pub smart_rooms: &'a HashMap<String, SmartRoom>,
+    pub revision: u64,
+    #[serde(flatten)]
+    pub settings: &'a HouseSettings,
 }
 
 impl Display for SmartHouse {
@@ -26,12 +98,239 @@ impl Display for SmartHouse {
 
 impl SmartHouse {
     pub fn new(house_name: String) -> SmartHouse {
+        Self::with_snapshot_interval(house_name, DEFAULT_SNAPSHOT_INTERVAL)
+    }
+
+    pub fn with_snapshot_interval(house_name: String, snapshot_interval: usize) -> SmartHouse {
         SmartHouse {
             house_name,
             smart_rooms: HashMap::new(),
+            revision: 0,
+            settings: HouseSettings::default(),
+            history: EventLog::new(snapshot_interval),
+            undo: UndoStack::default(),
+            subscribers: Subscribers::default(),
+            actors: DeviceActors::default(),
+            fades: Fades::default(),
+        }
+    }
+
+    /// Rebuilds the house from a stored log, starting from its latest snapshot.
+    pub fn from_log(house_name: String, history: EventLog) -> Result<SmartHouse, SmartHouseError> {
+        let mut house = SmartHouse::new(house_name);
+        (house.smart_rooms, house.settings, house.revision) = history_state(&history, u64::MAX)?;
+        house.history = history;
+        Ok(house)
+    }
+
+    pub fn house_name(&self) -> &str {
+        &self.house_name
+    }
+
+    /// Increases with every committed event.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn history(&self) -> &EventLog {
+        &self.history
+    }
+
+    pub fn view(&self) -> HouseView<'_> {
+        HouseView {
+            house_name: &self.house_name,
+            smart_rooms: &self.smart_rooms,
+            revision: self.revision,
+            settings: &self.settings,
         }
     }
 
+    /// Keeps the latest `snapshots` snapshots of the history and the events
+    /// after the oldest of them. Time travel ends at that snapshot.
+    pub fn set_history_retention(&mut self, snapshots: usize) {
+        self.history.set_snapshots_kept(snapshots);
+    }
+
+    /// Rooms by name. They only change through committed events.
+    pub fn smart_rooms(&self) -> &HashMap<String, SmartRoom> {
+        &self.smart_rooms
+    }
+
+    /// The house as it was at `timestamp` (milliseconds since the unix epoch).
+    pub fn as_of(&self, timestamp: u64) -> Result<SmartHouse, SmartHouseError> {
+        let mut house = SmartHouse::new(self.house_name.clone());
+        (house.smart_rooms, house.settings, house.revision) = history_state(&self.history, timestamp)?;
+        Ok(house)
+    }
+
+    pub fn commit(&mut self, event: HouseEvent) -> Result<(), SmartHouseError> {
+        self.commit_at(event, now_millis())
+    }
+
+    /// Applies `event` to the current state and appends it to the history.
+    /// Nothing is recorded if the event can't be applied.
+    pub fn commit_at(&mut self, event: HouseEvent, timestamp: u64) -> Result<(), SmartHouseError> {
+        if let HouseEvent::AvailabilityChanged { room_name, device_name, availability } = &event {
+            device_mut(&mut self.smart_rooms, room_name, device_name)?;
+            if self.smart_rooms[room_name].availability(device_name) == *availability {
+                return Ok(());
+            }
+        }
+        let reporter = event.reporter().map(|(room_name, device_name)| (room_name.to_string(), device_name.to_string()));
+        let alarms = self.watched_alarms();
+        let after = self.history.last_sequence();
+        let revision = after + 1;
+        apply_event(&mut self.smart_rooms, &mut self.settings, &event, revision, timestamp)?;
+        self.actors.follow(&event);
+        self.history.append(event, timestamp, &self.smart_rooms, &self.settings);
+        self.revision = revision;
+        if !self.subscribers.is_empty() {
+            let records = self.history.records_after(after).to_vec();
+            self.notify(&records, &alarms);
+        }
+        match reporter {
+            Some((room_name, device_name)) => self.seen(&room_name, &device_name, timestamp),
+            None => Ok(()),
+        }
+    }
+
+    /// Notes that the device was heard from at `timestamp`. Only brings it
+    /// online through an event, `last_seen` itself stays out of the log.
+    fn seen(&mut self, room_name: &str, device_name: &str, timestamp: u64) -> Result<(), SmartHouseError> {
+        device_mut(&mut self.smart_rooms, room_name, device_name)?;
+        room_mut(&mut self.smart_rooms, room_name)?.heartbeat_mut(device_name)?.seen(timestamp);
+        self.commit_at(
+            HouseEvent::AvailabilityChanged {
+                room_name: room_name.to_string(),
+                device_name: device_name.to_string(),
+                availability: Availability::Online,
+            },
+            timestamp,
+        )
+    }
+
+    /// Gets every later event matching `filter` and every alarm, until the
+    /// receiver falls too far behind.
+    pub fn subscribe(&mut self, filter: EventFilter) -> tokio::sync::mpsc::Receiver<Notification> {
+        self.subscribers.subscribe(filter)
+    }
+
+    /// Current alarms, only worth computing when somebody listens.
+    fn watched_alarms(&self) -> Vec<Alarm> {
+        if self.subscribers.is_empty() {
+            Vec::new()
+        } else {
+            self.alarms()
+        }
+    }
+
+    /// Publishes `records` and the alarms that are new compared to `alarms`.
+    fn notify(&mut self, records: &[EventRecord], alarms: &[Alarm]) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        for alarm in self.alarms() {
+            if !alarms.contains(&alarm) {
+                self.subscribers.publish_alarm(&alarm);
+            }
+        }
+        for record in records {
+            self.subscribers.publish_event(record);
+        }
+    }
+
+    /// Commits an edit that can be reverted with [`SmartHouse::undo`].
+    fn edit(&mut self, event: HouseEvent) -> Result<(), SmartHouseError> {
+        self.edit_at(event, now_millis())
+    }
+
+    fn edit_at(&mut self, event: HouseEvent, timestamp: u64) -> Result<(), SmartHouseError> {
+        let edit = Edit::prepare(&self.smart_rooms, &self.settings, &event);
+        self.commit_at(event, timestamp)?;
+        if let Some(mut edit) = edit {
+            edit.after = topology(&self.smart_rooms, &edit.rooms);
+            self.undo.push(edit);
+        }
+        Ok(())
+    }
+
+    /// Applies all operations or none of them. `apply` runs every operation
+    /// against a staged copy of the rooms, through the same methods and
+    /// checks as a single change. Only when all of them succeed are their
+    /// events committed to the house, where each applied edit can still be
+    /// undone separately.
+    pub fn transaction<T>(
+        &mut self,
+        operations: Vec<T>,
+        mut apply: impl FnMut(&mut SmartHouse, T) -> Result<(), SmartHouseError>,
+    ) -> BatchOutcome {
+        let len = operations.len();
+        let mut staged = self.staged();
+        for (index, operation) in operations.into_iter().enumerate() {
+            if let Err(err) = apply(&mut staged, operation) {
+                return BatchOutcome::rolled_back(len, index, err.to_string());
+            }
+        }
+        for record in staged.history.records() {
+            // Applied to the very same rooms already, so it can't fail
+            self.edit_at(record.event.clone(), record.timestamp)
+                .expect("staged event applies to the house");
+        }
+        BatchOutcome::committed(len)
+    }
+
+    /// The current rooms with an empty history that never takes snapshots.
+    fn staged(&self) -> SmartHouse {
+        SmartHouse {
+            house_name: self.house_name.clone(),
+            smart_rooms: self.smart_rooms.clone(),
+            revision: self.revision,
+            settings: self.settings.clone(),
+            history: EventLog::new(usize::MAX),
+            undo: UndoStack::default(),
+            subscribers: Subscribers::default(),
+            actors: DeviceActors::default(),
+            fades: Fades::default(),
+        }
+    }
+
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.undo.set_depth(depth);
+    }
+
+    /// Reverts the latest edit and returns it. Refuses when the
+    /// affected rooms were changed afterwards by events committed directly.
+    pub fn undo(&mut self) -> Result<HouseEvent, SmartHouseError> {
+        let edit = self.undo.last_undo().ok_or(SmartHouseError::NothingToUndo)?;
+        if topology(&self.smart_rooms, &edit.rooms) != edit.after {
+            return Err(SmartHouseError::UndoConflict(edit.rooms.join(", ")));
+        }
+        let edit = self.undo.pop_undo().unwrap();
+        if let Err(err) = self.commit(edit.inverse.clone()) {
+            self.undo.push_undo(edit);
+            return Err(err);
+        }
+        let event = edit.event.clone();
+        self.undo.push_redo(edit);
+        Ok(event)
+    }
+
+    /// Applies the latest undone edit again and returns it.
+    pub fn redo(&mut self) -> Result<HouseEvent, SmartHouseError> {
+        let edit = self.undo.last_redo().ok_or(SmartHouseError::NothingToRedo)?;
+        if topology(&self.smart_rooms, &edit.rooms) != edit.before {
+            return Err(SmartHouseError::UndoConflict(edit.rooms.join(", ")));
+        }
+        let edit = self.undo.pop_redo().unwrap();
+        if let Err(err) = self.commit(edit.event.clone()) {
+            self.undo.push_redo(edit);
+            return Err(err);
+        }
+        let event = edit.event.clone();
+        self.undo.push_undo(edit);
+        Ok(event)
+    }
+
     pub fn get_rooms_list(&self) -> Vec<&SmartRoom> {
         self.smart_rooms.values().collect()
     }
@@ -40,6 +339,35 @@ impl SmartHouse {
         self.smart_rooms.get(room.room_name.as_str())
     }
 
+    pub fn get_room_by_name(&self, room_name: &str) -> Option<&SmartRoom> {
+        self.smart_rooms.get(room_name)
+    }
+
+    pub fn get_device(&self, room_name: &str, device_name: &str) -> Result<&Device, SmartHouseError> {
+        self.get_room_by_name(room_name)
+            .ok_or_else(|| SmartHouseError::RoomNotFound(room_name.to_string()))?
+            .smart_device
+            .get(device_name)
+            .ok_or_else(|| SmartHouseError::DeviceNotFound(device_name.to_string()))
+    }
+
+    /// Routes commands for the device through `handle`, see [`crate::actor::command`].
+    /// The handle follows the device when it's moved or its room renamed and
+    /// is dropped together with the device.
+    pub fn attach_actor(&mut self, room_name: &str, device_name: &str, handle: DeviceHandle) -> Result<(), SmartHouseError> {
+        self.get_device(room_name, device_name)?;
+        self.actors.insert(room_name, device_name, handle);
+        Ok(())
+    }
+
+    pub fn detach_actor(&mut self, room_name: &str, device_name: &str) -> Option<DeviceHandle> {
+        self.actors.remove(room_name, device_name)
+    }
+
+    pub fn actor(&self, room_name: &str, device_name: &str) -> Option<&DeviceHandle> {
+        self.actors.get(room_name, device_name)
+    }
+
     pub fn device_info(&self, room: &String) -> Option<Vec<&Device>> {
         match self.smart_rooms.get(room) {
             Some(room) => {
@@ -52,34 +380,1072 @@ impl SmartHouse {
 
     pub fn add_smart_room(&mut self, room: &SmartRoom) -> Result<(), SmartHouseError> {
         if let Ok(_room_name) = room.get_room_name() {
-        self.smart_rooms.insert(room.get_room_name().unwrap(), room.clone());
-        Ok(())
+            let mut room = room.clone();
+            self.assign_ids(room.smart_device.values_mut())?;
+            self.edit(HouseEvent::RoomAdded(room))
         } else {
             Err(SmartHouseError::AddRoomError("Invalid room name".to_string()))
         }
     }
 
     pub fn remove_smart_room(&mut self, room: &SmartRoom) -> Result<(), SmartHouseError> {
-        if let Ok(_room_name) = room.get_room_name() {
-            self.smart_rooms.remove(&room.room_name);
-            Ok(())
+        if let Ok(room_name) = room.get_room_name() {
+            if !self.smart_rooms.contains_key(&room_name) {
+                return Ok(());
+            }
+            self.edit(HouseEvent::RoomRemoved { room_name })
         } else {
             Err(SmartHouseError::RemoveRoomError("There is no such room".to_string()))
         }
-        
+    }
+
+    pub fn add_device(&mut self, room_name: &str, mut device: Device) -> Result<(), SmartHouseError> {
+        self.assign_ids([&mut device])?;
+        self.edit(HouseEvent::DeviceAdded {
+            room_name: room_name.to_string(),
+            device,
+        })
+    }
+
+    /// Gives devices joining the house an id of their own, unless they
+    /// bring one that no other device has.
+    fn assign_ids<'a>(&self, devices: impl IntoIterator<Item = &'a mut Device>) -> Result<(), SmartHouseError> {
+        let mut taken: HashSet<String> = self
+            .smart_rooms
+            .values()
+            .flat_map(|room| room.smart_device.values())
+            .map(|device| device.id().to_string())
+            .collect();
+        for device in devices {
+            if device.id().is_empty() || !taken.insert(device.id().to_string()) {
+                let id = new_device_id()?;
+                taken.insert(id.clone());
+                device.set_id(id);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_device(&mut self, room_name: &str, device_name: &str) -> Result<(), SmartHouseError> {
+        self.edit(HouseEvent::DeviceRemoved {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+        })
+    }
+
+    pub fn move_device(&mut self, device_name: &str, from: &str, to: &str) -> Result<(), SmartHouseError> {
+        self.edit(HouseEvent::DeviceMoved {
+            from: from.to_string(),
+            to: to.to_string(),
+            device_name: device_name.to_string(),
+        })
+    }
+
+    pub fn rename_room(&mut self, room_name: &str, new_name: &str) -> Result<(), SmartHouseError> {
+        self.edit(HouseEvent::RoomRenamed {
+            room_name: room_name.to_string(),
+            new_name: new_name.to_string(),
+        })
+    }
+
+    pub fn switch_socket(&mut self, room_name: &str, device_name: &str, status: bool) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::SocketSwitched {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            status,
+        })
+    }
+
+    pub fn set_light(&mut self, room_name: &str, device_name: &str, state: LightState) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::LightChanged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            state,
+        })
+    }
+
+    /// Starts a fade of the light, see [`crate::transition::fade`], and
+    /// returns its ticket. A fade still running is cancelled, the state it
+    /// showed last is committed so the history matches the light.
+    pub fn start_fade(&mut self, room_name: &str, device_name: &str) -> Result<u64, SmartHouseError> {
+        let state = match self.get_device(room_name, device_name)? {
+            Device::SmartLight(light) => *light.state(),
+            _ => return Err(SmartHouseError::DeviceNotFound(format!("Light: {}", device_name))),
+        };
+        let (ticket, previewed) = self.fades.start(room_name, device_name);
+        if previewed {
+            self.set_light(room_name, device_name, state)?;
+        }
+        Ok(ticket)
+    }
+
+    /// Whether the fade with `ticket` is still the one of the light.
+    pub fn is_fading(&self, room_name: &str, device_name: &str, ticket: u64) -> bool {
+        self.fades.is_running(room_name, device_name, ticket)
+    }
+
+    pub fn end_fade(&mut self, room_name: &str, device_name: &str, ticket: u64) {
+        self.fades.end(room_name, device_name, ticket);
+    }
+
+    /// Shows an intermediate state of the fade with `ticket`. Unlike
+    /// [`SmartHouse::set_light`] nothing is recorded: no event, revision or
+    /// undo, until the fade ends or is cancelled.
+    pub fn preview_light(&mut self, room_name: &str, device_name: &str, ticket: u64, state: LightState) -> Result<(), SmartHouseError> {
+        if !self.fades.is_running(room_name, device_name, ticket) {
+            return Ok(());
+        }
+        match device_mut(&mut self.smart_rooms, room_name, device_name)? {
+            Device::SmartLight(light) => light.set_state(state)?,
+            _ => return Err(SmartHouseError::DeviceNotFound(format!("Light: {}", device_name))),
+        }
+        self.fades.previewed(room_name, device_name, ticket);
+        Ok(())
+    }
+
+    pub fn set_sensor(&mut self, room_name: &str, device_name: &str, active: bool) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::BinarySensorChanged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            active,
+        })
+    }
+
+    pub fn set_occupancy_timeout(&mut self, room_name: &str, timeout_ms: u64) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::OccupancyTimeoutSet {
+            room_name: room_name.to_string(),
+            timeout_ms,
+        })
+    }
+
+    pub fn configure_thermostat(
+        &mut self,
+        room_name: &str,
+        device_name: &str,
+        settings: ThermostatSettings,
+    ) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::ThermostatConfigured {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            settings,
+        })
+    }
+
+    pub fn record_metric(&mut self, room_name: &str, device_name: &str, metric: Metric, value: f32) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::MetricRecorded {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            metric,
+            value,
+        })
+    }
+
+    pub fn get_lock(&self, room_name: &str, device_name: &str) -> Result<&SmartLock, SmartHouseError> {
+        let room = self
+            .get_room_by_name(room_name)
+            .ok_or_else(|| SmartHouseError::RoomNotFound(room_name.to_string()))?;
+        match room.smart_device.get(device_name) {
+            Some(Device::SmartLock(lock)) => Ok(lock),
+            _ => Err(SmartHouseError::DeviceNotFound(format!("Lock: {}", device_name))),
+        }
+    }
+
+    pub fn set_lock(&mut self, room_name: &str, device_name: &str, locked: bool, method: AccessMethod) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::LockChanged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            locked,
+            method,
+        })
+    }
+
+    /// Operates the lock with a guest code. Only the code label is recorded.
+    /// A wrong PIN is recorded as well and too many of them lock out PINs
+    /// for a while, see [`SmartLock::reject_pin`].
+    pub fn set_lock_with_pin(&mut self, room_name: &str, device_name: &str, locked: bool, pin: &str) -> Result<(), SmartHouseError> {
+        let now = now_millis();
+        let lock = self.get_lock(room_name, device_name)?;
+        if let Some(until) = lock.locked_out_until(now) {
+            return Err(SmartHouseError::AccessDenied(format!("{} takes no PINs until {}", device_name, until)));
+        }
+        let Some(label) = lock.check_pin(pin, now).map(str::to_string) else {
+            self.commit_at(
+                HouseEvent::PinRejected {
+                    room_name: room_name.to_string(),
+                    device_name: device_name.to_string(),
+                    locked,
+                },
+                now,
+            )?;
+            return Err(SmartHouseError::AccessDenied(device_name.to_string()));
+        };
+        self.commit_at(
+            HouseEvent::LockChanged {
+                room_name: room_name.to_string(),
+                device_name: device_name.to_string(),
+                locked,
+                method: AccessMethod::Pin(label),
+            },
+            now,
+        )
+    }
+
+    pub fn report_lock(&mut self, room_name: &str, device_name: &str, jammed: bool, battery: u8) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::LockReported {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            jammed,
+            battery,
+        })
+    }
+
+    pub fn add_access_code(
+        &mut self,
+        room_name: &str,
+        device_name: &str,
+        label: String,
+        pin: &str,
+        valid_from: u64,
+        valid_until: Option<u64>,
+    ) -> Result<(), SmartHouseError> {
+        let code = AccessCode::new(label, pin, valid_from, valid_until)?;
+        self.commit(HouseEvent::AccessCodeAdded {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            code,
+        })
+    }
+
+    pub fn remove_access_code(&mut self, room_name: &str, device_name: &str, label: &str) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::AccessCodeRemoved {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            label: label.to_string(),
+        })
+    }
+
+    /// Latched alarms of all safety detectors.
+    pub fn alarms(&self) -> Vec<Alarm> {
+        let mut alarms: Vec<Alarm> = self
+            .smart_rooms
+            .values()
+            .flat_map(|room| {
+                room.smart_device.values().filter_map(|device| match device {
+                    Device::SafetyDetector(detector) => detector.alarm_since().map(|since| Alarm {
+                        room_name: room.room_name.clone(),
+                        device_name: detector.name.clone(),
+                        hazard: detector.hazard(),
+                        since,
+                    }),
+                    _ => None,
+                })
+            })
+            .collect();
+        alarms.sort_by_key(|alarm| alarm.since);
+        alarms
+    }
+
+    pub fn alarm_state(&self) -> HouseAlarm {
+        let alarms = self.alarms();
+        HouseAlarm {
+            active: !alarms.is_empty(),
+            alarms,
+        }
+    }
+
+    pub fn report_detector(&mut self, room_name: &str, device_name: &str, detected: bool, battery: u8) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::DetectorReported {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            detected,
+            battery,
+        })
+    }
+
+    pub fn test_detector(&mut self, room_name: &str, device_name: &str, passed: bool) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::DetectorTested {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            passed,
+        })
+    }
+
+    pub fn acknowledge_alarm(&mut self, room_name: &str, device_name: &str) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::AlarmAcknowledged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+        })
+    }
+
+    pub fn get_cover(&self, room_name: &str, device_name: &str) -> Result<&Cover, SmartHouseError> {
+        let room = self
+            .get_room_by_name(room_name)
+            .ok_or_else(|| SmartHouseError::RoomNotFound(room_name.to_string()))?;
+        match room.smart_device.get(device_name) {
+            Some(Device::Cover(cover)) => Ok(cover),
+            _ => Err(SmartHouseError::DeviceNotFound(format!("Cover: {}", device_name))),
+        }
+    }
+
+    pub fn set_cover(&mut self, room_name: &str, device_name: &str, state: CoverState) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::CoverChanged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            state,
+        })
+    }
+
+    pub fn calibrate_cover(&mut self, room_name: &str, device_name: &str, travel_ms: u64) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::CoverCalibrated {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            travel_ms,
+        })
+    }
+
+    pub fn record_power(&mut self, room_name: &str, device_name: &str, watts: f32) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::PowerRecorded {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            watts,
+        })
+    }
+
+    pub fn record_meter(&mut self, room_name: &str, device_name: &str, reading: MeterReading) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::MeterRecorded {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            reading,
+        })
+    }
+
+    /// Consumption between the hour boundaries `from` and `to` as of `now`.
+    pub fn energy(&self, from: u64, to: u64, now: u64) -> EnergyReport {
+        let mut rooms = Vec::new();
+        let mut meters = Vec::new();
+        for room in self.smart_rooms.values() {
+            let mut devices = Vec::new();
+            for device in room.smart_device.values() {
+                let (kwh, list) = match device {
+                    Device::SmartSocket(socket) => (socket.energy(from, to, now), &mut devices),
+                    Device::EnergyMeter(meter) => (meter.energy(from, to, now), &mut meters),
+                    _ => continue,
+                };
+                list.push(DeviceEnergy {
+                    room_name: room.room_name.clone(),
+                    device_name: device.device_name().unwrap_or_default(),
+                    kwh,
+                });
+            }
+            devices.sort_by(|a, b| a.device_name.cmp(&b.device_name));
+            rooms.push(RoomEnergy {
+                room_name: room.room_name.clone(),
+                kwh: devices.iter().fold(0.0, |kwh, device| kwh + device.kwh),
+                devices,
+            });
+        }
+        meters.sort_by(|a, b| (&a.room_name, &a.device_name).cmp(&(&b.room_name, &b.device_name)));
+        EnergyReport::new(from, to, rooms, meters)
+    }
+
+    /// Consumption in the day, week or month containing `now`.
+    pub fn energy_in(&self, period: Period, now: u64) -> EnergyReport {
+        let (from, to) = period.bounds(now);
+        self.energy(from, to, now)
+    }
+
+    pub fn tariff(&self) -> Option<&Tariff> {
+        self.settings.tariff.as_ref()
+    }
+
+    /// Replaces the tariff, can be undone.
+    pub fn set_tariff(&mut self, tariff: Tariff) -> Result<(), SmartHouseError> {
+        self.edit(HouseEvent::TariffSet { tariff: Some(tariff) })
+    }
+
+    /// Cost of every socket between the hour boundaries `from` and `to`
+    /// as of `now`, with a projection for the month containing `now`.
+    pub fn costs(&self, from: u64, to: u64, now: u64) -> Result<CostReport, SmartHouseError> {
+        let tariff = self.tariff().ok_or(SmartHouseError::TariffNotSet)?;
+        let mut rooms = Vec::new();
+        for room in self.smart_rooms.values() {
+            let mut devices = Vec::new();
+            for device in room.smart_device.values() {
+                if let Device::SmartSocket(socket) = device {
+                    let usage = socket.usage(from, to, now);
+                    devices.push(DeviceCost {
+                        room_name: room.room_name.clone(),
+                        device_name: socket.name.clone(),
+                        kwh: usage.values().fold(0.0, |kwh, wh| kwh + wh / 1_000.0),
+                        cost: tariff.cost(&usage),
+                    });
+                }
+            }
+            devices.sort_by(|a, b| a.device_name.cmp(&b.device_name));
+            rooms.push(RoomCost {
+                room_name: room.room_name.clone(),
+                kwh: devices.iter().fold(0.0, |kwh, device| kwh + device.kwh),
+                cost: devices.iter().fold(0.0, |cost, device| cost + device.cost),
+                devices,
+            });
+        }
+        let (month_start, month_end) = Period::Month.bounds(now);
+        let month_to_date = self
+            .smart_rooms
+            .values()
+            .flat_map(|room| room.smart_device.values())
+            .filter_map(|device| match device {
+                Device::SmartSocket(socket) => Some(tariff.cost(&socket.usage(month_start, month_end, now))),
+                _ => None,
+            })
+            .fold(0.0, |cost, socket| cost + socket);
+        let projection = MonthProjection::new(month_to_date, month_start, month_end, now);
+        Ok(CostReport::new(from, to, tariff.currency.clone(), rooms, projection))
+    }
+
+    pub fn costs_in(&self, period: Period, now: u64) -> Result<CostReport, SmartHouseError> {
+        let (from, to) = period.bounds(now);
+        self.costs(from, to, now)
+    }
+
+    pub fn set_tags(&mut self, room_name: &str, device_name: &str, tags: BTreeSet<String>) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::DeviceTagged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            tags,
+        })
+    }
+
+    /// Devices of all rooms matching `query`, ordered by room and name.
+    pub fn find_devices(&self, query: &DeviceQuery) -> Vec<TaggedDevice> {
+        let mut devices: Vec<TaggedDevice> = self
+            .smart_rooms
+            .values()
+            .flat_map(|room| {
+                room.smart_device
+                    .iter()
+                    .filter(|(device_name, device)| query.matches(room, device_name, device))
+                    .map(|(device_name, device)| TaggedDevice {
+                        room_name: room.room_name.clone(),
+                        device_name: device_name.clone(),
+                        kind: device.kind(),
+                        tags: room.tags(device_name),
+                        device: device.clone(),
+                    })
+            })
+            .collect();
+        devices.sort_by(|a, b| (&a.room_name, &a.device_name).cmp(&(&b.room_name, &b.device_name)));
+        devices
+    }
+
+    pub fn groups(&self) -> Vec<&Group> {
+        self.settings.groups.values().collect()
+    }
+
+    pub fn group(&self, name: &str) -> Result<&Group, SmartHouseError> {
+        self.settings
+            .groups
+            .get(name)
+            .ok_or_else(|| SmartHouseError::GroupNotFound(name.to_string()))
+    }
+
+    /// Creates or replaces a group, can be undone.
+    pub fn set_group(&mut self, name: &str, query: DeviceQuery) -> Result<(), SmartHouseError> {
+        self.edit(HouseEvent::GroupSet {
+            group: Group {
+                name: name.to_string(),
+                query,
+            },
+        })
+    }
+
+    pub fn remove_group(&mut self, name: &str) -> Result<Group, SmartHouseError> {
+        let group = self.group(name)?.clone();
+        self.edit(HouseEvent::GroupRemoved { name: name.to_string() })?;
+        Ok(group)
+    }
+
+    pub fn group_members(&self, name: &str) -> Result<Vec<TaggedDevice>, SmartHouseError> {
+        Ok(self.find_devices(&self.group(name)?.query))
+    }
+
+    pub fn group_summary(&self, name: &str, now: u64) -> Result<GroupSummary, SmartHouseError> {
+        let members = self.group_members(name)?;
+        let (from, to) = Period::Day.bounds(now);
+        let energy = members
+            .iter()
+            .filter_map(|member| match &member.device {
+                Device::SmartSocket(socket) => Some(socket.energy(from, to, now)),
+                _ => None,
+            })
+            .fold(0.0, |kwh, socket| kwh + socket);
+        Ok(GroupSummary::new(name.to_string(), &members, energy))
+    }
+
+    /// Floors from the bottom up, then zones.
+    pub fn areas(&self) -> Vec<&Area> {
+        let mut areas: Vec<&Area> = self.settings.areas.values().collect();
+        areas.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        areas
+    }
+
+    pub fn area(&self, name: &str) -> Result<&Area, SmartHouseError> {
+        self.settings
+            .areas
+            .get(name)
+            .ok_or_else(|| SmartHouseError::AreaNotFound(name.to_string()))
+    }
+
+    /// Creates or replaces a floor or zone, can be undone.
+    pub fn set_area(&mut self, area: Area) -> Result<(), SmartHouseError> {
+        self.edit(HouseEvent::AreaSet { area })
+    }
+
+    /// Only empty areas can be removed.
+    pub fn remove_area(&mut self, name: &str) -> Result<Area, SmartHouseError> {
+        let area = self.area(name)?.clone();
+        self.edit(HouseEvent::AreaRemoved { name: name.to_string() })?;
+        Ok(area)
+    }
+
+    /// Puts the room on a floor or zone, or takes it off with `None`.
+    pub fn place_room(&mut self, room_name: &str, area: Option<&str>) -> Result<(), SmartHouseError> {
+        if let Some(area) = area {
+            self.area(area)?;
+        }
+        self.edit(HouseEvent::RoomPlaced {
+            room_name: room_name.to_string(),
+            area: area.map(str::to_string),
+        })
+    }
+
+    pub fn rooms_in(&self, area: &str) -> Result<Vec<&SmartRoom>, SmartHouseError> {
+        self.area(area)?;
+        let mut rooms: Vec<&SmartRoom> = self
+            .smart_rooms
+            .values()
+            .filter(|room| room.area.as_deref() == Some(area))
+            .collect();
+        rooms.sort_by(|a, b| a.room_name.cmp(&b.room_name));
+        Ok(rooms)
+    }
+
+    /// The room, as long as it is placed on `area`.
+    pub fn room_in(&self, area: &str, room_name: &str) -> Result<&SmartRoom, SmartHouseError> {
+        self.area(area)?;
+        self.get_room_by_name(room_name)
+            .filter(|room| room.area.as_deref() == Some(area))
+            .ok_or_else(|| SmartHouseError::RoomNotFound(format!("{} in {}", room_name, area)))
+    }
+
+    /// Rooms that aren't on any known floor or zone.
+    pub fn unplaced_rooms(&self) -> Vec<&SmartRoom> {
+        let mut rooms: Vec<&SmartRoom> = self
+            .smart_rooms
+            .values()
+            .filter(|room| room.area.as_ref().is_none_or(|area| !self.settings.areas.contains_key(area)))
+            .collect();
+        rooms.sort_by(|a, b| a.room_name.cmp(&b.room_name));
+        rooms
+    }
+
+    pub fn area_node(&self, name: &str, now: u64) -> Result<AreaNode, SmartHouseError> {
+        Ok(AreaNode::new(self.area(name)?.clone(), &self.rooms_in(name)?, now))
+    }
+
+    /// The house with its floors, zones and rooms, aggregated at every level.
+    pub fn tree(&self, now: u64) -> HouseTree {
+        let areas = self
+            .areas()
+            .into_iter()
+            .filter_map(|area| self.area_node(&area.name, now).ok())
+            .collect();
+        HouseTree {
+            house_name: self.house_name.clone(),
+            summary: LevelSummary::new(self.smart_rooms.values(), now),
+            areas,
+            unplaced: room_nodes(&self.unplaced_rooms(), now),
+        }
+    }
+
+    pub fn heartbeat(&mut self, room_name: &str, device_name: &str) -> Result<(), SmartHouseError> {
+        self.heartbeat_at(room_name, device_name, now_millis())
+    }
+
+    /// Records nothing unless the device comes back online.
+    pub fn heartbeat_at(&mut self, room_name: &str, device_name: &str, timestamp: u64) -> Result<(), SmartHouseError> {
+        self.seen(room_name, device_name, timestamp)
+    }
+
+    pub fn set_heartbeat_timeout(&mut self, room_name: &str, device_name: &str, timeout_ms: u64) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::HeartbeatTimeoutSet {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            timeout_ms,
+        })
+    }
+
+    /// Commits a change of availability, nothing if the device already has it.
+    pub fn set_availability(&mut self, room_name: &str, device_name: &str, availability: Availability) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::AvailabilityChanged {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            availability,
+        })
+    }
+
+    pub fn device_availability(&self, room_name: &str, device_name: &str) -> Result<DeviceAvailability, SmartHouseError> {
+        self.get_device(room_name, device_name)?;
+        Ok(DeviceAvailability {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            heartbeat: self.smart_rooms[room_name].heartbeat(device_name),
+        })
+    }
+
+    /// Availability of every device, ordered by room and name.
+    pub fn availability(&self) -> Vec<DeviceAvailability> {
+        let mut devices: Vec<DeviceAvailability> = self
+            .smart_rooms
+            .values()
+            .flat_map(|room| {
+                room.smart_device.keys().map(|device_name| DeviceAvailability {
+                    room_name: room.room_name.clone(),
+                    device_name: device_name.clone(),
+                    heartbeat: room.heartbeat(device_name),
+                })
+            })
+            .collect();
+        devices.sort_by(|a, b| (&a.room_name, &a.device_name).cmp(&(&b.room_name, &b.device_name)));
+        devices
+    }
+
+    /// Takes the devices that weren't heard from within their timeout
+    /// offline and returns them.
+    pub fn check_availability(&mut self, now: u64) -> Result<Vec<DeviceAvailability>, SmartHouseError> {
+        let expired: Vec<DeviceAvailability> = self
+            .availability()
+            .into_iter()
+            .filter(|device| device.heartbeat.expired(now))
+            .collect();
+        let mut offline = Vec::new();
+        for mut device in expired {
+            self.commit_at(
+                HouseEvent::AvailabilityChanged {
+                    room_name: device.room_name.clone(),
+                    device_name: device.device_name.clone(),
+                    availability: Availability::Offline,
+                },
+                now,
+            )?;
+            device.heartbeat.availability = Availability::Offline;
+            offline.push(device);
+        }
+        Ok(offline)
+    }
+
+    pub fn record_reading(&mut self, room_name: &str, device_name: &str, value: f32) -> Result<(), SmartHouseError> {
+        self.commit(HouseEvent::ReadingRecorded {
+            room_name: room_name.to_string(),
+            device_name: device_name.to_string(),
+            value,
+        })
     }
 
     pub fn create_report(&self, provider: impl DeviceInfoProvider) -> String {
         let mut report = String::new();
         report.push_str(&format!("{}", self));
-        for smart_rooms in self.get_rooms_list() {
-            report.push_str(&format!("{} contains:\n", smart_rooms));
-            for devices in smart_rooms.smart_device.values() {
-                report.push_str(&format!("{}\n", provider.device_info(smart_rooms, devices)));
+        let now = now_millis();
+        for area in self.areas() {
+            let rooms = self.rooms_in(&area.name).unwrap_or_default();
+            report.push_str(&format!("{}: {}\n", area, LevelSummary::new(rooms.iter().copied(), now)));
+            for room in rooms {
+                self.report_room(&mut report, room, &provider);
             }
         }
+        for room in self.unplaced_rooms() {
+            self.report_room(&mut report, room, &provider);
+        }
         report
     }
+
+    fn report_room(&self, report: &mut String, room: &SmartRoom, provider: &impl DeviceInfoProvider) {
+        report.push_str(&format!("{} contains:\n", room));
+        for (device_name, devices) in &room.smart_device {
+            report.push_str(&format!("{}\n", provider.device_info(room, devices)));
+            report.push_str(&format!("{} is {}\n", device_name, room.availability(device_name)));
+        }
+        for summary in room.environment() {
+            report.push_str(&format!("{}\n", summary));
+        }
+    }
+}
+
+/// Folds the history up to `timestamp` into the room map, the settings of
+/// the house and its revision.
+fn history_state(
+    history: &EventLog,
+    timestamp: u64,
+) -> Result<(HashMap<String, SmartRoom>, HouseSettings, u64), SmartHouseError> {
+    let (snapshot, records) = history
+        .replay_from(timestamp)
+        .ok_or(SmartHouseError::HistoryCompacted(timestamp))?;
+    let (mut smart_rooms, mut settings, mut revision) = snapshot
+        .map(|snapshot| (snapshot.smart_rooms.clone(), snapshot.settings.clone(), snapshot.sequence))
+        .unwrap_or_default();
+    for record in records {
+        apply_event(&mut smart_rooms, &mut settings, &record.event, record.sequence, record.timestamp)?;
+        revision = record.sequence;
+    }
+    Ok((smart_rooms, settings, revision))
+}
+
+/// Rooms whose revision is bumped by `event`.
+fn touched_rooms(event: &HouseEvent) -> Vec<&str> {
+    match event {
+        HouseEvent::RoomAdded(room) => vec![&room.room_name],
+        HouseEvent::RoomRemoved { .. }
+        | HouseEvent::TariffSet { .. }
+        | HouseEvent::GroupSet { .. }
+        | HouseEvent::GroupRemoved { .. }
+        | HouseEvent::AreaSet { .. }
+        | HouseEvent::AreaRemoved { .. } => Vec::new(),
+        HouseEvent::DeviceAdded { room_name, .. }
+        | HouseEvent::DeviceRemoved { room_name, .. }
+        | HouseEvent::DeviceRestored { room_name, .. }
+        | HouseEvent::SocketSwitched { room_name, .. }
+        | HouseEvent::LightChanged { room_name, .. }
+        | HouseEvent::BinarySensorChanged { room_name, .. }
+        | HouseEvent::OccupancyTimeoutSet { room_name, .. }
+        | HouseEvent::ThermostatConfigured { room_name, .. }
+        | HouseEvent::ThermostatDemandChanged { room_name, .. }
+        | HouseEvent::MetricRecorded { room_name, .. }
+        | HouseEvent::LockChanged { room_name, .. }
+        | HouseEvent::PinRejected { room_name, .. }
+        | HouseEvent::LockReported { room_name, .. }
+        | HouseEvent::AccessCodeAdded { room_name, .. }
+        | HouseEvent::AccessCodeRemoved { room_name, .. }
+        | HouseEvent::CoverChanged { room_name, .. }
+        | HouseEvent::CoverCalibrated { room_name, .. }
+        | HouseEvent::DetectorReported { room_name, .. }
+        | HouseEvent::DetectorTested { room_name, .. }
+        | HouseEvent::AlarmAcknowledged { room_name, .. }
+        | HouseEvent::PowerRecorded { room_name, .. }
+        | HouseEvent::MeterRecorded { room_name, .. }
+        | HouseEvent::ReadingRecorded { room_name, .. }
+        | HouseEvent::DeviceTagged { room_name, .. }
+        | HouseEvent::HeartbeatTimeoutSet { room_name, .. }
+        | HouseEvent::AvailabilityChanged { room_name, .. }
+        | HouseEvent::RoomPlaced { room_name, .. } => vec![room_name],
+        HouseEvent::DeviceMoved { from, to, .. } => vec![from, to],
+        HouseEvent::RoomRenamed { new_name, .. } => vec![new_name],
+    }
+}
+
+fn apply_event(
+    smart_rooms: &mut HashMap<String, SmartRoom>,
+    settings: &mut HouseSettings,
+    event: &HouseEvent,
+    revision: u64,
+    timestamp: u64,
+) -> Result<(), SmartHouseError> {
+    change_settings(settings, smart_rooms, event)?;
+    change_rooms(smart_rooms, event, timestamp)?;
+    for room_name in touched_rooms(event) {
+        if let Some(room) = smart_rooms.get_mut(room_name) {
+            room.revision = revision;
+        }
+    }
+    Ok(())
+}
+
+fn change_settings(
+    settings: &mut HouseSettings,
+    smart_rooms: &HashMap<String, SmartRoom>,
+    event: &HouseEvent,
+) -> Result<(), SmartHouseError> {
+    match event {
+        HouseEvent::TariffSet { tariff } => {
+            if let Some(tariff) = tariff {
+                tariff.validate()?;
+            }
+            settings.tariff = tariff.clone();
+        }
+        HouseEvent::GroupSet { group } => {
+            if group.name.trim().is_empty() {
+                return Err(DeviceError::InvalidValue("Empty group name".to_string()).into());
+            }
+            settings.groups.insert(group.name.clone(), group.clone());
+        }
+        HouseEvent::GroupRemoved { name } => {
+            settings
+                .groups
+                .remove(name)
+                .ok_or_else(|| SmartHouseError::GroupNotFound(name.to_string()))?;
+        }
+        HouseEvent::AreaSet { area } => {
+            area.validate()?;
+            settings.areas.insert(area.name.clone(), area.clone());
+        }
+        HouseEvent::AreaRemoved { name } => {
+            if !settings.areas.contains_key(name) {
+                return Err(SmartHouseError::AreaNotFound(name.to_string()));
+            }
+            if smart_rooms.values().any(|room| room.area.as_ref() == Some(name)) {
+                return Err(SmartHouseError::AreaNotEmpty(name.to_string()));
+            }
+            settings.areas.remove(name);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn change_rooms(smart_rooms: &mut HashMap<String, SmartRoom>, event: &HouseEvent, timestamp: u64) -> Result<(), SmartHouseError> {
+    match event {
+        HouseEvent::RoomAdded(room) => {
+            if room.room_name.is_empty() {
+                return Err(SmartHouseError::AddRoomError("Invalid room name".to_string()));
+            }
+            smart_rooms.insert(room.room_name.clone(), room.clone());
+        }
+        HouseEvent::RoomRemoved { room_name } => {
+            smart_rooms.remove(room_name);
+        }
+        HouseEvent::DeviceAdded { room_name, device } => {
+            room_mut(smart_rooms, room_name)?
+                .add_smart_device(device.clone())
+                .map_err(|err| SmartHouseError::AddRoomError(err.to_string()))?;
+        }
+        HouseEvent::DeviceRemoved { room_name, device_name } => {
+            let room = room_mut(smart_rooms, room_name)?;
+            room.smart_device
+                .remove(device_name)
+                .ok_or_else(|| SmartHouseError::DeviceNotFound(device_name.clone()))?;
+            room.device_tags.remove(device_name);
+            room.heartbeats.remove(device_name);
+        }
+        HouseEvent::DeviceRestored { room_name, device, tags, heartbeat } => {
+            let device_name = device.device_name()?;
+            let room = room_mut(smart_rooms, room_name)?;
+            room.add_smart_device(device.clone())
+                .map_err(|err| SmartHouseError::AddRoomError(err.to_string()))?;
+            if !tags.is_empty() {
+                room.device_tags.insert(device_name.clone(), tags.clone());
+            }
+            if let Some(heartbeat) = heartbeat {
+                room.heartbeats.insert(device_name, heartbeat.clone());
+            }
+        }
+        HouseEvent::DeviceMoved { from, to, device_name } => {
+            if room_mut(smart_rooms, to)?.smart_device.contains_key(device_name) {
+                return Err(SmartHouseError::DeviceAlreadyExists(device_name.clone()));
+            }
+            let room = room_mut(smart_rooms, from)?;
+            let device = room
+                .smart_device
+                .remove(device_name)
+                .ok_or_else(|| SmartHouseError::DeviceNotFound(device_name.clone()))?;
+            let tags = room.device_tags.remove(device_name);
+            let heartbeat = room.heartbeats.remove(device_name);
+            let room = room_mut(smart_rooms, to)?;
+            room.smart_device.insert(device_name.clone(), device);
+            if let Some(tags) = tags {
+                room.device_tags.insert(device_name.clone(), tags);
+            }
+            if let Some(heartbeat) = heartbeat {
+                room.heartbeats.insert(device_name.clone(), heartbeat);
+            }
+        }
+        HouseEvent::RoomRenamed { room_name, new_name } => {
+            if new_name.is_empty() {
+                return Err(SmartHouseError::AddRoomError("Invalid room name".to_string()));
+            }
+            if smart_rooms.contains_key(new_name) {
+                return Err(SmartHouseError::RoomAlreadyExists(new_name.clone()));
+            }
+            let mut room = smart_rooms
+                .remove(room_name)
+                .ok_or_else(|| SmartHouseError::RoomNotFound(room_name.clone()))?;
+            room.room_name = new_name.clone();
+            smart_rooms.insert(new_name.clone(), room);
+        }
+        HouseEvent::SocketSwitched { room_name, device_name, status } => {
+            match device_mut(smart_rooms, room_name, device_name)? {
+                Device::SmartSocket(socket) => socket.switch_at(*status, timestamp),
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Socket: {}", device_name))),
+            }
+        }
+        HouseEvent::LightChanged { room_name, device_name, state } => {
+            match device_mut(smart_rooms, room_name, device_name)? {
+                Device::SmartLight(light) => light.set_state(*state)?,
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Light: {}", device_name))),
+            }
+        }
+        HouseEvent::BinarySensorChanged { room_name, device_name, active } => {
+            device_mut(smart_rooms, room_name, device_name)?.set_active(*active, timestamp)?;
+        }
+        HouseEvent::OccupancyTimeoutSet { room_name, timeout_ms } => {
+            room_mut(smart_rooms, room_name)?.occupancy_timeout = *timeout_ms;
+        }
+        HouseEvent::ThermostatConfigured { room_name, device_name, settings } => {
+            let room = room_mut(smart_rooms, room_name)?;
+            check_thermostat_wiring(room, settings)?;
+            match room.smart_device.get_mut(device_name) {
+                Some(Device::Thermostat(thermostat)) => thermostat.configure(settings.clone())?,
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Thermostat: {}", device_name))),
+            }
+        }
+        HouseEvent::ThermostatDemandChanged { room_name, device_name, demand } => {
+            match device_mut(smart_rooms, room_name, device_name)? {
+                Device::Thermostat(thermostat) => thermostat.set_demand(*demand),
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Thermostat: {}", device_name))),
+            }
+        }
+        HouseEvent::MetricRecorded { room_name, device_name, metric, value } => {
+            match device_mut(smart_rooms, room_name, device_name)? {
+                Device::EnvironmentSensor(sensor) => sensor.record(*metric, *value)?,
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Environment sensor: {}", device_name))),
+            }
+        }
+        HouseEvent::LockChanged { room_name, device_name, locked, method } => {
+            lock_mut(smart_rooms, room_name, device_name)?.set_locked(*locked, method.clone(), timestamp)?;
+        }
+        HouseEvent::PinRejected { room_name, device_name, locked } => {
+            lock_mut(smart_rooms, room_name, device_name)?.reject_pin(*locked, timestamp);
+        }
+        HouseEvent::LockReported { room_name, device_name, jammed, battery } => {
+            lock_mut(smart_rooms, room_name, device_name)?.report(*jammed, *battery)?;
+        }
+        HouseEvent::AccessCodeAdded { room_name, device_name, code } => {
+            lock_mut(smart_rooms, room_name, device_name)?.add_code(code.clone())?;
+        }
+        HouseEvent::AccessCodeRemoved { room_name, device_name, label } => {
+            lock_mut(smart_rooms, room_name, device_name)?.remove_code(label)?;
+        }
+        HouseEvent::CoverChanged { room_name, device_name, state } => {
+            cover_mut(smart_rooms, room_name, device_name)?.set_state(*state)?;
+        }
+        HouseEvent::CoverCalibrated { room_name, device_name, travel_ms } => {
+            cover_mut(smart_rooms, room_name, device_name)?.calibrate(*travel_ms)?;
+        }
+        HouseEvent::DetectorReported { room_name, device_name, detected, battery } => {
+            detector_mut(smart_rooms, room_name, device_name)?.report(*detected, *battery, timestamp)?;
+        }
+        HouseEvent::DetectorTested { room_name, device_name, passed } => {
+            detector_mut(smart_rooms, room_name, device_name)?.record_test(*passed, timestamp);
+        }
+        HouseEvent::AlarmAcknowledged { room_name, device_name } => {
+            detector_mut(smart_rooms, room_name, device_name)?.acknowledge()?;
+        }
+        HouseEvent::PowerRecorded { room_name, device_name, watts } => {
+            match device_mut(smart_rooms, room_name, device_name)? {
+                Device::SmartSocket(socket) => socket.record_power(*watts, timestamp)?,
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Socket: {}", device_name))),
+            }
+        }
+        HouseEvent::MeterRecorded { room_name, device_name, reading } => {
+            match device_mut(smart_rooms, room_name, device_name)? {
+                Device::EnergyMeter(meter) => meter.record(reading.clone(), timestamp)?,
+                _ => return Err(SmartHouseError::DeviceNotFound(format!("Energy meter: {}", device_name))),
+            }
+        }
+        HouseEvent::ReadingRecorded { room_name, device_name, value } => {
+            device_mut(smart_rooms, room_name, device_name)?.record_reading(*value)?;
+        }
+        HouseEvent::DeviceTagged { room_name, device_name, tags } => {
+            device_mut(smart_rooms, room_name, device_name)?;
+            room_mut(smart_rooms, room_name)?.set_tags(device_name, tags.clone())?;
+        }
+        HouseEvent::RoomPlaced { room_name, area } => {
+            room_mut(smart_rooms, room_name)?.area = area.clone();
+        }
+        HouseEvent::HeartbeatTimeoutSet { room_name, device_name, timeout_ms } => {
+            if *timeout_ms == 0 {
+                return Err(DeviceError::InvalidValue("Heartbeat timeout must be positive".to_string()).into());
+            }
+            device_mut(smart_rooms, room_name, device_name)?;
+            room_mut(smart_rooms, room_name)?.heartbeat_mut(device_name)?.timeout_ms = *timeout_ms;
+        }
+        HouseEvent::AvailabilityChanged { room_name, device_name, availability } => {
+            device_mut(smart_rooms, room_name, device_name)?;
+            room_mut(smart_rooms, room_name)?.heartbeat_mut(device_name)?.availability = *availability;
+        }
+        // Applied by change_settings
+        HouseEvent::TariffSet { .. }
+        | HouseEvent::GroupSet { .. }
+        | HouseEvent::GroupRemoved { .. }
+        | HouseEvent::AreaSet { .. }
+        | HouseEvent::AreaRemoved { .. } => {}
+    }
+    Ok(())
+}
+
+/// A thermostat may only use a thermometer and sockets of its own room.
+fn check_thermostat_wiring(room: &SmartRoom, settings: &ThermostatSettings) -> Result<(), SmartHouseError> {
+    if let Some(sensor) = &settings.sensor {
+        match room.smart_device.get(sensor) {
+            Some(Device::SmartThermometr(_)) => {}
+            _ => return Err(SmartHouseError::DeviceNotFound(format!("Thermometer: {}", sensor))),
+        }
+    }
+    for socket in settings.heater.iter().chain(settings.cooler.iter()) {
+        match room.smart_device.get(socket) {
+            Some(Device::SmartSocket(_)) => {}
+            _ => return Err(SmartHouseError::DeviceNotFound(format!("Socket: {}", socket))),
+        }
+    }
+    Ok(())
+}
+
+fn room_mut<'a>(smart_rooms: &'a mut HashMap<String, SmartRoom>, room_name: &str) -> Result<&'a mut SmartRoom, SmartHouseError> {
+    smart_rooms
+        .get_mut(room_name)
+        .ok_or_else(|| SmartHouseError::RoomNotFound(room_name.to_string()))
+}
+
+fn device_mut<'a>(
+    smart_rooms: &'a mut HashMap<String, SmartRoom>,
+    room_name: &str,
+    device_name: &str,
+) -> Result<&'a mut Device, SmartHouseError> {
+    room_mut(smart_rooms, room_name)?
+        .smart_device
+        .get_mut(device_name)
+        .ok_or_else(|| SmartHouseError::DeviceNotFound(device_name.to_string()))
+}
+
+fn lock_mut<'a>(
+    smart_rooms: &'a mut HashMap<String, SmartRoom>,
+    room_name: &str,
+    device_name: &str,
+) -> Result<&'a mut SmartLock, SmartHouseError> {
+    match device_mut(smart_rooms, room_name, device_name)? {
+        Device::SmartLock(lock) => Ok(lock),
+        _ => Err(SmartHouseError::DeviceNotFound(format!("Lock: {}", device_name))),
+    }
+}
+
+fn cover_mut<'a>(
+    smart_rooms: &'a mut HashMap<String, SmartRoom>,
+    room_name: &str,
+    device_name: &str,
+) -> Result<&'a mut Cover, SmartHouseError> {
+    match device_mut(smart_rooms, room_name, device_name)? {
+        Device::Cover(cover) => Ok(cover),
+        _ => Err(SmartHouseError::DeviceNotFound(format!("Cover: {}", device_name))),
+    }
+}
+
+fn detector_mut<'a>(
+    smart_rooms: &'a mut HashMap<String, SmartRoom>,
+    room_name: &str,
+    device_name: &str,
+) -> Result<&'a mut SafetyDetector, SmartHouseError> {
+    match device_mut(smart_rooms, room_name, device_name)? {
+        Device::SafetyDetector(detector) => Ok(detector),
+        _ => Err(SmartHouseError::DeviceNotFound(format!("Detector: {}", device_name))),
+    }
 }
 
 pub trait DeviceInfoProvider {
@@ -102,6 +1468,7 @@ impl DeviceInfoProvider for OwningDeviceInfoProvider {
         match devices {
             Device::SmartSocket(soket) => device_info.push_str(format!("{}", soket).as_str()),
             Device::SmartThermometr(thermo) => device_info.push_str(format!("{}", thermo).as_str()),
+            other => device_info.push_str(format!("{}", other).as_str()),
         }
         device_info
     }
@@ -112,6 +1479,7 @@ impl<'a, 'b> DeviceInfoProvider for BorrowingDeviceInfoProvider<'a, 'b> {
         match devices {
             Device::SmartSocket(soket) => device_info.push_str(format!("{}\n", soket).as_str()),
             Device::SmartThermometr(thermo) => device_info.push_str(format!("{}\n", thermo).as_str()),
+            other => device_info.push_str(format!("{}\n", other).as_str()),
         }
         device_info
     }
@@ -204,4 +1572,560 @@ mod tests {
         assert!(!house.create_report(info_provider_1).is_empty());
         assert!(!house.create_report(info_provider_2).is_empty());
     }
+
+    #[test]
+    fn replay_history() {
+        let mut house = SmartHouse::with_snapshot_interval("House".to_string(), 2);
+        let socket = Device::SmartSocket(SmartSocket::default("Smart_socket".to_string()));
+        house.commit_at(HouseEvent::RoomAdded(SmartRoom::default("Kitchen".to_string())), 10).unwrap();
+        house.commit_at(HouseEvent::DeviceAdded { room_name: "Kitchen".to_string(), device: socket }, 20).unwrap();
+        house.commit_at(HouseEvent::SocketSwitched {
+            room_name: "Kitchen".to_string(),
+            device_name: "Smart_socket".to_string(),
+            status: true,
+        }, 30).unwrap();
+        house.commit_at(HouseEvent::RoomRemoved { room_name: "Kitchen".to_string() }, 40).unwrap();
+        assert!(house.get_rooms_list().is_empty());
+        assert_eq!(house.history().snapshots().len(), 2);
+
+        let past = house.as_of(30).unwrap();
+        match past.smart_rooms["Kitchen"].get_device("Smart_socket".to_string()) {
+            Some(Device::SmartSocket(socket)) => assert!(socket.status()),
+            _ => panic!("socket is missing"),
+        }
+        assert!(house.as_of(5).unwrap().get_rooms_list().is_empty());
+
+        let rebuilt = SmartHouse::from_log("House".to_string(), house.history().clone()).unwrap();
+        assert!(rebuilt.get_rooms_list().is_empty());
+    }
+
+    #[test]
+    fn history_is_compacted_and_persisted() {
+        let mut house = SmartHouse::with_snapshot_interval("House".to_string(), 1);
+        house.set_history_retention(2);
+        for (timestamp, room) in [(10, "Kitchen"), (20, "Hall"), (30, "Bathroom"), (40, "Attic"), (50, "Garage")] {
+            house.commit_at(HouseEvent::RoomAdded(SmartRoom::default(room.to_string())), timestamp).unwrap();
+        }
+        assert_eq!(house.history().len(), 1);
+        assert_eq!(house.revision(), 5);
+        assert!(matches!(house.as_of(30), Err(SmartHouseError::HistoryCompacted(30))));
+
+        let json = serde_json::to_string(&house).unwrap();
+        let mut restored: SmartHouse = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.as_of(45).unwrap().get_rooms_list().len(), 4);
+        restored.commit_at(HouseEvent::RoomRemoved { room_name: "Garage".to_string() }, 60).unwrap();
+        assert_eq!(restored.revision(), 6);
+        assert_eq!(restored.smart_rooms().len(), 4);
+    }
+
+    #[test]
+    fn undo_redo_remove_room() {
+        let mut kitchen = SmartRoom::default("Kitchen".to_string());
+        kitchen.add_smart_device(Device::SmartSocket(SmartSocket::default("Smart_socket".to_string()))).unwrap();
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&kitchen).unwrap();
+        house.remove_smart_room(&kitchen).unwrap();
+
+        house.undo().unwrap();
+        assert!(house.get_room_by_name("Kitchen").unwrap().get_device("Smart_socket".to_string()).is_some());
+        house.redo().unwrap();
+        assert!(house.get_room_by_name("Kitchen").is_none());
+        house.undo().unwrap();
+        house.undo().unwrap();
+        assert!(house.get_rooms_list().is_empty());
+        assert!(matches!(house.undo(), Err(SmartHouseError::NothingToUndo)));
+    }
+
+    #[test]
+    fn undo_move_and_rename() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Smart_socket".to_string()))).unwrap();
+        house.move_device("Smart_socket", "Kitchen", "Hall").unwrap();
+        house.rename_room("Hall", "Corridor").unwrap();
+        assert!(house.get_room_by_name("Corridor").unwrap().get_device("Smart_socket".to_string()).is_some());
+
+        house.undo().unwrap();
+        house.undo().unwrap();
+        assert!(house.get_room_by_name("Kitchen").unwrap().get_device("Smart_socket".to_string()).is_some());
+        assert!(house.get_room_by_name("Corridor").is_none());
+    }
+
+    #[test]
+    fn undo_refuses_conflicting_edit() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Smart_socket".to_string()))).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Lamp".to_string()))).unwrap();
+        house.undo().unwrap();
+        house.redo().unwrap();
+
+        // Changed without going through the undo stack
+        house.commit(HouseEvent::DeviceRemoved {
+            room_name: "Kitchen".to_string(),
+            device_name: "Smart_socket".to_string(),
+        }).unwrap();
+        assert!(matches!(house.undo(), Err(SmartHouseError::UndoConflict(_))));
+
+        house.set_undo_depth(0);
+        assert!(matches!(house.undo(), Err(SmartHouseError::NothingToUndo)));
+    }
+
+    #[test]
+    fn undo_restores_removed_device_with_tags_and_heartbeat() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        house.set_tags("Kitchen", "Kettle", BTreeSet::from(["power".to_string()])).unwrap();
+        house.set_group("Power", DeviceQuery { tag: Some("power".to_string()), kind: None, room: None }).unwrap();
+        house.set_heartbeat_timeout("Kitchen", "Kettle", 1_000).unwrap();
+        house.heartbeat_at("Kitchen", "Kettle", 10_000).unwrap();
+        let heartbeat = house.device_availability("Kitchen", "Kettle").unwrap().heartbeat;
+
+        house.remove_device("Kitchen", "Kettle").unwrap();
+        assert!(house.group_members("Power").unwrap().is_empty());
+        house.undo().unwrap();
+        assert_eq!(house.group_members("Power").unwrap()[0].device_name, "Kettle");
+        let restored = house.device_availability("Kitchen", "Kettle").unwrap().heartbeat;
+        assert_eq!(restored.availability, heartbeat.availability);
+        assert_eq!(restored.timeout_ms, 1_000);
+        house.redo().unwrap();
+        assert!(house.get_device("Kitchen", "Kettle").is_err());
+    }
+
+    #[test]
+    fn undo_tells_devices_apart_by_id() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Lamp".to_string()))).unwrap();
+
+        // Replaced by a different lamp with the same name
+        house.commit(HouseEvent::DeviceRemoved {
+            room_name: "Kitchen".to_string(),
+            device_name: "Lamp".to_string(),
+        }).unwrap();
+        house.commit(HouseEvent::DeviceAdded {
+            room_name: "Kitchen".to_string(),
+            device: Device::SmartSocket(SmartSocket::default("Lamp".to_string())),
+        }).unwrap();
+        assert!(matches!(house.undo(), Err(SmartHouseError::UndoConflict(_))));
+    }
+
+    #[test]
+    fn devices_keep_their_ids() {
+        let socket = Device::SmartSocket(SmartSocket::default("Socket".to_string()));
+        let mut kitchen = SmartRoom::default("Kitchen".to_string());
+        kitchen.add_smart_device(socket.clone()).unwrap();
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&kitchen).unwrap();
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Hall", socket).unwrap();
+        let id = house.get_device("Kitchen", "Socket").unwrap().id().to_string();
+        assert!(!id.is_empty());
+        assert_ne!(house.get_device("Hall", "Socket").unwrap().id(), id);
+
+        house.remove_device("Hall", "Socket").unwrap();
+        house.move_device("Socket", "Kitchen", "Hall").unwrap();
+        house.rename_room("Hall", "Lobby").unwrap();
+        assert_eq!(house.get_device("Lobby", "Socket").unwrap().id(), id);
+    }
+
+    #[test]
+    fn no_redo_after_undo_is_disabled() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.undo().unwrap();
+
+        house.set_undo_depth(0);
+        assert!(matches!(house.redo(), Err(SmartHouseError::NothingToRedo)));
+        assert!(house.get_rooms_list().is_empty());
+    }
+
+    #[test]
+    fn transaction() {
+        let mut house = SmartHouse::new("House".to_string());
+        let socket = Device::SmartSocket(SmartSocket::default("Smart_socket".to_string()));
+        let outcome = house.transaction(
+            vec![
+                HouseEvent::RoomAdded(SmartRoom::default("Kitchen".to_string())),
+                HouseEvent::DeviceAdded { room_name: "Kitchen".to_string(), device: socket.clone() },
+            ],
+            SmartHouse::edit,
+        );
+        assert!(outcome.committed);
+        assert_eq!(house.history().len(), 2);
+        house.undo().unwrap();
+        assert!(house.get_room_by_name("Kitchen").unwrap().smart_device.is_empty());
+        house.redo().unwrap();
+
+        let outcome = house.transaction(
+            vec![
+                HouseEvent::RoomAdded(SmartRoom::default("Hall".to_string())),
+                HouseEvent::DeviceAdded { room_name: "Bathroom".to_string(), device: socket },
+                HouseEvent::RoomRemoved { room_name: "Kitchen".to_string() },
+            ],
+            SmartHouse::edit,
+        );
+        assert!(!outcome.committed);
+        assert_eq!(outcome.results[0], OperationStatus::RolledBack);
+        assert!(matches!(outcome.results[1], OperationStatus::Failed(_)));
+        assert_eq!(outcome.results[2], OperationStatus::Skipped);
+        assert!(house.get_room_by_name("Hall").is_none());
+        assert!(house.get_room_by_name("Kitchen").is_some());
+        assert_eq!(house.history().len(), 4);
+    }
+
+    #[test]
+    fn revisions() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Smart_socket".to_string()))).unwrap();
+        assert_eq!(house.revision(), 3);
+        assert_eq!(house.get_room_by_name("Kitchen").unwrap().revision, 3);
+        assert_eq!(house.get_room_by_name("Hall").unwrap().revision, 2);
+
+        house.switch_socket("Kitchen", "Smart_socket", true).unwrap();
+        assert_eq!(house.get_room_by_name("Kitchen").unwrap().revision, 4);
+        assert!(house.switch_socket("Hall", "Smart_socket", true).is_err());
+        assert_eq!(house.revision(), 4);
+        assert_eq!(house.as_of(u64::MAX).unwrap().revision(), 4);
+    }
+
+    #[test]
+    fn sensor_events_are_timestamped() {
+        let mut house = SmartHouse::new("House".to_string());
+        let sensor = |active| HouseEvent::BinarySensorChanged {
+            room_name: "Hall".to_string(),
+            device_name: "Motion".to_string(),
+            active,
+        };
+        house.commit_at(HouseEvent::RoomAdded(SmartRoom::default("Hall".to_string())), 0).unwrap();
+        house.commit_at(HouseEvent::DeviceAdded {
+            room_name: "Hall".to_string(),
+            device: Device::MotionSensor(MotionSensor::default("Motion".to_string())),
+        }, 0).unwrap();
+        house.commit_at(sensor(true), 1_000).unwrap();
+        house.commit_at(HouseEvent::OccupancyTimeoutSet { room_name: "Hall".to_string(), timeout_ms: 60_000 }, 1_500).unwrap();
+        house.commit_at(sensor(false), 2_000).unwrap();
+
+        let hall = house.get_room_by_name("Hall").unwrap();
+        assert_eq!(hall.last_motion(), Some(2_000));
+        assert!(hall.is_occupied(61_000));
+        assert!(!hall.is_occupied(62_000));
+
+        let past = house.as_of(1_000).unwrap();
+        let hall = past.get_room_by_name("Hall").unwrap();
+        assert_eq!(hall.last_motion(), Some(1_000));
+        assert!(hall.is_occupied(u64::MAX));
+    }
+
+    #[test]
+    fn environment_in_report() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Bathroom".to_string())).unwrap();
+        house.add_device("Bathroom", Device::EnvironmentSensor(EnvironmentSensor::humidity("Humidity".to_string()))).unwrap();
+        house.record_metric("Bathroom", "Humidity", Metric::Humidity, 70.0).unwrap();
+        assert!(house.record_metric("Bathroom", "Humidity", Metric::Humidity, -1.0).is_err());
+
+        let socket = SmartSocket::default("Smart_socket".to_string());
+        let report = house.create_report(OwningDeviceInfoProvider { socket });
+        assert!(report.contains("Average humidity: 70.0 %"));
+    }
+
+    #[test]
+    fn lock_with_pin() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Hall", Device::SmartLock(SmartLock::default("Door".to_string()))).unwrap();
+        house.add_access_code("Hall", "Door", "guest".to_string(), "2468", 0, None).unwrap();
+
+        assert!(matches!(
+            house.set_lock_with_pin("Hall", "Door", false, "1111"),
+            Err(SmartHouseError::AccessDenied(_))
+        ));
+        house.set_lock_with_pin("Hall", "Door", false, "2468").unwrap();
+        house.set_lock("Hall", "Door", true, AccessMethod::Automation).unwrap();
+
+        // Stored houses and logs keep the hashes, so the PIN still opens the lock
+        let mut stored: SmartHouse = serde_json::from_str(&serde_json::to_string(&house).unwrap()).unwrap();
+        stored.set_lock_with_pin("Hall", "Door", false, "2468").unwrap();
+        let log: EventLog = serde_json::from_str(&serde_json::to_string(house.history()).unwrap()).unwrap();
+        let mut restored = SmartHouse::from_log("House".to_string(), log).unwrap();
+        restored.set_lock_with_pin("Hall", "Door", false, "2468").unwrap();
+
+        let lock = house.get_lock("Hall", "Door").unwrap();
+        let methods: Vec<_> = lock.history().iter().map(|entry| entry.method.clone()).collect();
+        assert_eq!(
+            methods,
+            vec![AccessMethod::WrongPin, AccessMethod::Pin("guest".to_string()), AccessMethod::Automation]
+        );
+
+        for _ in 0..PIN_ATTEMPTS {
+            assert!(house.set_lock_with_pin("Hall", "Door", false, "1111").is_err());
+        }
+        // Even the right PIN is refused during the lockout
+        assert!(house.set_lock_with_pin("Hall", "Door", false, "2468").is_err());
+        assert_eq!(house.get_lock("Hall", "Door").unwrap().state(), LockState::Locked);
+
+        let json = redacted(|| serde_json::to_string(&house).unwrap());
+        let history = redacted(|| serde_json::to_string(house.history().records()).unwrap());
+        assert!(json.contains("guest") && !json.contains("pin_hash"));
+        assert!(history.contains("guest") && !history.contains("pin_hash"));
+    }
+
+    #[test]
+    fn alarm_is_pushed_to_every_subscriber() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SafetyDetector(SafetyDetector::new("Smoke".to_string(), Hazard::Smoke))).unwrap();
+        let mut hall = house.subscribe(EventFilter {
+            room: Some("Hall".to_string()),
+            device: None,
+        });
+
+        house.commit_at(HouseEvent::DetectorReported {
+            room_name: "Kitchen".to_string(),
+            device_name: "Smoke".to_string(),
+            detected: true,
+            battery: 80,
+        }, 100).unwrap();
+        match hall.try_recv() {
+            Ok(Notification::Alarm(alarm)) => assert_eq!((alarm.device_name.as_str(), alarm.since), ("Smoke", 100)),
+            other => panic!("Expected an alarm, got {:?}", other),
+        }
+        assert!(hall.try_recv().is_err());
+        assert!(house.alarm_state().active);
+
+        assert!(house.acknowledge_alarm("Kitchen", "Smoke").is_err());
+        house.report_detector("Kitchen", "Smoke", false, 80).unwrap();
+        house.acknowledge_alarm("Kitchen", "Smoke").unwrap();
+        assert!(!house.alarm_state().active);
+        assert!(hall.try_recv().is_err());
+    }
+
+    #[test]
+    fn energy_per_room_and_house() {
+        let hour = 3_600_000;
+        let mut house = SmartHouse::new("House".to_string());
+        for (room, socket) in [("Kitchen", "Kettle"), ("Kitchen", "Oven"), ("Hall", "Lamp")] {
+            if house.get_room_by_name(room).is_none() {
+                house.add_smart_room(&SmartRoom::default(room.to_string())).unwrap();
+            }
+            house.add_device(room, Device::SmartSocket(SmartSocket::default(socket.to_string()))).unwrap();
+        }
+        let commit = |house: &mut SmartHouse, event, at| house.commit_at(event, at).unwrap();
+        for (socket, room, watts) in [("Kettle", "Kitchen", 2_000.0), ("Oven", "Kitchen", 1_000.0), ("Lamp", "Hall", 100.0)] {
+            let (room_name, device_name) = (room.to_string(), socket.to_string());
+            commit(&mut house, HouseEvent::PowerRecorded { room_name: room_name.clone(), device_name: device_name.clone(), watts }, 0);
+            commit(&mut house, HouseEvent::SocketSwitched { room_name, device_name, status: true }, 0);
+        }
+        commit(&mut house, HouseEvent::SocketSwitched {
+            room_name: "Kitchen".to_string(),
+            device_name: "Kettle".to_string(),
+            status: false,
+        }, hour);
+
+        let report = house.energy_in(Period::Day, 2 * hour);
+        assert_eq!(report.kwh, 4.2);
+        assert_eq!(report.rooms[1].room_name, "Kitchen");
+        assert_eq!(report.rooms[1].kwh, 4.0);
+        let top: Vec<_> = report.top_consumers.iter().map(|device| device.device_name.as_str()).collect();
+        assert_eq!(top, vec!["Kettle", "Oven", "Lamp"]);
+        assert!(report.to_string().contains("1. Kettle in Kitchen: 2.000 kWh"));
+    }
+
+    #[test]
+    fn costs() {
+        let hour = 3_600_000;
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        assert!(matches!(house.costs_in(Period::Day, 0), Err(SmartHouseError::TariffNotSet)));
+        let night = Tariff {
+            currency: "EUR".to_string(),
+            utc_offset_minutes: 0,
+            pricing: Pricing::DayNight { day: 0.3, night: 0.1, night_start: 0, night_end: 5 * 60 },
+        };
+        house.set_tariff(night).unwrap();
+        assert!(house.set_tariff(Tariff::flat("EUR".to_string(), -1.0)).is_err());
+
+        let kettle = |event| match event {
+            true => HouseEvent::SocketSwitched { room_name: "Kitchen".to_string(), device_name: "Kettle".to_string(), status: true },
+            false => HouseEvent::PowerRecorded { room_name: "Kitchen".to_string(), device_name: "Kettle".to_string(), watts: 1_000.0 },
+        };
+        house.commit_at(kettle(false), 0).unwrap();
+        house.commit_at(kettle(true), 0).unwrap();
+
+        // Five night hours and one day hour
+        let report = house.costs_in(Period::Day, 6 * hour).unwrap();
+        assert!((report.kwh - 6.0).abs() < 1e-9);
+        assert!((report.cost - 0.8).abs() < 1e-9);
+        assert_eq!(report.rooms[0].devices[0].device_name, "Kettle");
+        assert!(report.projection.total > report.projection.month_to_date);
+        assert!(report.to_string().contains("Kitchen: 0.80 EUR for 6.000 kWh"));
+
+        // The tariff is part of the history
+        let revision = house.revision();
+        house.set_tariff(Tariff::flat("EUR".to_string(), 0.2)).unwrap();
+        assert_eq!(house.revision(), revision + 1);
+        let restored = SmartHouse::from_log("House".to_string(), house.history().clone()).unwrap();
+        assert_eq!(restored.tariff(), house.tariff());
+        assert!(matches!(house.undo().unwrap(), HouseEvent::TariffSet { .. }));
+        assert!(matches!(house.tariff().unwrap().pricing, Pricing::DayNight { .. }));
+    }
+
+    #[test]
+    fn groups_follow_tags() {
+        let mut house = SmartHouse::new("House".to_string());
+        for room in ["Kitchen", "Hall"] {
+            house.add_smart_room(&SmartRoom::default(room.to_string())).unwrap();
+            house.add_device(room, Device::SmartSocket(SmartSocket::default(format!("{} heater", room)))).unwrap();
+        }
+        house.add_device("Hall", Device::SmartThermometr(SmartThermometer::default("Thermometer".to_string()))).unwrap();
+        let heater = BTreeSet::from(["heater".to_string()]);
+        house.set_tags("Kitchen", "Kitchen heater", heater.clone()).unwrap();
+        house.set_tags("Hall", "Thermometer", heater.clone()).unwrap();
+        assert!(house.set_tags("Hall", "Nothing", heater.clone()).is_err());
+        assert!(house.set_tags("Hall", "Hall heater", BTreeSet::from([" ".to_string()])).is_err());
+
+        let query = DeviceQuery {
+            tag: Some("heater".to_string()),
+            kind: Some(DeviceKind::Socket),
+            room: None,
+        };
+        house.set_group("heaters", query.clone()).unwrap();
+        assert_eq!(house.find_devices(&query).len(), 1);
+
+        // Membership is looked up when the group is used
+        house.set_tags("Hall", "Hall heater", heater).unwrap();
+        house.move_device("Kitchen heater", "Kitchen", "Hall").unwrap();
+        let members: Vec<_> = house.group_members("heaters").unwrap().into_iter().map(|member| member.device_name).collect();
+        assert_eq!(members, vec!["Hall heater", "Kitchen heater"]);
+
+        house.switch_socket("Hall", "Hall heater", true).unwrap();
+        let summary = house.group_summary("heaters", 0).unwrap();
+        assert_eq!((summary.devices, summary.sockets_on), (2, 1));
+        assert!(matches!(house.group_members("outdoor"), Err(SmartHouseError::GroupNotFound(_))));
+
+        house.remove_device("Hall", "Hall heater").unwrap();
+        assert_eq!(house.group_members("heaters").unwrap().len(), 1);
+        assert!(!house.get_room_by_name("Hall").unwrap().device_tags.contains_key("Hall heater"));
+
+        // Groups are edits like any other
+        let revision = house.revision();
+        assert_eq!(house.remove_group("heaters").unwrap().query, query);
+        assert_eq!(house.revision(), revision + 1);
+        assert!(house.remove_group("heaters").is_err());
+        assert!(matches!(house.undo().unwrap(), HouseEvent::GroupRemoved { .. }));
+        assert_eq!(house.group("heaters").unwrap().query, query);
+        assert!(house.set_group(" ", query).is_err());
+    }
+
+    #[test]
+    fn devices_go_offline_without_heartbeats() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Kitchen".to_string())).unwrap();
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        house.add_device("Kitchen", Device::SmartThermometr(SmartThermometer::default("Thermometer".to_string()))).unwrap();
+        let mut kitchen = house.subscribe(EventFilter {
+            room: Some("Kitchen".to_string()),
+            device: None,
+        });
+        assert_eq!(house.device_availability("Kitchen", "Kettle").unwrap().heartbeat.availability, Availability::Unknown);
+        house.set_heartbeat_timeout("Kitchen", "Kettle", 1_000).unwrap();
+        assert!(house.set_heartbeat_timeout("Kitchen", "Kettle", 0).is_err());
+
+        house.heartbeat_at("Kitchen", "Kettle", 10_000).unwrap();
+        let revision = house.get_room_by_name("Kitchen").unwrap().revision;
+        let events = house.history.records().len();
+        // Later heartbeats of an online device only move last_seen
+        house.heartbeat_at("Kitchen", "Kettle", 10_200).unwrap();
+        assert_eq!(house.get_room_by_name("Kitchen").unwrap().revision, revision);
+        assert_eq!(house.history.records().len(), events);
+        assert_eq!(house.device_availability("Kitchen", "Kettle").unwrap().heartbeat.last_seen, Some(10_200));
+        // Any report of the device counts as a heartbeat
+        house.commit_at(HouseEvent::ReadingRecorded {
+            room_name: "Kitchen".to_string(),
+            device_name: "Thermometer".to_string(),
+            value: 20.0,
+        }, 10_000).unwrap();
+        assert!(house.check_availability(10_700).unwrap().is_empty());
+        while kitchen.try_recv().is_ok() {}
+
+        let offline = house.check_availability(11_200).unwrap();
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].to_string(), "Kettle in Kitchen is offline, last seen at 10200");
+        match kitchen.try_recv() {
+            Ok(Notification::Event(record)) => assert!(matches!(
+                record.event,
+                HouseEvent::AvailabilityChanged { availability: Availability::Offline, .. }
+            )),
+            other => panic!("Expected an event, got {:?}", other),
+        }
+        assert!(house.check_availability(20_000).unwrap().is_empty());
+        assert!(house.create_report(OwningDeviceInfoProvider { socket: SmartSocket::default("Kettle".to_string()) }).contains("Kettle is offline"));
+
+        // Heartbeats move with the device and bring it back online
+        house.move_device("Kettle", "Kitchen", "Hall").unwrap();
+        house.heartbeat("Hall", "Kettle").unwrap();
+        let kettle = house.device_availability("Hall", "Kettle").unwrap();
+        assert_eq!((kettle.heartbeat.availability, kettle.heartbeat.timeout_ms), (Availability::Online, 1_000));
+        let json = serde_json::to_value(&kettle).unwrap();
+        assert_eq!(json["availability"], "online");
+    }
+
+    #[test]
+    fn floors_and_zones() {
+        let mut house = SmartHouse::new("House".to_string());
+        for room in ["Kitchen", "Bedroom", "Shed"] {
+            house.add_smart_room(&SmartRoom::default(room.to_string())).unwrap();
+        }
+        house.add_device("Kitchen", Device::SmartSocket(SmartSocket::default("Kettle".to_string()))).unwrap();
+        house.set_area(Area::floor("Upstairs".to_string(), 1)).unwrap();
+        house.set_area(Area::floor("Ground".to_string(), 0)).unwrap();
+        house.set_area(Area::zone("Garden".to_string())).unwrap();
+        house.place_room("Kitchen", Some("Ground")).unwrap();
+        house.place_room("Bedroom", Some("Upstairs")).unwrap();
+        assert!(matches!(house.place_room("Shed", Some("Attic")), Err(SmartHouseError::AreaNotFound(_))));
+
+        let tree = house.tree(0);
+        let areas: Vec<_> = tree.areas.iter().map(|node| node.area.name.as_str()).collect();
+        assert_eq!(areas, vec!["Ground", "Upstairs", "Garden"]);
+        assert_eq!((tree.summary.rooms, tree.summary.devices), (3, 1));
+        assert_eq!(tree.areas[0].summary.devices, 1);
+        assert_eq!(tree.unplaced[0].room_name, "Shed");
+        assert!(house.room_in("Upstairs", "Kitchen").is_err());
+
+        let report = house.create_report(OwningDeviceInfoProvider {
+            socket: SmartSocket::default("Kettle".to_string()),
+        });
+        let ground = report.find("Ground (floor 0): 1 rooms").unwrap();
+        assert!(ground < report.find("Kitchen").unwrap());
+        assert!(report.find("Upstairs (floor 1)").unwrap() < report.find("Bedroom").unwrap());
+
+        assert!(matches!(house.remove_area("Ground"), Err(SmartHouseError::AreaNotEmpty(_))));
+        house.rename_room("Kitchen", "Cuisine").unwrap();
+        assert_eq!(house.rooms_in("Ground").unwrap()[0].room_name, "Cuisine");
+        house.undo().unwrap();
+        house.undo().unwrap();
+        assert!(house.rooms_in("Upstairs").unwrap().is_empty());
+        house.remove_area("Upstairs").unwrap();
+
+        // Areas are kept in the history and undone like rooms
+        let restored = SmartHouse::from_log("House".to_string(), house.history().clone()).unwrap();
+        assert_eq!(restored.areas().len(), 2);
+        assert!(matches!(house.undo().unwrap(), HouseEvent::AreaRemoved { .. }));
+        assert_eq!(house.area("Upstairs").unwrap().level, 1);
+    }
+
+    #[test]
+    fn failed_event_is_not_recorded() {
+        let mut house = SmartHouse::new("House".to_string());
+        assert!(house.remove_device("Kitchen", "Smart_socket").is_err());
+        assert!(house.history().is_empty());
+    }
 }
@@ -1,7 +1,12 @@
 use thiserror::Error;
+use crate::availability::*;
 use crate::devices::*;
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 #[derive(Debug, Error, Serialize, Deserialize)]
 pub enum SmartRoomError {
@@ -10,10 +15,59 @@ pub enum SmartRoomError {
     #[error("Error while deleting device")]
     DeleteDeviceError(String),
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct SmartRoom {
     pub room_name: String,
     pub smart_device: HashMap<String, Device>,
+    /// House revision of the last change to this room.
+    #[serde(default)]
+    pub revision: u64,
+    /// How long the room stays occupied after the last motion, in milliseconds.
+    #[serde(default = "default_occupancy_timeout")]
+    pub occupancy_timeout: u64,
+    /// Tags of the devices that have any.
+    #[serde(default)]
+    pub device_tags: BTreeMap<String, BTreeSet<String>>,
+    /// Floor or zone the room is placed on.
+    #[serde(default)]
+    pub area: Option<String>,
+    /// Devices that were heard from or have their own heartbeat timeout.
+    #[serde(default)]
+    pub heartbeats: BTreeMap<String, Heartbeat>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Occupancy {
+    pub occupied: bool,
+    pub last_motion: Option<u64>,
+    pub timeout_ms: u64,
+}
+
+/// A metric aggregated over all environment sensors of a room.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MetricSummary {
+    pub metric: Metric,
+    pub unit: String,
+    pub average: f32,
+    pub min: f32,
+    pub max: f32,
+    pub sensors: usize,
+}
+
+impl Display for MetricSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Average {}: {:.1} {} (min {}, max {}, {} sensors)",
+            self.metric, self.average, self.unit, self.min, self.max, self.sensors
+        )
+    }
+}
+
+pub const DEFAULT_OCCUPANCY_TIMEOUT: u64 = 5 * 60 * 1000;
+
+fn default_occupancy_timeout() -> u64 {
+    DEFAULT_OCCUPANCY_TIMEOUT
 }
 
 impl SmartRoom {
@@ -21,6 +75,11 @@ impl SmartRoom {
         SmartRoom {
             room_name,
             smart_device: HashMap::new(),
+            revision: 0,
+            occupancy_timeout: DEFAULT_OCCUPANCY_TIMEOUT,
+            device_tags: BTreeMap::new(),
+            area: None,
+            heartbeats: BTreeMap::new(),
         }
     }
     pub fn get_room_name (&self) -> Result<String, SmartRoomError> {
@@ -48,8 +107,115 @@ impl SmartRoom {
         }
     }
 
+    pub fn tags(&self, device_name: &str) -> BTreeSet<String> {
+        self.device_tags.get(device_name).cloned().unwrap_or_default()
+    }
+
+    pub fn has_tag(&self, device_name: &str, tag: &str) -> bool {
+        self.device_tags.get(device_name).is_some_and(|tags| tags.contains(tag))
+    }
+
+    pub fn set_tags(&mut self, device_name: &str, tags: BTreeSet<String>) -> Result<(), DeviceError> {
+        if !self.smart_device.contains_key(device_name) {
+            return Err(DeviceError::InvalidValue(format!("No device {} in {}", device_name, self.room_name)));
+        }
+        if tags.iter().any(|tag| tag.trim().is_empty() || tag.trim() != tag) {
+            return Err(DeviceError::InvalidValue("Tags can't be empty or padded".to_string()));
+        }
+        if tags.is_empty() {
+            self.device_tags.remove(device_name);
+        } else {
+            self.device_tags.insert(device_name.to_string(), tags);
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat(&self, device_name: &str) -> Heartbeat {
+        self.heartbeats.get(device_name).cloned().unwrap_or_default()
+    }
+
+    pub fn availability(&self, device_name: &str) -> Availability {
+        self.heartbeats
+            .get(device_name)
+            .map_or(Availability::Unknown, |heartbeat| heartbeat.availability)
+    }
+
+    /// The heartbeat of a device of this room, created on first use.
+    pub fn heartbeat_mut(&mut self, device_name: &str) -> Result<&mut Heartbeat, DeviceError> {
+        if !self.smart_device.contains_key(device_name) {
+            return Err(DeviceError::InvalidValue(format!("No device {} in {}", device_name, self.room_name)));
+        }
+        Ok(self.heartbeats.entry(device_name.to_string()).or_default())
+    }
+
     pub fn get_device(&self, device_name: String) -> Option<&Device> {
-        self.smart_device.get(&device_name).clone()
+        self.smart_device.get(&device_name)
+    }
+
+    fn motion_sensors(&self) -> impl Iterator<Item = &MotionSensor> {
+        self.smart_device.values().filter_map(|device| match device {
+            Device::MotionSensor(sensor) => Some(sensor),
+            _ => None,
+        })
+    }
+
+    /// Latest motion seen by any motion sensor in the room.
+    pub fn last_motion(&self) -> Option<u64> {
+        self.motion_sensors().filter_map(|sensor| sensor.last_motion()).max()
+    }
+
+    /// Occupied while any sensor sees motion and for `occupancy_timeout` after.
+    pub fn is_occupied(&self, now: u64) -> bool {
+        self.motion_sensors().any(|sensor| sensor.motion())
+            || self
+                .last_motion()
+                .is_some_and(|last_motion| now.saturating_sub(last_motion) < self.occupancy_timeout)
+    }
+
+    /// Readings of `metric` from every environment sensor that has one.
+    pub fn readings(&self, metric: Metric) -> Vec<f32> {
+        self.smart_device
+            .values()
+            .filter_map(|device| match device {
+                Device::EnvironmentSensor(sensor) => sensor.reading(metric),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn average(&self, metric: Metric) -> Option<f32> {
+        self.summary(metric).map(|summary| summary.average)
+    }
+
+    pub fn summary(&self, metric: Metric) -> Option<MetricSummary> {
+        let readings = self.readings(metric);
+        if readings.is_empty() {
+            return None;
+        }
+        Some(MetricSummary {
+            metric,
+            unit: metric.unit().to_string(),
+            average: readings.iter().sum::<f32>() / readings.len() as f32,
+            min: readings.iter().copied().fold(f32::INFINITY, f32::min),
+            max: readings.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            sensors: readings.len(),
+        })
+    }
+
+    /// Summaries of every metric measured in the room.
+    pub fn environment(&self) -> Vec<MetricSummary> {
+        [Metric::Humidity, Metric::Co2, Metric::Voc, Metric::Pm25]
+            .into_iter()
+            .filter_map(|metric| self.summary(metric))
+            .collect()
+    }
+
+    pub fn occupancy(&self, now: u64) -> Occupancy {
+        Occupancy {
+            occupied: self.is_occupied(now),
+            last_motion: self.last_motion(),
+            timeout_ms: self.occupancy_timeout,
+        }
     }
 }
 
@@ -86,4 +252,41 @@ mod tests {
         smart_room.delite_device(&soket).unwrap();
         assert!(smart_room.smart_device.is_empty());
     }
+
+    #[test]
+    fn environment() {
+        let mut smart_room = SmartRoom::default("bathroom".to_string());
+        let mut first = EnvironmentSensor::humidity("first".to_string());
+        first.record(Metric::Humidity, 40.0).unwrap();
+        let mut second = EnvironmentSensor::air_quality("second".to_string());
+        second.record(Metric::Humidity, 60.0).unwrap();
+        second.record(Metric::Co2, 900.0).unwrap();
+        smart_room.add_smart_device(Device::EnvironmentSensor(first)).unwrap();
+        smart_room.add_smart_device(Device::EnvironmentSensor(second)).unwrap();
+
+        assert_eq!(smart_room.average(Metric::Humidity), Some(50.0));
+        assert_eq!(smart_room.average(Metric::Voc), None);
+        let environment = smart_room.environment();
+        assert_eq!(environment.len(), 2);
+        assert_eq!(environment[0].min, 40.0);
+        assert_eq!(environment[0].sensors, 2);
+    }
+
+    #[test]
+    fn occupancy() {
+        let mut smart_room = SmartRoom::default("hall".to_string());
+        let mut sensor = MotionSensor::default("motion".to_string());
+        smart_room.add_smart_device(Device::MotionSensor(sensor.clone())).unwrap();
+        assert!(!smart_room.is_occupied(0));
+
+        sensor.set_motion(true, 1_000);
+        smart_room.add_smart_device(Device::MotionSensor(sensor.clone())).unwrap();
+        assert!(smart_room.is_occupied(1_000_000));
+
+        sensor.set_motion(false, 2_000);
+        smart_room.add_smart_device(Device::MotionSensor(sensor)).unwrap();
+        smart_room.occupancy_timeout = 1_000;
+        assert!(smart_room.is_occupied(2_500));
+        assert!(!smart_room.is_occupied(3_000));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+use crate::devices::Hazard;
+use crate::events::{EventRecord, HouseEvent};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use utoipa::ToSchema;
+
+/// Notifications a subscriber may fall behind by before it's disconnected.
+pub const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// A latched alarm of a safety detector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Alarm {
+    pub room_name: String,
+    pub device_name: String,
+    pub hazard: Hazard,
+    pub since: u64,
+}
+
+/// Alarm state of the whole house.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HouseAlarm {
+    pub active: bool,
+    pub alarms: Vec<Alarm>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum Notification {
+    Event(Box<EventRecord>),
+    Alarm(Alarm),
+}
+
+/// Selects the events a subscriber gets. Empty fields match everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct EventFilter {
+    pub room: Option<String>,
+    pub device: Option<String>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &HouseEvent) -> bool {
+        let room = self
+            .room
+            .as_ref()
+            .is_none_or(|room| event.room_names().contains(&room.as_str()));
+        let device = self
+            .device
+            .as_ref()
+            .is_none_or(|device| event.device_name().as_ref() == Some(device));
+        room && device
+    }
+}
+
+/// Receivers of committed events. Subscriptions belong to one house, so a
+/// clone of it starts without any. A subscriber that lags more than
+/// [`SUBSCRIBER_CAPACITY`] notifications behind is dropped, its receiver
+/// then ends.
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    subscribers: Vec<(EventFilter, Sender<Notification>)>,
+}
+
+impl Clone for Subscribers {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self, filter: EventFilter) -> Receiver<Notification> {
+        let (sender, receiver) = channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.push((filter, sender));
+        receiver
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Sends the event to the subscribers whose filter matches it.
+    pub fn publish_event(&mut self, record: &EventRecord) {
+        self.subscribers
+            .retain(|(filter, sender)| !filter.matches(&record.event) || send(sender, Notification::Event(Box::new(record.clone()))));
+    }
+
+    /// Alarms ignore the filters and go to everybody.
+    pub fn publish_alarm(&mut self, alarm: &Alarm) {
+        self.subscribers
+            .retain(|(_, sender)| send(sender, Notification::Alarm(alarm.clone())));
+    }
+}
+
+/// Whether the subscriber keeps up and is still listening.
+fn send(sender: &Sender<Notification>, notification: Notification) -> bool {
+    match sender.try_send(notification) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            log::warn!("Dropping a subscriber lagging {} notifications behind", SUBSCRIBER_CAPACITY);
+            false
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alarms_bypass_filters() {
+        let mut subscribers = Subscribers::default();
+        let filter = EventFilter {
+            room: Some("Hall".to_string()),
+            device: None,
+        };
+        let mut hall = subscribers.subscribe(filter);
+        let mut everything = subscribers.subscribe(EventFilter::default());
+        drop(subscribers.subscribe(EventFilter::default()));
+
+        let record = EventRecord {
+            sequence: 1,
+            timestamp: 0,
+            event: HouseEvent::RoomRemoved {
+                room_name: "Kitchen".to_string(),
+            },
+        };
+        subscribers.publish_event(&record);
+        subscribers.publish_alarm(&Alarm {
+            room_name: "Kitchen".to_string(),
+            device_name: "Smoke".to_string(),
+            hazard: Hazard::Smoke,
+            since: 0,
+        });
+        assert_eq!(subscribers.len(), 2);
+
+        assert!(matches!(everything.try_recv(), Ok(Notification::Event(_))));
+        assert!(matches!(everything.try_recv(), Ok(Notification::Alarm(_))));
+        assert!(matches!(hall.try_recv(), Ok(Notification::Alarm(_))));
+        assert!(hall.try_recv().is_err());
+    }
+
+    #[test]
+    fn lagging_subscribers_are_dropped() {
+        let mut subscribers = Subscribers::default();
+        let mut lagging = subscribers.subscribe(EventFilter::default());
+        let record = EventRecord {
+            sequence: 1,
+            timestamp: 0,
+            event: HouseEvent::RoomRemoved {
+                room_name: "Kitchen".to_string(),
+            },
+        };
+        for _ in 0..SUBSCRIBER_CAPACITY {
+            subscribers.publish_event(&record);
+        }
+        assert_eq!(subscribers.len(), 1);
+        subscribers.publish_event(&record);
+        assert!(subscribers.is_empty());
+
+        // What was queued is still delivered, then the receiver ends
+        for _ in 0..SUBSCRIBER_CAPACITY {
+            assert!(lagging.try_recv().is_ok());
+        }
+        assert!(lagging.blocking_recv().is_none());
+    }
+}
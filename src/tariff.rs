@@ -0,0 +1,274 @@
+use crate::energy::{DAY_MS, HOUR_MS};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum TariffError {
+    #[error("Invalid tariff: {0}")]
+    Invalid(String),
+}
+
+/// Price per kWh from `start` (minute of the local day) until the next band.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Band {
+    pub start: u16,
+    pub price: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum Pricing {
+    Flat {
+        price: f64,
+    },
+    /// `night` applies from `night_start` until `night_end`, minutes of the local day.
+    DayNight {
+        day: f64,
+        night: f64,
+        night_start: u16,
+        night_end: u16,
+    },
+    /// The last band of the day runs on into the first one.
+    TimeOfUse {
+        bands: Vec<Band>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Tariff {
+    pub currency: String,
+    /// Offset of the local time the bands are given in.
+    pub utc_offset_minutes: i32,
+    pub pricing: Pricing,
+}
+
+impl Tariff {
+    pub fn flat(currency: String, price: f64) -> Self {
+        Self {
+            currency,
+            utc_offset_minutes: 0,
+            pricing: Pricing::Flat { price },
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), TariffError> {
+        if self.currency.trim().is_empty() {
+            return Err(TariffError::Invalid("Currency is empty".to_string()));
+        }
+        if self.utc_offset_minutes.abs() >= MINUTES_PER_DAY as i32 {
+            return Err(TariffError::Invalid(format!("UTC offset {} minutes", self.utc_offset_minutes)));
+        }
+        let (prices, minutes) = match &self.pricing {
+            Pricing::Flat { price } => (vec![*price], Vec::new()),
+            Pricing::DayNight {
+                day,
+                night,
+                night_start,
+                night_end,
+            } => (vec![*day, *night], vec![*night_start, *night_end]),
+            Pricing::TimeOfUse { bands } => {
+                if bands.is_empty() {
+                    return Err(TariffError::Invalid("No bands".to_string()));
+                }
+                if bands.windows(2).any(|pair| pair[0].start >= pair[1].start) {
+                    return Err(TariffError::Invalid("Bands must be sorted by start".to_string()));
+                }
+                (bands.iter().map(|band| band.price).collect(), bands.iter().map(|band| band.start).collect())
+            }
+        };
+        if let Some(price) = prices.iter().find(|price| !price.is_finite() || **price < 0.0) {
+            return Err(TariffError::Invalid(format!("Price {}", price)));
+        }
+        if let Some(minute) = minutes.iter().find(|minute| **minute >= MINUTES_PER_DAY) {
+            return Err(TariffError::Invalid(format!("Minute of day {}", minute)));
+        }
+        Ok(())
+    }
+
+    /// Price per kWh at `timestamp`.
+    pub fn price_at(&self, timestamp: u64) -> f64 {
+        let local = timestamp as i64 + self.utc_offset_minutes as i64 * 60_000;
+        let minute = (local.rem_euclid(DAY_MS as i64) / 60_000) as u16;
+        match &self.pricing {
+            Pricing::Flat { price } => *price,
+            Pricing::DayNight {
+                day,
+                night,
+                night_start,
+                night_end,
+            } => {
+                let is_night = if night_start <= night_end {
+                    (*night_start..*night_end).contains(&minute)
+                } else {
+                    minute >= *night_start || minute < *night_end
+                };
+                if is_night {
+                    *night
+                } else {
+                    *day
+                }
+            }
+            Pricing::TimeOfUse { bands } => bands
+                .iter()
+                .rev()
+                .find(|band| band.start <= minute)
+                .or(bands.last())
+                .map(|band| band.price)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Cost of hourly usage in watt-hours keyed by the start of the hour.
+    /// Each hour is priced at its middle.
+    pub fn cost(&self, usage: &BTreeMap<u64, f64>) -> f64 {
+        usage
+            .iter()
+            .fold(0.0, |cost, (hour, wh)| cost + wh / 1_000.0 * self.price_at(hour + HOUR_MS / 2))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeviceCost {
+    pub room_name: String,
+    pub device_name: String,
+    pub kwh: f64,
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoomCost {
+    pub room_name: String,
+    pub kwh: f64,
+    pub cost: f64,
+    pub devices: Vec<DeviceCost>,
+}
+
+/// The current month extrapolated from what it has cost so far.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MonthProjection {
+    pub month_to_date: f64,
+    pub remaining: f64,
+    pub total: f64,
+}
+
+impl MonthProjection {
+    pub fn new(month_to_date: f64, from: u64, to: u64, now: u64) -> Self {
+        let elapsed = now.clamp(from, to) - from;
+        let total = if elapsed == 0 {
+            month_to_date
+        } else {
+            month_to_date * (to - from) as f64 / elapsed as f64
+        };
+        Self {
+            month_to_date,
+            remaining: total - month_to_date,
+            total,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CostReport {
+    pub from: u64,
+    pub to: u64,
+    pub currency: String,
+    pub kwh: f64,
+    pub cost: f64,
+    pub rooms: Vec<RoomCost>,
+    pub projection: MonthProjection,
+}
+
+impl CostReport {
+    pub fn new(from: u64, to: u64, currency: String, mut rooms: Vec<RoomCost>, projection: MonthProjection) -> Self {
+        rooms.sort_by(|a, b| a.room_name.cmp(&b.room_name));
+        Self {
+            from,
+            to,
+            currency,
+            kwh: rooms.iter().fold(0.0, |kwh, room| kwh + room.kwh),
+            cost: rooms.iter().fold(0.0, |cost, room| cost + room.cost),
+            rooms,
+            projection,
+        }
+    }
+}
+
+impl Display for CostReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let currency = &self.currency;
+        writeln!(f, "Cost from {} to {}: {:.2} {} for {:.3} kWh", self.from, self.to, self.cost, currency, self.kwh)?;
+        for room in &self.rooms {
+            writeln!(f, "{}: {:.2} {} for {:.3} kWh", room.room_name, room.cost, currency, room.kwh)?;
+            for device in &room.devices {
+                writeln!(f, "  {}: {:.2} {} for {:.3} kWh", device.device_name, device.cost, currency, device.kwh)?;
+            }
+        }
+        let projection = &self.projection;
+        writeln!(
+            f,
+            "This month: {:.2} {} so far, {:.2} {} to come, {:.2} {} in total",
+            projection.month_to_date, currency, projection.remaining, currency, projection.total, currency
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_night_prices() {
+        let tariff = Tariff {
+            currency: "EUR".to_string(),
+            utc_offset_minutes: 120,
+            pricing: Pricing::DayNight {
+                day: 0.30,
+                night: 0.10,
+                night_start: 23 * 60,
+                night_end: 7 * 60,
+            },
+        };
+        tariff.validate().unwrap();
+        // 22:30 UTC is 00:30 local time
+        assert_eq!(tariff.price_at(22 * HOUR_MS + HOUR_MS / 2), 0.10);
+        assert_eq!(tariff.price_at(12 * HOUR_MS), 0.30);
+
+        let usage = BTreeMap::from([(0, 1_000.0), (12 * HOUR_MS, 2_000.0)]);
+        assert!((tariff.cost(&usage) - 0.70).abs() < 1e-9);
+    }
+
+    #[test]
+    fn time_of_use_bands_wrap() {
+        let band = |start, price| Band { start, price };
+        let tariff = Tariff {
+            currency: "USD".to_string(),
+            utc_offset_minutes: 0,
+            pricing: Pricing::TimeOfUse {
+                bands: vec![band(6 * 60, 0.2), band(17 * 60, 0.4), band(21 * 60, 0.15)],
+            },
+        };
+        tariff.validate().unwrap();
+        assert_eq!(tariff.price_at(3 * HOUR_MS), 0.15);
+        assert_eq!(tariff.price_at(18 * HOUR_MS), 0.4);
+
+        let unsorted = Tariff {
+            pricing: Pricing::TimeOfUse {
+                bands: vec![band(60, 0.2), band(0, 0.1)],
+            },
+            ..tariff
+        };
+        assert!(unsorted.validate().is_err());
+        assert!(Tariff::flat("".to_string(), 0.2).validate().is_err());
+    }
+
+    #[test]
+    fn projection() {
+        let projection = MonthProjection::new(10.0, 0, 30 * DAY_MS, 10 * DAY_MS);
+        assert_eq!(projection.total, 30.0);
+        assert_eq!(projection.remaining, 20.0);
+    }
+}
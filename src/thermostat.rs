@@ -0,0 +1,258 @@
+use crate::devices::{Demand, Device, Thermostat};
+use crate::events::HouseEvent;
+use crate::smarthouse::{SmartHouse, SmartHouseError};
+use crate::smartroom::SmartRoom;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+fn temperature(room: &SmartRoom, thermostat: &Thermostat) -> Option<f32> {
+    let sensor = thermostat.settings().sensor.as_ref()?;
+    match room.smart_device.get(sensor) {
+        Some(Device::SmartThermometr(thermometer)) => Some(thermometer.temperature()),
+        _ => None,
+    }
+}
+
+fn socket_status(room: &SmartRoom, socket: &str) -> Option<bool> {
+    match room.smart_device.get(socket) {
+        Some(Device::SmartSocket(socket)) => Some(socket.status()),
+        _ => None,
+    }
+}
+
+/// Longest simulation [`Simulation::run`] is asked for by the API.
+pub const MAX_SIMULATION_STEPS: usize = 10_000;
+
+/// Runs every thermostat of the house once: reads its thermometer and
+/// switches its heater and cooler sockets. Thermostats without a
+/// thermometer reading are left alone, as are heaters and coolers that
+/// were removed from the room since.
+pub fn regulate(house: &mut SmartHouse, now: u64) -> Result<(), SmartHouseError> {
+    for event in plan(house, now) {
+        house.commit_at(event, now)?;
+    }
+    Ok(())
+}
+
+/// The events [`regulate`] would commit. Houses with device actors pass
+/// them to [`crate::actor::actuate`] so sockets are switched on the
+/// devices before the house records it.
+pub fn plan(house: &SmartHouse, now: u64) -> Vec<HouseEvent> {
+    let mut events = Vec::new();
+    for room in house.get_rooms_list() {
+        for device in room.smart_device.values() {
+            let Device::Thermostat(thermostat) = device else {
+                continue;
+            };
+            let Some(temperature) = temperature(room, thermostat) else {
+                continue;
+            };
+            let demand = thermostat.decide(temperature, now);
+            if demand != thermostat.demand() {
+                events.push(HouseEvent::ThermostatDemandChanged {
+                    room_name: room.room_name.clone(),
+                    device_name: thermostat.name.clone(),
+                    demand,
+                });
+            }
+            let settings = thermostat.settings();
+            let outputs = [(&settings.heater, Demand::Heating), (&settings.cooler, Demand::Cooling)];
+            for (socket, wanted) in outputs {
+                let Some(socket) = socket else {
+                    continue;
+                };
+                let status = demand == wanted;
+                match socket_status(room, socket) {
+                    Some(current) if current != status => events.push(HouseEvent::SocketSwitched {
+                        room_name: room.room_name.clone(),
+                        device_name: socket.clone(),
+                        status,
+                    }),
+                    Some(_) => {}
+                    None => log::warn!(
+                        "Socket {} of thermostat {} in {} is missing",
+                        socket,
+                        thermostat.name,
+                        room.room_name
+                    ),
+                }
+            }
+        }
+    }
+    events
+}
+
+/// Simple thermal model of a room: the heater or cooler changes the
+/// temperature by `power` degrees per minute while the room loses `loss`
+/// of its difference to the `ambient` temperature every minute.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SimulationModel {
+    pub ambient: f32,
+    pub power: f32,
+    pub loss: f32,
+}
+
+impl Default for SimulationModel {
+    fn default() -> Self {
+        Self {
+            ambient: 10.0,
+            power: 0.5,
+            loss: 0.02,
+        }
+    }
+}
+
+/// Drives a thermostat against [`SimulationModel`] in simulated time,
+/// on a house of its own that holds only the room of the thermostat.
+pub struct Simulation {
+    pub house: SmartHouse,
+    pub room_name: String,
+    pub thermostat: String,
+    pub model: SimulationModel,
+    /// Simulated time, milliseconds since the unix epoch.
+    pub now: u64,
+}
+
+impl Simulation {
+    pub fn new(room: SmartRoom, thermostat: &str, model: SimulationModel, now: u64) -> Result<Simulation, SmartHouseError> {
+        let room_name = room.room_name.clone();
+        let mut house = SmartHouse::new("Simulation".to_string());
+        house.add_smart_room(&room)?;
+        Ok(Simulation {
+            house,
+            room_name,
+            thermostat: thermostat.to_string(),
+            model,
+            now,
+        })
+    }
+
+    /// Advances the simulation by `minutes` and returns the new temperature.
+    pub fn step(&mut self, minutes: u32) -> Result<f32, SmartHouseError> {
+        let room = self
+            .house
+            .get_room_by_name(&self.room_name)
+            .ok_or_else(|| SmartHouseError::RoomNotFound(self.room_name.clone()))?;
+        let thermostat = match room.smart_device.get(&self.thermostat) {
+            Some(Device::Thermostat(thermostat)) => thermostat,
+            _ => return Err(SmartHouseError::DeviceNotFound(format!("Thermostat: {}", self.thermostat))),
+        };
+        let settings = thermostat.settings().clone();
+        let sensor = settings
+            .sensor
+            .clone()
+            .ok_or_else(|| SmartHouseError::DeviceNotFound(format!("Thermometer of {}", self.thermostat)))?;
+        let temperature =
+            temperature(room, thermostat).ok_or_else(|| SmartHouseError::DeviceNotFound(sensor.clone()))?;
+        let heating = settings.heater.as_deref().and_then(|socket| socket_status(room, socket)) == Some(true);
+        let cooling = settings.cooler.as_deref().and_then(|socket| socket_status(room, socket)) == Some(true);
+
+        let mut change = -self.model.loss * (temperature - self.model.ambient);
+        if heating {
+            change += self.model.power;
+        }
+        if cooling {
+            change -= self.model.power;
+        }
+        let temperature = temperature + change * minutes as f32;
+
+        self.now += minutes as u64 * 60_000;
+        self.house.commit_at(
+            HouseEvent::ReadingRecorded {
+                room_name: self.room_name.clone(),
+                device_name: sensor,
+                value: temperature,
+            },
+            self.now,
+        )?;
+        regulate(&mut self.house, self.now)?;
+        Ok(temperature)
+    }
+
+    /// Temperatures after each of `steps` steps.
+    pub fn run(&mut self, steps: usize, minutes: u32) -> Result<Vec<f32>, SmartHouseError> {
+        (0..steps).map(|_| self.step(minutes)).collect()
+    }
+}
+
+/// Whether the second half of `trace` stays within `tolerance` of `target`.
+pub fn converged(trace: &[f32], target: f32, tolerance: f32) -> bool {
+    !trace.is_empty()
+        && trace[trace.len() / 2..]
+            .iter()
+            .all(|temperature| (temperature - target).abs() <= tolerance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::*;
+
+    fn heated_room(mode: ThermostatMode, temperature: f32) -> SmartHouse {
+        let mut house = SmartHouse::new("House".to_string());
+        let mut room = SmartRoom::default("Bedroom".to_string());
+        room.add_smart_device(Device::SmartThermometr(SmartThermometer::default("Thermo".to_string()))).unwrap();
+        room.add_smart_device(Device::SmartSocket(SmartSocket::default("Heater".to_string()))).unwrap();
+        room.add_smart_device(Device::SmartSocket(SmartSocket::default("Fan".to_string()))).unwrap();
+        room.add_smart_device(Device::Thermostat(Thermostat::default("Thermostat".to_string()))).unwrap();
+        house.add_smart_room(&room).unwrap();
+        house.record_reading("Bedroom", "Thermo", temperature).unwrap();
+        let settings = ThermostatSettings {
+            mode,
+            target: 21.0,
+            hysteresis: 0.5,
+            sensor: Some("Thermo".to_string()),
+            heater: Some("Heater".to_string()),
+            cooler: Some("Fan".to_string()),
+            ..ThermostatSettings::default()
+        };
+        house.configure_thermostat("Bedroom", "Thermostat", settings).unwrap();
+        house
+    }
+
+    fn socket(house: &SmartHouse, name: &str) -> bool {
+        socket_status(house.get_room_by_name("Bedroom").unwrap(), name).unwrap()
+    }
+
+    #[test]
+    fn regulate_switches_heater() {
+        let mut house = heated_room(ThermostatMode::Heat, 15.0);
+        regulate(&mut house, 0).unwrap();
+        assert!(socket(&house, "Heater"));
+        assert!(!socket(&house, "Fan"));
+
+        house.record_reading("Bedroom", "Thermo", 25.0).unwrap();
+        regulate(&mut house, 0).unwrap();
+        assert!(!socket(&house, "Heater"));
+        assert!(!socket(&house, "Fan"));
+    }
+
+    #[test]
+    fn removed_socket_is_skipped() {
+        let mut house = heated_room(ThermostatMode::Heat, 15.0);
+        house.remove_device("Bedroom", "Fan").unwrap();
+        regulate(&mut house, 0).unwrap();
+        assert!(socket(&house, "Heater"));
+        assert!(plan(&house, 0).is_empty());
+    }
+
+    #[test]
+    fn wiring_must_be_in_the_room() {
+        let mut house = heated_room(ThermostatMode::Heat, 15.0);
+        let settings = ThermostatSettings {
+            heater: Some("Thermo".to_string()),
+            ..ThermostatSettings::default()
+        };
+        assert!(house.configure_thermostat("Bedroom", "Thermostat", settings).is_err());
+    }
+
+    #[test]
+    fn simulation_converges() {
+        for (mode, start) in [(ThermostatMode::Heat, 12.0), (ThermostatMode::Auto, 30.0)] {
+            let room = heated_room(mode, start).get_room_by_name("Bedroom").cloned().unwrap();
+            let mut simulation = Simulation::new(room, "Thermostat", SimulationModel::default(), 0).unwrap();
+            let trace = simulation.run(240, 1).unwrap();
+            assert!(converged(&trace, 21.0, 1.0), "{:?}", trace);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use crate::devices::{CoverMotion, CoverState, Device, LightState};
+use crate::registry::SharedHouse;
+use crate::smarthouse::{SmartHouse, SmartHouseError};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const FADE_STEP: Duration = Duration::from_millis(100);
+pub const TRAVEL_STEP: Duration = Duration::from_millis(250);
+
+/// Fades running per light. A fade holds the ticket it got when it
+/// started and stops once a newer fade of the light took over.
+#[derive(Debug, Clone, Default)]
+pub struct Fades {
+    running: HashMap<(String, String), Fade>,
+    last_ticket: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    ticket: u64,
+    /// Shows a state that isn't committed.
+    previewed: bool,
+}
+
+impl Fades {
+    /// Starts a fade of the light. Returns its ticket and whether the fade
+    /// it replaces showed a state that isn't committed.
+    pub(crate) fn start(&mut self, room_name: &str, device_name: &str) -> (u64, bool) {
+        self.last_ticket += 1;
+        let fade = Fade {
+            ticket: self.last_ticket,
+            previewed: false,
+        };
+        let replaced = self.running.insert((room_name.to_string(), device_name.to_string()), fade);
+        (fade.ticket, replaced.is_some_and(|fade| fade.previewed))
+    }
+
+    pub(crate) fn is_running(&self, room_name: &str, device_name: &str, ticket: u64) -> bool {
+        self.running
+            .get(&(room_name.to_string(), device_name.to_string()))
+            .is_some_and(|fade| fade.ticket == ticket)
+    }
+
+    pub(crate) fn previewed(&mut self, room_name: &str, device_name: &str, ticket: u64) {
+        if let Some(fade) = self.running.get_mut(&(room_name.to_string(), device_name.to_string())) {
+            fade.previewed |= fade.ticket == ticket;
+        }
+    }
+
+    pub(crate) fn end(&mut self, room_name: &str, device_name: &str, ticket: u64) {
+        if self.is_running(room_name, device_name, ticket) {
+            self.running.remove(&(room_name.to_string(), device_name.to_string()));
+        }
+    }
+}
+
+fn light_state(house: &SmartHouse, room_name: &str, device_name: &str) -> Result<LightState, SmartHouseError> {
+    let room = house
+        .get_room_by_name(room_name)
+        .ok_or_else(|| SmartHouseError::RoomNotFound(room_name.to_string()))?;
+    match room.get_device(device_name.to_string()) {
+        Some(Device::SmartLight(light)) => Ok(*light.state()),
+        _ => Err(SmartHouseError::DeviceNotFound(format!("Light: {}", device_name))),
+    }
+}
+
+/// Fades a light to `target` over `duration`, showing an intermediate
+/// state every [`FADE_STEP`]. Only the target is committed to the history.
+/// A fade of the light still running is cancelled and the state it showed
+/// last is committed. Stops early if somebody else changes the light in
+/// the meantime.
+pub async fn fade(
+    house: SharedHouse,
+    room_name: String,
+    device_name: String,
+    target: LightState,
+    duration: Duration,
+) -> Result<(), SmartHouseError> {
+    target.validate()?;
+    let (from, ticket) = {
+        let mut house = house.write().await;
+        let ticket = house.start_fade(&room_name, &device_name)?;
+        if duration.is_zero() {
+            house.end_fade(&room_name, &device_name, ticket);
+            return house.set_light(&room_name, &device_name, target);
+        }
+        (light_state(&house, &room_name, &device_name)?, ticket)
+    };
+    let steps = (duration.as_millis() / FADE_STEP.as_millis()).max(1) as u32;
+    let mut interval = tokio::time::interval(duration / steps);
+    interval.tick().await;
+
+    let mut last = from;
+    for step in 1..=steps {
+        interval.tick().await;
+        let mut house = house.write().await;
+        if !house.is_fading(&room_name, &device_name, ticket) {
+            log::info!("Fade of {} in {} was replaced", device_name, room_name);
+            return Ok(());
+        }
+        let current = light_state(&house, &room_name, &device_name);
+        if step == steps || current.as_ref().ok() != Some(&last) {
+            house.end_fade(&room_name, &device_name, ticket);
+        }
+        if current? != last {
+            // Whoever changed the light committed the change
+            log::info!("Fade of {} in {} was interrupted", device_name, room_name);
+            return Ok(());
+        }
+        if step == steps {
+            return house.set_light(&room_name, &device_name, target);
+        }
+        let state = from.interpolate(&target, step as f32 / steps as f32);
+        house.preview_light(&room_name, &device_name, ticket, state)?;
+        last = state;
+    }
+    Ok(())
+}
+
+/// Moves a cover to `position` at the speed given by its calibration,
+/// reporting the intermediate position every [`TRAVEL_STEP`]. Like a fade
+/// it stops once somebody else changes the cover, e.g. a stop command.
+pub async fn travel(
+    house: SharedHouse,
+    room_name: String,
+    device_name: String,
+    position: u8,
+) -> Result<(), SmartHouseError> {
+    let (from, duration) = {
+        let mut house = house.write().await;
+        let cover = house.get_cover(&room_name, &device_name)?;
+        let from = *cover.state();
+        let duration = Duration::from_millis(cover.travel_time(position));
+        if duration.is_zero() {
+            // Already there, or an uncalibrated cover that moves at once
+            let state = CoverState { position, motion: CoverMotion::Stopped, ..from };
+            return house.set_cover(&room_name, &device_name, state);
+        }
+        let motion = match position.cmp(&from.position) {
+            std::cmp::Ordering::Greater => CoverMotion::Opening,
+            std::cmp::Ordering::Less => CoverMotion::Closing,
+            std::cmp::Ordering::Equal => CoverMotion::Stopped,
+        };
+        let state = CoverState { motion, ..from };
+        house.set_cover(&room_name, &device_name, state)?;
+        (state, duration)
+    };
+    let steps = (duration.as_millis() / TRAVEL_STEP.as_millis()).max(1) as u32;
+    let mut interval = tokio::time::interval(duration / steps);
+    interval.tick().await;
+
+    let mut last = from;
+    for step in 1..=steps {
+        interval.tick().await;
+        let mut house = house.write().await;
+        if *house.get_cover(&room_name, &device_name)?.state() != last {
+            log::info!("Travel of {} in {} was interrupted", device_name, room_name);
+            return Ok(());
+        }
+        let distance = (position as f32 - from.position as f32) * step as f32 / steps as f32;
+        let state = CoverState {
+            position: (from.position as f32 + distance).round() as u8,
+            motion: if step == steps { CoverMotion::Stopped } else { from.motion },
+            ..from
+        };
+        house.set_cover(&room_name, &device_name, state)?;
+        last = state;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{Cover, CoverKind, SmartLight};
+    use crate::events::HouseEvent;
+    use crate::smartroom::SmartRoom;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn house_with_light() -> SharedHouse {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Hall", Device::SmartLight(SmartLight::default("Lamp".to_string()))).unwrap();
+        Arc::new(RwLock::new(house))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fade_in() {
+        let house = house_with_light();
+        let target = LightState {
+            status: true,
+            brightness: 60,
+            ..LightState::default()
+        };
+        let task = tokio::spawn(fade(house.clone(), "Hall".to_string(), "Lamp".to_string(), target, Duration::from_secs(1)));
+
+        tokio::time::sleep(Duration::from_millis(550)).await;
+        let halfway = light_state(&*house.read().await, "Hall", "Lamp").unwrap();
+        assert!(halfway.status);
+        assert!(halfway.brightness > 0 && halfway.brightness < 60);
+
+        task.await.unwrap().unwrap();
+        let house = house.read().await;
+        assert_eq!(light_state(&house, "Hall", "Lamp").unwrap(), target);
+        // Only the target is in the history
+        let events = house.history().records();
+        assert!(matches!(events.last().unwrap().event, HouseEvent::LightChanged { state, .. } if state == target));
+        assert_eq!(events.iter().filter(|record| matches!(record.event, HouseEvent::LightChanged { .. })).count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fade_without_duration() {
+        let house = house_with_light();
+        let target = LightState {
+            status: true,
+            ..LightState::default()
+        };
+        fade(house.clone(), "Hall".to_string(), "Lamp".to_string(), target, Duration::ZERO).await.unwrap();
+        assert_eq!(light_state(&*house.read().await, "Hall", "Lamp").unwrap(), target);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fade_is_interrupted() {
+        let house = house_with_light();
+        let target = LightState {
+            status: true,
+            ..LightState::default()
+        };
+        let task = tokio::spawn(fade(house.clone(), "Hall".to_string(), "Lamp".to_string(), target, Duration::from_secs(1)));
+
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        house.write().await.set_light("Hall", "Lamp", LightState::default()).unwrap();
+        task.await.unwrap().unwrap();
+        assert_eq!(light_state(&*house.read().await, "Hall", "Lamp").unwrap(), LightState::default());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn newer_fade_takes_over() {
+        let house = house_with_light();
+        let bright = LightState {
+            status: true,
+            brightness: 100,
+            ..LightState::default()
+        };
+        let first = tokio::spawn(fade(house.clone(), "Hall".to_string(), "Lamp".to_string(), bright, Duration::from_secs(1)));
+        tokio::time::sleep(Duration::from_millis(450)).await;
+        let shown = light_state(&*house.read().await, "Hall", "Lamp").unwrap();
+
+        // The state the first fade showed last is committed before the second one starts
+        let second = tokio::spawn(fade(house.clone(), "Hall".to_string(), "Lamp".to_string(), LightState::default(), Duration::from_secs(1)));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        {
+            let house = house.read().await;
+            assert!(matches!(house.history().records().last().unwrap().event, HouseEvent::LightChanged { state, .. } if state == shown));
+        }
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+
+        let house = house.read().await;
+        let replayed = SmartHouse::from_log("House".to_string(), house.history().clone()).unwrap();
+        assert_eq!(light_state(&house, "Hall", "Lamp").unwrap(), LightState::default());
+        assert_eq!(light_state(&replayed, "Hall", "Lamp").unwrap(), LightState::default());
+        let changes = house.history().records().iter().filter(|record| matches!(record.event, HouseEvent::LightChanged { .. }));
+        assert_eq!(changes.count(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cover_travel() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Hall", Device::Cover(Cover::new("Blind".to_string(), CoverKind::Blind))).unwrap();
+        house.calibrate_cover("Hall", "Blind", 2_000).unwrap();
+        let house = Arc::new(RwLock::new(house));
+        let state = |house: &SmartHouse| *house.get_cover("Hall", "Blind").unwrap().state();
+
+        let task = tokio::spawn(travel(house.clone(), "Hall".to_string(), "Blind".to_string(), 100));
+        tokio::time::sleep(Duration::from_millis(1_100)).await;
+        let halfway = state(&*house.read().await);
+        assert_eq!(halfway.motion, CoverMotion::Opening);
+        assert!(halfway.position > 40 && halfway.position < 60);
+
+        task.await.unwrap().unwrap();
+        assert_eq!(state(&*house.read().await).position, 100);
+        assert_eq!(state(&*house.read().await).motion, CoverMotion::Stopped);
+
+        let task = tokio::spawn(travel(house.clone(), "Hall".to_string(), "Blind".to_string(), 0));
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        {
+            let mut house = house.write().await;
+            let stopped = CoverState {
+                motion: CoverMotion::Stopped,
+                ..state(&house)
+            };
+            house.set_cover("Hall", "Blind", stopped).unwrap();
+        }
+        task.await.unwrap().unwrap();
+        let stopped = state(&*house.read().await);
+        assert!(stopped.position > 60 && stopped.position < 80);
+        assert_eq!(stopped.motion, CoverMotion::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn travel_to_current_position() {
+        let mut house = SmartHouse::new("House".to_string());
+        house.add_smart_room(&SmartRoom::default("Hall".to_string())).unwrap();
+        house.add_device("Hall", Device::Cover(Cover::new("Blind".to_string(), CoverKind::Blind))).unwrap();
+        house.calibrate_cover("Hall", "Blind", 2_000).unwrap();
+        let position = house.get_cover("Hall", "Blind").unwrap().state().position;
+        let house = Arc::new(RwLock::new(house));
+
+        travel(house.clone(), "Hall".to_string(), "Blind".to_string(), position).await.unwrap();
+        let state = *house.read().await.get_cover("Hall", "Blind").unwrap().state();
+        assert_eq!(state.position, position);
+        assert_eq!(state.motion, CoverMotion::Stopped);
+    }
+}
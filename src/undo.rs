@@ -0,0 +1,219 @@
+use crate::events::{HouseEvent, HouseSettings};
+use crate::smartroom::SmartRoom;
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_UNDO_DEPTH: usize = 50;
+
+/// Device ids of every given room, `None` for rooms that don't exist.
+pub type Topology = Vec<Option<Vec<String>>>;
+
+/// An edit of the topology or the settings of the house together with the
+/// event that reverts it.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub event: HouseEvent,
+    pub inverse: HouseEvent,
+    pub rooms: Vec<String>,
+    pub before: Topology,
+    pub after: Topology,
+}
+
+impl Edit {
+    /// Describes `event` against the state it is about to be applied to.
+    /// Returns `None` for events that can't be undone.
+    pub fn prepare(smart_rooms: &HashMap<String, SmartRoom>, settings: &HouseSettings, event: &HouseEvent) -> Option<Edit> {
+        let inverse = inverse(smart_rooms, settings, event)?;
+        let rooms = affected_rooms(event);
+        let before = topology(smart_rooms, &rooms);
+        Some(Edit {
+            event: event.clone(),
+            inverse,
+            after: before.clone(),
+            rooms,
+            before,
+        })
+    }
+}
+
+pub fn topology(smart_rooms: &HashMap<String, SmartRoom>, rooms: &[String]) -> Topology {
+    rooms
+        .iter()
+        .map(|room_name| {
+            smart_rooms.get(room_name).map(|room| {
+                let mut devices: Vec<String> = room.smart_device.values().map(|device| device.id().to_string()).collect();
+                devices.sort();
+                devices
+            })
+        })
+        .collect()
+}
+
+fn affected_rooms(event: &HouseEvent) -> Vec<String> {
+    match event {
+        HouseEvent::RoomAdded(room) => vec![room.room_name.clone()],
+        HouseEvent::RoomRemoved { room_name }
+        | HouseEvent::DeviceAdded { room_name, .. }
+        | HouseEvent::DeviceRemoved { room_name, .. }
+        | HouseEvent::DeviceRestored { room_name, .. }
+        | HouseEvent::RoomPlaced { room_name, .. } => vec![room_name.clone()],
+        HouseEvent::DeviceMoved { from, to, .. } => vec![from.clone(), to.clone()],
+        HouseEvent::RoomRenamed { room_name, new_name } => vec![room_name.clone(), new_name.clone()],
+        _ => Vec::new(),
+    }
+}
+
+fn inverse(smart_rooms: &HashMap<String, SmartRoom>, settings: &HouseSettings, event: &HouseEvent) -> Option<HouseEvent> {
+    match event {
+        HouseEvent::RoomAdded(room) => Some(match smart_rooms.get(&room.room_name) {
+            Some(old) => HouseEvent::RoomAdded(old.clone()),
+            None => HouseEvent::RoomRemoved {
+                room_name: room.room_name.clone(),
+            },
+        }),
+        HouseEvent::RoomRemoved { room_name } => smart_rooms
+            .get(room_name)
+            .map(|old| HouseEvent::RoomAdded(old.clone())),
+        HouseEvent::DeviceAdded { room_name, device } => {
+            let device_name = device.device_name().ok()?;
+            let room = smart_rooms.get(room_name)?;
+            Some(match room.smart_device.get(&device_name) {
+                Some(old) => HouseEvent::DeviceAdded {
+                    room_name: room_name.clone(),
+                    device: old.clone(),
+                },
+                None => HouseEvent::DeviceRemoved {
+                    room_name: room_name.clone(),
+                    device_name,
+                },
+            })
+        }
+        HouseEvent::DeviceRemoved { room_name, device_name } => {
+            let room = smart_rooms.get(room_name)?;
+            Some(HouseEvent::DeviceRestored {
+                room_name: room_name.clone(),
+                device: room.smart_device.get(device_name)?.clone(),
+                tags: room.device_tags.get(device_name).cloned().unwrap_or_default(),
+                heartbeat: room.heartbeats.get(device_name).cloned(),
+            })
+        }
+        HouseEvent::DeviceRestored { room_name, device, .. } => Some(HouseEvent::DeviceRemoved {
+            room_name: room_name.clone(),
+            device_name: device.device_name().ok()?,
+        }),
+        HouseEvent::DeviceMoved { from, to, device_name } => Some(HouseEvent::DeviceMoved {
+            from: to.clone(),
+            to: from.clone(),
+            device_name: device_name.clone(),
+        }),
+        HouseEvent::RoomRenamed { room_name, new_name } => Some(HouseEvent::RoomRenamed {
+            room_name: new_name.clone(),
+            new_name: room_name.clone(),
+        }),
+        HouseEvent::RoomPlaced { room_name, .. } => Some(HouseEvent::RoomPlaced {
+            room_name: room_name.clone(),
+            area: smart_rooms.get(room_name)?.area.clone(),
+        }),
+        HouseEvent::TariffSet { .. } => Some(HouseEvent::TariffSet {
+            tariff: settings.tariff.clone(),
+        }),
+        HouseEvent::GroupSet { group } => Some(match settings.groups.get(&group.name) {
+            Some(old) => HouseEvent::GroupSet { group: old.clone() },
+            None => HouseEvent::GroupRemoved {
+                name: group.name.clone(),
+            },
+        }),
+        HouseEvent::GroupRemoved { name } => settings
+            .groups
+            .get(name)
+            .map(|old| HouseEvent::GroupSet { group: old.clone() }),
+        HouseEvent::AreaSet { area } => Some(match settings.areas.get(&area.name) {
+            Some(old) => HouseEvent::AreaSet { area: old.clone() },
+            None => HouseEvent::AreaRemoved {
+                name: area.name.clone(),
+            },
+        }),
+        HouseEvent::AreaRemoved { name } => settings
+            .areas
+            .get(name)
+            .map(|old| HouseEvent::AreaSet { area: old.clone() }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UndoStack {
+    depth: usize,
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl Default for UndoStack {
+    fn default() -> Self {
+        Self::new(DEFAULT_UNDO_DEPTH)
+    }
+}
+
+impl UndoStack {
+    pub fn new(depth: usize) -> UndoStack {
+        UndoStack {
+            depth,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    /// A smaller depth also forgets everything that could be redone.
+    pub fn set_depth(&mut self, depth: usize) {
+        if depth < self.depth {
+            self.redo.clear();
+        }
+        self.depth = depth;
+        while self.undo.len() > depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Records a new edit. Anything that could be redone is forgotten.
+    pub fn push(&mut self, edit: Edit) {
+        self.redo.clear();
+        self.push_undo(edit);
+    }
+
+    pub fn push_undo(&mut self, edit: Edit) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.undo.len() == self.depth {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+    }
+
+    pub fn push_redo(&mut self, edit: Edit) {
+        self.redo.push(edit);
+    }
+
+    pub fn last_undo(&self) -> Option<&Edit> {
+        self.undo.back()
+    }
+
+    pub fn last_redo(&self) -> Option<&Edit> {
+        self.redo.last()
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Edit> {
+        self.undo.pop_back()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Edit> {
+        self.redo.pop()
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+}